@@ -189,7 +189,8 @@ class MainActivity : AppCompatActivity(), BluetoothTransport.Listener, WifiDirec
                     }
                     5 -> { // File offered
                         addChat("[File] ${senderName} offers: $data (${formatSize(value)})")
-                        showFileOfferDialog(senderName ?: "?", data ?: "?", value, extra ?: "")
+                        showFileOfferDialog(senderName ?: "?", data ?: "?", value, extra ?: "",
+                            intent.getByteArrayExtra("binary_data"))
                     }
                     6 -> { // File progress
                         addChat("[File] Transfer: ${value}%")
@@ -316,6 +317,56 @@ class MainActivity : AppCompatActivity(), BluetoothTransport.Listener, WifiDirec
                     31 -> { // HistoryLoaded
                         // Could populate chat from stored messages
                     }
+                    32 -> { // FileDeclined
+                        addChat("[File] Offer declined")
+                    }
+                    33 -> { // FileCancelled
+                        addChat("[File] Transfer cancelled: $data")
+                    }
+                    34 -> { // FileFailed
+                        addChat("[File] Transfer failed: $data")
+                    }
+                    35 -> { // FileFetchStarted
+                        addChat("[File] Fetching from swarm: $data (${formatSize(value)})")
+                    }
+                    36 -> { // FileAutoAccepted
+                        addChat("[File] Auto-accepted from ${senderName}: $data")
+                    }
+                    37 -> { // FileAutoRejected
+                        addChat("[File] Auto-rejected from ${senderName}: $data")
+                    }
+                    38 -> { // BundleOffered
+                        val manifest = JSONObject(data ?: "{}")
+                        val name = manifest.optString("name", "bundle")
+                        val entries = manifest.optJSONArray("entries") ?: JSONArray()
+                        addChat("[File] ${senderName} offers $name: ${entries.length()} files (${formatSize(value)})")
+                        showBundleOfferDialog(senderName ?: "?", name, entries, extra ?: "")
+                    }
+                    39 -> { // RouteFound
+                        addChat("[Route] ${nodeId?.take(8)}: $value hop(s)")
+                    }
+                    40 -> { // NoRoute
+                        addChat("[Route] No route to ${nodeId?.take(8)}")
+                    }
+                    41, 42 -> { // PingResult, TraceResult
+                        val result = JSONObject(data ?: "{}")
+                        val hops = result.optJSONArray("hops") ?: JSONArray()
+                        val label = if (eventType == 41) "Ping" else "Trace"
+                        val status = if (result.optBoolean("reached")) "reached" else "not reached"
+                        addChat("[$label] ${nodeId?.take(8)}: $status, ${hops.length()} hop(s)")
+                        for (i in 0 until hops.length()) {
+                            val hop = hops.getJSONObject(i)
+                            val id = if (hop.isNull("node_id")) "* (no answer)" else hop.getString("node_id").take(8)
+                            val rtt = if (hop.isNull("rtt_ms")) "" else " ${hop.getLong("rtt_ms")}ms"
+                            addChat("  ${i + 1}. $id$rtt")
+                        }
+                    }
+                    43 -> { // Topology
+                        val map = JSONObject(data ?: "{}")
+                        val nodes = map.optJSONArray("nodes") ?: JSONArray()
+                        val links = map.optJSONArray("links") ?: JSONArray()
+                        addChat("[Map] ${nodes.length()} node(s), ${links.length()} link(s)")
+                    }
                 }
             }
         }
@@ -1429,15 +1480,53 @@ class MainActivity : AppCompatActivity(), BluetoothTransport.Listener, WifiDirec
             .show()
     }
 
-    private fun showFileOfferDialog(sender: String, filename: String, size: Long, fileIdHex: String) {
-        AlertDialog.Builder(this)
+    private fun showFileOfferDialog(sender: String, filename: String, size: Long, fileIdHex: String, preview: ByteArray?) {
+        val builder = AlertDialog.Builder(this)
+        preview?.let { BitmapFactory.decodeByteArray(it, 0, it.size) }?.let { bitmap ->
+            builder.setView(ImageView(this).apply {
+                setImageBitmap(bitmap)
+                adjustViewBounds = true
+                setPadding(32, 16, 32, 0)
+            })
+        }
+        builder
             .setTitle("File Offer")
             .setMessage("$sender wants to send:\n$filename (${formatSize(size)})")
             .setPositiveButton("Accept") { _, _ ->
                 MeshBridge.meshAcceptFile(fileIdHex)
                 addChat("[File] Accepted: $filename")
             }
-            .setNegativeButton("Decline", null)
+            .setNegativeButton("Decline") { _, _ ->
+                MeshBridge.meshDeclineFile(fileIdHex)
+                addChat("[File] Declined: $filename")
+            }
+            .show()
+    }
+
+    private fun showBundleOfferDialog(sender: String, name: String, entries: JSONArray, fileIdHex: String) {
+        val labels = Array(entries.length()) { i ->
+            val e = entries.getJSONObject(i)
+            "${e.optString("path")} (${formatSize(e.optLong("size"))})"
+        }
+        val checked = BooleanArray(labels.size) { true }
+        AlertDialog.Builder(this)
+            .setTitle("$sender wants to send $name")
+            .setMultiChoiceItems(labels, checked) { _, which, isChecked -> checked[which] = isChecked }
+            .setPositiveButton("Accept") { _, _ ->
+                val selected = checked.indices.filter { checked[it] }
+                if (selected.size == labels.size) {
+                    MeshBridge.meshAcceptFile(fileIdHex)
+                } else if (selected.isNotEmpty()) {
+                    MeshBridge.meshAcceptFileEntries(fileIdHex, selected.joinToString(","))
+                } else {
+                    MeshBridge.meshDeclineFile(fileIdHex)
+                }
+                addChat("[File] Accepted ${selected.size} of ${labels.size} files from $name")
+            }
+            .setNegativeButton("Decline") { _, _ ->
+                MeshBridge.meshDeclineFile(fileIdHex)
+                addChat("[File] Declined: $name")
+            }
             .show()
     }
 
@@ -1809,6 +1898,12 @@ class MainActivity : AppCompatActivity(), BluetoothTransport.Listener, WifiDirec
                         activeInterface = "$type ($ip)"
                     }
                 }
+                if (obj.has("avg_etx") && obj.optInt("total_peers") > 0) {
+                    val rtt = obj.optDouble("avg_rtt_ms").toInt()
+                    val jitter = obj.optDouble("avg_jitter_ms").toInt()
+                    val loss = (obj.optDouble("avg_loss") * 100).toInt()
+                    sb.appendLine("Links: ${rtt} ms RTT, ±${jitter} ms jitter, ${loss}% loss")
+                }
                 // Update connectivity display in settings
                 settingsLayout.findViewWithTag<TextView>("connectivity_info")?.text =
                     if (sb.isEmpty()) "No interfaces detected" else sb.toString()
@@ -19,7 +19,30 @@ object MeshBridge {
 
     // --- File Transfer ---
     external fun meshSendFile(destHex: String, filePath: String): Int
+    external fun meshSendFileToGroup(groupName: String, filePath: String): Int
+    external fun meshSendFileBroadcast(filePath: String): Int
     external fun meshAcceptFile(fileIdHex: String): Int
+    /** entries: comma-separated bundle manifest indices ("0,2,5"). */
+    external fun meshAcceptFileEntries(fileIdHex: String, entries: String): Int
+    /** paths: newline-separated files and/or directories. */
+    external fun meshSendBundle(destHex: String, paths: String): Int
+    external fun meshDeclineFile(fileIdHex: String): Int
+    external fun meshCancelFile(fileIdHex: String): Int
+    external fun meshFetchFile(contentHashHex: String): Int
+    external fun meshSetFavorite(nodeIdHex: String, favorite: Int): Int
+    external fun meshSetVerified(nodeIdHex: String, verified: Int): Int
+    /** Answered asynchronously with a route_found (39) or no_route (40) event. */
+    external fun meshDiscoverRoute(nodeIdHex: String): Int
+    /** Answered with ping_result (41) / trace_result (42); data is the hop list as JSON. */
+    external fun meshPingNode(nodeIdHex: String): Int
+    external fun meshTraceroute(nodeIdHex: String): Int
+    /** mode: 0 = full flood, 1 = gossip, 2 = counter, 3 = neighbor coverage. SOS always floods. */
+    external fun meshSetFloodStrategy(mode: Int, probability: Float, threshold: Int, maxDelayMs: Int): Int
+    /** allowedTypes/blockedExtensions: comma-separated ("pdf,image/*"); sizes <= 0 = no limit. */
+    external fun meshSetFilePolicy(
+        acceptFavorites: Int, acceptVerified: Int, maxAutoSize: Long,
+        allowedTypes: String?, blockedExtensions: String?, minFreeDisk: Long
+    ): Int
 
     // --- Voice ---
     external fun meshSendVoice(destHex: String?, audioData: ByteArray, durationMs: Int): Int
@@ -41,6 +64,8 @@ object MeshBridge {
     external fun meshStop(): Int
     external fun meshGetStats(): Int
     external fun meshGetPeersList(): Int
+    /** Answered with topology (43): data = mesh map JSON, extra = Graphviz DOT. */
+    external fun meshGetTopology(): Int
 
     /**
      * Event types from the native mesh engine.
@@ -60,7 +85,9 @@ object MeshBridge {
         val value: Long,
         val float1: Double,
         val float2: Double,
-        val binaryData: ByteArray?
+        val binaryData: ByteArray?,
+        /** MIME type of binaryData when it is a file offer thumbnail. */
+        val binaryMime: String?
     ) {
         val isFileOffered get() = eventType == 5
         val isFileProgress get() = eventType == 6
@@ -74,6 +101,9 @@ object MeshBridge {
         val filename get() = data
         val fileSize get() = value
         val progressPct get() = value.toInt()
+        val bytesPerSec get() = float1.toLong()
+        /** Seconds remaining, or -1 when no estimate is available yet. */
+        val etaSecs get() = float2.toInt()
         val durationMs get() = value.toInt()
         val latitude get() = float1
         val longitude get() = float2
@@ -180,6 +180,7 @@ class MeshService : Service() {
             putExtra("float1", event.float1)
             putExtra("float2", event.float2)
             event.binaryData?.let { putExtra("binary_data", it) }
+            event.binaryMime?.let { putExtra("binary_mime", it) }
         }
         LocalBroadcastManager.getInstance(this).sendBroadcast(intent)
     }
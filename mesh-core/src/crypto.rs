@@ -1,6 +1,6 @@
 use x25519_dalek::{PublicKey, StaticSecret};
 use chacha20poly1305::{
-    aead::{Aead, KeyInit},
+    aead::{Aead, KeyInit, Payload},
     ChaCha20Poly1305, Nonce,
 };
 use rand::rngs::OsRng;
@@ -14,6 +14,8 @@ pub struct SessionKeys {
     shared_key: [u8; 32],
     /// Our public key for this session.
     pub our_public: [u8; 32],
+    /// The peer's public key for this session.
+    pub their_public: [u8; 32],
 }
 
 /// Generate an X25519 static secret and its public key.
@@ -39,12 +41,19 @@ impl SessionKeys {
         Self {
             shared_key: derived,
             our_public,
+            their_public: *their_public,
         }
     }
 
     /// Encrypt a plaintext message using ChaCha20-Poly1305.
     /// Returns: [12-byte nonce][ciphertext+tag]
     pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        self.encrypt_with_ad(plaintext, &[])
+    }
+
+    /// Like [`encrypt`](Self::encrypt), also authenticating `ad`, which is
+    /// not included in the output: decryption fails unless it is given the same.
+    pub fn encrypt_with_ad(&self, plaintext: &[u8], ad: &[u8]) -> Result<Vec<u8>> {
         let cipher = ChaCha20Poly1305::new_from_slice(&self.shared_key)
             .map_err(|e| anyhow::anyhow!("Failed to create cipher: {}", e))?;
 
@@ -52,7 +61,7 @@ impl SessionKeys {
         OsRng.fill_bytes(&mut nonce_bytes);
         let nonce = Nonce::from_slice(&nonce_bytes);
 
-        let ciphertext = cipher.encrypt(nonce, plaintext)
+        let ciphertext = cipher.encrypt(nonce, Payload { msg: plaintext, aad: ad })
             .map_err(|e| anyhow::anyhow!("Encryption failed: {}", e))?;
 
         let mut result = Vec::with_capacity(12 + ciphertext.len());
@@ -63,6 +72,11 @@ impl SessionKeys {
 
     /// Decrypt a message. Input format: [12-byte nonce][ciphertext+tag]
     pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        self.decrypt_with_ad(data, &[])
+    }
+
+    /// Decrypt a message sealed with [`encrypt_with_ad`](Self::encrypt_with_ad).
+    pub fn decrypt_with_ad(&self, data: &[u8], ad: &[u8]) -> Result<Vec<u8>> {
         if data.len() < 12 {
             anyhow::bail!("Ciphertext too short");
         }
@@ -73,7 +87,7 @@ impl SessionKeys {
         let nonce = Nonce::from_slice(&data[..12]);
         let ciphertext = &data[12..];
 
-        cipher.decrypt(nonce, ciphertext)
+        cipher.decrypt(nonce, Payload { msg: ciphertext, aad: ad })
             .map_err(|e| anyhow::anyhow!("Decryption failed: {}", e))
     }
 }
@@ -185,6 +199,21 @@ mod tests {
         assert!(session_b.decrypt(&encrypted).is_err());
     }
 
+    #[test]
+    fn test_associated_data_must_match() {
+        let (secret_a, public_a) = generate_x25519_keypair();
+        let (secret_b, public_b) = generate_x25519_keypair();
+
+        let session_a = SessionKeys::from_exchange(&secret_a, &public_b.to_bytes());
+        let session_b = SessionKeys::from_exchange(&secret_b, &public_a.to_bytes());
+        assert_eq!(session_a.their_public, session_b.our_public);
+
+        let encrypted = session_a.encrypt_with_ad(b"bound", b"context").unwrap();
+        assert_eq!(session_b.decrypt_with_ad(&encrypted, b"context").unwrap(), b"bound");
+        assert!(session_b.decrypt_with_ad(&encrypted, b"elsewhere").is_err());
+        assert!(session_b.decrypt(&encrypted).is_err());
+    }
+
     #[test]
     fn test_broadcast_key_encrypt_decrypt() {
         let key = [42u8; 32];
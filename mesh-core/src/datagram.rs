@@ -0,0 +1,351 @@
+//! Encrypted UDP path between neighbors, for traffic that would rather be
+//! lost than late (live call audio, typing indicators).
+//!
+//! Once a TCP neighbor's session keys are set, each side offers its UDP port
+//! and a random token. Datagrams start with the receiver's token and the
+//! sender's sequence number, followed by the payload sealed with the session
+//! keys. The seal also covers the token, the sequence number and the
+//! sender's public key, so a datagram can't be readdressed or reflected back
+//! to its sender, and a replay window drops copies. Probes in both directions
+//! prove the path works; until they do, or once they stop, everything stays
+//! on the TCP link.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use serde::{Serialize, Deserialize};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, watch};
+use tracing::{info, debug};
+use anyhow::Result;
+
+use crate::crypto::SessionKeys;
+use crate::message::MeshMessage;
+
+/// A path is used only while probes keep confirming it.
+pub const DATAGRAM_PATH_TIMEOUT: Duration = Duration::from_secs(30);
+/// Larger messages take the TCP link rather than risk IP fragmentation.
+const MAX_DATAGRAM: usize = 1200;
+const TOKEN_LEN: usize = 8;
+const SEQ_LEN: usize = 8;
+/// How far behind the newest datagram a late one may arrive and still be accepted.
+const REPLAY_WINDOW: u64 = 64;
+
+/// What a datagram carries once opened.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Datagram {
+    /// Keepalive; `heard` says whether the sender has had datagrams from us lately.
+    Probe { heard: bool },
+    Message(MeshMessage),
+}
+
+/// The node's UDP socket, shared by all neighbor paths.
+#[derive(Clone)]
+pub struct DatagramSocket {
+    /// Non-blocking handle for sending straight from the routing loop.
+    socket: Arc<std::net::UdpSocket>,
+    port: u16,
+}
+
+impl DatagramSocket {
+    /// Bind on `port` and pass every datagram that arrives to the returned receiver.
+    pub async fn bind(port: u16, shutdown: watch::Receiver<bool>) -> Result<(Self, mpsc::Receiver<(SocketAddr, Vec<u8>)>)> {
+        let socket = std::net::UdpSocket::bind(("0.0.0.0", port))?;
+        socket.set_nonblocking(true)?;
+        let port = socket.local_addr()?.port();
+        let recv_socket = UdpSocket::from_std(socket.try_clone()?)?;
+        info!("Datagram path listening on UDP {}", port);
+
+        let (tx, rx) = mpsc::channel(256);
+        let mut shutdown_rx = shutdown;
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 2048];
+            loop {
+                tokio::select! {
+                    result = recv_socket.recv_from(&mut buf) => {
+                        let Ok((len, from)) = result else { continue };
+                        // Loss-tolerant by definition: drop rather than queue behind a busy node
+                        let _ = tx.try_send((from, buf[..len].to_vec()));
+                    }
+                    _ = shutdown_rx.changed() => break,
+                }
+            }
+        });
+        Ok((Self { socket: Arc::new(socket), port }, rx))
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+/// Token a datagram is addressed with, if it is long enough to have one.
+pub fn token_of(data: &[u8]) -> Option<[u8; TOKEN_LEN]> {
+    data.first_chunk::<TOKEN_LEN>().copied()
+}
+
+/// Open a datagram addressed to us with the sender's session keys.
+/// Returns its sequence number and contents.
+pub fn open(keys: &SessionKeys, data: &[u8]) -> Result<(u64, Datagram)> {
+    let Some((header, sealed)) = data.split_first_chunk::<{ TOKEN_LEN + SEQ_LEN }>() else {
+        anyhow::bail!("Datagram too short");
+    };
+    let seq = u64::from_be_bytes(header[TOKEN_LEN..].try_into()?);
+    let plain = keys.decrypt_with_ad(sealed, &associated_data(header, &keys.their_public))?;
+    Ok((seq, bincode::deserialize(&plain)?))
+}
+
+fn seal(token: &[u8; TOKEN_LEN], seq: u64, keys: &SessionKeys, datagram: &Datagram) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(MAX_DATAGRAM);
+    out.extend_from_slice(token);
+    out.extend_from_slice(&seq.to_be_bytes());
+    let sealed = keys.encrypt_with_ad(&bincode::serialize(datagram)?, &associated_data(&out, &keys.our_public))?;
+    out.extend_from_slice(&sealed);
+    Ok(out)
+}
+
+/// What the seal covers besides the payload: the token and sequence number
+/// in front of it, and which end of the session sent it.
+fn associated_data(header: &[u8], sender_public: &[u8; 32]) -> Vec<u8> {
+    [header, sender_public].concat()
+}
+
+/// Sequence numbers recently accepted from a neighbor.
+#[derive(Debug, Clone, Default)]
+struct ReplayWindow {
+    newest: u64,
+    /// Bit i set: `newest - i` was accepted.
+    seen: u64,
+}
+
+impl ReplayWindow {
+    /// Record `seq`. False for a copy of one already accepted, or one too far
+    /// behind to tell.
+    fn accept(&mut self, seq: u64) -> bool {
+        if seq > self.newest {
+            let ahead = seq - self.newest;
+            self.seen = if ahead >= REPLAY_WINDOW { 0 } else { self.seen << ahead };
+            self.seen |= 1;
+            self.newest = seq;
+            return true;
+        }
+        let behind = self.newest - seq;
+        if behind >= REPLAY_WINDOW || self.seen & (1 << behind) != 0 {
+            return false;
+        }
+        self.seen |= 1 << behind;
+        true
+    }
+}
+
+/// UDP path to one neighbor.
+#[derive(Clone)]
+pub struct DatagramPath {
+    socket: DatagramSocket,
+    remote: SocketAddr,
+    /// The neighbor's token, which our datagrams to it start with.
+    token: [u8; TOKEN_LEN],
+    /// Sequence number of our next datagram. It starts at the clock in
+    /// microseconds, so a path set up again after a reconnect carries on
+    /// above anything the neighbor accepted before.
+    next_seq: Arc<AtomicU64>,
+    replay: ReplayWindow,
+    /// Last datagram from the neighbor.
+    heard: Option<Instant>,
+    /// Last probe in which the neighbor said it hears us.
+    confirmed: Option<Instant>,
+}
+
+impl std::fmt::Debug for DatagramPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DatagramPath")
+            .field("remote", &self.remote)
+            .field("heard", &self.heard)
+            .field("confirmed", &self.confirmed)
+            .finish()
+    }
+}
+
+impl DatagramPath {
+    pub fn new(socket: DatagramSocket, remote: SocketAddr, token: [u8; TOKEN_LEN]) -> Self {
+        let start = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_micros() as u64);
+        Self {
+            socket,
+            remote,
+            token,
+            next_seq: Arc::new(AtomicU64::new(start)),
+            replay: ReplayWindow::default(),
+            heard: None,
+            confirmed: None,
+        }
+    }
+
+    /// Whether datagrams are known to get through in both directions.
+    pub fn is_up(&self, now: Instant) -> bool {
+        self.confirmed.is_some_and(|t| now.duration_since(t) < DATAGRAM_PATH_TIMEOUT)
+    }
+
+    /// Send a probe saying whether we hear the neighbor.
+    pub fn probe(&self, keys: &SessionKeys, now: Instant) {
+        let heard = self.heard.is_some_and(|t| now.duration_since(t) < DATAGRAM_PATH_TIMEOUT);
+        self.send(keys, &Datagram::Probe { heard });
+    }
+
+    /// Send a message over UDP. False if it is too large or the socket refused it.
+    pub fn send_message(&self, keys: &SessionKeys, msg: &MeshMessage) -> bool {
+        self.send(keys, &Datagram::Message(msg.clone()))
+    }
+
+    fn send(&self, keys: &SessionKeys, datagram: &Datagram) -> bool {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        match seal(&self.token, seq, keys, datagram) {
+            Ok(data) if data.len() <= MAX_DATAGRAM => self.socket.socket.send_to(&data, self.remote).is_ok(),
+            Ok(_) => false,
+            Err(e) => {
+                debug!("Failed to seal datagram: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Account for an authenticated datagram from the neighbor, with sequence
+    /// number `seq`. Replayed copies are ignored. Replies to a probe from a
+    /// neighbor that doesn't hear us yet, so both ends confirm within a round
+    /// trip. Returns the message it carried, if any.
+    pub fn receive(&mut self, keys: &SessionKeys, from: SocketAddr, seq: u64, datagram: Datagram, now: Instant) -> Option<MeshMessage> {
+        let newest = seq > self.replay.newest;
+        if !self.replay.accept(seq) {
+            debug!("Dropping replayed datagram {} from {}", seq, from);
+            return None;
+        }
+        self.heard = Some(now);
+        // Follow the neighbor if its NAT mapping or address changed; only the
+        // newest datagram counts, so a delayed or replayed one can't divert the path
+        if newest {
+            self.remote = from;
+        }
+        match datagram {
+            Datagram::Probe { heard } => {
+                if heard {
+                    self.confirmed = Some(now);
+                } else {
+                    self.probe(keys, now);
+                }
+                None
+            }
+            Datagram::Message(msg) => Some(msg),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::generate_x25519_keypair;
+    use crate::message::{MessageType, VoiceStreamPayload};
+
+    fn session_pair() -> (SessionKeys, SessionKeys) {
+        let (a_secret, a_public) = generate_x25519_keypair();
+        let (b_secret, b_public) = generate_x25519_keypair();
+        (
+            SessionKeys::from_exchange(&a_secret, &b_public.to_bytes()),
+            SessionKeys::from_exchange(&b_secret, &a_public.to_bytes()),
+        )
+    }
+
+    async fn recv(rx: &mut mpsc::Receiver<(SocketAddr, Vec<u8>)>) -> (SocketAddr, Vec<u8>) {
+        tokio::time::timeout(Duration::from_secs(2), rx.recv()).await.unwrap().unwrap()
+    }
+
+    fn deliver(path: &mut DatagramPath, keys: &SessionKeys, from: SocketAddr, data: &[u8], now: Instant) -> Option<MeshMessage> {
+        let (seq, datagram) = open(keys, data).unwrap();
+        path.receive(keys, from, seq, datagram, now)
+    }
+
+    #[tokio::test]
+    async fn test_probes_confirm_path_and_carry_voice() {
+        let (_shutdown_tx, shutdown) = watch::channel(false);
+        let (a_socket, mut a_rx) = DatagramSocket::bind(0, shutdown.clone()).await.unwrap();
+        let (b_socket, mut b_rx) = DatagramSocket::bind(0, shutdown).await.unwrap();
+        let (a_keys, b_keys) = session_pair();
+        let (a_token, b_token) = ([1u8; 8], [2u8; 8]);
+        let mut a_path = DatagramPath::new(a_socket.clone(), ([127, 0, 0, 1], b_socket.port()).into(), b_token);
+        let mut b_path = DatagramPath::new(b_socket, ([127, 0, 0, 1], a_socket.port()).into(), a_token);
+        let now = Instant::now();
+        assert!(!a_path.is_up(now));
+
+        // A probes; B hears it and answers, which confirms the path for A
+        a_path.probe(&a_keys, now);
+        let (from, data) = recv(&mut b_rx).await;
+        assert_eq!(token_of(&data), Some(b_token));
+        deliver(&mut b_path, &b_keys, from, &data, now);
+        let (from, data) = recv(&mut a_rx).await;
+        deliver(&mut a_path, &a_keys, from, &data, now);
+        assert!(a_path.is_up(now) && !b_path.is_up(now));
+
+        // A's next probe says it hears B
+        a_path.probe(&a_keys, now);
+        let (from, data) = recv(&mut b_rx).await;
+        deliver(&mut b_path, &b_keys, from, &data, now);
+        assert!(b_path.is_up(now));
+
+        let frame = VoiceStreamPayload { stream_id: [3u8; 16], sequence: 1, audio_frame: vec![0u8; 640] };
+        let msg = MeshMessage::voice_stream([1u8; 32], [2u8; 32], &frame);
+        assert!(a_path.send_message(&a_keys, &msg));
+        let (from, data) = recv(&mut b_rx).await;
+        let got = deliver(&mut b_path, &b_keys, from, &data, now).unwrap();
+        assert_eq!((got.msg_type, got.msg_id), (MessageType::VoiceStream, msg.msg_id));
+
+        // Only the session keys of this pair open it, and a path that goes quiet is dropped
+        let (stranger, _) = session_pair();
+        assert!(open(&stranger, &data).is_err());
+        // ...and only in the direction it was sent
+        assert!(open(&a_keys, &data).is_err());
+        assert!(!a_path.is_up(now + DATAGRAM_PATH_TIMEOUT));
+        let big = MeshMessage::text_to([1u8; 32], [2u8; 32], &"x".repeat(MAX_DATAGRAM));
+        assert!(!a_path.send_message(&a_keys, &big));
+    }
+
+    #[tokio::test]
+    async fn test_replays_are_dropped() {
+        let (_shutdown_tx, shutdown) = watch::channel(false);
+        let (a_socket, _a_rx) = DatagramSocket::bind(0, shutdown.clone()).await.unwrap();
+        let (b_socket, mut b_rx) = DatagramSocket::bind(0, shutdown).await.unwrap();
+        let (a_keys, b_keys) = session_pair();
+        let a_addr: SocketAddr = ([127, 0, 0, 1], a_socket.port()).into();
+        let a_path = DatagramPath::new(a_socket, ([127, 0, 0, 1], b_socket.port()).into(), [2u8; 8]);
+        let mut b_path = DatagramPath::new(b_socket, a_addr, [1u8; 8]);
+        let now = Instant::now();
+
+        let msg = MeshMessage::typing_start([1u8; 32], Some([2u8; 32]));
+        assert!(a_path.send_message(&a_keys, &msg));
+        let (_, first) = recv(&mut b_rx).await;
+        assert!(a_path.send_message(&a_keys, &msg));
+        let (_, second) = recv(&mut b_rx).await;
+
+        // A copy of the newest from elsewhere is dropped, and doesn't move the path
+        let elsewhere: SocketAddr = ([10, 0, 0, 9], 4000).into();
+        assert!(deliver(&mut b_path, &b_keys, a_addr, &second, now).is_some());
+        assert!(deliver(&mut b_path, &b_keys, elsewhere, &second, now).is_none());
+        // A late original is still accepted once, but the path stays put
+        assert!(deliver(&mut b_path, &b_keys, elsewhere, &first, now).is_some());
+        assert!(deliver(&mut b_path, &b_keys, a_addr, &first, now).is_none());
+        assert_eq!(b_path.remote, a_addr);
+
+        // The token and sequence number are sealed in
+        let mut readdressed = second.clone();
+        readdressed[0] ^= 1;
+        assert!(open(&b_keys, &readdressed).is_err());
+        let mut renumbered = second;
+        renumbered[TOKEN_LEN + SEQ_LEN - 1] ^= 1;
+        assert!(open(&b_keys, &renumbered).is_err());
+
+        let mut window = ReplayWindow::default();
+        assert!(window.accept(1000));
+        assert!(window.accept(1000 - REPLAY_WINDOW + 1));
+        assert!(!window.accept(1000 - REPLAY_WINDOW));
+        assert!(window.accept(1000 + 2 * REPLAY_WINDOW));
+        assert!(!window.accept(1000));
+    }
+}
@@ -0,0 +1,276 @@
+use std::path::Path;
+use std::time::Duration;
+use anyhow::{Result, Context};
+use rusqlite::{Connection, params};
+
+use crate::message::{MeshMessage, MessageId};
+
+/// How long a relay keeps a message for an unreachable destination.
+pub const HOLD_EXPIRY: Duration = Duration::from_secs(24 * 3600);
+/// Most messages held for any one destination.
+pub const MAX_HELD_PER_DEST: usize = 50;
+/// Most messages held in total.
+pub const MAX_HELD_TOTAL: usize = 2000;
+/// After handing a message to a multi-hop route, wait this long for its
+/// delivery receipt before trying again.
+const HANDOVER_RETRY: Duration = Duration::from_secs(60);
+/// Routed handovers per message. Older destinations never send receipts,
+/// so after this many the message waits for the destination to connect.
+const MAX_HANDOVERS: u32 = 5;
+
+/// Store-and-forward queue for delay-tolerant delivery.
+///
+/// Directed messages whose destination can't be reached are held here (on
+/// disk, so they survive restarts) until the destination shows up again,
+/// a delivery receipt signed by the destination says someone else got it
+/// there, or they expire.
+/// The destination side also remembers which messages it has receipted, so
+/// copies arriving late from other custodians aren't shown twice.
+pub struct DtnStore {
+    db: Connection,
+}
+
+impl DtnStore {
+    /// Open (or create) the store in the given directory.
+    pub fn open(data_dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(data_dir).context("Failed to create data directory")?;
+        let db = Connection::open(data_dir.join("dtn.db")).context("Failed to open DTN store")?;
+        db.execute_batch("PRAGMA journal_mode=WAL; PRAGMA synchronous=NORMAL;")?;
+        Self::init(db)
+    }
+
+    /// A store that lives only as long as the process (when the disk isn't usable).
+    pub fn in_memory() -> Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(db: Connection) -> Result<Self> {
+        db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS held (
+                msg_id BLOB PRIMARY KEY,
+                destination BLOB NOT NULL,
+                frame BLOB NOT NULL,
+                held_at INTEGER NOT NULL,
+                expires_at INTEGER NOT NULL,
+                handed_over_at INTEGER,
+                handovers INTEGER NOT NULL DEFAULT 0
+            );
+            CREATE INDEX IF NOT EXISTS held_destination ON held (destination);
+
+            CREATE TABLE IF NOT EXISTS receipted (
+                msg_id BLOB PRIMARY KEY,
+                at INTEGER NOT NULL
+            );",
+        )?;
+        Ok(Self { db })
+    }
+
+    /// Take custody of a message. Returns false if it isn't storable, is
+    /// already held, or a quota is full.
+    pub fn hold(&self, msg: &MeshMessage) -> Result<bool> {
+        let Some(dest) = msg.destination.filter(|_| msg.is_storable()) else {
+            return Ok(false);
+        };
+        let for_dest: i64 = self.db.query_row(
+            "SELECT COUNT(*) FROM held WHERE destination = ?1", params![dest.as_slice()], |r| r.get(0),
+        )?;
+        if for_dest as usize >= MAX_HELD_PER_DEST || self.held_count()? >= MAX_HELD_TOTAL {
+            return Ok(false);
+        }
+        let now = now_millis();
+        let inserted = self.db.execute(
+            "INSERT OR IGNORE INTO held (msg_id, destination, frame, held_at, expires_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                msg.msg_id.as_slice(),
+                dest.as_slice(),
+                msg.to_bytes(),
+                now,
+                now + HOLD_EXPIRY.as_millis() as i64,
+            ],
+        )?;
+        Ok(inserted > 0)
+    }
+
+    /// The destination is a direct neighbor again: hand over everything held
+    /// for it and give up custody.
+    pub fn take_for(&self, dest: &[u8; 32]) -> Result<Vec<MeshMessage>> {
+        let msgs = self.held_for(dest, false)?;
+        self.db.execute("DELETE FROM held WHERE destination = ?1", params![dest.as_slice()])?;
+        Ok(msgs)
+    }
+
+    /// Messages whose destination now has a multi-hop route. They stay held
+    /// until a delivery receipt releases them, and come up again here if none
+    /// arrives in time, up to `MAX_HANDOVERS` times.
+    pub fn due_handovers(&self, routable: impl Fn(&[u8; 32]) -> bool) -> Result<Vec<MeshMessage>> {
+        let retry_before = now_millis() - HANDOVER_RETRY.as_millis() as i64;
+        let mut stmt = self.db.prepare(
+            "SELECT DISTINCT destination FROM held
+             WHERE (handed_over_at IS NULL OR handed_over_at < ?1) AND handovers < ?2",
+        )?;
+        let dests: Vec<Vec<u8>> = stmt.query_map(params![retry_before, MAX_HANDOVERS], |r| r.get(0))?
+            .filter_map(|r| r.ok())
+            .collect();
+        let mut due = Vec::new();
+        for dest in dests.iter().filter_map(|d| <[u8; 32]>::try_from(d.as_slice()).ok()) {
+            if routable(&dest) {
+                due.extend(self.held_for(&dest, true)?);
+                self.db.execute(
+                    "UPDATE held SET handed_over_at = ?1, handovers = handovers + 1
+                     WHERE destination = ?2 AND (handed_over_at IS NULL OR handed_over_at < ?3) AND handovers < ?4",
+                    params![now_millis(), dest.as_slice(), retry_before, MAX_HANDOVERS],
+                )?;
+            }
+        }
+        Ok(due)
+    }
+
+    fn held_for(&self, dest: &[u8; 32], only_due: bool) -> Result<Vec<MeshMessage>> {
+        let (retry_before, max_handovers) = if only_due {
+            (now_millis() - HANDOVER_RETRY.as_millis() as i64, MAX_HANDOVERS)
+        } else {
+            (i64::MAX, u32::MAX)
+        };
+        let mut stmt = self.db.prepare(
+            "SELECT frame FROM held
+             WHERE destination = ?1 AND (handed_over_at IS NULL OR handed_over_at < ?2) AND handovers < ?3
+             ORDER BY held_at",
+        )?;
+        let frames: Vec<Vec<u8>> = stmt.query_map(params![dest.as_slice(), retry_before, max_handovers], |r| r.get(0))?
+            .filter_map(|r| r.ok())
+            .collect();
+        Ok(frames.iter().filter_map(|f| MeshMessage::from_bytes(f).ok()).collect())
+    }
+
+    /// The message's destination (`receipted_by`) sent a delivery receipt for
+    /// it: drop our copy. A receipt from anyone else releases nothing.
+    pub fn release(&self, msg_id: &MessageId, receipted_by: &[u8; 32]) -> Result<bool> {
+        Ok(self.db.execute(
+            "DELETE FROM held WHERE msg_id = ?1 AND destination = ?2",
+            params![msg_id.as_slice(), receipted_by.as_slice()],
+        )? > 0)
+    }
+
+    /// Remember that we, as the destination, receipted this message.
+    /// Returns false if we already had (the message is a late duplicate).
+    pub fn record_receipt(&self, msg_id: &MessageId) -> Result<bool> {
+        let inserted = self.db.execute(
+            "INSERT OR IGNORE INTO receipted (msg_id, at) VALUES (?1, ?2)",
+            params![msg_id.as_slice(), now_millis()],
+        )?;
+        Ok(inserted > 0)
+    }
+
+    /// Every message we have receipted and still remember.
+    pub fn receipts(&self) -> Result<Vec<MessageId>> {
+        let mut stmt = self.db.prepare("SELECT msg_id FROM receipted")?;
+        let ids: Vec<Vec<u8>> = stmt.query_map([], |r| r.get(0))?
+            .filter_map(|r| r.ok())
+            .collect();
+        Ok(ids.iter().filter_map(|id| MessageId::try_from(id.as_slice()).ok()).collect())
+    }
+
+    pub fn held_count(&self) -> Result<usize> {
+        let count: i64 = self.db.query_row("SELECT COUNT(*) FROM held", [], |r| r.get(0))?;
+        Ok(count as usize)
+    }
+
+    /// Drop held messages past their expiry, and receipts older than any
+    /// copy that could still be in flight. Returns how many messages expired.
+    pub fn expire(&self) -> Result<u32> {
+        let now = now_millis();
+        let expired = self.db.execute("DELETE FROM held WHERE expires_at <= ?1", params![now])?;
+        self.db.execute(
+            "DELETE FROM receipted WHERE at <= ?1",
+            params![now - 2 * HOLD_EXPIRY.as_millis() as i64],
+        )?;
+        Ok(expired as u32)
+    }
+}
+
+fn now_millis() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::MessageType;
+
+    fn dm(dest: [u8; 32], text: &str) -> MeshMessage {
+        MeshMessage::text_to([1u8; 32], dest, text)
+    }
+
+    #[test]
+    fn test_hold_and_hand_over() {
+        let dir = std::env::temp_dir().join(format!("mesh_dtn_test_{}", rand::random::<u32>()));
+        let (dest, other) = ([5u8; 32], [6u8; 32]);
+        let first = dm(dest, "first");
+        {
+            let store = DtnStore::open(&dir).unwrap();
+            assert!(store.hold(&first).unwrap());
+            assert!(!store.hold(&first).unwrap());
+            assert!(store.hold(&dm(dest, "second")).unwrap());
+            assert!(store.hold(&dm(other, "elsewhere")).unwrap());
+            // Broadcasts and file chunks aren't held
+            assert!(!store.hold(&MeshMessage::text([1u8; 32], "all")).unwrap());
+            assert!(!store.hold(&MeshMessage::new(MessageType::FileChunk, [1u8; 32], 5, Some(dest), vec![])).unwrap());
+        }
+
+        // Survives a restart
+        let store = DtnStore::open(&dir).unwrap();
+        assert_eq!(store.held_count().unwrap(), 3);
+
+        // A route appears: handed over once, then waits for a receipt
+        let due = store.due_handovers(|d| *d == dest).unwrap();
+        assert_eq!(due.len(), 2);
+        assert_eq!(due[0].payload, b"first");
+        assert!(store.due_handovers(|_| true).unwrap().iter().all(|m| m.destination == Some(other)));
+        assert!(!store.release(&first.msg_id, &other).unwrap());
+        assert!(store.release(&first.msg_id, &dest).unwrap());
+        assert_eq!(store.held_count().unwrap(), 2);
+
+        // The destination itself shows up
+        let taken = store.take_for(&dest).unwrap();
+        assert_eq!(taken.len(), 1);
+        assert_eq!(taken[0].payload, b"second");
+        assert!(store.take_for(&dest).unwrap().is_empty());
+
+        drop(store);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_handovers_stop_without_receipts() {
+        let store = DtnStore::in_memory().unwrap();
+        let dest = [5u8; 32];
+        assert!(store.hold(&dm(dest, "to an old node")).unwrap());
+        for _ in 0..MAX_HANDOVERS {
+            assert_eq!(store.due_handovers(|_| true).unwrap().len(), 1);
+            // Pretend the retry interval has passed
+            store.db.execute("UPDATE held SET handed_over_at = 0", []).unwrap();
+        }
+        assert!(store.due_handovers(|_| true).unwrap().is_empty());
+
+        // Still handed over if the destination connects directly
+        assert_eq!(store.take_for(&dest).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_quota_and_receipts() {
+        let store = DtnStore::in_memory().unwrap();
+        let dest = [5u8; 32];
+        for i in 0..MAX_HELD_PER_DEST {
+            assert!(store.hold(&dm(dest, &i.to_string())).unwrap());
+        }
+        assert!(!store.hold(&dm(dest, "one too many")).unwrap());
+        assert!(store.hold(&dm([6u8; 32], "other destination")).unwrap());
+
+        let id = [9u8; 32];
+        assert!(store.record_receipt(&id).unwrap());
+        assert!(!store.record_receipt(&id).unwrap());
+        assert_eq!(store.receipts().unwrap(), vec![id]);
+        assert_eq!(store.expire().unwrap(), 0);
+    }
+}
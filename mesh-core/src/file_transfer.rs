@@ -1,22 +1,528 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use sha2::{Sha256, Digest};
 use rand::RngCore;
 use rand::rngs::OsRng;
 
-use crate::message::{FileOfferPayload, FileChunkPayload};
+use crate::message::{BundleEntry, FileOfferPayload, FileChunkPayload, FilePreview};
 
 pub const CHUNK_SIZE: usize = 64 * 1024; // 64KB
 pub const MAX_FILE_SIZE: u64 = 100 * 1024 * 1024; // 100MB
+/// Maximum number of unacknowledged chunks in flight per transfer.
+pub const SEND_WINDOW: u32 = 8;
+/// How long to wait for a chunk acknowledgement before resending it.
+pub const CHUNK_ACK_TIMEOUT: Duration = Duration::from_secs(5);
+/// Offers nobody accepted (or declined) within this window are dropped.
+pub const OFFER_TIMEOUT: Duration = Duration::from_secs(300);
+/// Accepted transfers with no chunk or ack activity for this long are failed.
+pub const STALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Incoming transfers idle this long start pulling missing chunks from other holders.
+pub const SWARM_FALLBACK_AFTER: Duration = Duration::from_secs(10);
+/// How often to re-flood a SwarmQuery for a file we still need.
+pub const SWARM_QUERY_INTERVAL: Duration = Duration::from_secs(30);
+/// A file asked for with `want_file` that no holder answers for within this long is given up.
+pub const WANT_TIMEOUT: Duration = Duration::from_secs(600);
+/// Most chunks served in reply to a single ChunkRequest.
+pub const MAX_CHUNKS_PER_REQUEST: usize = SEND_WINDOW as usize;
+
+/// Longest edge, in pixels, of the thumbnail sent with image offers.
+pub const PREVIEW_MAX_DIM: u32 = 160;
+/// Thumbnails larger than this after encoding are left out of the offer.
+pub const PREVIEW_MAX_BYTES: usize = 24 * 1024;
+
+/// Most files a single bundle transfer may carry.
+pub const MAX_BUNDLE_ENTRIES: usize = 1000;
+/// Deepest directory nesting kept when extracting a bundle.
+const MAX_BUNDLE_DEPTH: usize = 16;
+
+/// Longest filename (in bytes) we will write to disk.
+const MAX_FILENAME_LEN: usize = 200;
+/// Names Windows refuses to open as regular files, regardless of extension.
+const RESERVED_NAMES: &[&str] = &[
+    "con", "prn", "aux", "nul",
+    "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8", "com9",
+    "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+
+/// Reduce a remote-supplied filename to a single safe path component.
+///
+/// Directory parts (either separator), control characters and characters that
+/// are invalid on Windows are removed, leading dots are stripped so the file can
+/// never be hidden or a relative reference, and reserved device names are
+/// prefixed. Always returns a non-empty name.
+pub fn sanitize_filename(name: &str) -> String {
+    let base = name.rsplit(['/', '\\']).next().unwrap_or("");
+    let mut clean: String = base.chars()
+        .filter(|c| !c.is_control() && !matches!(c, '<' | '>' | ':' | '"' | '|' | '?' | '*'))
+        .collect();
+
+    clean = clean.trim_start_matches(['.', ' ']).trim_end_matches(['.', ' ']).to_string();
+    if clean.is_empty() {
+        return "unnamed".to_string();
+    }
+
+    let stem = clean.split('.').next().unwrap_or("").to_ascii_lowercase();
+    if RESERVED_NAMES.contains(&stem.as_str()) {
+        clean = format!("_{}", clean);
+    }
+
+    if clean.len() > MAX_FILENAME_LEN {
+        // Keep the extension (if short) and cut the stem on a char boundary
+        let ext = Path::new(&clean).extension()
+            .map(|e| e.to_string_lossy().to_string())
+            .filter(|e| e.len() <= 16);
+        let keep = MAX_FILENAME_LEN - ext.as_ref().map(|e| e.len() + 1).unwrap_or(0);
+        let mut cut = keep;
+        while !clean.is_char_boundary(cut) {
+            cut -= 1;
+        }
+        clean = match ext {
+            Some(e) => format!("{}.{}", &clean[..cut], e),
+            None => clean[..cut].to_string(),
+        };
+    }
+    clean
+}
+
+/// SHA-256 of one chunk, as listed in `FileOfferPayload::chunk_hashes`.
+pub fn hash_chunk(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+/// Whether an offer carries a usable per-chunk hash list (needed for swarm fetches).
+fn has_chunk_hashes(metadata: &FileOfferPayload) -> bool {
+    metadata.chunk_count > 0 && metadata.chunk_hashes.len() == metadata.chunk_count as usize
+}
+
+/// Create a new file named `filename` inside `dir` without overwriting anything.
+/// If the name is taken, "name (1).ext", "name (2).ext", ... are tried in turn.
+fn create_unique_file(dir: &Path, filename: &str) -> std::io::Result<(PathBuf, std::fs::File)> {
+    let path = Path::new(filename);
+    let stem = path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    let ext = path.extension().map(|e| e.to_string_lossy().to_string());
+
+    for n in 0..10_000u32 {
+        let candidate = if n == 0 {
+            filename.to_string()
+        } else {
+            match &ext {
+                Some(e) => format!("{} ({}).{}", stem, n, e),
+                None => format!("{} ({})", stem, n),
+            }
+        };
+        let full = dir.join(&candidate);
+        match std::fs::OpenOptions::new().write(true).create_new(true).open(&full) {
+            Ok(file) => return Ok((full, file)),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, "no free filename"))
+}
+
+/// Create a new directory named `name` inside `dir`, numbering it like
+/// `create_unique_file` if the name is taken.
+fn create_unique_dir(dir: &Path, name: &str) -> std::io::Result<PathBuf> {
+    for n in 0..10_000u32 {
+        let candidate = if n == 0 { name.to_string() } else { format!("{} ({})", name, n) };
+        let full = dir.join(&candidate);
+        match std::fs::create_dir(&full) {
+            Ok(()) => return Ok(full),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, "no free directory name"))
+}
+
+/// Turn a remote-supplied bundle path into a relative path that stays inside
+/// the bundle root: empty, "." and ".." parts are dropped, every remaining
+/// part is sanitized like a filename, and nesting is capped.
+pub fn bundle_relative_path(path: &str) -> PathBuf {
+    let parts: Vec<String> = path.split(['/', '\\'])
+        .filter(|p| !p.is_empty() && *p != "." && *p != "..")
+        .map(sanitize_filename)
+        .collect();
+    let Some((name, dirs)) = parts.split_last() else {
+        return PathBuf::from("unnamed");
+    };
+    let skip = dirs.len().saturating_sub(MAX_BUNDLE_DEPTH - 1);
+    dirs[skip..].iter().chain(std::iter::once(name)).collect()
+}
+
+/// Length of chunk `sequence` in a transfer of `size` bytes.
+fn chunk_len(size: u64, sequence: u32) -> u64 {
+    size.saturating_sub(sequence as u64 * CHUNK_SIZE as u64).min(CHUNK_SIZE as u64)
+}
+
+/// True if an offer's sizes agree with each other and stay within limits. A
+/// bundle's manifest has to add up to the transfer, or a selection of its
+/// entries could name chunks that don't exist.
+fn offer_is_consistent(offer: &FileOfferPayload) -> bool {
+    let manifest_size = offer.bundle.iter().try_fold(0u64, |total, entry| total.checked_add(entry.size));
+    offer.size_bytes <= MAX_FILE_SIZE
+        && offer.chunk_count as u64 == offer.size_bytes.div_ceil(CHUNK_SIZE as u64).max(1)
+        && offer.bundle.len() <= MAX_BUNDLE_ENTRIES
+        && (offer.bundle.is_empty() || manifest_size == Some(offer.size_bytes))
+}
+
+/// Chunks holding any byte of the given bundle entries. Always includes chunk 0,
+/// so a selection of only empty files still completes. None if an index is out of range.
+fn bundle_chunks(bundle: &[BundleEntry], entries: &[u32]) -> Option<HashSet<u32>> {
+    let mut offsets = Vec::with_capacity(bundle.len());
+    let mut offset = 0u64;
+    for entry in bundle {
+        offsets.push(offset);
+        offset += entry.size;
+    }
+
+    let mut chunks = HashSet::from([0]);
+    for &idx in entries {
+        let entry = bundle.get(idx as usize)?;
+        if entry.size == 0 {
+            continue;
+        }
+        let start = offsets[idx as usize];
+        let first = (start / CHUNK_SIZE as u64) as u32;
+        let last = ((start + entry.size - 1) / CHUNK_SIZE as u64) as u32;
+        chunks.extend(first..=last);
+    }
+    Some(chunks)
+}
+
+/// Expand the paths picked for a bundle into (file on disk, relative path) pairs.
+/// Directories are walked recursively and keep their own name as the top-level
+/// folder; symlinks are skipped so a bundle never reaches outside what was picked.
+fn collect_bundle_files(paths: &[PathBuf]) -> Result<Vec<(PathBuf, String)>, String> {
+    fn walk(dir: &Path, prefix: &str, out: &mut Vec<(PathBuf, String)>) -> Result<(), String> {
+        let mut entries: Vec<_> = std::fs::read_dir(dir)
+            .map_err(|e| format!("Failed to read directory {}: {}", dir.display(), e))?
+            .filter_map(|e| e.ok())
+            .collect();
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let rel = format!("{}/{}", prefix, name);
+            let Ok(file_type) = entry.file_type() else { continue };
+            if file_type.is_dir() {
+                walk(&entry.path(), &rel, out)?;
+            } else if file_type.is_file() {
+                out.push((entry.path(), rel));
+            }
+            if out.len() > MAX_BUNDLE_ENTRIES {
+                return Err(format!("Too many files (max {})", MAX_BUNDLE_ENTRIES));
+            }
+        }
+        Ok(())
+    }
+
+    let mut files = Vec::new();
+    for path in paths {
+        let name = path.file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+        let meta = std::fs::symlink_metadata(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        if meta.is_dir() {
+            walk(path, &name, &mut files)?;
+        } else if meta.is_file() {
+            files.push((path.clone(), name));
+        }
+    }
+    if files.is_empty() {
+        return Err("No files to send".to_string());
+    }
+    if files.len() > MAX_BUNDLE_ENTRIES {
+        return Err(format!("Too many files (max {})", MAX_BUNDLE_ENTRIES));
+    }
+    Ok(files)
+}
+
+/// Progress snapshot for a transfer, with throughput and estimated time remaining.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransferProgress {
+    pub pct: u8,
+    pub bytes_done: u64,
+    pub bytes_per_sec: u64,
+    /// None until enough data has moved to estimate a rate.
+    pub eta_secs: Option<u32>,
+}
+
+impl TransferProgress {
+    fn compute(bytes_done: u64, total: u64, started_at: Instant) -> Self {
+        let pct = if total == 0 {
+            100
+        } else {
+            ((bytes_done as f64 / total as f64) * 100.0).min(100.0) as u8
+        };
+        let elapsed = started_at.elapsed().as_secs_f64();
+        let bytes_per_sec = if elapsed > 0.0 { (bytes_done as f64 / elapsed) as u64 } else { 0 };
+        let eta_secs = total.saturating_sub(bytes_done)
+            .checked_div(bytes_per_sec)
+            .map(|secs| secs as u32);
+        Self { pct, bytes_done, bytes_per_sec, eta_secs }
+    }
+}
+
+/// Rules for accepting incoming file offers without asking the user.
+///
+/// Offers are rejected outright if their extension is blocked or they would
+/// push free disk space below `min_free_disk`. They are accepted automatically
+/// only when the sender is trusted (a favorite or verified contact, as enabled)
+/// and the file fits the size and type limits. Everything else is left to the user.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FileAcceptPolicy {
+    /// Auto-accept from contacts marked as favorites.
+    pub accept_favorites: bool,
+    /// Auto-accept from contacts whose safety number has been verified.
+    pub accept_verified: bool,
+    /// Largest file to auto-accept (None = up to `MAX_FILE_SIZE`).
+    pub max_auto_size: Option<u64>,
+    /// Extensions (without the dot, case-insensitive) allowed for auto-accept; empty = any.
+    pub allowed_extensions: Vec<String>,
+    /// MIME types allowed for auto-accept, e.g. "application/pdf" or "image/*"; empty = any.
+    pub allowed_mime_types: Vec<String>,
+    /// Extensions that are always rejected, even from trusted senders.
+    pub blocked_extensions: Vec<String>,
+    /// Reject offers that would leave less than this many bytes free.
+    pub min_free_disk: Option<u64>,
+}
+
+/// What we know about an offer's sender when applying a `FileAcceptPolicy`.
+#[derive(Debug, Clone, Default)]
+pub struct SenderTrust {
+    pub name: String,
+    pub favorite: bool,
+    pub verified: bool,
+}
+
+/// Outcome of applying a `FileAcceptPolicy` to an offer, with a human-readable reason.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyDecision {
+    Accept(String),
+    Reject(String),
+    /// No rule applies: ask the user as usual.
+    Ask,
+}
+
+impl FileAcceptPolicy {
+    /// Decide what to do with an offer. `free_disk` is the free space where the
+    /// file would be saved, if known.
+    pub fn evaluate(&self, offer: &FileOfferPayload, sender: &SenderTrust, free_disk: Option<u64>) -> PolicyDecision {
+        // A bundle is judged by every file inside it, not by the bundle's name
+        let exts: Vec<String> = if offer.bundle.is_empty() {
+            vec![file_extension(&offer.filename)]
+        } else {
+            offer.bundle.iter().map(|e| file_extension(&e.path)).collect()
+        };
+
+        for ext in &exts {
+            if !ext.is_empty() && self.blocked_extensions.iter().any(|b| b.eq_ignore_ascii_case(ext)) {
+                return PolicyDecision::Reject(format!(".{} files are blocked", ext));
+            }
+        }
+        if let (Some(min), Some(free)) = (self.min_free_disk, free_disk) {
+            if free.saturating_sub(offer.size_bytes) < min {
+                return PolicyDecision::Reject(format!(
+                    "Not enough free disk space ({} bytes free, {} bytes offered)",
+                    free, offer.size_bytes
+                ));
+            }
+        }
+
+        let trusted_as = if self.accept_favorites && sender.favorite {
+            "favorite"
+        } else if self.accept_verified && sender.verified {
+            "verified"
+        } else {
+            return PolicyDecision::Ask;
+        };
+        if offer.size_bytes > self.max_auto_size.unwrap_or(MAX_FILE_SIZE) {
+            return PolicyDecision::Ask;
+        }
+        for ext in &exts {
+            let mime = mime_for_extension(ext);
+            if !self.allowed_extensions.is_empty()
+                && !self.allowed_extensions.iter().any(|a| a.eq_ignore_ascii_case(ext))
+            {
+                return PolicyDecision::Ask;
+            }
+            if !self.allowed_mime_types.is_empty()
+                && !self.allowed_mime_types.iter().any(|m| mime_matches(m, mime))
+            {
+                return PolicyDecision::Ask;
+            }
+        }
+
+        let kind = match exts.as_slice() {
+            [ext] => mime_for_extension(ext).to_string(),
+            _ => format!("{} files", exts.len()),
+        };
+        PolicyDecision::Accept(format!("From {} contact {} ({})", trusted_as, sender.name, kind))
+    }
+}
+
+/// Lowercase extension of a filename, or "" if it has none.
+fn file_extension(filename: &str) -> String {
+    Path::new(filename).extension()
+        .map(|e| e.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default()
+}
+
+/// Best-effort MIME type for a lowercase file extension.
+pub fn mime_for_extension(ext: &str) -> &'static str {
+    match ext {
+        "txt" | "log" | "md" => "text/plain",
+        "csv" => "text/csv",
+        "html" | "htm" => "text/html",
+        "json" => "application/json",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "bmp" => "image/bmp",
+        "wav" => "audio/wav",
+        "mp3" => "audio/mpeg",
+        "ogg" | "opus" => "audio/ogg",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "gpx" | "kml" | "xml" => "application/xml",
+        "doc" => "application/msword",
+        "docx" => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        "xlsx" => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Match a MIME type against a pattern such as "image/png" or "image/*".
+fn mime_matches(pattern: &str, mime: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(prefix) => mime.split('/').next().is_some_and(|top| top.eq_ignore_ascii_case(prefix)),
+        None => pattern.eq_ignore_ascii_case(mime),
+    }
+}
+
+/// Free space in bytes on the filesystem holding `path` (or its nearest existing
+/// ancestor). None where this isn't supported.
+#[cfg(unix)]
+pub fn free_disk_space(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let existing = path.ancestors().find(|p| p.exists())?;
+    let c_path = std::ffi::CString::new(existing.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: c_path is a valid NUL-terminated string and stat is a valid out-pointer.
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Free space in bytes on the filesystem holding `path`. None where this isn't supported.
+#[cfg(not(unix))]
+pub fn free_disk_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Build a JPEG thumbnail for an image file. None for anything that isn't a
+/// decodable image, or when the thumbnail would still be too big to send.
+pub fn make_preview(filename: &str, data: &[u8]) -> Option<FilePreview> {
+    if !mime_for_extension(&file_extension(filename)).starts_with("image/") {
+        return None;
+    }
+    let img = image::load_from_memory(data).ok()?;
+    // JPEG has no alpha channel, so flatten to RGB first
+    let thumb = image::DynamicImage::ImageRgb8(img.thumbnail(PREVIEW_MAX_DIM, PREVIEW_MAX_DIM).to_rgb8());
+
+    let mut out = std::io::Cursor::new(Vec::new());
+    let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut out, 70);
+    thumb.write_with_encoder(encoder).ok()?;
+    let data = out.into_inner();
+    if data.len() > PREVIEW_MAX_BYTES {
+        return None;
+    }
+    Some(FilePreview { mime_type: "image/jpeg".into(), data })
+}
+
+/// Who an outgoing file is offered to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileAudience {
+    /// A single node, addressed directly.
+    Peer([u8; 32]),
+    /// Every member of a named group (offer and chunks are flooded).
+    Group(String),
+    /// The whole mesh (offer and chunks are flooded).
+    Everyone,
+}
+
+impl FileAudience {
+    /// Wire destination for offers and chunks: the peer, or None to flood.
+    pub fn destination(&self) -> Option<[u8; 32]> {
+        match self {
+            FileAudience::Peer(id) => Some(*id),
+            FileAudience::Group(_) | FileAudience::Everyone => None,
+        }
+    }
+
+    /// Whether `node` may fetch a shared copy. Group chunks are flooded to the
+    /// whole mesh anyway, and other nodes' memberships aren't known, so only a
+    /// single-peer audience restricts who is served.
+    pub fn admits(&self, node: &[u8; 32]) -> bool {
+        match self {
+            FileAudience::Peer(id) => id == node,
+            FileAudience::Group(_) | FileAudience::Everyone => true,
+        }
+    }
+}
+
+/// A transfer dropped by `expire_stale`, so the node can notify the other end(s).
+#[derive(Debug, Clone)]
+pub struct ExpiredTransfer {
+    pub file_id: [u8; 16],
+    /// Who to notify (the sender for incoming transfers).
+    pub audience: FileAudience,
+    pub reason: String,
+}
 
 /// Tracks an outgoing file transfer.
+///
+/// Chunks are sent once per audience, not once per receiver: a flooded chunk
+/// reaches every group member, and each receiver acknowledges independently.
 #[derive(Debug)]
 pub struct OutgoingTransfer {
     pub metadata: FileOfferPayload,
-    pub dest: [u8; 32],
+    pub audience: FileAudience,
     pub chunks: Vec<Vec<u8>>,
-    pub next_chunk: u32,
-    pub accepted: bool,
+    /// Receivers that accepted, with the chunks each has acknowledged.
+    pub receivers: HashMap<[u8; 32], HashSet<u32>>,
+    /// Chunks sent but not yet acknowledged, with the time they were (re)sent.
+    pub in_flight: HashMap<u32, Instant>,
+    pub started_at: Option<Instant>,
+    pub last_activity: Instant,
+}
+
+impl OutgoingTransfer {
+    /// Whether any accepted receiver still lacks this chunk.
+    fn is_needed(&self, sequence: u32) -> bool {
+        self.receivers.values().any(|acked| !acked.contains(&sequence))
+    }
+
+    /// Whether at least one receiver accepted and all of them have every chunk.
+    fn is_done(&self) -> bool {
+        !self.receivers.is_empty()
+            && self.receivers.values().all(|acked| acked.len() as u32 >= self.metadata.chunk_count)
+    }
+
+    /// Bytes acknowledged by the slowest receiver.
+    fn bytes_acked(&self) -> u64 {
+        self.receivers.values()
+            .map(|acked| acked.iter().map(|seq| self.chunks[*seq as usize].len() as u64).sum())
+            .min()
+            .unwrap_or(0)
+    }
 }
 
 /// Tracks an incoming file transfer.
@@ -27,6 +533,54 @@ pub struct IncomingTransfer {
     pub chunks: HashMap<u32, Vec<u8>>,
     pub accepted: bool,
     pub save_dir: PathBuf,
+    pub bytes_received: u64,
+    pub started_at: Option<Instant>,
+    pub last_activity: Instant,
+    /// Other nodes holding the complete file, found via SwarmQuery.
+    pub holders: HashSet<[u8; 32]>,
+    /// Chunks pulled from holders and not yet received, with the request time.
+    pub requested: HashMap<u32, Instant>,
+    pub last_query: Option<Instant>,
+    /// Bundle entries accepted, with the chunks they span. None = the whole transfer.
+    pub selection: Option<(Vec<u32>, HashSet<u32>)>,
+    /// Who we may serve the finished file to. None for files sent to us
+    /// directly, which stay private.
+    pub share_with: Option<FileAudience>,
+}
+
+impl IncomingTransfer {
+    /// Whether this chunk is part of what we accepted.
+    fn needs(&self, sequence: u32) -> bool {
+        self.selection.as_ref().map(|(_, chunks)| chunks.contains(&sequence)).unwrap_or(true)
+    }
+
+    /// Number of chunks needed to complete, and how many of those we have.
+    fn chunk_progress(&self) -> (u32, u32) {
+        match &self.selection {
+            Some((_, needed)) => (
+                needed.len() as u32,
+                needed.iter().filter(|seq| self.chunks.contains_key(seq)).count() as u32,
+            ),
+            None => (self.metadata.chunk_count, self.chunks.len() as u32),
+        }
+    }
+
+    /// Total bytes in the chunks we need.
+    fn bytes_needed(&self) -> u64 {
+        match &self.selection {
+            Some((_, needed)) => needed.iter().map(|seq| chunk_len(self.metadata.size_bytes, *seq)).sum(),
+            None => self.metadata.size_bytes,
+        }
+    }
+}
+
+/// A complete file we can serve to swarm requesters, by content hash.
+#[derive(Debug, Clone)]
+pub struct SharedFile {
+    pub metadata: FileOfferPayload,
+    pub path: PathBuf,
+    /// Who may fetch it.
+    pub audience: FileAudience,
 }
 
 /// Manages in-progress file transfers (both sending and receiving).
@@ -34,6 +588,14 @@ pub struct FileTransferManager {
     outgoing: HashMap<[u8; 16], OutgoingTransfer>,
     incoming: HashMap<[u8; 16], IncomingTransfer>,
     save_dir: PathBuf,
+    /// When set, received files land here instead of `save_dir` for review.
+    quarantine_dir: Option<PathBuf>,
+    /// Files offered to a group or the whole mesh, sent or received in full,
+    /// keyed by SHA-256 (in memory only). Direct transfers are never listed.
+    library: HashMap<[u8; 32], SharedFile>,
+    /// Content hashes requested with `want_file`: when they were asked for,
+    /// and when we last queried for them.
+    wanted: HashMap<[u8; 32], (Instant, Instant)>,
 }
 
 impl FileTransferManager {
@@ -42,18 +604,95 @@ impl FileTransferManager {
             outgoing: HashMap::new(),
             incoming: HashMap::new(),
             save_dir,
+            quarantine_dir: None,
+            library: HashMap::new(),
+            wanted: HashMap::new(),
         }
     }
 
-    /// Read a file, split into chunks, and register as outgoing transfer.
+    /// Route newly offered files into a quarantine directory (None to disable).
+    pub fn set_quarantine_dir(&mut self, dir: Option<PathBuf>) {
+        self.quarantine_dir = dir;
+    }
+
+    /// Directory newly offered files will be saved into.
+    pub fn incoming_dir(&self) -> &Path {
+        self.quarantine_dir.as_deref().unwrap_or(&self.save_dir)
+    }
+
+    /// Read a file, split into chunks, and register as outgoing transfer to one peer.
     /// Returns the metadata to send as a FileOffer.
     pub fn prepare_send(&mut self, dest: [u8; 32], file_path: &Path) -> Result<FileOfferPayload, String> {
+        self.prepare_send_to(FileAudience::Peer(dest), file_path)
+    }
+
+    /// Like `prepare_send`, but for any audience (a peer, a group, or everyone).
+    /// A directory is sent as a bundle of every file beneath it.
+    pub fn prepare_send_to(&mut self, audience: FileAudience, file_path: &Path) -> Result<FileOfferPayload, String> {
+        if file_path.is_dir() {
+            return self.prepare_bundle_to(audience, &[file_path.to_path_buf()]);
+        }
         let data = std::fs::read(file_path).map_err(|e| format!("Failed to read file: {}", e))?;
 
         if data.len() as u64 > MAX_FILE_SIZE {
             return Err(format!("File too large: {} bytes (max {})", data.len(), MAX_FILE_SIZE));
         }
 
+        let filename = file_path.file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+        let preview = make_preview(&filename, &data);
+        let metadata = self.register_outgoing(audience.clone(), filename, data, preview, Vec::new());
+        if audience.destination().is_none() {
+            self.library.insert(metadata.sha256_hash, SharedFile {
+                metadata: metadata.clone(),
+                path: file_path.to_path_buf(),
+                audience,
+            });
+        }
+        Ok(metadata)
+    }
+
+    /// Send several files and/or directories as one transfer. The offer carries
+    /// a manifest of relative paths, sizes and hashes; the file bytes are
+    /// concatenated in manifest order and chunked like a single file.
+    pub fn prepare_bundle_to(&mut self, audience: FileAudience, paths: &[PathBuf]) -> Result<FileOfferPayload, String> {
+        let files = collect_bundle_files(paths)?;
+
+        let mut data = Vec::new();
+        let mut bundle = Vec::with_capacity(files.len());
+        for (path, rel) in files {
+            let contents = std::fs::read(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            if (data.len() + contents.len()) as u64 > MAX_FILE_SIZE {
+                return Err(format!("Bundle too large (max {} bytes)", MAX_FILE_SIZE));
+            }
+            bundle.push(BundleEntry {
+                path: rel,
+                size: contents.len() as u64,
+                sha256: Sha256::digest(&contents).into(),
+            });
+            data.extend_from_slice(&contents);
+        }
+
+        let name = match paths {
+            [single] => single.file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_else(|| "bundle".to_string()),
+            _ => format!("{} files", bundle.len()),
+        };
+        Ok(self.register_outgoing(audience, name, data, None, bundle))
+    }
+
+    /// Chunk `data` and track it as a new outgoing transfer.
+    fn register_outgoing(
+        &mut self,
+        audience: FileAudience,
+        filename: String,
+        data: Vec<u8>,
+        preview: Option<FilePreview>,
+        bundle: Vec<BundleEntry>,
+    ) -> FileOfferPayload {
         let mut file_id = [0u8; 16];
         OsRng.fill_bytes(&mut file_id);
 
@@ -61,10 +700,12 @@ impl FileTransferManager {
         hasher.update(&data);
         let hash: [u8; 32] = hasher.finalize().into();
 
-        let chunk_count = ((data.len() + CHUNK_SIZE - 1) / CHUNK_SIZE).max(1) as u32;
-        let filename = file_path.file_name()
-            .map(|n| n.to_string_lossy().to_string())
-            .unwrap_or_else(|| "unknown".to_string());
+        let chunk_count = data.len().div_ceil(CHUNK_SIZE).max(1) as u32;
+        let chunks: Vec<Vec<u8>> = if data.is_empty() {
+            vec![vec![]]
+        } else {
+            data.chunks(CHUNK_SIZE).map(|c| c.to_vec()).collect()
+        };
 
         let metadata = FileOfferPayload {
             file_id,
@@ -72,62 +713,146 @@ impl FileTransferManager {
             size_bytes: data.len() as u64,
             chunk_count,
             sha256_hash: hash,
-        };
-
-        let chunks: Vec<Vec<u8>> = if data.is_empty() {
-            vec![vec![]]
-        } else {
-            data.chunks(CHUNK_SIZE).map(|c| c.to_vec()).collect()
+            chunk_hashes: chunks.iter().map(|c| hash_chunk(c)).collect(),
+            preview,
+            bundle,
         };
 
         self.outgoing.insert(file_id, OutgoingTransfer {
             metadata: metadata.clone(),
-            dest,
+            audience,
             chunks,
-            next_chunk: 0,
-            accepted: false,
+            receivers: HashMap::new(),
+            in_flight: HashMap::new(),
+            started_at: None,
+            last_activity: Instant::now(),
         });
 
-        Ok(metadata)
+        metadata
     }
 
-    /// Mark an outgoing transfer as accepted by the receiver.
-    pub fn mark_accepted(&mut self, file_id: &[u8; 16]) -> bool {
-        if let Some(transfer) = self.outgoing.get_mut(file_id) {
-            transfer.accepted = true;
-            true
-        } else {
-            false
+    /// Record that `receiver` accepted an outgoing transfer. A direct transfer
+    /// only accepts its addressee; group and mesh-wide offers take anyone.
+    pub fn mark_accepted(&mut self, file_id: &[u8; 16], receiver: [u8; 32]) -> bool {
+        let Some(transfer) = self.outgoing.get_mut(file_id) else {
+            return false;
+        };
+        if let FileAudience::Peer(dest) = transfer.audience {
+            if dest != receiver {
+                return false;
+            }
+        }
+        transfer.receivers.entry(receiver).or_default();
+        transfer.started_at.get_or_insert_with(Instant::now);
+        transfer.last_activity = Instant::now();
+        true
+    }
+
+    /// Like `mark_accepted`, for a receiver that only wants some entries of a
+    /// bundle (empty = all). Chunks outside the selection count as already
+    /// delivered to that receiver, so they are never sent on its behalf.
+    pub fn mark_accepted_entries(&mut self, file_id: &[u8; 16], receiver: [u8; 32], entries: &[u32]) -> bool {
+        if !self.mark_accepted(file_id, receiver) {
+            return false;
         }
+        if entries.is_empty() {
+            return true;
+        }
+        let Some(transfer) = self.outgoing.get_mut(file_id) else {
+            return false;
+        };
+        let Some(wanted) = bundle_chunks(&transfer.metadata.bundle, entries) else {
+            return true;
+        };
+        let acked = transfer.receivers.entry(receiver).or_default();
+        acked.extend((0..transfer.metadata.chunk_count).filter(|seq| !wanted.contains(seq)));
+        true
     }
 
     /// Get the next chunk to send for an outgoing transfer.
-    /// Returns (sequence_number, chunk_data) or None if done/not accepted.
+    /// Chunks whose acknowledgement timed out are resent first; otherwise the
+    /// lowest chunk some receiver still needs is released, while fewer than
+    /// `SEND_WINDOW` are in flight. A receiver that accepts late therefore
+    /// gets the earlier chunks resent to the whole audience.
+    /// Returns None if the window is full, the transfer is done, or not accepted.
     pub fn next_chunk(&mut self, file_id: &[u8; 16]) -> Option<FileChunkPayload> {
         let transfer = self.outgoing.get_mut(file_id)?;
-        if !transfer.accepted || transfer.next_chunk >= transfer.metadata.chunk_count {
+        if transfer.receivers.is_empty() {
             return None;
         }
-        let seq = transfer.next_chunk;
-        let data = transfer.chunks[seq as usize].clone();
-        transfer.next_chunk += 1;
+
+        let receivers = &transfer.receivers;
+        transfer.in_flight.retain(|seq, _| receivers.values().any(|acked| !acked.contains(seq)));
+
+        let timed_out = transfer.in_flight.iter()
+            .filter(|(_, sent)| sent.elapsed() >= CHUNK_ACK_TIMEOUT)
+            .map(|(seq, _)| *seq)
+            .min();
+        let seq = match timed_out {
+            Some(seq) => seq,
+            None => {
+                if transfer.in_flight.len() as u32 >= SEND_WINDOW {
+                    return None;
+                }
+                (0..transfer.metadata.chunk_count)
+                    .find(|seq| !transfer.in_flight.contains_key(seq) && transfer.is_needed(*seq))?
+            }
+        };
+
+        transfer.in_flight.insert(seq, Instant::now());
         Some(FileChunkPayload {
             file_id: *file_id,
             sequence: seq,
-            data,
+            data: transfer.chunks[seq as usize].clone(),
         })
     }
 
-    /// Check if all chunks have been sent for an outgoing transfer.
+    /// Record a chunk acknowledgement from a receiver, freeing a window slot once
+    /// every receiver has it. Returns the sender-side progress of the slowest
+    /// receiver, or None if the transfer or receiver is unknown.
+    pub fn ack_chunk(&mut self, file_id: &[u8; 16], from: &[u8; 32], sequence: u32) -> Option<TransferProgress> {
+        let transfer = self.outgoing.get_mut(file_id)?;
+        let chunk_count = transfer.metadata.chunk_count;
+        let acked = transfer.receivers.get_mut(from)?;
+        if sequence < chunk_count {
+            acked.insert(sequence);
+        }
+        transfer.last_activity = Instant::now();
+        if !transfer.is_needed(sequence) {
+            transfer.in_flight.remove(&sequence);
+        }
+        let started_at = transfer.started_at.unwrap_or_else(Instant::now);
+        Some(TransferProgress::compute(transfer.bytes_acked(), transfer.metadata.size_bytes, started_at))
+    }
+
+    /// Check if every accepted receiver has acknowledged every chunk.
+    pub fn is_outgoing_acked(&self, file_id: &[u8; 16]) -> bool {
+        self.outgoing.get(file_id)
+            .map(|t| t.is_done())
+            .unwrap_or(true)
+    }
+
+    /// IDs of accepted outgoing transfers that still have chunks to send or confirm.
+    pub fn active_outgoing(&self) -> Vec<[u8; 16]> {
+        self.outgoing.iter()
+            .filter(|(_, t)| !t.receivers.is_empty() && !t.is_done())
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Check if every chunk a receiver still needs has been sent at least once.
     pub fn is_outgoing_complete(&self, file_id: &[u8; 16]) -> bool {
         self.outgoing.get(file_id)
-            .map(|t| t.next_chunk >= t.metadata.chunk_count)
+            .map(|t| {
+                !t.receivers.is_empty()
+                    && !(0..t.metadata.chunk_count).any(|seq| !t.in_flight.contains_key(&seq) && t.is_needed(seq))
+            })
             .unwrap_or(true)
     }
 
-    /// Get the destination node for an outgoing transfer.
-    pub fn outgoing_dest(&self, file_id: &[u8; 16]) -> Option<[u8; 32]> {
-        self.outgoing.get(file_id).map(|t| t.dest)
+    /// Get the audience of an outgoing transfer.
+    pub fn outgoing_audience(&self, file_id: &[u8; 16]) -> Option<FileAudience> {
+        self.outgoing.get(file_id).map(|t| t.audience.clone())
     }
 
     /// Remove a completed outgoing transfer.
@@ -135,46 +860,211 @@ impl FileTransferManager {
         self.outgoing.remove(file_id);
     }
 
-    /// Register an incoming file offer.
-    pub fn register_incoming(&mut self, metadata: FileOfferPayload, sender_id: [u8; 32]) {
+    /// Decline an incoming offer and forget it.
+    /// Returns the sender's node_id for sending FileDecline.
+    pub fn decline_incoming(&mut self, file_id: &[u8; 16]) -> Option<[u8; 32]> {
+        self.incoming.remove(file_id).map(|t| t.sender_id)
+    }
+
+    /// Cancel a transfer in either direction from our side.
+    /// Returns who to send FileCancel to.
+    pub fn cancel_transfer(&mut self, file_id: &[u8; 16]) -> Option<FileAudience> {
+        if let Some(t) = self.outgoing.remove(file_id) {
+            return Some(t.audience);
+        }
+        self.incoming.remove(file_id).map(|t| FileAudience::Peer(t.sender_id))
+    }
+
+    /// Handle a FileDecline from `from`. Only the recipient of a direct offer can
+    /// end it this way; group and mesh-wide offers stay open for other receivers.
+    pub fn handle_remote_decline(&mut self, file_id: &[u8; 16], from: &[u8; 32]) -> bool {
+        if self.outgoing.get(file_id).map(|t| &t.audience) == Some(&FileAudience::Peer(*from)) {
+            self.outgoing.remove(file_id);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Handle a FileCancel from `from`. Returns true if the whole transfer ended;
+    /// a receiver leaving a group or mesh-wide transfer only drops that receiver.
+    pub fn handle_remote_cancel(&mut self, file_id: &[u8; 16], from: &[u8; 32]) -> bool {
+        if let Some(t) = self.outgoing.get_mut(file_id) {
+            if t.audience == FileAudience::Peer(*from) {
+                self.outgoing.remove(file_id);
+                return true;
+            }
+            if t.audience.destination().is_none() {
+                t.receivers.remove(from);
+            }
+            false
+        } else if self.incoming.get(file_id).map(|t| &t.sender_id) == Some(from) {
+            self.incoming.remove(file_id);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Drop offers left unanswered for `offer_timeout` and accepted transfers
+    /// with no activity for `stall_timeout`.
+    ///
+    /// Group and mesh-wide offers stay open for late acceptors: stalled receivers
+    /// are dropped rather than failing the transfer, and once everyone who
+    /// accepted is done the offer is retired silently after `offer_timeout`.
+    pub fn expire_stale(&mut self, offer_timeout: Duration, stall_timeout: Duration) -> Vec<ExpiredTransfer> {
+        fn reason(accepted: bool) -> String {
+            if accepted { "Transfer stalled".to_string() } else { "Offer timed out".to_string() }
+        }
+        let is_stale = |accepted: bool, last_activity: Instant| {
+            let limit = if accepted { stall_timeout } else { offer_timeout };
+            last_activity.elapsed() >= limit
+        };
+
+        let mut expired = Vec::new();
+        self.outgoing.retain(|id, t| {
+            let idle = t.last_activity.elapsed();
+            if t.audience.destination().is_none() {
+                if !t.is_done() && !t.receivers.is_empty() && idle >= stall_timeout {
+                    let chunk_count = t.metadata.chunk_count;
+                    t.receivers.retain(|_, acked| acked.len() as u32 >= chunk_count);
+                }
+                if idle < offer_timeout {
+                    return true;
+                }
+                if t.receivers.is_empty() {
+                    expired.push(ExpiredTransfer { file_id: *id, audience: t.audience.clone(), reason: reason(false) });
+                }
+                return false;
+            }
+            let accepted = !t.receivers.is_empty();
+            if is_stale(accepted, t.last_activity) {
+                expired.push(ExpiredTransfer { file_id: *id, audience: t.audience.clone(), reason: reason(accepted) });
+                false
+            } else {
+                true
+            }
+        });
+        self.incoming.retain(|id, t| {
+            if is_stale(t.accepted, t.last_activity) {
+                expired.push(ExpiredTransfer {
+                    file_id: *id,
+                    audience: FileAudience::Peer(t.sender_id),
+                    reason: reason(t.accepted),
+                });
+                false
+            } else {
+                true
+            }
+        });
+        expired
+    }
+
+    /// Register an incoming file offer. The offered filename is sanitized here,
+    /// so everything downstream (events, UI, disk) only sees the safe name.
+    /// Returns false, registering nothing, for an offer whose sizes don't add up.
+    pub fn register_incoming(&mut self, metadata: FileOfferPayload, sender_id: [u8; 32]) -> bool {
+        self.register_incoming_shared(metadata, sender_id, None)
+    }
+
+    /// Like `register_incoming`, for a file offered to a group or the whole
+    /// mesh: once complete, we serve it to the same audience.
+    pub fn register_incoming_shared(&mut self, mut metadata: FileOfferPayload, sender_id: [u8; 32], share_with: Option<FileAudience>) -> bool {
+        if !offer_is_consistent(&metadata) {
+            return false;
+        }
+        metadata.filename = sanitize_filename(&metadata.filename);
+        let save_dir = self.quarantine_dir.clone().unwrap_or_else(|| self.save_dir.clone());
         self.incoming.insert(metadata.file_id, IncomingTransfer {
             metadata,
             sender_id,
             chunks: HashMap::new(),
             accepted: false,
-            save_dir: self.save_dir.clone(),
+            save_dir,
+            bytes_received: 0,
+            started_at: None,
+            last_activity: Instant::now(),
+            holders: HashSet::new(),
+            requested: HashMap::new(),
+            last_query: None,
+            selection: None,
+            share_with,
         });
+        true
     }
 
     /// Accept an incoming transfer. Returns the sender's node_id for sending FileAccept.
     pub fn accept_incoming(&mut self, file_id: &[u8; 16]) -> Option<[u8; 32]> {
         let transfer = self.incoming.get_mut(file_id)?;
         transfer.accepted = true;
+        transfer.started_at.get_or_insert_with(Instant::now);
+        transfer.last_activity = Instant::now();
         Some(transfer.sender_id)
     }
 
+    /// Accept only some entries of an incoming bundle (indices into its manifest).
+    /// Returns the sender's node_id, or None for an unknown transfer or a bad index.
+    pub fn accept_incoming_entries(&mut self, file_id: &[u8; 16], entries: &[u32]) -> Option<[u8; 32]> {
+        let transfer = self.incoming.get_mut(file_id)?;
+        if entries.is_empty() || transfer.metadata.bundle.is_empty() {
+            return None;
+        }
+        let mut entries = entries.to_vec();
+        entries.sort_unstable();
+        entries.dedup();
+        let chunks = bundle_chunks(&transfer.metadata.bundle, &entries)?;
+        if entries.len() < transfer.metadata.bundle.len() {
+            transfer.selection = Some((entries, chunks));
+        }
+        self.accept_incoming(file_id)
+    }
+
     /// Receive a chunk for an incoming transfer. Returns progress percentage.
     pub fn receive_chunk(&mut self, file_id: &[u8; 16], sequence: u32, data: Vec<u8>) -> Option<u8> {
         let transfer = self.incoming.get_mut(file_id)?;
-        if !transfer.accepted {
+        if !transfer.accepted || sequence >= transfer.metadata.chunk_count || !transfer.needs(sequence) {
             return None;
         }
-        transfer.chunks.insert(sequence, data);
-        let pct = ((transfer.chunks.len() as f64 / transfer.metadata.chunk_count as f64) * 100.0) as u8;
+        if has_chunk_hashes(&transfer.metadata)
+            && hash_chunk(&data) != transfer.metadata.chunk_hashes[sequence as usize]
+        {
+            return None;
+        }
+        transfer.last_activity = Instant::now();
+        transfer.requested.remove(&sequence);
+        let len = data.len() as u64;
+        if transfer.chunks.insert(sequence, data).is_none() {
+            transfer.bytes_received += len;
+        }
+        let (needed, have) = transfer.chunk_progress();
+        let pct = ((have as f64 / needed as f64) * 100.0) as u8;
         Some(pct.min(100))
     }
 
     /// Check if all chunks have been received for an incoming transfer.
     pub fn is_incoming_complete(&self, file_id: &[u8; 16]) -> bool {
         self.incoming.get(file_id)
-            .map(|t| t.chunks.len() as u32 >= t.metadata.chunk_count)
+            .map(|t| {
+                let (needed, have) = t.chunk_progress();
+                have >= needed
+            })
             .unwrap_or(false)
     }
 
+    /// Receiver-side progress (throughput and ETA) for an incoming transfer.
+    pub fn incoming_progress(&self, file_id: &[u8; 16]) -> Option<TransferProgress> {
+        let transfer = self.incoming.get(file_id)?;
+        let started_at = transfer.started_at?;
+        Some(TransferProgress::compute(transfer.bytes_received, transfer.bytes_needed(), started_at))
+    }
+
     /// Finalize an incoming transfer: reassemble, verify hash, write to disk.
     pub fn finalize_incoming(&mut self, file_id: &[u8; 16]) -> Result<PathBuf, String> {
         let transfer = self.incoming.remove(file_id)
             .ok_or_else(|| "Transfer not found".to_string())?;
+        if !transfer.metadata.bundle.is_empty() {
+            return Self::finalize_bundle(transfer);
+        }
 
         // Reassemble chunks in order
         let mut data = Vec::with_capacity(transfer.metadata.size_bytes as usize);
@@ -192,16 +1082,255 @@ impl FileTransferManager {
             return Err("File hash mismatch - transfer corrupted".to_string());
         }
 
-        // Save to disk
+        // Save to disk under a sanitized, non-colliding name
         std::fs::create_dir_all(&transfer.save_dir)
             .map_err(|e| format!("Failed to create directory: {}", e))?;
-        let path = transfer.save_dir.join(&transfer.metadata.filename);
-        std::fs::write(&path, &data)
-            .map_err(|e| format!("Failed to write file: {}", e))?;
+        let filename = sanitize_filename(&transfer.metadata.filename);
+        let (path, mut file) = create_unique_file(&transfer.save_dir, &filename)
+            .map_err(|e| format!("Failed to create file: {}", e))?;
+        use std::io::Write;
+        if let Err(e) = file.write_all(&data) {
+            drop(file);
+            let _ = std::fs::remove_file(&path);
+            return Err(format!("Failed to write file: {}", e));
+        }
+
+        // Everyone who finishes a shared download can serve it to the rest of its audience
+        let mut metadata = transfer.metadata;
+        metadata.filename = path.file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or(metadata.filename);
+        self.wanted.remove(&metadata.sha256_hash);
+        if let Some(audience) = transfer.share_with {
+            self.library.insert(metadata.sha256_hash, SharedFile { metadata, path: path.clone(), audience });
+        }
 
         Ok(path)
     }
 
+    /// Extract a received bundle into a fresh directory under the save dir. Each
+    /// accepted entry is checked against its manifest hash and written beneath
+    /// the bundle root only; remote paths can never climb out of it.
+    fn finalize_bundle(transfer: IncomingTransfer) -> Result<PathBuf, String> {
+        let metadata = &transfer.metadata;
+        let selected: Vec<u32> = match &transfer.selection {
+            Some((entries, _)) => entries.clone(),
+            None => (0..metadata.bundle.len() as u32).collect(),
+        };
+
+        // Without a subset selection every byte arrived, so check the whole-transfer hash too
+        if transfer.selection.is_none() {
+            let mut hasher = Sha256::new();
+            for i in 0..metadata.chunk_count {
+                let chunk = transfer.chunks.get(&i).ok_or_else(|| format!("Missing chunk {}", i))?;
+                hasher.update(chunk);
+            }
+            let hash: [u8; 32] = hasher.finalize().into();
+            if hash != metadata.sha256_hash {
+                return Err("File hash mismatch - transfer corrupted".to_string());
+            }
+        }
+
+        // Read `len` bytes at `offset` out of the received chunks
+        let read_range = |offset: u64, len: u64| -> Result<Vec<u8>, String> {
+            let mut out = Vec::with_capacity(len as usize);
+            let mut pos = offset;
+            while pos < offset + len {
+                let seq = (pos / CHUNK_SIZE as u64) as u32;
+                let chunk = transfer.chunks.get(&seq).ok_or_else(|| format!("Missing chunk {}", seq))?;
+                let start = (pos % CHUNK_SIZE as u64) as usize;
+                let take = ((offset + len - pos) as usize).min(chunk.len().saturating_sub(start));
+                if take == 0 {
+                    return Err(format!("Short chunk {}", seq));
+                }
+                out.extend_from_slice(&chunk[start..start + take]);
+                pos += take as u64;
+            }
+            Ok(out)
+        };
+
+        let mut files = Vec::with_capacity(selected.len());
+        let mut offset = 0u64;
+        for (idx, entry) in metadata.bundle.iter().enumerate() {
+            if selected.contains(&(idx as u32)) {
+                let data = read_range(offset, entry.size)?;
+                if Sha256::digest(&data).as_slice() != entry.sha256 {
+                    return Err(format!("Hash mismatch for {}", entry.path));
+                }
+                files.push((bundle_relative_path(&entry.path), data));
+            }
+            offset += entry.size;
+        }
+
+        std::fs::create_dir_all(&transfer.save_dir)
+            .map_err(|e| format!("Failed to create directory: {}", e))?;
+        let root = create_unique_dir(&transfer.save_dir, &sanitize_filename(&metadata.filename))
+            .map_err(|e| format!("Failed to create directory: {}", e))?;
+        for (rel, data) in files {
+            let dir = rel.parent().map(|p| root.join(p)).unwrap_or_else(|| root.clone());
+            let name = rel.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+            let written = std::fs::create_dir_all(&dir)
+                .and_then(|_| create_unique_file(&dir, &name))
+                .and_then(|(_, mut file)| {
+                    use std::io::Write;
+                    file.write_all(&data)
+                });
+            if let Err(e) = written {
+                let _ = std::fs::remove_dir_all(&root);
+                return Err(format!("Failed to write {}: {}", rel.display(), e));
+            }
+        }
+        Ok(root)
+    }
+
+    /// A shared file `requester` is allowed to fetch.
+    fn shared_for(&self, content_hash: &[u8; 32], requester: &[u8; 32]) -> Option<&SharedFile> {
+        self.library.get(content_hash).filter(|f| f.audience.admits(requester))
+    }
+
+    /// Metadata for a file we can serve by content hash to `requester` (only
+    /// files with chunk hashes).
+    pub fn shared_offer(&self, content_hash: &[u8; 32], requester: &[u8; 32]) -> Option<FileOfferPayload> {
+        self.shared_for(content_hash, requester)
+            .map(|f| f.metadata.clone())
+            .filter(has_chunk_hashes)
+    }
+
+    /// Read one chunk of a shared file from disk for `requester`, verified
+    /// against its chunk hash so a file changed since it was shared is never served.
+    pub fn read_shared_chunk(&self, content_hash: &[u8; 32], sequence: u32, requester: &[u8; 32]) -> Option<Vec<u8>> {
+        use std::io::{Read, Seek, SeekFrom};
+        let shared = self.shared_for(content_hash, requester)?;
+        let expected = shared.metadata.chunk_hashes.get(sequence as usize)?;
+        let offset = sequence as u64 * CHUNK_SIZE as u64;
+        let len = shared.metadata.size_bytes.saturating_sub(offset).min(CHUNK_SIZE as u64) as usize;
+
+        let mut file = std::fs::File::open(&shared.path).ok()?;
+        file.seek(SeekFrom::Start(offset)).ok()?;
+        let mut data = vec![0u8; len];
+        file.read_exact(&mut data).ok()?;
+        (hash_chunk(&data) == *expected).then_some(data)
+    }
+
+    /// Start looking for a file by content hash. Returns false if we already
+    /// have it or are already looking for or downloading it.
+    pub fn want_file(&mut self, content_hash: [u8; 32]) -> bool {
+        if self.library.contains_key(&content_hash)
+            || self.wanted.contains_key(&content_hash)
+            || self.incoming.values().any(|t| t.metadata.sha256_hash == content_hash)
+        {
+            return false;
+        }
+        let now = Instant::now();
+        self.wanted.insert(content_hash, (now, now));
+        true
+    }
+
+    /// Handle a SwarmHave from `holder`. The holder is added to any matching
+    /// download whose chunk hashes agree with its offer; a file we asked for with
+    /// `want_file` starts downloading here. Returns the new file_id in that case.
+    pub fn handle_swarm_have(&mut self, holder: [u8; 32], mut offer: FileOfferPayload) -> Option<[u8; 16]> {
+        if !has_chunk_hashes(&offer) || !offer.bundle.is_empty() || !offer_is_consistent(&offer) {
+            return None;
+        }
+        if let Some(transfer) = self.incoming.values_mut()
+            .find(|t| t.metadata.sha256_hash == offer.sha256_hash)
+        {
+            if transfer.metadata.chunk_hashes == offer.chunk_hashes {
+                transfer.holders.insert(holder);
+            }
+            return None;
+        }
+        self.wanted.remove(&offer.sha256_hash)?;
+
+        let mut file_id = [0u8; 16];
+        OsRng.fill_bytes(&mut file_id);
+        offer.file_id = file_id;
+        // Holders only answer for files offered beyond a single peer
+        self.register_incoming_shared(offer, holder, Some(FileAudience::Everyone));
+        self.accept_incoming(&file_id);
+        if let Some(transfer) = self.incoming.get_mut(&file_id) {
+            transfer.holders.insert(holder);
+        }
+        Some(file_id)
+    }
+
+    /// Content hashes to flood a SwarmQuery for: wanted files, and accepted
+    /// downloads idle for `SWARM_FALLBACK_AFTER` (e.g. the sender left) that
+    /// have no holders yet. Each hash is re-queried at most every `SWARM_QUERY_INTERVAL`.
+    /// Wanted files nobody answered for within `WANT_TIMEOUT` are dropped.
+    pub fn swarm_queries_due(&mut self) -> Vec<[u8; 32]> {
+        let mut due = Vec::new();
+        self.wanted.retain(|_, (since, _)| since.elapsed() < WANT_TIMEOUT);
+        for (hash, (_, last)) in self.wanted.iter_mut() {
+            if last.elapsed() >= SWARM_QUERY_INTERVAL {
+                *last = Instant::now();
+                due.push(*hash);
+            }
+        }
+        for transfer in self.incoming.values_mut() {
+            let query_due = transfer.last_query
+                .map(|q| q.elapsed() >= SWARM_QUERY_INTERVAL)
+                .unwrap_or(true);
+            if transfer.accepted
+                && transfer.holders.is_empty()
+                && has_chunk_hashes(&transfer.metadata)
+                && transfer.metadata.bundle.is_empty()
+                && transfer.last_activity.elapsed() >= SWARM_FALLBACK_AFTER
+                && query_due
+            {
+                transfer.last_query = Some(Instant::now());
+                due.push(transfer.metadata.sha256_hash);
+            }
+        }
+        due
+    }
+
+    /// Chunk requests to send to holders: missing chunks not already requested
+    /// (or whose request timed out), spread round-robin across the holders with
+    /// at most `SEND_WINDOW` outstanding per holder.
+    /// Returns (holder, content_hash, sequences) per request.
+    pub fn swarm_requests(&mut self) -> Vec<([u8; 32], [u8; 32], Vec<u32>)> {
+        let mut requests = Vec::new();
+        for transfer in self.incoming.values_mut() {
+            if !transfer.accepted || transfer.holders.is_empty() {
+                continue;
+            }
+            transfer.requested.retain(|_, at| at.elapsed() < CHUNK_ACK_TIMEOUT);
+            let mut holders: Vec<[u8; 32]> = transfer.holders.iter().copied().collect();
+            holders.sort();
+            let budget = (SEND_WINDOW as usize * holders.len()).saturating_sub(transfer.requested.len());
+            let missing: Vec<u32> = (0..transfer.metadata.chunk_count)
+                .filter(|seq| transfer.needs(*seq) && !transfer.chunks.contains_key(seq) && !transfer.requested.contains_key(seq))
+                .take(budget)
+                .collect();
+            if missing.is_empty() {
+                continue;
+            }
+
+            let mut per_holder: Vec<Vec<u32>> = vec![Vec::new(); holders.len()];
+            for (i, seq) in missing.into_iter().enumerate() {
+                per_holder[i % holders.len()].push(seq);
+                transfer.requested.insert(seq, Instant::now());
+            }
+            for (holder, sequences) in holders.into_iter().zip(per_holder) {
+                if !sequences.is_empty() {
+                    requests.push((holder, transfer.metadata.sha256_hash, sequences));
+                }
+            }
+        }
+        requests
+    }
+
+    /// Receive a chunk served by a swarm holder. Returns the local file_id and
+    /// progress percentage, or None if we aren't downloading it or it fails verification.
+    pub fn receive_swarm_chunk(&mut self, content_hash: &[u8; 32], sequence: u32, data: Vec<u8>) -> Option<([u8; 16], u8)> {
+        let file_id = self.incoming.iter()
+            .find(|(_, t)| t.metadata.sha256_hash == *content_hash && has_chunk_hashes(&t.metadata))
+            .map(|(id, _)| *id)?;
+        self.receive_chunk(&file_id, sequence, data).map(|pct| (file_id, pct))
+    }
+
     /// Get metadata for an incoming transfer (for display).
     pub fn get_incoming_metadata(&self, file_id: &[u8; 16]) -> Option<&FileOfferPayload> {
         self.incoming.get(file_id).map(|t| &t.metadata)
@@ -239,7 +1368,7 @@ mod tests {
         assert!(mgr.next_chunk(&metadata.file_id).is_none());
 
         // Accept
-        mgr.mark_accepted(&metadata.file_id);
+        mgr.mark_accepted(&metadata.file_id, dest);
 
         // Register as incoming on receiver side
         let sender_id = [1u8; 32];
@@ -265,6 +1394,553 @@ mod tests {
         let _ = std::fs::remove_dir_all(&dir);
     }
 
+    #[test]
+    fn test_send_window_and_acks() {
+        let dir = std::env::temp_dir().join("mesh_test_send_window");
+        std::fs::create_dir_all(&dir).unwrap();
+        let src_path = dir.join("window.bin");
+        let chunk_count = SEND_WINDOW as usize + 4;
+        std::fs::write(&src_path, vec![7u8; CHUNK_SIZE * chunk_count]).unwrap();
+
+        let mut mgr = FileTransferManager::new(dir.join("received"));
+        let dest = [2u8; 32];
+        let metadata = mgr.prepare_send(dest, &src_path).unwrap();
+        assert_eq!(metadata.chunk_count as usize, chunk_count);
+        assert!(!mgr.mark_accepted(&metadata.file_id, [3u8; 32]));
+        assert!(mgr.mark_accepted(&metadata.file_id, dest));
+
+        // Only a window's worth of chunks is released before any ack
+        let mut sent = Vec::new();
+        while let Some(chunk) = mgr.next_chunk(&metadata.file_id) {
+            sent.push(chunk.sequence);
+        }
+        assert_eq!(sent, (0..SEND_WINDOW).collect::<Vec<_>>());
+        assert_eq!(mgr.active_outgoing(), vec![metadata.file_id]);
+
+        // Each ack frees exactly one slot
+        let progress = mgr.ack_chunk(&metadata.file_id, &dest, 0).unwrap();
+        assert_eq!(progress.bytes_done, CHUNK_SIZE as u64);
+        assert_eq!(mgr.next_chunk(&metadata.file_id).unwrap().sequence, SEND_WINDOW);
+        assert!(mgr.next_chunk(&metadata.file_id).is_none());
+
+        // Duplicate acks are not double-counted
+        let progress = mgr.ack_chunk(&metadata.file_id, &dest, 0).unwrap();
+        assert_eq!(progress.bytes_done, CHUNK_SIZE as u64);
+
+        // Drain the rest by acking everything that is sent
+        for seq in 1..=SEND_WINDOW {
+            mgr.ack_chunk(&metadata.file_id, &dest, seq);
+        }
+        while let Some(chunk) = mgr.next_chunk(&metadata.file_id) {
+            mgr.ack_chunk(&metadata.file_id, &dest, chunk.sequence);
+        }
+        assert!(mgr.is_outgoing_complete(&metadata.file_id));
+        assert!(mgr.is_outgoing_acked(&metadata.file_id));
+        assert!(mgr.active_outgoing().is_empty());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_sanitize_hostile_filenames() {
+        let cases = [
+            ("../../.bashrc", "bashrc"),
+            ("/etc/passwd", "passwd"),
+            ("..\\..\\Windows\\System32\\evil.dll", "evil.dll"),
+            ("C:\\Users\\victim\\run.bat", "run.bat"),
+            ("C:evil.exe", "Cevil.exe"),
+            ("..", "unnamed"),
+            (".", "unnamed"),
+            ("", "unnamed"),
+            ("////", "unnamed"),
+            ("dir/", "unnamed"),
+            ("...hidden", "hidden"),
+            ("trailing. . .", "trailing"),
+            ("nul\u{0}byte.txt", "nulbyte.txt"),
+            ("line\nbreak\r.txt", "linebreak.txt"),
+            ("\u{1b}[31mred.txt", "[31mred.txt"),
+            ("what?<>|*\"name\".pdf", "whatname.pdf"),
+            ("CON", "_CON"),
+            ("nul.txt", "_nul.txt"),
+            ("Com1.log", "_Com1.log"),
+            ("photo.jpg", "photo.jpg"),
+            ("résumé 2024.pdf", "résumé 2024.pdf"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(sanitize_filename(input), expected, "input: {:?}", input);
+        }
+
+        let long = format!("{}.txt", "é".repeat(300));
+        let clean = sanitize_filename(&long);
+        assert!(clean.len() <= MAX_FILENAME_LEN);
+        assert!(clean.ends_with(".txt"));
+    }
+
+    #[test]
+    fn test_hostile_offer_stays_in_save_dir() {
+        let dir = std::env::temp_dir().join(format!("mesh_test_hostile_{}", rand::random::<u32>()));
+        let recv_dir = dir.join("received");
+        let data = b"export EVIL=1".to_vec();
+        let hash: [u8; 32] = Sha256::digest(&data).into();
+
+        let mut mgr = FileTransferManager::new(recv_dir.clone());
+        for (i, name) in ["../../.bashrc", "/tmp/../../.bashrc", "..\\.bashrc"].iter().enumerate() {
+            let offer = FileOfferPayload {
+                file_id: [i as u8; 16],
+                filename: name.to_string(),
+                size_bytes: data.len() as u64,
+                chunk_count: 1,
+                sha256_hash: hash,
+                chunk_hashes: Vec::new(),
+                preview: None,
+                bundle: Vec::new(),
+            };
+            mgr.register_incoming(offer, [1u8; 32]);
+            assert_eq!(mgr.get_incoming_metadata(&[i as u8; 16]).unwrap().filename, "bashrc");
+            mgr.accept_incoming(&[i as u8; 16]);
+            mgr.receive_chunk(&[i as u8; 16], 0, data.clone());
+            let path = mgr.finalize_incoming(&[i as u8; 16]).unwrap();
+            assert_eq!(path.parent().unwrap(), recv_dir.as_path());
+        }
+
+        // Same name three times: nothing overwritten, numbered copies instead
+        let mut names: Vec<String> = std::fs::read_dir(&recv_dir).unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["bashrc", "bashrc (1)", "bashrc (2)"]);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_collision_keeps_extension() {
+        let dir = std::env::temp_dir().join(format!("mesh_test_collide_{}", rand::random::<u32>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("map.pdf"), b"original").unwrap();
+
+        let (path, _) = create_unique_file(&dir, "map.pdf").unwrap();
+        assert_eq!(path, dir.join("map (1).pdf"));
+        assert_eq!(std::fs::read(dir.join("map.pdf")).unwrap(), b"original");
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_quarantine_dir() {
+        let dir = std::env::temp_dir().join(format!("mesh_test_quarantine_{}", rand::random::<u32>()));
+        let data = b"untrusted".to_vec();
+        let offer = FileOfferPayload {
+            file_id: [9u8; 16],
+            filename: "report.pdf".into(),
+            size_bytes: data.len() as u64,
+            chunk_count: 1,
+            sha256_hash: Sha256::digest(&data).into(),
+            chunk_hashes: vec![hash_chunk(&data)],
+            preview: None,
+            bundle: Vec::new(),
+        };
+
+        let mut mgr = FileTransferManager::new(dir.join("received"));
+        mgr.set_quarantine_dir(Some(dir.join("quarantine")));
+        mgr.register_incoming(offer, [1u8; 32]);
+        mgr.accept_incoming(&[9u8; 16]);
+        mgr.receive_chunk(&[9u8; 16], 0, data);
+        let path = mgr.finalize_incoming(&[9u8; 16]).unwrap();
+        assert_eq!(path, dir.join("quarantine").join("report.pdf"));
+        assert!(!dir.join("received").exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_group_send_shares_chunks() {
+        let dir = std::env::temp_dir().join(format!("mesh_test_group_{}", rand::random::<u32>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let src_path = dir.join("map.pdf");
+        std::fs::write(&src_path, vec![3u8; CHUNK_SIZE * 3]).unwrap();
+        let (alice, bob) = ([1u8; 32], [2u8; 32]);
+
+        let mut mgr = FileTransferManager::new(dir.join("received"));
+        let metadata = mgr.prepare_send_to(FileAudience::Group("leads".into()), &src_path).unwrap();
+        let id = metadata.file_id;
+        assert_eq!(mgr.outgoing_audience(&id).unwrap().destination(), None);
+
+        // Anyone may accept a group offer; each chunk goes out once for both
+        assert!(mgr.mark_accepted(&id, alice));
+        assert!(mgr.mark_accepted(&id, bob));
+        let mut sent = Vec::new();
+        while let Some(chunk) = mgr.next_chunk(&id) {
+            sent.push(chunk.sequence);
+        }
+        assert_eq!(sent, vec![0, 1, 2]);
+
+        // A chunk stays in flight until every receiver has it
+        for seq in 0..3 {
+            mgr.ack_chunk(&id, &alice, seq);
+        }
+        assert!(mgr.next_chunk(&id).is_none());
+        let progress = mgr.ack_chunk(&id, &bob, 0).unwrap();
+        assert_eq!(progress.bytes_done, CHUNK_SIZE as u64); // slowest receiver
+        assert!(mgr.ack_chunk(&id, &[9u8; 32], 1).is_none()); // never accepted
+        mgr.ack_chunk(&id, &bob, 1);
+        mgr.ack_chunk(&id, &bob, 2);
+        assert!(mgr.is_outgoing_acked(&id));
+        assert!(mgr.active_outgoing().is_empty());
+
+        // A late receiver gets the chunks resent from the start
+        let carol = [3u8; 32];
+        assert!(mgr.mark_accepted(&id, carol));
+        assert!(!mgr.is_outgoing_acked(&id));
+        assert_eq!(mgr.next_chunk(&id).unwrap().sequence, 0);
+
+        // A declining or cancelling receiver doesn't end the offer for everyone
+        assert!(!mgr.handle_remote_decline(&id, &carol));
+        assert!(!mgr.handle_remote_cancel(&id, &carol));
+        assert!(mgr.is_outgoing_acked(&id));
+
+        // Finished group offers retire without reporting a failure
+        assert!(mgr.expire_stale(Duration::ZERO, STALL_TIMEOUT).is_empty());
+        assert!(mgr.outgoing_audience(&id).is_none());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_swarm_fetch_from_multiple_holders() {
+        let dir = std::env::temp_dir().join(format!("mesh_test_swarm_{}", rand::random::<u32>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let src_path = dir.join("library.pdf");
+        let content: Vec<u8> = (0..CHUNK_SIZE * 3 + 100).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&src_path, &content).unwrap();
+        let (alice, bob, carol, mallory) = ([1u8; 32], [2u8; 32], [3u8; 32], [6u8; 32]);
+
+        // Sending a file straight to one peer never makes it fetchable by others
+        let mut a = FileTransferManager::new(dir.join("a"));
+        let direct = a.prepare_send(bob, &src_path).unwrap();
+        assert!(a.shared_offer(&direct.sha256_hash, &mallory).is_none());
+        assert!(a.read_shared_chunk(&direct.sha256_hash, 0, &bob).is_none());
+
+        // Alice shares the file by offering it to the mesh; the sender is a holder too
+        let offer_a = a.prepare_send_to(FileAudience::Everyone, &src_path).unwrap();
+        let hash = offer_a.sha256_hash;
+        assert_eq!(offer_a.chunk_hashes.len(), 4);
+        assert_eq!(a.read_shared_chunk(&hash, 3, &carol).unwrap(), content[CHUNK_SIZE * 3..]);
+
+        // Carol fetches it from Alice alone, then holds it as well
+        let mut c = FileTransferManager::new(dir.join("c"));
+        assert!(c.want_file(hash));
+        let c_id = c.handle_swarm_have(alice, a.shared_offer(&hash, &carol).unwrap()).unwrap();
+        for (holder, h, seqs) in c.swarm_requests() {
+            assert_eq!((holder, h), (alice, hash));
+            for seq in seqs {
+                c.receive_swarm_chunk(&hash, seq, a.read_shared_chunk(&hash, seq, &carol).unwrap()).unwrap();
+            }
+        }
+        c.finalize_incoming(&c_id).unwrap();
+        assert!(!c.want_file(hash));
+
+        // Bob pulls from both holders; a holder with different chunk hashes is ignored
+        let mut b = FileTransferManager::new(dir.join("b"));
+        assert!(b.want_file(hash));
+        assert!(!b.want_file(hash));
+        let b_id = b.handle_swarm_have(alice, a.shared_offer(&hash, &bob).unwrap()).unwrap();
+        assert!(b.handle_swarm_have(carol, c.shared_offer(&hash, &bob).unwrap()).is_none());
+        let mut bogus = a.shared_offer(&hash, &bob).unwrap();
+        bogus.chunk_hashes[0] = [0u8; 32];
+        assert!(b.handle_swarm_have(mallory, bogus).is_none());
+
+        let requests = b.swarm_requests();
+        let holders: HashSet<[u8; 32]> = requests.iter().map(|r| r.0).collect();
+        assert_eq!(holders, HashSet::from([alice, carol]));
+        assert!(b.swarm_requests().is_empty()); // everything is outstanding
+
+        // Chunks failing their hash are dropped; good ones are accepted from anyone
+        assert!(b.receive_swarm_chunk(&hash, 0, vec![0u8; CHUNK_SIZE]).is_none());
+        for (holder, _, seqs) in requests {
+            let source = if holder == alice { &a } else { &c };
+            for seq in seqs {
+                b.receive_swarm_chunk(&hash, seq, source.read_shared_chunk(&hash, seq, &bob).unwrap()).unwrap();
+            }
+        }
+        assert!(b.is_incoming_complete(&b_id));
+        let path = b.finalize_incoming(&b_id).unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), content);
+
+        // A holder whose file changed on disk stops serving the stale chunks
+        std::fs::write(&src_path, b"edited").unwrap();
+        assert!(a.read_shared_chunk(&hash, 0, &bob).is_none());
+
+        // A wanted file nobody answers for is eventually given up
+        let mut d = FileTransferManager::new(dir.join("d"));
+        assert!(d.want_file([9u8; 32]));
+        assert_eq!(d.swarm_queries_due(), Vec::<[u8; 32]>::new());
+        d.wanted.get_mut(&[9u8; 32]).unwrap().0 -= WANT_TIMEOUT;
+        assert!(d.swarm_queries_due().is_empty());
+        assert!(d.wanted.is_empty());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_bundle_subset() {
+        let dir = std::env::temp_dir().join(format!("mesh_test_bundle_{}", rand::random::<u32>()));
+        let src = dir.join("trip");
+        std::fs::create_dir_all(src.join("photos")).unwrap();
+        let big: Vec<u8> = (0..CHUNK_SIZE * 2 + 100).map(|i| (i % 251) as u8).collect();
+        std::fs::write(src.join("notes.txt"), b"packing list").unwrap();
+        std::fs::write(src.join("photos/a.bin"), &big).unwrap();
+        std::fs::write(src.join("photos/b.txt"), b"last file").unwrap();
+
+        let bob = [2u8; 32];
+        let mut sender = FileTransferManager::new(dir.join("sent"));
+        let offer = sender.prepare_send(bob, &src).unwrap();
+        assert_eq!(offer.filename, "trip");
+        let paths: Vec<&str> = offer.bundle.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, vec!["trip/notes.txt", "trip/photos/a.bin", "trip/photos/b.txt"]);
+        assert_eq!(offer.size_bytes, 12 + big.len() as u64 + 9);
+
+        // Bob only wants the last file, which lives in the final chunk (plus chunk 0)
+        let mut receiver = FileTransferManager::new(dir.join("received"));
+        receiver.register_incoming(offer.clone(), [1u8; 32]);
+        assert!(receiver.accept_incoming_entries(&offer.file_id, &[7]).is_none());
+        assert!(receiver.accept_incoming_entries(&offer.file_id, &[2]).is_some());
+        assert!(sender.mark_accepted_entries(&offer.file_id, bob, &[2]));
+
+        let mut sent = Vec::new();
+        while let Some(chunk) = sender.next_chunk(&offer.file_id) {
+            sent.push(chunk.sequence);
+            receiver.receive_chunk(&offer.file_id, chunk.sequence, chunk.data);
+            sender.ack_chunk(&offer.file_id, &bob, chunk.sequence);
+        }
+        assert_eq!(sent, vec![0, 2]);
+        assert!(sender.is_outgoing_complete(&offer.file_id));
+        assert!(receiver.is_incoming_complete(&offer.file_id));
+
+        let root = receiver.finalize_incoming(&offer.file_id).unwrap();
+        assert_eq!(std::fs::read(root.join("trip/photos/b.txt")).unwrap(), b"last file");
+        assert!(!root.join("trip/notes.txt").exists());
+        assert!(!root.join("trip/photos/a.bin").exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_bundle_paths_stay_inside_root() {
+        assert_eq!(bundle_relative_path("docs/readme.md"), PathBuf::from("docs/readme.md"));
+        assert_eq!(bundle_relative_path("../../etc/passwd"), PathBuf::from("etc/passwd"));
+        assert_eq!(bundle_relative_path("/abs/./x/../y.txt"), PathBuf::from("abs/x/y.txt"));
+        assert_eq!(bundle_relative_path("..\\..\\boot.ini"), PathBuf::from("boot.ini"));
+        assert_eq!(bundle_relative_path("../.."), PathBuf::from("unnamed"));
+
+        // A hostile manifest still extracts beneath the bundle root
+        let dir = std::env::temp_dir().join(format!("mesh_test_bundle_evil_{}", rand::random::<u32>()));
+        let data = b"owned".to_vec();
+        let offer = FileOfferPayload {
+            file_id: [3u8; 16],
+            filename: "../evil".into(),
+            size_bytes: data.len() as u64,
+            chunk_count: 1,
+            sha256_hash: Sha256::digest(&data).into(),
+            chunk_hashes: vec![hash_chunk(&data)],
+            preview: None,
+            bundle: vec![BundleEntry {
+                path: "../../../.bashrc".into(),
+                size: data.len() as u64,
+                sha256: Sha256::digest(&data).into(),
+            }],
+        };
+        let mut mgr = FileTransferManager::new(dir.join("received"));
+        mgr.register_incoming(offer, [1u8; 32]);
+        mgr.accept_incoming(&[3u8; 16]);
+        mgr.receive_chunk(&[3u8; 16], 0, data);
+        let root = mgr.finalize_incoming(&[3u8; 16]).unwrap();
+        assert_eq!(root, dir.join("received/evil"));
+        assert_eq!(std::fs::read(root.join("bashrc")).unwrap(), b"owned");
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_inconsistent_bundle_manifest_is_refused() {
+        let entry = |size| BundleEntry { path: "f".into(), size, sha256: [0u8; 32] };
+        let offer = |bundle: Vec<BundleEntry>, size_bytes: u64| FileOfferPayload {
+            file_id: [4u8; 16],
+            filename: "bundle".into(),
+            size_bytes,
+            chunk_count: size_bytes.div_ceil(CHUNK_SIZE as u64).max(1) as u32,
+            sha256_hash: [0u8; 32],
+            chunk_hashes: Vec::new(),
+            preview: None,
+            bundle,
+        };
+        let mut mgr = FileTransferManager::new(std::env::temp_dir().join("mesh_test_unused"));
+
+        // Sizes that wrap around to the total, and a single entry spanning far more chunks than the transfer
+        assert!(!mgr.register_incoming(offer(vec![entry(u64::MAX), entry(11)], 10), [1u8; 32]));
+        assert!(!mgr.register_incoming(offer(vec![entry(1 << 40)], 10), [1u8; 32]));
+        assert!(!mgr.register_incoming(offer(vec![entry(MAX_FILE_SIZE + 1)], MAX_FILE_SIZE + 1), [1u8; 32]));
+        let mut wrong_count = offer(vec![entry(10)], 10);
+        wrong_count.chunk_count = 5;
+        assert!(!mgr.register_incoming(wrong_count, [1u8; 32]));
+        assert!(mgr.get_incoming_metadata(&[4u8; 16]).is_none());
+
+        assert!(mgr.register_incoming(offer(vec![entry(4), entry(6)], 10), [1u8; 32]));
+        assert!(mgr.accept_incoming_entries(&[4u8; 16], &[1]).is_some());
+    }
+
+    #[test]
+    fn test_accept_policy() {
+        let mut pdf = offer([1u8; 16]);
+        pdf.filename = "Map.PDF".into();
+        pdf.size_bytes = 2_000_000;
+        let mut exe = offer([2u8; 16]);
+        exe.filename = "setup.exe".into();
+        let friend = SenderTrust { name: "Alice".into(), favorite: true, verified: false };
+        let stranger = SenderTrust { name: "Eve".into(), ..Default::default() };
+
+        // Default policy never decides on its own
+        let policy = FileAcceptPolicy::default();
+        assert_eq!(policy.evaluate(&pdf, &friend, Some(0)), PolicyDecision::Ask);
+
+        let policy = FileAcceptPolicy {
+            accept_favorites: true,
+            max_auto_size: Some(5_000_000),
+            allowed_mime_types: vec!["application/pdf".into(), "image/*".into()],
+            blocked_extensions: vec!["exe".into()],
+            min_free_disk: Some(1_000_000),
+            ..Default::default()
+        };
+        match policy.evaluate(&pdf, &friend, None) {
+            PolicyDecision::Accept(reason) => assert!(reason.contains("favorite contact Alice")),
+            other => panic!("expected accept, got {:?}", other),
+        }
+        assert_eq!(policy.evaluate(&pdf, &stranger, None), PolicyDecision::Ask);
+
+        // Size and type limits fall back to asking
+        let mut big = pdf.clone();
+        big.size_bytes = 6_000_000;
+        assert_eq!(policy.evaluate(&big, &friend, None), PolicyDecision::Ask);
+        let mut zip = pdf.clone();
+        zip.filename = "bundle.zip".into();
+        assert_eq!(policy.evaluate(&zip, &friend, None), PolicyDecision::Ask);
+        let mut photo = pdf.clone();
+        photo.filename = "site.jpeg".into();
+        assert!(matches!(policy.evaluate(&photo, &friend, None), PolicyDecision::Accept(_)));
+
+        // Blocked types and low disk are rejected even from favorites
+        assert!(matches!(policy.evaluate(&exe, &friend, None), PolicyDecision::Reject(_)));
+        assert!(matches!(policy.evaluate(&pdf, &friend, Some(2_500_000)), PolicyDecision::Reject(_)));
+
+        // Verified contacts only count when enabled
+        let verified = SenderTrust { name: "Bob".into(), favorite: false, verified: true };
+        assert_eq!(policy.evaluate(&pdf, &verified, None), PolicyDecision::Ask);
+        let policy = FileAcceptPolicy { accept_verified: true, ..policy };
+        assert!(matches!(policy.evaluate(&pdf, &verified, None), PolicyDecision::Accept(_)));
+
+        assert!(free_disk_space(&std::env::temp_dir().join("does/not/exist/yet")).is_some() || cfg!(not(unix)));
+    }
+
+    #[test]
+    fn test_image_preview() {
+        let dir = std::env::temp_dir().join(format!("mesh_test_preview_{}", rand::random::<u32>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let photo = dir.join("photo.png");
+        image::RgbaImage::from_pixel(1200, 800, image::Rgba([200, 40, 40, 255])).save(&photo).unwrap();
+        let notes = dir.join("notes.txt");
+        std::fs::write(&notes, b"not a picture").unwrap();
+
+        let mut mgr = FileTransferManager::new(dir.join("received"));
+        let offer = mgr.prepare_send([1u8; 32], &photo).unwrap();
+        let preview = offer.preview.expect("image offer should carry a preview");
+        assert_eq!(preview.mime_type, "image/jpeg");
+        assert!(preview.data.len() <= PREVIEW_MAX_BYTES);
+        let thumb = image::load_from_memory(&preview.data).unwrap();
+        assert_eq!(thumb.width(), PREVIEW_MAX_DIM);
+        assert!((106..=107).contains(&thumb.height()));
+
+        assert!(mgr.prepare_send([1u8; 32], &notes).unwrap().preview.is_none());
+        // Image extension but not image bytes
+        assert!(make_preview("fake.jpg", b"not a picture").is_none());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    fn offer(file_id: [u8; 16]) -> FileOfferPayload {
+        FileOfferPayload {
+            file_id,
+            filename: "notes.txt".into(),
+            size_bytes: 4,
+            chunk_count: 1,
+            sha256_hash: Sha256::digest(b"data").into(),
+            chunk_hashes: vec![hash_chunk(b"data")],
+            preview: None,
+            bundle: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_decline_and_cancel() {
+        let dir = std::env::temp_dir().join(format!("mesh_test_cancel_{}", rand::random::<u32>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let src_path = dir.join("notes.txt");
+        std::fs::write(&src_path, b"data").unwrap();
+        let (alice, bob, mallory) = ([1u8; 32], [2u8; 32], [3u8; 32]);
+
+        // Sender side: only the intended receiver can decline
+        let mut sender = FileTransferManager::new(dir.join("a"));
+        let meta = sender.prepare_send(bob, &src_path).unwrap();
+        assert!(!sender.handle_remote_decline(&meta.file_id, &mallory));
+        assert!(sender.handle_remote_decline(&meta.file_id, &bob));
+        assert!(!sender.mark_accepted(&meta.file_id, bob));
+
+        // Receiver side: declining forgets the offer and reports the sender
+        let mut receiver = FileTransferManager::new(dir.join("b"));
+        receiver.register_incoming(offer([5u8; 16]), alice);
+        assert_eq!(receiver.decline_incoming(&[5u8; 16]), Some(alice));
+        assert!(receiver.accept_incoming(&[5u8; 16]).is_none());
+
+        // Remote cancel only from the other end of the transfer
+        receiver.register_incoming(offer([6u8; 16]), alice);
+        receiver.accept_incoming(&[6u8; 16]);
+        assert!(!receiver.handle_remote_cancel(&[6u8; 16], &mallory));
+        assert!(receiver.handle_remote_cancel(&[6u8; 16], &alice));
+        assert!(receiver.receive_chunk(&[6u8; 16], 0, b"data".to_vec()).is_none());
+
+        // Local cancel works in both directions
+        let meta = sender.prepare_send(bob, &src_path).unwrap();
+        assert_eq!(sender.cancel_transfer(&meta.file_id), Some(FileAudience::Peer(bob)));
+        receiver.register_incoming(offer([7u8; 16]), alice);
+        assert_eq!(receiver.cancel_transfer(&[7u8; 16]), Some(FileAudience::Peer(alice)));
+        assert_eq!(receiver.cancel_transfer(&[7u8; 16]), None);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_expire_stale() {
+        let mut mgr = FileTransferManager::new(std::env::temp_dir().join("mesh_test_expire"));
+        mgr.register_incoming(offer([1u8; 16]), [9u8; 32]);
+        mgr.register_incoming(offer([2u8; 16]), [9u8; 32]);
+        mgr.accept_incoming(&[2u8; 16]);
+
+        // Generous timeouts: nothing expires
+        assert!(mgr.expire_stale(OFFER_TIMEOUT, STALL_TIMEOUT).is_empty());
+
+        // Offer timeout only hits the unaccepted transfer
+        let expired = mgr.expire_stale(Duration::ZERO, STALL_TIMEOUT);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].file_id, [1u8; 16]);
+        assert_eq!(expired[0].audience, FileAudience::Peer([9u8; 32]));
+        assert_eq!(expired[0].reason, "Offer timed out");
+
+        let expired = mgr.expire_stale(OFFER_TIMEOUT, Duration::ZERO);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].file_id, [2u8; 16]);
+        assert_eq!(expired[0].reason, "Transfer stalled");
+        assert!(mgr.get_incoming_metadata(&[2u8; 16]).is_none());
+    }
+
     #[test]
     fn test_file_too_large() {
         let dir = std::env::temp_dir().join("mesh_test_file_large");
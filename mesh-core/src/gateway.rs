@@ -139,6 +139,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::overly_complex_bool_expr)]
     fn test_detect_interfaces_runs() {
         let (interfaces, _active) = detect_interfaces();
         // Should find at least loopback
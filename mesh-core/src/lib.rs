@@ -9,9 +9,27 @@ pub mod peer;
 pub mod file_transfer;
 pub mod gateway;
 pub mod storage;
+pub mod dtn;
+pub mod trace;
+pub mod topology;
+pub mod queue;
+pub mod serial;
+pub mod datagram;
+pub mod quic;
+pub mod websocket;
 
 pub use identity::NodeIdentity;
 pub use node::{NodeConfig, NodeCommand, NodeEvent, NodeHandle, MeshStats, PeerListEntry, start_mesh_node};
+pub use peer::{LinkQuality, LinkStats};
+pub use transport::{LinkAddr, LoopbackTransport, Reliability, TcpTransport, Transport};
+pub use serial::{Framing, SerialConfig, SerialTransport};
+pub use quic::QuicTransport;
+pub use websocket::WebSocketTransport;
 pub use gateway::{NetworkInterface, InterfaceType};
+pub use file_transfer::FileAcceptPolicy;
+pub use router::FloodStrategy;
+pub use trace::TraceHop;
+pub use topology::{TopologyGraph, TopologyNode, TopologyLink};
+pub use message::{BundleEntry, FilePreview};
 pub use storage::{MeshStorage, StoredMessage, Contact};
 pub use message::{TriagePayload, TriageLevel, ResourceRequestPayload, CheckInPayload, DisappearingPayload, GroupPayload, GroupControlPayload, ReadReceiptPayload};
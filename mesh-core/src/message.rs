@@ -1,7 +1,10 @@
+use bincode::Options;
 use serde::{Serialize, Deserialize};
 use rand::RngCore;
 use rand::rngs::OsRng;
 
+use crate::identity::NodeIdentity;
+
 /// Message types in the mesh protocol.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[repr(u8)]
@@ -15,12 +18,26 @@ pub enum MessageType {
     FileChunk = 0x20,
     FileOffer = 0x21,
     FileAccept = 0x22,
+    FileChunkAck = 0x23,
+    FileDecline = 0x24,
+    FileCancel = 0x25,
+    FileAnnounce = 0x26,
+    SwarmQuery = 0x27,
+    SwarmHave = 0x28,
+    ChunkRequest = 0x29,
+    ChunkData = 0x2A,
     Voice = 0x30,
     VoiceStream = 0x31,
     CallStart = 0x32,
     CallEnd = 0x33,
     PeerExchange = 0x40,
+    RouteRequest = 0x41,
+    RouteReply = 0x42,
+    Trace = 0x43,
+    TraceReply = 0x44,
+    LinkState = 0x45,
     KeyExchange = 0x50,
+    DatagramOffer = 0x51,
     ProfileUpdate = 0x60,
     ReadReceipt = 0x13,
     TypingStart = 0x14,
@@ -32,6 +49,33 @@ pub enum MessageType {
     Triage = 0x1A,
     ResourceReq = 0x1B,
     Disappearing = 0x1C,
+    DeliveryReceipt = 0x1D,
+}
+
+/// Scheduling class of a message on a peer link. Lower classes are always
+/// written first, so an SOS or a pong never waits behind file chunks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// SOS, triage, resource requests and check-ins.
+    Emergency = 0,
+    /// Heartbeats, key exchange, routing, acks and call setup.
+    Control = 1,
+    /// Chat and everything a person is waiting to see.
+    Interactive = 2,
+    /// Live call audio.
+    Voice = 3,
+    /// File chunks.
+    Bulk = 4,
+}
+
+impl Priority {
+    pub const ALL: [Priority; 5] = [
+        Priority::Emergency,
+        Priority::Control,
+        Priority::Interactive,
+        Priority::Voice,
+        Priority::Bulk,
+    ];
 }
 
 /// A unique message ID (32 bytes random).
@@ -57,6 +101,42 @@ pub struct MeshMessage {
     /// Ed25519 signature over (msg_type, sender_id, msg_id, payload)
     #[serde(default)]
     pub signature: Option<Vec<u8>>,
+    /// Relays this message has passed through (incremented on forward).
+    #[serde(default)]
+    pub hop_count: u8,
+    /// TTL the originator sent it with. 0 = unknown (message from an older node).
+    #[serde(default)]
+    pub initial_ttl: u8,
+}
+
+/// Header layout used before `hop_count` and `initial_ttl` were added.
+/// Older nodes still send it; newer fields simply trail after it, which
+/// older decoders ignore.
+#[derive(Deserialize)]
+struct LegacyMeshMessage {
+    msg_type: MessageType,
+    sender_id: [u8; 32],
+    msg_id: MessageId,
+    ttl: u8,
+    destination: Option<[u8; 32]>,
+    payload: Vec<u8>,
+    signature: Option<Vec<u8>>,
+}
+
+impl From<LegacyMeshMessage> for MeshMessage {
+    fn from(old: LegacyMeshMessage) -> Self {
+        Self {
+            msg_type: old.msg_type,
+            sender_id: old.sender_id,
+            msg_id: old.msg_id,
+            ttl: old.ttl,
+            destination: old.destination,
+            payload: old.payload,
+            signature: old.signature,
+            hop_count: 0,
+            initial_ttl: 0,
+        }
+    }
 }
 
 impl MeshMessage {
@@ -75,6 +155,8 @@ impl MeshMessage {
             destination,
             payload,
             signature: None,
+            hop_count: 0,
+            initial_ttl: ttl,
         }
     }
 
@@ -123,19 +205,78 @@ impl MeshMessage {
         Self::new(MessageType::FileOffer, sender_id, 10, Some(dest), bytes)
     }
 
-    /// Create a file chunk (direct to recipient).
-    pub fn file_chunk(sender_id: [u8; 32], dest: [u8; 32], payload: &FileChunkPayload) -> Self {
+    /// Create a group/mesh-wide file offer (flooded to all nodes).
+    pub fn file_announce(sender_id: [u8; 32], payload: &FileAnnouncePayload) -> Self {
+        let bytes = bincode::serialize(payload).expect("FileAnnounce serialization failed");
+        Self::new(MessageType::FileAnnounce, sender_id, 10, None, bytes)
+    }
+
+    /// Ask the mesh for holders of a content-addressed file (flooded).
+    pub fn swarm_query(sender_id: [u8; 32], content_hash: [u8; 32]) -> Self {
+        let payload = SwarmQueryPayload { content_hash };
+        let bytes = bincode::serialize(&payload).expect("SwarmQuery serialization failed");
+        Self::new(MessageType::SwarmQuery, sender_id, 10, None, bytes)
+    }
+
+    /// Tell a querying node we hold a file (direct to querier).
+    pub fn swarm_have(sender_id: [u8; 32], dest: [u8; 32], offer: &FileOfferPayload) -> Self {
+        let payload = SwarmHavePayload { offer: offer.clone() };
+        let bytes = bincode::serialize(&payload).expect("SwarmHave serialization failed");
+        Self::new(MessageType::SwarmHave, sender_id, 10, Some(dest), bytes)
+    }
+
+    /// Request chunks of a content-addressed file (direct to a holder).
+    pub fn chunk_request(sender_id: [u8; 32], dest: [u8; 32], content_hash: [u8; 32], sequences: Vec<u32>) -> Self {
+        let payload = ChunkRequestPayload { content_hash, sequences };
+        let bytes = bincode::serialize(&payload).expect("ChunkRequest serialization failed");
+        Self::new(MessageType::ChunkRequest, sender_id, 10, Some(dest), bytes)
+    }
+
+    /// Serve one chunk of a content-addressed file (direct to requester).
+    pub fn chunk_data(sender_id: [u8; 32], dest: [u8; 32], payload: &ChunkDataPayload) -> Self {
+        let bytes = bincode::serialize(payload).expect("ChunkData serialization failed");
+        Self::new(MessageType::ChunkData, sender_id, 10, Some(dest), bytes)
+    }
+
+    /// Create a file chunk (direct to recipient, or None to flood to a group/mesh-wide audience).
+    pub fn file_chunk(sender_id: [u8; 32], dest: Option<[u8; 32]>, payload: &FileChunkPayload) -> Self {
         let bytes = bincode::serialize(payload).expect("FileChunk serialization failed");
-        Self::new(MessageType::FileChunk, sender_id, 10, Some(dest), bytes)
+        Self::new(MessageType::FileChunk, sender_id, 10, dest, bytes)
     }
 
     /// Create a file accept response (direct to sender).
     pub fn file_accept(sender_id: [u8; 32], dest: [u8; 32], file_id: [u8; 16]) -> Self {
-        let payload = FileAcceptPayload { file_id };
+        Self::file_accept_entries(sender_id, dest, file_id, Vec::new())
+    }
+
+    /// Accept only some entries of a bundle offer.
+    pub fn file_accept_entries(sender_id: [u8; 32], dest: [u8; 32], file_id: [u8; 16], entries: Vec<u32>) -> Self {
+        let payload = FileAcceptPayload { file_id, entries };
         let bytes = bincode::serialize(&payload).expect("FileAccept serialization failed");
         Self::new(MessageType::FileAccept, sender_id, 10, Some(dest), bytes)
     }
 
+    /// Create a chunk acknowledgement (direct to the file sender).
+    pub fn file_chunk_ack(sender_id: [u8; 32], dest: [u8; 32], file_id: [u8; 16], sequence: u32) -> Self {
+        let payload = FileChunkAckPayload { file_id, sequence };
+        let bytes = bincode::serialize(&payload).expect("FileChunkAck serialization failed");
+        Self::new(MessageType::FileChunkAck, sender_id, 10, Some(dest), bytes)
+    }
+
+    /// Create a file decline response (direct to sender).
+    pub fn file_decline(sender_id: [u8; 32], dest: [u8; 32], file_id: [u8; 16]) -> Self {
+        let payload = FileControlPayload { file_id, reason: String::new() };
+        let bytes = bincode::serialize(&payload).expect("FileDecline serialization failed");
+        Self::new(MessageType::FileDecline, sender_id, 10, Some(dest), bytes)
+    }
+
+    /// Create a file cancel notice (direct to the other end, or None to flood to all receivers).
+    pub fn file_cancel(sender_id: [u8; 32], dest: Option<[u8; 32]>, file_id: [u8; 16], reason: &str) -> Self {
+        let payload = FileControlPayload { file_id, reason: reason.to_string() };
+        let bytes = bincode::serialize(&payload).expect("FileCancel serialization failed");
+        Self::new(MessageType::FileCancel, sender_id, 10, dest, bytes)
+    }
+
     /// Create a voice note message.
     pub fn voice_note(sender_id: [u8; 32], dest: Option<[u8; 32]>, payload: &VoiceNotePayload) -> Self {
         let bytes = bincode::serialize(payload).expect("Voice serialization failed");
@@ -167,6 +308,14 @@ impl MeshMessage {
         Self::new(MessageType::ReadReceipt, sender_id, 10, Some(dest), bytes)
     }
 
+    /// Create a delivery receipt, sent by the destination when a directed
+    /// message reaches it. Relays holding a copy drop it when one passes.
+    pub fn delivery_receipt(sender_id: [u8; 32], dest: [u8; 32], original_msg_id: MessageId) -> Self {
+        let payload = ReadReceiptPayload { original_msg_id };
+        let bytes = bincode::serialize(&payload).expect("DeliveryReceipt serialization failed");
+        Self::new(MessageType::DeliveryReceipt, sender_id, 10, Some(dest), bytes)
+    }
+
     /// Create a typing start indicator.
     pub fn typing_start(sender_id: [u8; 32], dest: Option<[u8; 32]>) -> Self {
         Self::new(MessageType::TypingStart, sender_id, 1, dest, vec![])
@@ -228,9 +377,75 @@ impl MeshMessage {
         bincode::serialize(self).expect("Message serialization should not fail")
     }
 
-    /// Deserialize from bytes.
+    /// Deserialize from bytes, accepting the older header without hop fields.
     pub fn from_bytes(data: &[u8]) -> Result<Self, bincode::Error> {
         bincode::deserialize(data)
+            .or_else(|_| bincode::deserialize::<LegacyMeshMessage>(data).map(Self::from))
+    }
+
+    /// Serialize with variable-length integers and tags: about 17 bytes less
+    /// header, for narrowband links where both ends know to expect it.
+    pub fn to_compact_bytes(&self) -> Vec<u8> {
+        bincode::DefaultOptions::new().serialize(self).expect("Message serialization should not fail")
+    }
+
+    pub fn from_compact_bytes(data: &[u8]) -> Result<Self, bincode::Error> {
+        bincode::DefaultOptions::new().deserialize(data)
+    }
+
+    /// Directed messages worth holding for a destination that is out of reach:
+    /// chat and voice notes, and the receipts that confirm them.
+    pub fn is_storable(&self) -> bool {
+        self.destination.is_some()
+            && (self.wants_delivery_receipt() || self.msg_type == MessageType::DeliveryReceipt)
+    }
+
+    /// Whether the destination should confirm this message with a delivery receipt.
+    pub fn wants_delivery_receipt(&self) -> bool {
+        self.destination.is_some()
+            && matches!(self.msg_type, MessageType::Text | MessageType::Voice | MessageType::Disappearing)
+    }
+
+    /// Sign as the originator. The signature covers the payload but not the
+    /// TTL or hop count, so it stays valid as relays forward the message.
+    pub fn sign(&mut self, identity: &NodeIdentity) {
+        self.signature = Some(identity.sign(&self.signing_bytes()).to_vec());
+    }
+
+    /// True if the message carries a valid signature from its sender.
+    pub fn verify_signature(&self) -> bool {
+        let Some(sig) = self.signature.as_deref().and_then(|s| <[u8; 64]>::try_from(s).ok()) else {
+            return false;
+        };
+        NodeIdentity::verify(&self.sender_id, &self.signing_bytes(), &sig).is_ok()
+    }
+
+    /// Scheduling class, derived from the message type.
+    pub fn priority(&self) -> Priority {
+        use MessageType::*;
+        match self.msg_type {
+            SOS | Triage | ResourceReq | CheckIn => Priority::Emergency,
+            Discovery | Ping | Pong | KeyExchange | DatagramOffer | PeerExchange | RouteRequest | RouteReply
+            | Trace | TraceReply | LinkState | CallStart | CallEnd | DeliveryReceipt
+            | FileAccept | FileChunkAck | FileDecline | FileCancel
+            | SwarmQuery | SwarmHave | ChunkRequest => Priority::Control,
+            Text | PublicBroadcast | GroupMessage | GroupJoin | GroupLeave | Disappearing
+            | ReadReceipt | TypingStart | TypingStop | ProfileUpdate | Voice
+            | FileOffer | FileAnnounce => Priority::Interactive,
+            VoiceStream => Priority::Voice,
+            FileChunk | ChunkData => Priority::Bulk,
+        }
+    }
+
+    /// Traffic that is worthless once late, so a lost copy is better than a
+    /// delayed one. It may take a neighbor's UDP path.
+    pub fn is_loss_tolerant(&self) -> bool {
+        matches!(self.msg_type, MessageType::VoiceStream | MessageType::TypingStart | MessageType::TypingStop)
+    }
+
+    /// Relays passed so far, or None if the originator didn't record its TTL.
+    pub fn hops(&self) -> Option<u8> {
+        (self.initial_ttl != 0).then_some(self.hop_count)
     }
 
     /// Create a length-prefixed frame: [4-byte big-endian length][message bytes]
@@ -272,7 +487,193 @@ pub struct FileOfferPayload {
     pub filename: String,
     pub size_bytes: u64,
     pub chunk_count: u32,
+    /// SHA-256 of the whole file; also the file's content address for swarm fetches.
     pub sha256_hash: [u8; 32],
+    /// SHA-256 of each chunk, so chunks from any holder can be verified on arrival.
+    /// Empty from older senders, which disables swarm fetching for that file.
+    pub chunk_hashes: Vec<[u8; 32]>,
+    /// Small thumbnail for image files, so receivers can see a photo before downloading it.
+    pub preview: Option<FilePreview>,
+    /// Manifest of a multi-file bundle, in the order the files' bytes are
+    /// concatenated into the transfer. Empty for a single file.
+    pub bundle: Vec<BundleEntry>,
+}
+
+impl FileOfferPayload {
+    /// Decode an offer, accepting the shorter layouts older senders use.
+    pub fn from_message(msg: &MeshMessage) -> Result<Self, bincode::Error> {
+        Self::decode(&msg.payload)
+    }
+
+    /// Newest layout first: an older, shorter offer runs out of bytes when
+    /// read as a newer one, while a newer offer would decode as an older one.
+    fn decode(bytes: &[u8]) -> Result<Self, bincode::Error> {
+        bincode::deserialize(bytes)
+            .or_else(|e| bincode::deserialize::<OfferBeforeBundle>(bytes).map(Self::from).map_err(|_| e))
+            .or_else(|e| bincode::deserialize::<OfferBeforePreview>(bytes).map(Self::from).map_err(|_| e))
+            .or_else(|e| bincode::deserialize::<OfferBeforeChunkHashes>(bytes).map(Self::from).map_err(|_| e))
+    }
+}
+
+/// Offer layout used before per-chunk hashes were added. bincode has no field
+/// names or defaults, so an older offer is too short for the current struct
+/// and has to be read with its own.
+#[derive(Deserialize)]
+struct OfferBeforeChunkHashes {
+    file_id: [u8; 16],
+    filename: String,
+    size_bytes: u64,
+    chunk_count: u32,
+    sha256_hash: [u8; 32],
+}
+
+impl From<OfferBeforeChunkHashes> for FileOfferPayload {
+    fn from(old: OfferBeforeChunkHashes) -> Self {
+        Self {
+            file_id: old.file_id,
+            filename: old.filename,
+            size_bytes: old.size_bytes,
+            chunk_count: old.chunk_count,
+            sha256_hash: old.sha256_hash,
+            chunk_hashes: Vec::new(),
+            preview: None,
+            bundle: Vec::new(),
+        }
+    }
+}
+
+/// Offer layout used before image previews were added.
+#[derive(Deserialize)]
+struct OfferBeforePreview {
+    file_id: [u8; 16],
+    filename: String,
+    size_bytes: u64,
+    chunk_count: u32,
+    sha256_hash: [u8; 32],
+    chunk_hashes: Vec<[u8; 32]>,
+}
+
+impl From<OfferBeforePreview> for FileOfferPayload {
+    fn from(old: OfferBeforePreview) -> Self {
+        Self {
+            file_id: old.file_id,
+            filename: old.filename,
+            size_bytes: old.size_bytes,
+            chunk_count: old.chunk_count,
+            sha256_hash: old.sha256_hash,
+            chunk_hashes: old.chunk_hashes,
+            preview: None,
+            bundle: Vec::new(),
+        }
+    }
+}
+
+/// Offer layout used before multi-file bundles were added.
+#[derive(Deserialize)]
+struct OfferBeforeBundle {
+    file_id: [u8; 16],
+    filename: String,
+    size_bytes: u64,
+    chunk_count: u32,
+    sha256_hash: [u8; 32],
+    chunk_hashes: Vec<[u8; 32]>,
+    preview: Option<FilePreview>,
+}
+
+impl From<OfferBeforeBundle> for FileOfferPayload {
+    fn from(old: OfferBeforeBundle) -> Self {
+        Self {
+            file_id: old.file_id,
+            filename: old.filename,
+            size_bytes: old.size_bytes,
+            chunk_count: old.chunk_count,
+            sha256_hash: old.sha256_hash,
+            chunk_hashes: old.chunk_hashes,
+            preview: old.preview,
+            bundle: Vec::new(),
+        }
+    }
+}
+
+/// One file inside a bundle transfer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BundleEntry {
+    /// Path relative to the bundle root, '/'-separated.
+    pub path: String,
+    pub size: u64,
+    pub sha256: [u8; 32],
+}
+
+/// Downscaled image preview carried inside a file offer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FilePreview {
+    pub mime_type: String,
+    pub data: Vec<u8>,
+}
+
+/// Group or mesh-wide file offer payload.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileAnnouncePayload {
+    pub offer: FileOfferPayload,
+    /// Only members of this group should offer it to the user (None = everyone).
+    pub group: Option<String>,
+}
+
+impl FileAnnouncePayload {
+    /// Decode an announcement, accepting offers in the layouts older senders use.
+    pub fn from_message(msg: &MeshMessage) -> Result<Self, bincode::Error> {
+        let bytes = &msg.payload;
+        bincode::deserialize(bytes)
+            .or_else(|e| {
+                bincode::deserialize::<(OfferBeforeBundle, Option<String>)>(bytes)
+                    .map(|(offer, group)| Self { offer: offer.into(), group })
+                    .map_err(|_| e)
+            })
+            .or_else(|e| {
+                bincode::deserialize::<(OfferBeforePreview, Option<String>)>(bytes)
+                    .map(|(offer, group)| Self { offer: offer.into(), group })
+                    .map_err(|_| e)
+            })
+            .or_else(|e| {
+                bincode::deserialize::<(OfferBeforeChunkHashes, Option<String>)>(bytes)
+                    .map(|(offer, group)| Self { offer: offer.into(), group })
+                    .map_err(|_| e)
+            })
+    }
+}
+
+/// Ask the mesh who holds a file with this content hash (flooded).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SwarmQueryPayload {
+    pub content_hash: [u8; 32],
+}
+
+/// Reply to a SwarmQuery: we hold this file and can serve its chunks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SwarmHavePayload {
+    pub offer: FileOfferPayload,
+}
+
+impl SwarmHavePayload {
+    /// Decode a reply, accepting offers in the layouts older holders use.
+    pub fn from_message(msg: &MeshMessage) -> Result<Self, bincode::Error> {
+        FileOfferPayload::decode(&msg.payload).map(|offer| Self { offer })
+    }
+}
+
+/// Request specific chunks of a content-addressed file from one holder.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkRequestPayload {
+    pub content_hash: [u8; 32],
+    pub sequences: Vec<u32>,
+}
+
+/// A chunk served in response to a ChunkRequest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkDataPayload {
+    pub content_hash: [u8; 32],
+    pub sequence: u32,
+    pub data: Vec<u8>,
 }
 
 /// File chunk data payload.
@@ -287,6 +688,34 @@ pub struct FileChunkPayload {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileAcceptPayload {
     pub file_id: [u8; 16],
+    /// Bundle entries wanted (indices into the manifest); empty means all of them.
+    pub entries: Vec<u32>,
+}
+
+impl FileAcceptPayload {
+    /// Decode an accept, including the bare file ID older receivers send.
+    pub fn from_message(msg: &MeshMessage) -> Result<Self, bincode::Error> {
+        bincode::deserialize(&msg.payload).or_else(|e| {
+            bincode::deserialize::<[u8; 16]>(&msg.payload)
+                .map(|file_id| Self { file_id, entries: Vec::new() })
+                .map_err(|_| e)
+        })
+    }
+}
+
+/// File chunk acknowledgement payload (drives the sender's window).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileChunkAckPayload {
+    pub file_id: [u8; 16],
+    pub sequence: u32,
+}
+
+/// File decline/cancel payload.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileControlPayload {
+    pub file_id: [u8; 16],
+    /// Human-readable reason (empty for a plain decline).
+    pub reason: String,
 }
 
 /// Voice note payload.
@@ -359,6 +788,7 @@ impl TriageLevel {
         }
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Option<Self> {
         match s.to_lowercase().as_str() {
             "black" => Some(TriageLevel::Black),
@@ -462,6 +892,51 @@ impl KeyExchangePayload {
     }
 }
 
+/// Sent to a neighbor once the session keys are set: where to reach us over
+/// UDP, and the token our datagrams from that neighbor must start with.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct DatagramOfferPayload {
+    pub port: u16,
+    pub token: [u8; 8],
+}
+
+impl DatagramOfferPayload {
+    pub fn to_message(&self, sender_id: [u8; 32], dest: [u8; 32]) -> MeshMessage {
+        let payload = bincode::serialize(self).expect("DatagramOffer serialization failed");
+        MeshMessage::new(MessageType::DatagramOffer, sender_id, 1, Some(dest), payload)
+    }
+
+    pub fn from_message(msg: &MeshMessage) -> Result<Self, bincode::Error> {
+        bincode::deserialize(&msg.payload)
+    }
+}
+
+/// Heartbeat ping. The peer echoes the payload back verbatim in its Pong,
+/// so the sender can time the round trip on its own clock.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PingPayload {
+    pub seq: u32,
+    /// Sender's clock when the ping left (Unix micros).
+    pub sent_us: i64,
+}
+
+impl PingPayload {
+    pub fn to_message(&self, sender_id: [u8; 32]) -> MeshMessage {
+        let payload = bincode::serialize(self).expect("Ping serialization failed");
+        MeshMessage::new(MessageType::Ping, sender_id, 1, None, payload)
+    }
+
+    /// The pong answering a ping: same payload, straight back to its sender.
+    pub fn pong(ping: &MeshMessage, sender_id: [u8; 32]) -> MeshMessage {
+        MeshMessage::new(MessageType::Pong, sender_id, 1, Some(ping.sender_id), ping.payload.clone())
+    }
+
+    /// Decode a ping or pong. Older nodes send them empty, which gives None.
+    pub fn from_message(msg: &MeshMessage) -> Option<Self> {
+        bincode::deserialize(&msg.payload).ok()
+    }
+}
+
 /// Peer exchange payload: share known peers with neighbors.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PeerExchangePayload {
@@ -475,6 +950,126 @@ pub struct PeerInfo {
     pub addr: String,
 }
 
+/// Route request (RREQ): flooded when the originator has no route to `target`.
+/// Every node it passes learns the reverse path to the originator.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouteRequestPayload {
+    pub target: [u8; 32],
+    /// Originator's own sequence number, freshening its reverse route.
+    pub origin_seq: u32,
+    /// Last sequence number the originator knew for `target` (0 = none).
+    pub target_seq: u32,
+}
+
+impl RouteRequestPayload {
+    pub fn to_message(&self, sender_id: [u8; 32], ttl: u8) -> MeshMessage {
+        let payload = bincode::serialize(self).expect("RouteRequest serialization failed");
+        MeshMessage::new(MessageType::RouteRequest, sender_id, ttl, None, payload)
+    }
+
+    pub fn from_message(msg: &MeshMessage) -> Result<Self, bincode::Error> {
+        bincode::deserialize(&msg.payload)
+    }
+}
+
+/// Route reply (RREP): sent by the target back along the reverse path,
+/// teaching every relay the forward route to the target.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouteReplyPayload {
+    /// Target's sequence number at the time of the reply.
+    pub target_seq: u32,
+}
+
+impl RouteReplyPayload {
+    pub fn to_message(&self, sender_id: [u8; 32], dest: [u8; 32]) -> MeshMessage {
+        let payload = bincode::serialize(self).expect("RouteReply serialization failed");
+        MeshMessage::new(MessageType::RouteReply, sender_id, 10, Some(dest), payload)
+    }
+
+    pub fn from_message(msg: &MeshMessage) -> Result<Self, bincode::Error> {
+        bincode::deserialize(&msg.payload)
+    }
+}
+
+/// A node a trace probe passed through, with its own clock reading.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TraceStamp {
+    pub node_id: [u8; 32],
+    /// Unix millis on that node's clock when it handled the probe.
+    pub at_ms: i64,
+}
+
+/// Diagnostic probe for mesh ping and traceroute. Each relay appends itself
+/// to `path` on the way to the destination.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TracePayload {
+    pub probe_id: u64,
+    pub path: Vec<TraceStamp>,
+}
+
+impl TracePayload {
+    pub fn to_message(&self, sender_id: [u8; 32], dest: [u8; 32], ttl: u8) -> MeshMessage {
+        let payload = bincode::serialize(self).expect("Trace serialization failed");
+        MeshMessage::new(MessageType::Trace, sender_id, ttl, Some(dest), payload)
+    }
+
+    pub fn from_message(msg: &MeshMessage) -> Result<Self, bincode::Error> {
+        bincode::deserialize(&msg.payload)
+    }
+}
+
+/// Answer to a trace probe, from its destination (`reached`) or from the
+/// relay where the probe's TTL ran out.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TraceReplyPayload {
+    pub probe_id: u64,
+    /// The probe's path, ending with the node that replied.
+    pub path: Vec<TraceStamp>,
+    pub reached: bool,
+}
+
+impl TraceReplyPayload {
+    pub fn to_message(&self, sender_id: [u8; 32], dest: [u8; 32]) -> MeshMessage {
+        let payload = bincode::serialize(self).expect("TraceReply serialization failed");
+        MeshMessage::new(MessageType::TraceReply, sender_id, 10, Some(dest), payload)
+    }
+
+    pub fn from_message(msg: &MeshMessage) -> Result<Self, bincode::Error> {
+        bincode::deserialize(&msg.payload)
+    }
+}
+
+/// One direct link in a node's link-state advertisement.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LinkInfo {
+    pub node_id: [u8; 32],
+    /// Link quality, 0 (unusable) to 255 (perfect).
+    pub quality: u8,
+}
+
+/// A node's signed list of its direct neighbors, flooded mesh-wide so every
+/// node can draw the whole topology.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinkStatePayload {
+    /// Advertiser's clock (Unix millis); a newer advertisement replaces an older one.
+    pub issued_ms: i64,
+    pub display_name: String,
+    pub links: Vec<LinkInfo>,
+}
+
+impl LinkStatePayload {
+    pub fn to_message(&self, identity: &NodeIdentity) -> MeshMessage {
+        let payload = bincode::serialize(self).expect("LinkState serialization failed");
+        let mut msg = MeshMessage::new(MessageType::LinkState, identity.node_id, 10, None, payload);
+        msg.sign(identity);
+        msg
+    }
+
+    pub fn from_message(msg: &MeshMessage) -> Result<Self, bincode::Error> {
+        bincode::deserialize(&msg.payload)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -526,6 +1121,42 @@ mod tests {
         assert!(!msg.decrement_ttl());
     }
 
+    #[test]
+    fn test_hop_fields_and_legacy_decode() {
+        let msg = MeshMessage::public_broadcast([1u8; 32], "far");
+        assert_eq!((msg.initial_ttl, msg.hop_count), (50, 0));
+        assert_eq!(msg.hops(), Some(0));
+
+        // A frame from a node that predates the hop fields
+        #[derive(Serialize)]
+        struct Old<'a> {
+            msg_type: MessageType,
+            sender_id: [u8; 32],
+            msg_id: MessageId,
+            ttl: u8,
+            destination: Option<[u8; 32]>,
+            payload: &'a [u8],
+            signature: Option<Vec<u8>>,
+        }
+        let old = Old {
+            msg_type: msg.msg_type,
+            sender_id: msg.sender_id,
+            msg_id: msg.msg_id,
+            ttl: 7,
+            destination: None,
+            payload: &msg.payload,
+            signature: None,
+        };
+        let decoded = MeshMessage::from_bytes(&bincode::serialize(&old).unwrap()).unwrap();
+        assert_eq!(decoded.ttl, 7);
+        assert_eq!(decoded.hops(), None);
+
+        // ...and older decoders still read our frames
+        let back: LegacyMeshMessage = bincode::deserialize(&msg.to_bytes()).unwrap();
+        assert_eq!((back.msg_type, back.ttl), (MessageType::PublicBroadcast, 50));
+        assert_eq!(back.payload, msg.payload);
+    }
+
     #[test]
     fn test_discovery_payload() {
         let payload = DiscoveryPayload::new([3u8; 32], "Node3".into(), 7332, false);
@@ -553,6 +1184,22 @@ mod tests {
         assert!(bytes.len() < 200, "Serialized size {} is not compact", bytes.len());
     }
 
+    #[test]
+    fn test_compact_header_roundtrip() {
+        let identity = NodeIdentity::generate("compact".into());
+        let mut msg = MeshMessage::text_to(identity.node_id, [2u8; 32], "over the air");
+        msg.sign(&identity);
+        msg.hop_count = 3;
+
+        let compact = msg.to_compact_bytes();
+        assert!(compact.len() + 15 < msg.to_bytes().len());
+        let back = MeshMessage::from_compact_bytes(&compact).unwrap();
+        assert_eq!((back.msg_type, back.msg_id, back.destination), (msg.msg_type, msg.msg_id, msg.destination));
+        assert_eq!((back.ttl, back.hop_count, back.initial_ttl), (msg.ttl, 3, msg.initial_ttl));
+        assert!(back.verify_signature());
+        assert!(MeshMessage::from_compact_bytes(&msg.to_bytes()).is_err());
+    }
+
     #[test]
     fn test_public_broadcast() {
         let msg = MeshMessage::public_broadcast([1u8; 32], "emergency info");
@@ -599,6 +1246,9 @@ mod tests {
             size_bytes: 1024,
             chunk_count: 1,
             sha256_hash: [0u8; 32],
+            chunk_hashes: vec![[7u8; 32]],
+            preview: Some(FilePreview { mime_type: "image/jpeg".into(), data: vec![0xFF, 0xD8] }),
+            bundle: Vec::new(),
         };
         let msg = MeshMessage::file_offer([1u8; 32], [2u8; 32], &offer);
         assert_eq!(msg.msg_type, MessageType::FileOffer);
@@ -606,6 +1256,52 @@ mod tests {
         let decoded: FileOfferPayload = bincode::deserialize(&msg.payload).unwrap();
         assert_eq!(decoded.filename, "test.txt");
         assert_eq!(decoded.size_bytes, 1024);
+        assert_eq!(decoded.chunk_hashes, vec![[7u8; 32]]);
+        assert_eq!(decoded.preview.unwrap().mime_type, "image/jpeg");
+    }
+
+    #[test]
+    fn test_file_offer_from_older_senders() {
+        // Offer fields as sent before chunk hashes existed
+        let old = ([42u8; 16], "old.txt", 1024u64, 1u32, [3u8; 32]);
+        let mut msg = MeshMessage::new(MessageType::FileOffer, [1u8; 32], 10, Some([2u8; 32]), bincode::serialize(&old).unwrap());
+        assert!(bincode::deserialize::<FileOfferPayload>(&msg.payload).is_err());
+        let offer = FileOfferPayload::from_message(&msg).unwrap();
+        assert_eq!((offer.filename.as_str(), offer.size_bytes, offer.sha256_hash), ("old.txt", 1024, [3u8; 32]));
+        assert!(offer.chunk_hashes.is_empty());
+
+        msg.payload = bincode::serialize(&(old, Some("medics"))).unwrap();
+        let announce = FileAnnouncePayload::from_message(&msg).unwrap();
+        assert_eq!((announce.offer.file_id, announce.group.as_deref()), ([42u8; 16], Some("medics")));
+
+        // ...and with chunk hashes but before previews
+        let hashed = ([42u8; 16], "old.txt", 1024u64, 1u32, [3u8; 32], vec![[4u8; 32]]);
+        msg.payload = bincode::serialize(&hashed).unwrap();
+        let offer = FileOfferPayload::from_message(&msg).unwrap();
+        assert_eq!((offer.chunk_hashes, offer.preview), (vec![[4u8; 32]], None));
+        let have = SwarmHavePayload::from_message(&msg).unwrap();
+        assert_eq!(have.offer.chunk_hashes, vec![[4u8; 32]]);
+
+        msg.payload = bincode::serialize(&(hashed, None::<String>)).unwrap();
+        let announce = FileAnnouncePayload::from_message(&msg).unwrap();
+        assert_eq!((announce.offer.chunk_hashes, announce.group), (vec![[4u8; 32]], None));
+
+        // ...and with a preview but before bundles
+        let preview = Some(FilePreview { mime_type: "image/jpeg".into(), data: vec![5] });
+        let previewed = ([42u8; 16], "old.jpg", 1024u64, 1u32, [3u8; 32], vec![[4u8; 32]], preview.clone());
+        msg.payload = bincode::serialize(&previewed).unwrap();
+        let offer = FileOfferPayload::from_message(&msg).unwrap();
+        assert_eq!(offer.preview, preview);
+        assert!(offer.bundle.is_empty());
+
+        msg.payload = bincode::serialize(&(previewed, Some("medics"))).unwrap();
+        let announce = FileAnnouncePayload::from_message(&msg).unwrap();
+        assert_eq!((announce.offer.preview, announce.group.as_deref()), (preview, Some("medics")));
+
+        // An accept from before bundles names only the file
+        msg.payload = bincode::serialize(&[42u8; 16]).unwrap();
+        let accept = FileAcceptPayload::from_message(&msg).unwrap();
+        assert_eq!((accept.file_id, accept.entries), ([42u8; 16], vec![]));
     }
 
     #[test]
@@ -621,4 +1317,31 @@ mod tests {
         assert_eq!(decoded.duration_ms, 5000);
         assert_eq!(decoded.audio_data, vec![1, 2, 3, 4, 5]);
     }
+
+    #[test]
+    fn test_link_state_signature() {
+        let id = NodeIdentity::generate("Relay".into());
+        let ls = LinkStatePayload {
+            issued_ms: 1,
+            display_name: "Relay".into(),
+            links: vec![LinkInfo { node_id: [2u8; 32], quality: 200 }],
+        };
+        let msg = ls.to_message(&id);
+        assert!(msg.verify_signature());
+
+        // Still valid after a relay forwards it
+        let mut forwarded = MeshMessage::from_bytes(&msg.to_bytes()).unwrap();
+        assert!(forwarded.decrement_ttl());
+        forwarded.hop_count += 1;
+        assert!(forwarded.verify_signature());
+
+        // A relay can't rewrite the neighbor list or claim another origin
+        let mut tampered = msg.clone();
+        tampered.payload = bincode::serialize(&LinkStatePayload { links: vec![], ..ls }).unwrap();
+        assert!(!tampered.verify_signature());
+        let mut spoofed = msg.clone();
+        spoofed.sender_id = [3u8; 32];
+        assert!(!spoofed.verify_signature());
+        assert!(!MeshMessage::text(id.node_id, "unsigned").verify_signature());
+    }
 }
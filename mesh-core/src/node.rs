@@ -1,25 +1,40 @@
 use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
 use std::path::PathBuf;
-use std::time::Duration;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
 use tokio::sync::{mpsc, watch};
 use tracing::{info, warn, debug};
-use anyhow::Result;
+use anyhow::{Context, Result};
 
 use crate::crypto::{generate_x25519_keypair, SessionKeys};
-use crate::discovery::DiscoveryService;
-use crate::file_transfer::FileTransferManager;
+use crate::datagram::{self, DatagramPath, DatagramSocket};
+use crate::discovery::{DiscoveredPeer, DiscoveryService};
+use crate::file_transfer::{
+    free_disk_space, FileAcceptPolicy, FileAudience, FileTransferManager, PolicyDecision, SenderTrust,
+    MAX_CHUNKS_PER_REQUEST, OFFER_TIMEOUT, STALL_TIMEOUT,
+};
 use crate::gateway;
 use crate::identity::NodeIdentity;
 use crate::message::*;
-use crate::peer::{PeerManager, PeerState};
-use crate::router::{Router, RoutingTable};
+use crate::peer::{LinkQuality, LinkStats, PeerManager, PeerState};
+use crate::queue::LinkSender;
+use crate::router::{DiscoveryStep, FloodStrategy, RelayPlan, Router, RouteWait, RoutingTable};
 use crate::storage::{MeshStorage, StoredMessage, Contact};
-use crate::transport::{TcpTransport, IncomingMessage, InboundConnection};
+use crate::dtn::{DtnStore, HOLD_EXPIRY};
+use crate::topology::{TopologyGraph, TopologyMap, LINK_STATE_INTERVAL};
+use crate::trace::{self, TraceHop, TraceKind, TraceOutcome, TraceTracker};
+use crate::transport::{IncomingMessage, InboundConnection, LinkAddr, TcpTransport, Transport};
 
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
 const GATEWAY_CHECK_INTERVAL: Duration = Duration::from_secs(30);
 const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+const FILE_RETRANSMIT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const ROUTE_DISCOVERY_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+/// File messages waiting for the file transfer task; beyond this they are dropped
+/// (chunks are resent, and everything else is retried by its sender).
+const FILE_QUEUE_SIZE: usize = 256;
 const TCP_PORT: u16 = 7332;
 
 // ---------------------------------------------------------------------------
@@ -34,6 +49,16 @@ pub struct MeshStats {
     pub messages_received: u64,
     pub unique_nodes_seen: u32,
     pub avg_hops: f32,
+    /// Broadcasts this node chose not to relay under its flood strategy.
+    pub broadcasts_suppressed: u64,
+    /// Messages held here for destinations that are out of reach.
+    pub messages_held: u32,
+    /// Averages over the links to connected peers (RTT only over peers that report one).
+    pub avg_rtt_ms: f32,
+    pub avg_jitter_ms: f32,
+    /// Share of heartbeats lost, 0.0 to 1.0.
+    pub avg_loss: f32,
+    pub avg_etx: f32,
     pub interfaces: Vec<gateway::NetworkInterface>,
     pub active_interface: String,
 }
@@ -46,6 +71,10 @@ pub struct PeerListEntry {
     pub addr: String,
     pub is_gateway: bool,
     pub bio: String,
+    pub link: LinkStats,
+    pub quality: LinkQuality,
+    /// Call audio to this peer currently goes over UDP rather than its TCP link.
+    pub udp: bool,
 }
 
 /// Events emitted by the node for the application layer.
@@ -73,15 +102,68 @@ pub enum NodeEvent {
         file_id: [u8; 16],
         filename: String,
         size: u64,
+        /// Thumbnail for image files, if the sender included one.
+        preview: Option<FilePreview>,
+    },
+    /// A multi-file bundle was offered; accept all of it or pick entries by index.
+    BundleOffered {
+        sender_id: [u8; 32],
+        sender_name: String,
+        file_id: [u8; 16],
+        name: String,
+        size: u64,
+        entries: Vec<BundleEntry>,
     },
     FileProgress {
         file_id: [u8; 16],
         pct: u8,
+        bytes_per_sec: u64,
+        eta_secs: Option<u32>,
     },
     FileComplete {
         file_id: [u8; 16],
         path: String,
     },
+    /// The recipient declined our offer.
+    FileDeclined {
+        file_id: [u8; 16],
+        by_peer: [u8; 32],
+    },
+    /// The other end aborted the transfer.
+    FileCancelled {
+        file_id: [u8; 16],
+        by_peer: [u8; 32],
+        reason: String,
+    },
+    /// A file requested by content hash was found and is downloading from the swarm.
+    FileFetchStarted {
+        file_id: [u8; 16],
+        content_hash: [u8; 32],
+        filename: String,
+        size: u64,
+    },
+    /// An offer was accepted automatically by the file policy.
+    FileAutoAccepted {
+        sender_id: [u8; 32],
+        sender_name: String,
+        file_id: [u8; 16],
+        filename: String,
+        size: u64,
+        reason: String,
+    },
+    /// An offer was declined automatically by the file policy.
+    FileAutoRejected {
+        sender_id: [u8; 32],
+        sender_name: String,
+        file_id: [u8; 16],
+        filename: String,
+        reason: String,
+    },
+    /// The transfer failed locally (timeout, stall, hash mismatch, disk error).
+    FileFailed {
+        file_id: [u8; 16],
+        reason: String,
+    },
     // Voice events
     VoiceReceived {
         sender_id: [u8; 32],
@@ -115,6 +197,27 @@ pub enum NodeEvent {
     GatewayLost {
         node_id: [u8; 32],
     },
+    // Route discovery
+    RouteFound {
+        node_id: [u8; 32],
+        hops: u8,
+    },
+    NoRoute {
+        node_id: [u8; 32],
+    },
+    // Diagnostics
+    PingResult {
+        target: [u8; 32],
+        reached: bool,
+        /// The path the probe took; only the last hop has a round-trip time.
+        hops: Vec<TraceHop>,
+    },
+    TraceResult {
+        target: [u8; 32],
+        reached: bool,
+        /// One entry per distance; `node_id: None` marks where the path breaks.
+        hops: Vec<TraceHop>,
+    },
     // Stats
     Stats {
         stats: MeshStats,
@@ -122,6 +225,10 @@ pub enum NodeEvent {
     PeerList {
         peers: Vec<PeerListEntry>,
     },
+    /// The whole mesh as gossiped neighbor lists describe it (answer to `GetTopology`).
+    Topology {
+        graph: TopologyGraph,
+    },
     // Public broadcast / SOS
     PublicBroadcast {
         sender_id: [u8; 32],
@@ -162,6 +269,14 @@ pub struct NodeConfig {
     pub listen_port: u16,
     pub key_path: PathBuf,
     pub data_dir: Option<PathBuf>,
+    /// Hold received files here for review instead of the normal download folder.
+    pub quarantine_dir: Option<PathBuf>,
+    /// Rules for auto-accepting or auto-rejecting file offers (default: always ask).
+    pub file_policy: FileAcceptPolicy,
+    /// How broadcasts are relayed (default: full flood).
+    pub flood_strategy: FloodStrategy,
+    /// Bearers to carry mesh traffic over (default: TCP on `listen_port`).
+    pub transports: Vec<Arc<dyn Transport>>,
 }
 
 impl Default for NodeConfig {
@@ -171,6 +286,10 @@ impl Default for NodeConfig {
             listen_port: TCP_PORT,
             key_path: PathBuf::from("mesh_identity.key"),
             data_dir: None,
+            quarantine_dir: None,
+            file_policy: FileAcceptPolicy::default(),
+            flood_strategy: FloodStrategy::default(),
+            transports: Vec::new(),
         }
     }
 }
@@ -181,7 +300,16 @@ pub enum NodeCommand {
     SendDirect { dest: [u8; 32], text: String },
     // File transfer
     SendFile { dest: [u8; 32], file_path: String },
+    SendFileToGroup { group_name: String, file_path: String },
+    SendFileBroadcast { file_path: String },
+    SendBundle { dest: [u8; 32], paths: Vec<String> },
     AcceptFile { file_id: [u8; 16] },
+    AcceptFileEntries { file_id: [u8; 16], entries: Vec<u32> },
+    DeclineFile { file_id: [u8; 16] },
+    CancelFile { file_id: [u8; 16] },
+    FetchFile { content_hash: [u8; 32] },
+    SetFilePolicy { policy: FileAcceptPolicy },
+    SetFloodStrategy { strategy: FloodStrategy },
     // Voice
     SendVoice { dest: Option<[u8; 32]>, audio_data: Vec<u8>, duration_ms: u32 },
     // PTT
@@ -212,11 +340,18 @@ pub enum NodeCommand {
     LoadHistory { peer: Option<[u8; 32]>, group: Option<String> },
     // Contacts
     SetNickname { node_id: [u8; 32], nickname: String },
+    SetFavorite { node_id: [u8; 32], favorite: bool },
+    SetVerified { node_id: [u8; 32], verified: bool },
+    // Routing
+    DiscoverRoute { node_id: [u8; 32] },
+    MeshPing { target: [u8; 32] },
+    Traceroute { target: [u8; 32] },
     // Admin
     Nuke,
     Shutdown,
     GetStats,
     GetPeers,
+    GetTopology,
 }
 
 /// A handle for sending commands from the application layer.
@@ -246,11 +381,52 @@ impl NodeHandle {
         }).await.map_err(|_| anyhow::anyhow!("Node command channel closed"))
     }
 
+    pub async fn send_file_to_group(&self, group_name: &str, file_path: &str) -> Result<()> {
+        self.command_tx.send(NodeCommand::SendFileToGroup {
+            group_name: group_name.to_string(),
+            file_path: file_path.to_string(),
+        }).await.map_err(|_| anyhow::anyhow!("Node command channel closed"))
+    }
+
+    pub async fn send_file_broadcast(&self, file_path: &str) -> Result<()> {
+        self.command_tx.send(NodeCommand::SendFileBroadcast {
+            file_path: file_path.to_string(),
+        }).await.map_err(|_| anyhow::anyhow!("Node command channel closed"))
+    }
+
     pub async fn accept_file(&self, file_id: [u8; 16]) -> Result<()> {
         self.command_tx.send(NodeCommand::AcceptFile { file_id })
             .await.map_err(|_| anyhow::anyhow!("Node command channel closed"))
     }
 
+    /// Send several files and/or directories to a peer as one bundle transfer.
+    pub async fn send_bundle(&self, dest: [u8; 32], paths: Vec<String>) -> Result<()> {
+        self.command_tx.send(NodeCommand::SendBundle { dest, paths })
+            .await.map_err(|_| anyhow::anyhow!("Node command channel closed"))
+    }
+
+    /// Accept only the given entries (manifest indices) of a bundle offer.
+    pub async fn accept_file_entries(&self, file_id: [u8; 16], entries: Vec<u32>) -> Result<()> {
+        self.command_tx.send(NodeCommand::AcceptFileEntries { file_id, entries })
+            .await.map_err(|_| anyhow::anyhow!("Node command channel closed"))
+    }
+
+    pub async fn decline_file(&self, file_id: [u8; 16]) -> Result<()> {
+        self.command_tx.send(NodeCommand::DeclineFile { file_id })
+            .await.map_err(|_| anyhow::anyhow!("Node command channel closed"))
+    }
+
+    pub async fn cancel_file(&self, file_id: [u8; 16]) -> Result<()> {
+        self.command_tx.send(NodeCommand::CancelFile { file_id })
+            .await.map_err(|_| anyhow::anyhow!("Node command channel closed"))
+    }
+
+    /// Download a file by its SHA-256 from whichever nodes hold it.
+    pub async fn fetch_file(&self, content_hash: [u8; 32]) -> Result<()> {
+        self.command_tx.send(NodeCommand::FetchFile { content_hash })
+            .await.map_err(|_| anyhow::anyhow!("Node command channel closed"))
+    }
+
     pub async fn send_voice(&self, dest: Option<[u8; 32]>, audio_data: Vec<u8>, duration_ms: u32) -> Result<()> {
         self.command_tx.send(NodeCommand::SendVoice { dest, audio_data, duration_ms })
             .await.map_err(|_| anyhow::anyhow!("Node command channel closed"))
@@ -306,11 +482,35 @@ impl NodeHandle {
             .await.map_err(|_| anyhow::anyhow!("Node command channel closed"))
     }
 
+    /// Look for a route to `node_id`; answered with `RouteFound` or `NoRoute`.
+    pub async fn discover_route(&self, node_id: [u8; 32]) -> Result<()> {
+        self.command_tx.send(NodeCommand::DiscoverRoute { node_id })
+            .await.map_err(|_| anyhow::anyhow!("Node command channel closed"))
+    }
+
+    /// Ping any node through the mesh; answered with `PingResult`.
+    pub async fn mesh_ping(&self, target: [u8; 32]) -> Result<()> {
+        self.command_tx.send(NodeCommand::MeshPing { target })
+            .await.map_err(|_| anyhow::anyhow!("Node command channel closed"))
+    }
+
+    /// Trace the path to any node hop by hop; answered with `TraceResult`.
+    pub async fn traceroute(&self, target: [u8; 32]) -> Result<()> {
+        self.command_tx.send(NodeCommand::Traceroute { target })
+            .await.map_err(|_| anyhow::anyhow!("Node command channel closed"))
+    }
+
     pub async fn get_peers(&self) -> Result<()> {
         self.command_tx.send(NodeCommand::GetPeers)
             .await.map_err(|_| anyhow::anyhow!("Node command channel closed"))
     }
 
+    /// Request the mesh map; answered with `Topology`.
+    pub async fn get_topology(&self) -> Result<()> {
+        self.command_tx.send(NodeCommand::GetTopology)
+            .await.map_err(|_| anyhow::anyhow!("Node command channel closed"))
+    }
+
     pub async fn send_read_receipt(&self, dest: [u8; 32], original_msg_id: [u8; 32]) -> Result<()> {
         self.command_tx.send(NodeCommand::SendReadReceipt { dest, original_msg_id })
             .await.map_err(|_| anyhow::anyhow!("Node command channel closed"))
@@ -375,6 +575,30 @@ impl NodeHandle {
         }).await.map_err(|_| anyhow::anyhow!("Node command channel closed"))
     }
 
+    pub async fn set_favorite(&self, node_id: [u8; 32], favorite: bool) -> Result<()> {
+        self.command_tx.send(NodeCommand::SetFavorite { node_id, favorite })
+            .await.map_err(|_| anyhow::anyhow!("Node command channel closed"))
+    }
+
+    /// Mark a contact's safety number as verified (or clear it).
+    pub async fn set_verified(&self, node_id: [u8; 32], verified: bool) -> Result<()> {
+        self.command_tx.send(NodeCommand::SetVerified { node_id, verified })
+            .await.map_err(|_| anyhow::anyhow!("Node command channel closed"))
+    }
+
+    /// Replace the file auto-accept policy.
+    pub async fn set_file_policy(&self, policy: FileAcceptPolicy) -> Result<()> {
+        self.command_tx.send(NodeCommand::SetFilePolicy { policy })
+            .await.map_err(|_| anyhow::anyhow!("Node command channel closed"))
+    }
+
+    /// Change how new broadcasts are relayed to neighbors; applies to
+    /// broadcasts received from now on.
+    pub async fn set_flood_strategy(&self, strategy: FloodStrategy) -> Result<()> {
+        self.command_tx.send(NodeCommand::SetFloodStrategy { strategy })
+            .await.map_err(|_| anyhow::anyhow!("Node command channel closed"))
+    }
+
     /// Raw command send for FFI/custom commands.
     pub async fn send_command(&self, cmd: NodeCommand) -> Result<()> {
         self.command_tx.send(cmd)
@@ -401,18 +625,51 @@ pub async fn start_mesh_node(config: NodeConfig) -> Result<(NodeIdentity, NodeHa
     let has_internet = gateway::check_internet();
     info!("Internet gateway: {}", has_internet);
 
-    // Start TCP listener
-    let transport = TcpTransport::new(config.listen_port);
-    transport.start_listener(incoming_tx.clone(), inbound_conn_tx, shutdown_rx.clone()).await?;
+    // Start the transports
+    let transports = if config.transports.is_empty() {
+        vec![Arc::new(TcpTransport::new(config.listen_port)) as Arc<dyn Transport>]
+    } else {
+        config.transports.clone()
+    };
+    // A transport that proves an identity must prove ours, or neighbors would
+    // see one node on its links and another in everything it signs
+    if let Some(transport) = transports.iter().find(|t| t.identity().is_some_and(|id| id != identity.node_id)) {
+        anyhow::bail!("The {} transport was set up with a different identity than the one at {}",
+            transport.name(), config.key_path.display());
+    }
+    for transport in &transports {
+        transport.listen(incoming_tx.clone(), inbound_conn_tx.clone(), shutdown_rx.clone()).await
+            .with_context(|| format!("Failed to start {} transport", transport.name()))?;
+    }
 
-    // Start discovery
-    let discovery = DiscoveryService::new(
-        identity.node_id,
-        identity.display_name.clone(),
-        config.listen_port,
-        has_internet,
-    );
-    let mut discovery_rx = discovery.start(shutdown_rx.clone()).await?;
+    // Start discovery, advertising the first transport LAN peers can dial.
+    // Without one the channel stays closed and the loop never sees a discovery.
+    let lan = transports.iter().find(|t| t.discovery_port().is_some());
+    let lan_port = lan.and_then(|t| t.discovery_port());
+    let (_, mut discovery_rx) = mpsc::channel::<DiscoveredPeer>(1);
+    if let Some(port) = lan_port {
+        let discovery = DiscoveryService::new(
+            identity.node_id,
+            identity.display_name.clone(),
+            port,
+            has_internet,
+        );
+        discovery_rx = discovery.start(shutdown_rx.clone()).await?;
+    }
+
+    // UDP path for call audio, on the same port number as the LAN transport.
+    // Only ordered links hold audio up behind a lost segment; QUIC has its own datagrams.
+    let (_, mut datagram_rx) = mpsc::channel::<(SocketAddr, Vec<u8>)>(1);
+    let mut datagram_socket = None;
+    if let Some(port) = lan_port.filter(|_| lan.is_some_and(|t| t.reliability().ordered)) {
+        match DatagramSocket::bind(port, shutdown_rx.clone()).await {
+            Ok((socket, rx)) => {
+                datagram_socket = Some(socket);
+                datagram_rx = rx;
+            }
+            Err(e) => warn!("UDP port {} unavailable ({}), calls stay on TCP", port, e),
+        }
+    }
 
     // X25519 keypair
     let (x25519_secret, x25519_public) = generate_x25519_keypair();
@@ -427,14 +684,59 @@ pub async fn start_mesh_node(config: NodeConfig) -> Result<(NodeIdentity, NodeHa
     if storage.is_none() {
         warn!("Failed to open storage, persistence disabled");
     }
+    let dtn = match DtnStore::open(&data_dir) {
+        Ok(store) => store,
+        Err(e) => {
+            warn!("Failed to open DTN store ({}), held messages won't survive a restart", e);
+            DtnStore::in_memory()?
+        }
+    };
 
     let our_node_id = identity.node_id;
-    let _our_display_name = identity.display_name.clone();
-    let _identity_clone = identity.clone();
+    let mut our_display_name = identity.display_name.clone();
+    let signer = identity.clone();
     let key_path = config.key_path.clone();
     let save_dir = config.key_path.parent()
         .unwrap_or_else(|| std::path::Path::new("."))
         .join("mesh_received_files");
+    let flood_strategy = config.flood_strategy;
+
+    // Group state
+    let mut joined_groups: HashSet<String> = HashSet::new();
+    if let Some(ref st) = storage {
+        if let Ok(groups) = st.get_groups() {
+            joined_groups.extend(groups);
+        }
+    }
+    // Receipts we sent, to recognise late copies from other custodians without asking the store
+    let mut receipted: HashMap<MessageId, Instant> = dtn.receipts().unwrap_or_default()
+        .into_iter()
+        .map(|id| (id, Instant::now()))
+        .collect();
+
+    // Subsystems: the databases and file transfers run beside the routing loop,
+    // so a slow disk never holds up routing
+    let messages_held = Arc::new(AtomicU32::new(dtn.held_count().unwrap_or(0) as u32));
+    let storage_tx = spawn_storage(storage, event_tx.clone());
+    let (custody_tx, mut handover_rx) = spawn_custody(dtn, messages_held.clone());
+    let (outbound_tx, mut outbound_rx) = mpsc::channel::<MeshMessage>(256);
+    let (file_cmd_tx, file_cmd_rx) = mpsc::unbounded_channel::<NodeCommand>();
+    let (file_msg_tx, file_msg_rx) = mpsc::channel::<(MeshMessage, String)>(FILE_QUEUE_SIZE);
+    let mut file_mgr = FileTransferManager::new(save_dir);
+    file_mgr.set_quarantine_dir(config.quarantine_dir.clone());
+    let files = FileTask {
+        file_mgr,
+        policy: config.file_policy.clone(),
+        storage: MeshStorage::open(&data_dir).ok(),
+        our_node_id,
+        signer: identity.clone(),
+        outbound: outbound_tx,
+        event_tx: event_tx.clone(),
+    };
+    // Its disk I/O blocks, so it gets a thread of its own rather than a worker
+    let runtime = tokio::runtime::Handle::current();
+    tokio::task::spawn_blocking(move || runtime.block_on(files.run(file_cmd_rx, file_msg_rx)));
+    let (connected_tx, mut connected_rx) = mpsc::channel::<(DiscoveredPeer, Option<LinkSender>, Arc<dyn Transport>)>(16);
     let mut shutdown_rx2 = shutdown_rx.clone();
 
     let _ = event_tx.send(NodeEvent::Started {
@@ -449,36 +751,32 @@ pub async fn start_mesh_node(config: NodeConfig) -> Result<(NodeIdentity, NodeHa
     tokio::spawn(async move {
         let mut peers = PeerManager::new();
         let mut router = Router::new(our_node_id);
-        let mut file_mgr = FileTransferManager::new(save_dir);
+        router.set_flood_strategy(flood_strategy);
         let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
         let mut gateway_timer = tokio::time::interval(GATEWAY_CHECK_INTERVAL);
         let x25519_public_bytes = x25519_public.to_bytes();
         let mut known_gateways: HashSet<[u8; 32]> = HashSet::new();
         let mut active_call: Option<([u8; 32], [u8; 16])> = None; // (peer, stream_id)
 
-        // Track write senders from inbound TCP connections
-        let mut inbound_senders: HashMap<SocketAddr, mpsc::Sender<MeshMessage>> = HashMap::new();
-
-        // Group state
-        let mut joined_groups: HashSet<String> = HashSet::new();
-        if let Some(ref st) = storage {
-            if let Ok(groups) = st.get_groups() {
-                for g in groups {
-                    joined_groups.insert(g);
-                }
-            }
-        }
-
-        // Offline message queue
-        let mut offline_queue: HashMap<[u8; 32], Vec<MeshMessage>> = HashMap::new();
+        // Accepted links waiting for a key exchange
+        let mut inbound_links: HashMap<LinkAddr, InboundConnection> = HashMap::new();
+        // Discovered peers we are dialing
+        let mut connecting: HashSet<[u8; 32]> = HashSet::new();
 
         // Routing table
         let mut routing_table = RoutingTable::new();
+        let mut route_timer = tokio::time::interval(ROUTE_DISCOVERY_CHECK_INTERVAL);
+        let mut traces = TraceTracker::new();
+
+        // Mesh map from signed neighbor lists
+        let mut topology = TopologyMap::new();
+        let mut topology_timer = tokio::time::interval(LINK_STATE_INTERVAL);
 
         // Disappearing message cleanup timer
         let mut cleanup_timer = tokio::time::interval(Duration::from_secs(30));
 
         loop {
+            let relay_due = router.next_relay_due();
             tokio::select! {
                 // ---------------------------------------------------------------
                 // Commands from the application
@@ -487,46 +785,32 @@ pub async fn start_mesh_node(config: NodeConfig) -> Result<(NodeIdentity, NodeHa
                     match cmd {
                         NodeCommand::SendBroadcast { text } => {
                             let msg = MeshMessage::text(our_node_id, &text);
-                            for (_, sender) in peers.broadcast_senders() {
-                                let _ = sender.send(msg.clone()).await;
-                            }
+                            send_routed(&peers, &mut routing_table, &custody_tx, &msg);
                         }
                         NodeCommand::SendDirect { dest, text } => {
                             let msg = MeshMessage::text_to(our_node_id, dest, &text);
-                            for (_, sender) in peers.broadcast_senders() {
-                                let _ = sender.send(msg.clone()).await;
-                            }
+                            send_routed(&peers, &mut routing_table, &custody_tx, &msg);
                         }
-                        NodeCommand::SendFile { dest, file_path } => {
-                            match file_mgr.prepare_send(dest, std::path::Path::new(&file_path)) {
-                                Ok(metadata) => {
-                                    let msg = MeshMessage::file_offer(our_node_id, dest, &metadata);
-                                    for (_, sender) in peers.broadcast_senders() {
-                                        let _ = sender.send(msg.clone()).await;
-                                    }
-                                    info!("File offer sent: {} ({} bytes, {} chunks)",
-                                        metadata.filename, metadata.size_bytes, metadata.chunk_count);
-                                }
-                                Err(e) => {
-                                    warn!("Failed to prepare file: {}", e);
-                                }
-                            }
+                        cmd @ (NodeCommand::SendFile { .. }
+                            | NodeCommand::SendFileToGroup { .. }
+                            | NodeCommand::SendFileBroadcast { .. }
+                            | NodeCommand::SendBundle { .. }
+                            | NodeCommand::AcceptFileEntries { .. }
+                            | NodeCommand::AcceptFile { .. }
+                            | NodeCommand::DeclineFile { .. }
+                            | NodeCommand::SetFilePolicy { .. }
+                            | NodeCommand::FetchFile { .. }
+                            | NodeCommand::CancelFile { .. }) => {
+                            let _ = file_cmd_tx.send(cmd);
                         }
-                        NodeCommand::AcceptFile { file_id } => {
-                            if let Some(sender_id) = file_mgr.accept_incoming(&file_id) {
-                                let msg = MeshMessage::file_accept(our_node_id, sender_id, file_id);
-                                for (_, sender) in peers.broadcast_senders() {
-                                    let _ = sender.send(msg.clone()).await;
-                                }
-                                info!("Accepted file transfer {:?}", hex::encode(file_id));
-                            }
+                        NodeCommand::SetFloodStrategy { strategy } => {
+                            info!("Flood strategy set to {:?}", strategy);
+                            router.set_flood_strategy(strategy);
                         }
                         NodeCommand::SendVoice { dest, audio_data, duration_ms } => {
                             let payload = VoiceNotePayload { duration_ms, audio_data };
                             let msg = MeshMessage::voice_note(our_node_id, dest, &payload);
-                            for (_, sender) in peers.broadcast_senders() {
-                                let _ = sender.send(msg.clone()).await;
-                            }
+                            send_routed(&peers, &mut routing_table, &custody_tx, &msg);
                         }
                         NodeCommand::StartVoiceCall { peer } => {
                             let mut stream_id = [0u8; 16];
@@ -534,17 +818,13 @@ pub async fn start_mesh_node(config: NodeConfig) -> Result<(NodeIdentity, NodeHa
                             active_call = Some((peer, stream_id));
                             let ctrl = CallControlPayload { stream_id };
                             let msg = MeshMessage::call_start(our_node_id, peer, &ctrl);
-                            for (_, sender) in peers.broadcast_senders() {
-                                let _ = sender.send(msg.clone()).await;
-                            }
+                            send_routed(&peers, &mut routing_table, &custody_tx, &msg);
                         }
                         NodeCommand::EndVoiceCall => {
                             if let Some((peer, stream_id)) = active_call.take() {
                                 let ctrl = CallControlPayload { stream_id };
                                 let msg = MeshMessage::call_end(our_node_id, peer, &ctrl);
-                                for (_, sender) in peers.broadcast_senders() {
-                                    let _ = sender.send(msg.clone()).await;
-                                }
+                                send_routed(&peers, &mut routing_table, &custody_tx, &msg);
                             }
                         }
                         NodeCommand::SendAudioFrame { peer, data } => {
@@ -558,135 +838,131 @@ pub async fn start_mesh_node(config: NodeConfig) -> Result<(NodeIdentity, NodeHa
                                 let msg = MeshMessage::voice_stream(our_node_id, peer, &payload);
                                 // Send directly to the call peer only
                                 if let Some(p) = peers.get(&peer) {
-                                    let _ = p.sender.send(msg).await;
+                                    let _ = p.try_send(msg);
                                 }
                             }
                         }
                         NodeCommand::UpdateProfile { name, bio } => {
+                            our_display_name = name.clone();
                             let payload = ProfilePayload {
                                 display_name: name,
                                 bio,
                                 capabilities: vec!["text".into(), "voice".into(), "file".into()],
                             };
                             let msg = MeshMessage::profile_update(our_node_id, &payload);
-                            for (_, sender) in peers.broadcast_senders() {
-                                let _ = sender.send(msg.clone()).await;
-                            }
+                            send_routed(&peers, &mut routing_table, &custody_tx, &msg);
                         }
                         NodeCommand::SendPublicBroadcast { text } => {
                             let msg = MeshMessage::public_broadcast(our_node_id, &text);
-                            for (_, sender) in peers.broadcast_senders() {
-                                let _ = sender.send(msg.clone()).await;
-                            }
+                            send_routed(&peers, &mut routing_table, &custody_tx, &msg);
                         }
                         NodeCommand::SendSOS { text, location } => {
                             let payload = SOSPayload { text, location };
                             let msg = MeshMessage::sos(our_node_id, &payload);
-                            for (_, sender) in peers.broadcast_senders() {
-                                let _ = sender.send(msg.clone()).await;
-                            }
+                            send_routed(&peers, &mut routing_table, &custody_tx, &msg);
                         }
                         NodeCommand::SendReadReceipt { dest, original_msg_id } => {
                             let msg = MeshMessage::read_receipt(our_node_id, dest, original_msg_id);
-                            for (_, sender) in peers.broadcast_senders() {
-                                let _ = sender.send(msg.clone()).await;
-                            }
+                            send_routed(&peers, &mut routing_table, &custody_tx, &msg);
                         }
                         NodeCommand::SendTypingStart { dest } => {
                             let msg = MeshMessage::typing_start(our_node_id, dest);
-                            for (_, sender) in peers.broadcast_senders() {
-                                let _ = sender.send(msg.clone()).await;
-                            }
+                            send_routed(&peers, &mut routing_table, &custody_tx, &msg);
                         }
                         NodeCommand::SendTypingStop { dest } => {
                             let msg = MeshMessage::typing_stop(our_node_id, dest);
-                            for (_, sender) in peers.broadcast_senders() {
-                                let _ = sender.send(msg.clone()).await;
-                            }
+                            send_routed(&peers, &mut routing_table, &custody_tx, &msg);
                         }
                         NodeCommand::JoinGroup { group_name } => {
                             joined_groups.insert(group_name.clone());
-                            if let Some(ref st) = storage {
-                                let _ = st.join_group(&group_name);
-                            }
+                            let _ = storage_tx.send(StorageJob::JoinGroup(group_name.clone()));
                             let msg = MeshMessage::group_join(our_node_id, &group_name);
-                            for (_, sender) in peers.broadcast_senders() {
-                                let _ = sender.send(msg.clone()).await;
-                            }
+                            send_routed(&peers, &mut routing_table, &custody_tx, &msg);
                             info!("Joined group: {}", group_name);
                         }
                         NodeCommand::LeaveGroup { group_name } => {
                             joined_groups.remove(&group_name);
-                            if let Some(ref st) = storage {
-                                let _ = st.leave_group(&group_name);
-                            }
+                            let _ = storage_tx.send(StorageJob::LeaveGroup(group_name.clone()));
                             let msg = MeshMessage::group_leave(our_node_id, &group_name);
-                            for (_, sender) in peers.broadcast_senders() {
-                                let _ = sender.send(msg.clone()).await;
-                            }
+                            send_routed(&peers, &mut routing_table, &custody_tx, &msg);
                             info!("Left group: {}", group_name);
                         }
                         NodeCommand::SendGroupMessage { group_name, text } => {
                             let msg = MeshMessage::group_message(our_node_id, &group_name, &text);
-                            for (_, sender) in peers.broadcast_senders() {
-                                let _ = sender.send(msg.clone()).await;
-                            }
+                            send_routed(&peers, &mut routing_table, &custody_tx, &msg);
                         }
                         NodeCommand::SendTriage { triage } => {
                             let msg = MeshMessage::triage(our_node_id, &triage);
-                            for (_, sender) in peers.broadcast_senders() {
-                                let _ = sender.send(msg.clone()).await;
-                            }
+                            send_routed(&peers, &mut routing_table, &custody_tx, &msg);
                         }
                         NodeCommand::SendResourceRequest { request } => {
                             let msg = MeshMessage::resource_request(our_node_id, &request);
-                            for (_, sender) in peers.broadcast_senders() {
-                                let _ = sender.send(msg.clone()).await;
-                            }
+                            send_routed(&peers, &mut routing_table, &custody_tx, &msg);
                         }
                         NodeCommand::SendCheckIn { check_in } => {
                             let msg = MeshMessage::check_in(our_node_id, &check_in);
-                            for (_, sender) in peers.broadcast_senders() {
-                                let _ = sender.send(msg.clone()).await;
-                            }
+                            send_routed(&peers, &mut routing_table, &custody_tx, &msg);
                         }
                         NodeCommand::SendDisappearing { dest, text, ttl_seconds } => {
                             let msg = MeshMessage::disappearing(our_node_id, dest, &text, ttl_seconds);
-                            for (_, sender) in peers.broadcast_senders() {
-                                let _ = sender.send(msg.clone()).await;
-                            }
+                            send_routed(&peers, &mut routing_table, &custody_tx, &msg);
                         }
                         NodeCommand::LoadHistory { peer, group } => {
-                            if let Some(ref st) = storage {
-                                let messages = if let Some(ref g) = group {
-                                    st.get_group_history(g, 100).unwrap_or_default()
-                                } else if let Some(ref p) = peer {
-                                    st.get_dm_history(p, 100).unwrap_or_default()
-                                } else {
-                                    st.get_messages(100, None).unwrap_or_default()
-                                };
-                                let _ = event_tx.send(NodeEvent::HistoryLoaded { messages }).await;
-                            }
+                            let _ = storage_tx.send(StorageJob::LoadHistory { peer, group });
                         }
                         NodeCommand::SetNickname { node_id, nickname } => {
-                            if let Some(ref st) = storage {
-                                let _ = st.set_nickname(&node_id, &nickname);
-                            }
+                            let _ = storage_tx.send(StorageJob::SetNickname { node_id, nickname });
+                        }
+                        NodeCommand::SetFavorite { node_id, favorite } => {
+                            let _ = storage_tx.send(StorageJob::SetFavorite { node_id, favorite });
+                        }
+                        NodeCommand::SetVerified { node_id, verified } => {
+                            let safety = verified.then(|| NodeIdentity::safety_number(&our_node_id, &node_id));
+                            let _ = storage_tx.send(StorageJob::SetSafetyNumber { node_id, safety });
                         }
                         NodeCommand::GetStats => {
                             let rs = &router.stats;
                             let (ifaces, active_iface) = gateway::detect_interfaces();
+                            let (avg_rtt_ms, avg_jitter_ms, avg_loss, avg_etx) = link_averages(&peers);
                             let stats = MeshStats {
                                 total_peers: peers.count() as u32,
                                 messages_relayed: rs.messages_relayed,
                                 messages_received: rs.messages_received,
                                 unique_nodes_seen: rs.unique_nodes_seen,
                                 avg_hops: rs.avg_hops(),
+                                broadcasts_suppressed: rs.broadcasts_suppressed,
+                                messages_held: messages_held.load(Ordering::Relaxed),
+                                avg_rtt_ms,
+                                avg_jitter_ms,
+                                avg_loss,
+                                avg_etx,
                                 interfaces: ifaces,
                                 active_interface: active_iface,
                             };
                             let _ = event_tx.send(NodeEvent::Stats { stats }).await;
                         }
+                        NodeCommand::GetTopology => {
+                            let graph = topology.graph(our_node_id, &our_display_name, &our_links(&peers));
+                            let _ = event_tx.send(NodeEvent::Topology { graph }).await;
+                        }
+                        NodeCommand::DiscoverRoute { node_id } => {
+                            if routing_table.next_hop(&node_id, |id| peers.contains(id)).is_some() {
+                                let hops = routing_table.hops_to(&node_id).filter(|_| !peers.contains(&node_id)).unwrap_or(1);
+                                let _ = event_tx.send(NodeEvent::RouteFound { node_id, hops }).await;
+                            } else if let Some(rreq) = routing_table.discover(our_node_id, node_id) {
+                                send_routed(&peers, &mut routing_table, &custody_tx, &rreq);
+                            }
+                        }
+                        NodeCommand::MeshPing { target } => {
+                            for probe in traces.start(TraceKind::Ping, our_node_id, target) {
+                                send_routed(&peers, &mut routing_table, &custody_tx, &probe);
+                            }
+                        }
+                        NodeCommand::Traceroute { target } => {
+                            for probe in traces.start(TraceKind::Traceroute, our_node_id, target) {
+                                send_routed(&peers, &mut routing_table, &custody_tx, &probe);
+                            }
+                        }
                         NodeCommand::GetPeers => {
                             let peer_list: Vec<PeerListEntry> = peers.all().map(|p| {
                                 PeerListEntry {
@@ -695,6 +971,9 @@ pub async fn start_mesh_node(config: NodeConfig) -> Result<(NodeIdentity, NodeHa
                                     addr: p.addr.to_string(),
                                     is_gateway: p.is_gateway,
                                     bio: p.bio.clone(),
+                                    link: LinkStats { dropped: p.sender.dropped(), ..p.link },
+                                    quality: p.quality,
+                                    udp: p.datagram.as_ref().is_some_and(|d| d.is_up(Instant::now())),
                                 }
                             }).collect();
                             let _ = event_tx.send(NodeEvent::PeerList { peers: peer_list }).await;
@@ -716,11 +995,16 @@ pub async fn start_mesh_node(config: NodeConfig) -> Result<(NodeIdentity, NodeHa
                 }
 
                 // ---------------------------------------------------------------
-                // Track inbound TCP connections
+                // Track accepted links
                 // ---------------------------------------------------------------
                 Some(conn) = inbound_conn_rx.recv() => {
-                    debug!("Inbound TCP connection from {}, storing write sender", conn.addr);
-                    inbound_senders.insert(conn.addr, conn.sender);
+                    debug!("Inbound {} link from {}, storing write sender", conn.transport, conn.addr);
+                    // A dialer introduces itself; on links where neither side dialed, we have to
+                    if !conn.dialed {
+                        let kx = KeyExchangePayload { x25519_public: x25519_public_bytes };
+                        let _ = conn.sender.try_send(kx.to_message(our_node_id, [0u8; 32]));
+                    }
+                    inbound_links.insert(conn.addr.clone(), conn);
                 }
 
                 // ---------------------------------------------------------------
@@ -749,64 +1033,98 @@ pub async fn start_mesh_node(config: NodeConfig) -> Result<(NodeIdentity, NodeHa
                         continue;
                     }
 
-                    info!("Connecting to discovered peer: {} at {}", discovered.display_name, discovered.addr);
-                    match TcpTransport::connect_to_peer(discovered.addr, incoming_tx.clone()).await {
-                        Ok((sender, _handle)) => {
-                            let mut peer = PeerState::new(
-                                discovered.node_id,
-                                discovered.display_name.clone(),
-                                discovered.addr,
-                                sender.clone(),
-                            );
-                            peer.is_gateway = discovered.has_internet;
-                            peers.add(peer);
-
-                            let kx = KeyExchangePayload { x25519_public: x25519_public_bytes };
-                            let kx_msg = kx.to_message(our_node_id, discovered.node_id);
-                            let _ = sender.send(kx_msg).await;
-
-                            let _ = event_tx.send(NodeEvent::PeerConnected {
-                                node_id: discovered.node_id,
-                                display_name: discovered.display_name.clone(),
-                            }).await;
-
-                            let disc_name = discovered.display_name.clone();
-
-                            if discovered.has_internet {
-                                let _ = event_tx.send(NodeEvent::GatewayFound {
-                                    node_id: discovered.node_id,
-                                    display_name: discovered.display_name,
-                                }).await;
-                                known_gateways.insert(discovered.node_id);
+                    // Dial in the background; an unreachable address must not hold up the loop
+                    let addr = LinkAddr::Ip(discovered.addr);
+                    let Some(transport) = transports.iter().find(|t| t.can_dial(&addr)).cloned() else {
+                        continue;
+                    };
+                    if !connecting.insert(discovered.node_id) {
+                        continue;
+                    }
+                    info!("Connecting to discovered peer: {} at {} over {}", discovered.display_name, addr, transport.name());
+                    let incoming_tx = incoming_tx.clone();
+                    let connected_tx = connected_tx.clone();
+                    tokio::spawn(async move {
+                        let link = match transport.dial(addr, discovered.node_id, incoming_tx).await {
+                            Ok(sender) => Some(sender),
+                            Err(e) => {
+                                warn!("Failed to connect to {}: {}", discovered.addr, e);
+                                None
                             }
+                        };
+                        let _ = connected_tx.send((discovered, link, transport)).await;
+                    });
+                }
 
-                            // Deliver queued offline messages
-                            if let Some(queued) = offline_queue.remove(&discovered.node_id) {
-                                for qmsg in queued {
-                                    let _ = sender.send(qmsg).await;
-                                }
-                                info!("Delivered offline queue to {}", hex::encode(&discovered.node_id[..4]));
-                            }
+                Some((discovered, link, transport)) = connected_rx.recv() => {
+                    connecting.remove(&discovered.node_id);
+                    let Some(sender) = link else { continue };
+                    if peers.contains(&discovered.node_id) {
+                        // It dialed us meanwhile; keep that link
+                        sender.close();
+                        continue;
+                    }
+                    let mut peer = PeerState::new(
+                        discovered.node_id,
+                        discovered.display_name.clone(),
+                        LinkAddr::Ip(discovered.addr),
+                        sender.clone(),
+                    );
+                    peer.is_gateway = discovered.has_internet;
+                    peer.congestion_timeout = transport.congestion_timeout();
+                    peer.mtu = transport.mtu();
+                    peers.add(peer);
+
+                    let kx = KeyExchangePayload { x25519_public: x25519_public_bytes };
+                    let _ = sender.try_send(kx.to_message(our_node_id, discovered.node_id));
+
+                    let _ = event_tx.send(NodeEvent::PeerConnected {
+                        node_id: discovered.node_id,
+                        display_name: discovered.display_name.clone(),
+                    }).await;
+
+                    let disc_name = discovered.display_name.clone();
+
+                    if discovered.has_internet {
+                        let _ = event_tx.send(NodeEvent::GatewayFound {
+                            node_id: discovered.node_id,
+                            display_name: discovered.display_name,
+                        }).await;
+                        known_gateways.insert(discovered.node_id);
+                    }
 
-                            // Save as contact
-                            if let Some(ref st) = storage {
-                                let now = chrono::Utc::now().timestamp_millis();
-                                let contact = Contact {
-                                    node_id: discovered.node_id,
-                                    display_name: disc_name,
-                                    nickname: None,
-                                    bio: String::new(),
-                                    first_seen: now,
-                                    last_seen: now,
-                                    is_favorite: false,
-                                    safety_number: None,
-                                };
-                                let _ = st.save_contact(&contact);
-                            }
-                        }
-                        Err(e) => {
-                            warn!("Failed to connect to {}: {}", discovered.addr, e);
-                        }
+                    // Hand over anything we were holding for this peer
+                    let _ = custody_tx.send(CustodyJob::TakeFor(discovered.node_id));
+
+                    // Save as contact
+                    let now = chrono::Utc::now().timestamp_millis();
+                    let _ = storage_tx.send(StorageJob::SaveContact(Contact {
+                        node_id: discovered.node_id,
+                        display_name: disc_name,
+                        nickname: None,
+                        bio: String::new(),
+                        first_seen: now,
+                        last_seen: now,
+                        is_favorite: false,
+                        safety_number: None,
+                    }));
+                }
+
+                // ---------------------------------------------------------------
+                // Datagrams from neighbors' UDP paths
+                // ---------------------------------------------------------------
+                Some((from, data)) = datagram_rx.recv() => {
+                    let Some(token) = datagram::token_of(&data) else { continue };
+                    let Some(id) = peers.all().find(|p| p.datagram_token == token).map(|p| p.node_id) else { continue };
+                    let Some(peer) = peers.get_mut(&id) else { continue };
+                    let (Some(keys), Some(path)) = (&peer.session_keys, &mut peer.datagram) else { continue };
+                    let Ok((seq, opened)) = datagram::open(keys, &data) else {
+                        debug!("Undecryptable datagram from {}", from);
+                        continue;
+                    };
+                    if let Some(msg) = path.receive(keys, from, seq, opened, Instant::now()) {
+                        // From here on it is handled like anything that came over the link
+                        let _ = incoming_tx.try_send(IncomingMessage { msg, from_addr: LinkAddr::Ip(from), from_peer: Some(id) });
                     }
                 }
 
@@ -821,13 +1139,27 @@ pub async fn start_mesh_node(config: NodeConfig) -> Result<(NodeIdentity, NodeHa
                     if msg.msg_type == MessageType::KeyExchange {
                         if let Ok(kx) = KeyExchangePayload::from_message(&msg) {
                             let session = SessionKeys::from_exchange(&x25519_secret, &kx.x25519_public);
-                            if let Some(peer) = peers.get_mut(&msg.sender_id) {
+                            if let Some(peer) = peers.get_mut(&msg.sender_id).filter(|peer| peer.addr == from_addr) {
                                 peer.session_keys = Some(session);
                                 peer.touch();
                                 debug!("Session keys established with {}", peer.display_name);
-                            } else if let Some(sender) = inbound_senders.remove(&from_addr) {
+                                offer_datagram_path(peer, datagram_socket.as_ref(), our_node_id);
+                            } else if peers.contains(&msg.sender_id) {
+                                // Only the link a neighbor is on may rekey it; a second one waits until it drops
+                                debug!("Key exchange on {} from {}, already connected elsewhere", from_addr,
+                                    hex::encode(&msg.sender_id[..4]));
+                            } else if let Some(certified) = inbound_links.get(&from_addr)
+                                .and_then(|conn| conn.peer.get().copied())
+                                .filter(|id| *id != msg.sender_id)
+                            {
+                                warn!("Key exchange on {} claims {}, but the link is {}", from_addr,
+                                    hex::encode(&msg.sender_id[..4]), hex::encode(&certified[..4]));
+                            } else if let Some(conn) = inbound_links.remove(&from_addr) {
                                 let name = format!("node-{}", hex::encode(&msg.sender_id[..4]));
                                 info!("Inbound peer registered: {} from {}", name, from_addr);
+                                // From here on the link speaks for this neighbor
+                                let _ = conn.peer.set(msg.sender_id);
+                                let sender = conn.sender;
                                 let mut peer = PeerState::new(
                                     msg.sender_id,
                                     name.clone(),
@@ -835,11 +1167,16 @@ pub async fn start_mesh_node(config: NodeConfig) -> Result<(NodeIdentity, NodeHa
                                     sender.clone(),
                                 );
                                 peer.session_keys = Some(session);
-                                peers.add(peer);
+                                if let Some(transport) = transports.iter().find(|t| t.name() == conn.transport) {
+                                    peer.congestion_timeout = transport.congestion_timeout();
+                                    peer.mtu = transport.mtu();
+                                }
 
                                 let kx_resp = KeyExchangePayload { x25519_public: x25519_public_bytes };
-                                let kx_msg = kx_resp.to_message(our_node_id, msg.sender_id);
-                                let _ = sender.send(kx_msg).await;
+                                let _ = sender.try_send(kx_resp.to_message(our_node_id, msg.sender_id));
+                                offer_datagram_path(&peer, datagram_socket.as_ref(), our_node_id);
+                                peers.add(peer);
+                                let _ = custody_tx.send(CustodyJob::TakeFor(msg.sender_id));
 
                                 let _ = event_tx.send(NodeEvent::PeerConnected {
                                     node_id: msg.sender_id,
@@ -852,12 +1189,29 @@ pub async fn start_mesh_node(config: NodeConfig) -> Result<(NodeIdentity, NodeHa
                         continue;
                     }
 
+                    // --- UDP path offered by a neighbor ---
+                    if msg.msg_type == MessageType::DatagramOffer {
+                        let offer = DatagramOfferPayload::from_message(&msg);
+                        // The offer isn't sealed, so only the neighbor's own link may redirect its audio
+                        let neighbor = peers.get_mut(&msg.sender_id)
+                            .filter(|peer| incoming.from_peer == Some(msg.sender_id) && peer.addr == from_addr);
+                        if let (Ok(offer), Some(peer), Some(socket)) = (offer, neighbor, &datagram_socket) {
+                            if let LinkAddr::Ip(addr) = peer.addr {
+                                let path = DatagramPath::new(socket.clone(), SocketAddr::new(addr.ip(), offer.port), offer.token);
+                                if let Some(keys) = &peer.session_keys {
+                                    path.probe(keys, Instant::now());
+                                }
+                                peer.datagram = Some(path);
+                            }
+                        }
+                        continue;
+                    }
+
                     // --- Ping/Pong ---
                     if msg.msg_type == MessageType::Ping {
                         if let Some(peer) = peers.get_mut(&msg.sender_id) {
                             peer.touch();
-                            let pong = MeshMessage::new(MessageType::Pong, our_node_id, 1, Some(msg.sender_id), vec![]);
-                            let _ = peer.sender.send(pong).await;
+                            let _ = peer.sender.try_send(PingPayload::pong(&msg, our_node_id));
                         }
                         continue;
                     }
@@ -865,12 +1219,67 @@ pub async fn start_mesh_node(config: NodeConfig) -> Result<(NodeIdentity, NodeHa
                     if msg.msg_type == MessageType::Pong {
                         if let Some(peer) = peers.get_mut(&msg.sender_id) {
                             peer.touch();
+                            peer.pong_received(PingPayload::from_message(&msg));
+                            routing_table.set_link_etx(msg.sender_id, peer.quality.etx);
                         }
                         continue;
                     }
 
+                    // Unsigned peer lists aren't believed; neighbor lists come from link state
+                    if msg.msg_type == MessageType::PeerExchange {
+                        continue;
+                    }
+
+                    // --- Route learning: the link this arrived on leads back to its origin ---
+                    let upstream = incoming.from_peer.filter(|id| peers.contains(id));
+                    if let (Some(via), Some(hops)) = (upstream, msg.hops()) {
+                        if msg.sender_id != our_node_id {
+                            let hops = hops.saturating_add(1);
+                            match origin_seq(&msg) {
+                                Some(seq) => routing_table.update_route_seq(msg.sender_id, via, hops, seq),
+                                None => routing_table.update_route(msg.sender_id, via, hops),
+                            }
+                        }
+                    }
+
                     // --- Routing: dedup, TTL check ---
-                    if !router.should_process(&msg) {
+                    let fresh = router.should_process(&msg);
+                    if let Some(peer) = upstream.and_then(|id| peers.get_mut(&id)) {
+                        peer.link.received += 1;
+                        if !fresh {
+                            peer.link.duplicates += 1;
+                        }
+                    }
+                    if !fresh {
+                        router.heard_again(&msg.msg_id, upstream);
+                        continue;
+                    }
+
+                    // --- Delay-tolerant delivery: receipts signed by the destination release custody ---
+                    if msg.msg_type == MessageType::DeliveryReceipt {
+                        if !msg.verify_signature() {
+                            debug!("Dropping unsigned delivery receipt claiming to be from {}", hex::encode(&msg.sender_id[..4]));
+                            continue;
+                        }
+                        if let Ok(rr) = bincode::deserialize::<ReadReceiptPayload>(&msg.payload) {
+                            let _ = custody_tx.send(CustodyJob::Release { msg_id: rr.original_msg_id, by: msg.sender_id });
+                        }
+                    }
+                    if msg.destination == Some(our_node_id) && msg.wants_delivery_receipt() {
+                        let mut receipt = MeshMessage::delivery_receipt(our_node_id, msg.sender_id, msg.msg_id);
+                        receipt.sign(&signer);
+                        send_routed(&peers, &mut routing_table, &custody_tx, &receipt);
+                        // Other custodians may deliver their copies long after the first
+                        if receipted.insert(msg.msg_id, Instant::now()).is_some() {
+                            debug!("Dropping late copy of {}", hex::encode(&msg.msg_id[..4]));
+                            continue;
+                        }
+                        let _ = custody_tx.send(CustodyJob::RecordReceipt(msg.msg_id));
+                    }
+
+                    // Neighbor lists are only believed (and passed on) if their origin signed them
+                    if msg.msg_type == MessageType::LinkState && !msg.verify_signature() {
+                        debug!("Dropping unsigned link state claiming to be from {}", hex::encode(&msg.sender_id[..4]));
                         continue;
                     }
 
@@ -921,57 +1330,28 @@ pub async fn start_mesh_node(config: NodeConfig) -> Result<(NodeIdentity, NodeHa
                                     }).await;
                                 }
                             }
-                            MessageType::FileOffer => {
-                                if let Ok(offer) = bincode::deserialize::<FileOfferPayload>(&msg.payload) {
-                                    file_mgr.register_incoming(offer.clone(), msg.sender_id);
-                                    let _ = event_tx.send(NodeEvent::FileOffered {
-                                        sender_id: msg.sender_id,
-                                        sender_name,
-                                        file_id: offer.file_id,
-                                        filename: offer.filename,
-                                        size: offer.size_bytes,
-                                    }).await;
-                                }
-                            }
-                            MessageType::FileAccept => {
-                                if let Ok(accept) = bincode::deserialize::<FileAcceptPayload>(&msg.payload) {
-                                    if file_mgr.mark_accepted(&accept.file_id) {
-                                        // Send all chunks
-                                        let dest = file_mgr.outgoing_dest(&accept.file_id).unwrap_or(msg.sender_id);
-                                        while let Some(chunk_payload) = file_mgr.next_chunk(&accept.file_id) {
-                                            let chunk_msg = MeshMessage::file_chunk(our_node_id, dest, &chunk_payload);
-                                            for (_, sender) in peers.broadcast_senders() {
-                                                let _ = sender.send(chunk_msg.clone()).await;
-                                            }
-                                        }
-                                        file_mgr.remove_outgoing(&accept.file_id);
-                                        info!("File transfer complete (sender side)");
-                                    }
+                            MessageType::FileAnnounce => {
+                                let for_us = FileAnnouncePayload::from_message(&msg)
+                                    .map(|announce| announce.group.map(|g| joined_groups.contains(&g)).unwrap_or(true))
+                                    .unwrap_or(false);
+                                if for_us && file_msg_tx.try_send((msg.clone(), sender_name)).is_err() {
+                                    debug!("File transfers busy, dropping an announcement");
                                 }
                             }
-                            MessageType::FileChunk => {
-                                if let Ok(chunk) = bincode::deserialize::<FileChunkPayload>(&msg.payload) {
-                                    if let Some(pct) = file_mgr.receive_chunk(&chunk.file_id, chunk.sequence, chunk.data) {
-                                        let _ = event_tx.send(NodeEvent::FileProgress {
-                                            file_id: chunk.file_id,
-                                            pct,
-                                        }).await;
-
-                                        if file_mgr.is_incoming_complete(&chunk.file_id) {
-                                            match file_mgr.finalize_incoming(&chunk.file_id) {
-                                                Ok(path) => {
-                                                    let _ = event_tx.send(NodeEvent::FileComplete {
-                                                        file_id: chunk.file_id,
-                                                        path: path.to_string_lossy().to_string(),
-                                                    }).await;
-                                                    info!("File received: {}", path.display());
-                                                }
-                                                Err(e) => {
-                                                    warn!("File finalization failed: {}", e);
-                                                }
-                                            }
-                                        }
-                                    }
+                            MessageType::FileOffer
+                            | MessageType::FileAccept
+                            | MessageType::FileChunkAck
+                            | MessageType::FileDecline
+                            | MessageType::FileCancel
+                            | MessageType::FileChunk
+                            | MessageType::SwarmQuery
+                            | MessageType::SwarmHave
+                            | MessageType::ChunkRequest
+                            | MessageType::ChunkData => {
+                                // Chunks and requests are resent, so a backed-up disk only costs a retry
+                                let busy = file_msg_tx.try_send((msg.clone(), sender_name)).is_err();
+                                if busy {
+                                    debug!("File transfers busy, dropping {:?}", msg.msg_type);
                                 }
                             }
                             MessageType::Voice => {
@@ -1011,15 +1391,37 @@ pub async fn start_mesh_node(config: NodeConfig) -> Result<(NodeIdentity, NodeHa
                             }
                             MessageType::ReadReceipt => {
                                 if let Ok(rr) = bincode::deserialize::<ReadReceiptPayload>(&msg.payload) {
-                                    if let Some(ref st) = storage {
-                                        let _ = st.mark_delivered(&rr.original_msg_id);
-                                    }
+                                    let _ = storage_tx.send(StorageJob::MarkDelivered(rr.original_msg_id));
+                                    let _ = event_tx.send(NodeEvent::MessageDelivered {
+                                        msg_id: rr.original_msg_id,
+                                        by_peer: msg.sender_id,
+                                    }).await;
+                                }
+                            }
+                            MessageType::DeliveryReceipt => {
+                                if let Ok(rr) = bincode::deserialize::<ReadReceiptPayload>(&msg.payload) {
+                                    let _ = storage_tx.send(StorageJob::MarkDelivered(rr.original_msg_id));
                                     let _ = event_tx.send(NodeEvent::MessageDelivered {
                                         msg_id: rr.original_msg_id,
                                         by_peer: msg.sender_id,
                                     }).await;
                                 }
                             }
+                            MessageType::Trace => {
+                                if let Ok(probe) = TracePayload::from_message(&msg) {
+                                    let mut path = probe.path;
+                                    path.push(trace::stamp(our_node_id));
+                                    let reply = TraceReplyPayload { probe_id: probe.probe_id, path, reached: true };
+                                    send_routed(&peers, &mut routing_table, &custody_tx, &reply.to_message(our_node_id, msg.sender_id));
+                                }
+                            }
+                            MessageType::TraceReply => {
+                                if let Ok(reply) = TraceReplyPayload::from_message(&msg) {
+                                    if let Some(outcome) = traces.on_reply(reply) {
+                                        let _ = event_tx.send(trace_event(outcome)).await;
+                                    }
+                                }
+                            }
                             MessageType::TypingStart => {
                                 let _ = event_tx.send(NodeEvent::TypingStarted {
                                     peer: msg.sender_id,
@@ -1097,17 +1499,65 @@ pub async fn start_mesh_node(config: NodeConfig) -> Result<(NodeIdentity, NodeHa
                                     }).await;
                                 }
                             }
+                            MessageType::RouteRequest => {
+                                if let Ok(rreq) = RouteRequestPayload::from_message(&msg) {
+                                    if rreq.target == our_node_id {
+                                        // We are the target: answer along the reverse path, don't flood further
+                                        let reply = RouteReplyPayload { target_seq: routing_table.reply_seq(rreq.target_seq) };
+                                        let rrep = reply.to_message(our_node_id, msg.sender_id);
+                                        send_routed(&peers, &mut routing_table, &custody_tx, &rrep);
+                                        continue;
+                                    }
+                                }
+                            }
+                            MessageType::RouteReply => {
+                                if let Some(queued) = routing_table.route_found(&msg.sender_id) {
+                                    for waiting in &queued {
+                                        send_routed(&peers, &mut routing_table, &custody_tx, waiting);
+                                    }
+                                    let hops = routing_table.hops_to(&msg.sender_id).unwrap_or(1);
+                                    let _ = event_tx.send(NodeEvent::RouteFound { node_id: msg.sender_id, hops }).await;
+                                }
+                            }
+                            MessageType::LinkState => {
+                                if let Ok(ls) = LinkStatePayload::from_message(&msg) {
+                                    // A neighbor's signed list is what coverage-based flooding trusts
+                                    if peers.contains(&msg.sender_id) {
+                                        let neighbors = ls.links.iter().map(|l| l.node_id).collect();
+                                        router.set_neighbor_set(msg.sender_id, neighbors);
+                                    }
+                                    topology.update(msg.sender_id, ls);
+                                }
+                            }
                             _ => {} // Discovery, Ping, Pong, PeerExchange handled above
                         }
                     }
 
                     // --- Forward to other peers ---
-                    if router.should_forward(&msg) {
-                        if let Some(forwarded) = router.prepare_forward(&msg) {
-                            for (peer_id, sender) in peers.broadcast_senders() {
-                                if peer_id != msg.sender_id {
-                                    let _ = sender.send(forwarded.clone()).await;
+                    if router.should_forward(&msg) && router.plan_relay(&msg, upstream) == RelayPlan::Now {
+                        if let Some(mut forwarded) = router.prepare_forward(&msg) {
+                            // Trace probes collect the relays they pass; the one where the TTL ends answers
+                            if let (MessageType::Trace, Ok(mut probe)) = (msg.msg_type, TracePayload::from_message(&msg)) {
+                                probe.path.push(trace::stamp(our_node_id));
+                                if forwarded.ttl == 0 {
+                                    let reply = TraceReplyPayload { probe_id: probe.probe_id, path: probe.path, reached: false };
+                                    send_routed(&peers, &mut routing_table, &custody_tx, &reply.to_message(our_node_id, msg.sender_id));
+                                    continue;
                                 }
+                                forwarded.payload = bincode::serialize(&probe).unwrap_or_default();
+                            }
+                            let exclude: Vec<[u8; 32]> = std::iter::once(msg.sender_id).chain(upstream).collect();
+                            let sent = fan_out(&peers, &routing_table, &forwarded, &exclude);
+                            for id in &sent {
+                                if let Some(peer) = peers.get_mut(id) {
+                                    peer.link.forwarded += 1;
+                                }
+                            }
+                            // Nowhere to pass it on, or no room on the way: keep a copy until its destination
+                            // reappears. Once it went on, whether along the route or as a flood, the relays
+                            // after us hold it, so every node on the way doesn't store its own copy.
+                            if sent.is_empty() && msg.destination.is_some() && msg.is_storable() {
+                                let _ = custody_tx.send(CustodyJob::Hold(msg.clone()));
                             }
                         }
                     }
@@ -1122,25 +1572,127 @@ pub async fn start_mesh_node(config: NodeConfig) -> Result<(NodeIdentity, NodeHa
                 // Heartbeat
                 // ---------------------------------------------------------------
                 _ = heartbeat.tick() => {
-                    for (_, sender) in peers.broadcast_senders() {
-                        let ping = MeshMessage::new(MessageType::Ping, our_node_id, 1, None, vec![]);
-                        let _ = sender.send(ping).await;
+                    // Timestamped pings; a ping still unanswered from last time counts as lost
+                    for id in peers.peer_ids() {
+                        if let Some(peer) = peers.get_mut(&id) {
+                            let ping = peer.next_ping().to_message(our_node_id);
+                            routing_table.set_link_etx(id, peer.quality.etx);
+                            let _ = peer.sender.try_send(ping);
+                            // Keep UDP paths confirmed (and NAT mappings open)
+                            if let (Some(path), Some(keys)) = (&peer.datagram, &peer.session_keys) {
+                                path.probe(keys, Instant::now());
+                            }
+                        }
                     }
 
+                    // Peers that went quiet, and peers whose links stopped draining
                     let stale = peers.prune_stale(PEER_TIMEOUT);
-                    for id in &stale {
+                    let congested = peers.prune_congested();
+                    for id in &congested {
+                        warn!("Disconnecting {}: its link stayed congested", hex::encode(&id[..4]));
+                    }
+                    for id in stale.iter().chain(&congested) {
                         if known_gateways.remove(id) {
                             let _ = event_tx.send(NodeEvent::GatewayLost { node_id: *id }).await;
                         }
+                        routing_table.remove_via(id);
+                        router.forget_neighbor(id);
                         let _ = event_tx.send(NodeEvent::PeerDisconnected { node_id: *id }).await;
                     }
 
+                    // Destinations that became reachable through the mesh get their held messages
+                    let reachable: Vec<[u8; 32]> = routing_table.destinations().into_iter()
+                        .chain(peers.peer_ids())
+                        .filter(|dest| routing_table.next_hop(dest, |id| peers.contains(id)).is_some())
+                        .collect();
+                    let _ = custody_tx.send(CustodyJob::HandOver(reachable));
+
                     // Update peer count in stats
                     router.stats.total_peers = peers.count() as u32;
 
                     debug!("Heartbeat: {} peers connected, {} msgs seen", peers.count(), router.seen_count());
                 }
 
+                // ---------------------------------------------------------------
+                // Link state: flood our signed neighbor list for the mesh map
+                // ---------------------------------------------------------------
+                _ = topology_timer.tick() => {
+                    topology.expire();
+                    let ls = LinkStatePayload {
+                        issued_ms: chrono::Utc::now().timestamp_millis(),
+                        display_name: our_display_name.clone(),
+                        links: our_links(&peers),
+                    };
+                    send_routed(&peers, &mut routing_table, &custody_tx, &ls.to_message(&signer));
+                }
+
+                // ---------------------------------------------------------------
+                // Messages the file transfer task wants sent
+                // ---------------------------------------------------------------
+                Some(msg) = outbound_rx.recv() => {
+                    send_routed(&peers, &mut routing_table, &custody_tx, &msg);
+                }
+
+                // ---------------------------------------------------------------
+                // Held messages given back by the custody thread
+                // ---------------------------------------------------------------
+                Some(handover) = handover_rx.recv() => {
+                    match handover.peer.and_then(|id| peers.get(&id)) {
+                        Some(peer) => {
+                            for held in handover.msgs {
+                                if peer.sender.try_send(held.clone()).is_err() {
+                                    let _ = custody_tx.send(CustodyJob::Hold(held));
+                                }
+                            }
+                        }
+                        None => {
+                            for held in &handover.msgs {
+                                send_routed(&peers, &mut routing_table, &custody_tx, held);
+                            }
+                        }
+                    }
+                }
+
+                // ---------------------------------------------------------------
+                // Deferred broadcast relays (counter / coverage flooding)
+                // ---------------------------------------------------------------
+                _ = sleep_until_due(relay_due), if relay_due.is_some() => {
+                    let neighbors = peers.peer_ids();
+                    for (forwarded, exclude) in router.due_relays(&neighbors) {
+                        for id in fan_out(&peers, &routing_table, &forwarded, &exclude) {
+                            if let Some(peer) = peers.get_mut(&id) {
+                                peer.link.forwarded += 1;
+                            }
+                        }
+                    }
+                }
+
+                // ---------------------------------------------------------------
+                // Route discovery: widen unanswered requests, then give up
+                // ---------------------------------------------------------------
+                _ = route_timer.tick() => {
+                    for outcome in traces.expire() {
+                        let _ = event_tx.send(trace_event(outcome)).await;
+                    }
+                    for step in routing_table.poll_discoveries(our_node_id) {
+                        match step {
+                            DiscoveryStep::Retry(rreq) => {
+                                send_routed(&peers, &mut routing_table, &custody_tx, &rreq);
+                            }
+                            DiscoveryStep::Failed { target, queued } => {
+                                debug!("No route to {}, flooding {} waiting messages", hex::encode(&target[..4]), queued.len());
+                                // Last resort: relays that predate route discovery may still get them through,
+                                // and we keep custody of chat until the destination turns up
+                                for waiting in queued {
+                                    fan_out(&peers, &routing_table, &waiting, &[]);
+                                    let _ = custody_tx.send(CustodyJob::Hold(waiting));
+                                }
+                                let _ = event_tx.send(NodeEvent::NoRoute { node_id: target }).await;
+                            }
+                        }
+                    }
+                }
+
                 // ---------------------------------------------------------------
                 // Gateway re-check
                 // ---------------------------------------------------------------
@@ -1155,19 +1707,11 @@ pub async fn start_mesh_node(config: NodeConfig) -> Result<(NodeIdentity, NodeHa
                 // Disappearing message cleanup + routing table cleanup
                 // ---------------------------------------------------------------
                 _ = cleanup_timer.tick() => {
-                    if let Some(ref st) = storage {
-                        let deleted = st.delete_expired().unwrap_or(0);
-                        if deleted > 0 {
-                            debug!("Cleaned up {} expired messages", deleted);
-                        }
-                    }
+                    let _ = storage_tx.send(StorageJob::DeleteExpired);
+                    let _ = custody_tx.send(CustodyJob::Expire);
+                    receipted.retain(|_, at| at.elapsed() < 2 * HOLD_EXPIRY);
                     routing_table.cleanup();
-
-                    // Expire old offline queue entries (>1hr)
-                    offline_queue.retain(|_, msgs| {
-                        msgs.retain(|_| true); // Keep for now; could add timestamps
-                        !msgs.is_empty()
-                    });
+                    router.cleanup();
                 }
 
                 // ---------------------------------------------------------------
@@ -1185,3 +1729,825 @@ pub async fn start_mesh_node(config: NodeConfig) -> Result<(NodeIdentity, NodeHa
 
     Ok((identity, handle, event_rx))
 }
+
+/// Links a message should be written to. A directed message goes to its
+/// destination if that is a neighbor, else to the learned next hop; broadcasts,
+/// and directed messages with no usable route, are flooded. Peers in `exclude`
+/// (the origin and the link it arrived on) are never sent to.
+fn route_peers<'a>(
+    peers: &'a PeerManager,
+    routes: &RoutingTable,
+    msg: &MeshMessage,
+    exclude: &[[u8; 32]],
+) -> Vec<&'a PeerState> {
+    if let Some(dest) = msg.destination {
+        let hop = routes.next_hop(&dest, |id| peers.contains(id) && !exclude.contains(id));
+        if let Some(peer) = hop.and_then(|h| peers.get(&h)) {
+            return vec![peer];
+        }
+    }
+    peers.all()
+        .filter(|p| !exclude.contains(&p.node_id))
+        .collect()
+}
+
+/// Queue a message on the links `route_peers` picks, without waiting on
+/// any of them. Returns the peers that took it; a link whose class is full
+/// refuses it and counts the drop.
+fn fan_out(peers: &PeerManager, routes: &RoutingTable, msg: &MeshMessage, exclude: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    route_peers(peers, routes, msg, exclude).into_iter()
+        .filter(|peer| peer.try_send(msg.clone()).is_ok())
+        .map(|peer| peer.node_id)
+        .collect()
+}
+
+/// Tell a neighbor on an IP link where to send us datagrams, once its
+/// session keys are set.
+fn offer_datagram_path(peer: &PeerState, socket: Option<&DatagramSocket>, our_node_id: [u8; 32]) {
+    if let (Some(socket), LinkAddr::Ip(_)) = (socket, &peer.addr) {
+        let offer = DatagramOfferPayload { port: socket.port(), token: peer.datagram_token };
+        let _ = peer.sender.try_send(offer.to_message(our_node_id, peer.node_id));
+    }
+}
+
+/// Send a message we originated, unicast along the routing table when possible.
+/// A directed message with no route is held back while a route request
+/// looks for one, instead of being flooded. One that no link has room for
+/// goes into custody (if storable) and is retried from there.
+fn send_routed(peers: &PeerManager, routes: &mut RoutingTable, custody: &mpsc::UnboundedSender<CustodyJob>, msg: &MeshMessage) {
+    if let Some(dest) = msg.destination {
+        if routes.next_hop(&dest, |id| peers.contains(id)).is_none() {
+            match routes.await_route(msg) {
+                RouteWait::Queued => return,
+                RouteWait::Request(rreq) => {
+                    fan_out(peers, routes, &rreq, &[]);
+                    return;
+                }
+                RouteWait::Full => {}
+            }
+        }
+    }
+    if fan_out(peers, routes, msg, &[]).is_empty() && msg.destination.is_some() && msg.is_storable() {
+        let _ = custody.send(CustodyJob::Hold(msg.clone()));
+    }
+}
+
+/// Our direct links as they appear in our link-state advertisement.
+fn our_links(peers: &PeerManager) -> Vec<LinkInfo> {
+    peers.all().map(|p| LinkInfo { node_id: p.node_id, quality: p.quality.score() }).collect()
+}
+
+/// Average RTT, jitter, loss and ETX over the links to connected peers.
+fn link_averages(peers: &PeerManager) -> (f32, f32, f32, f32) {
+    let count = peers.count().max(1) as f32;
+    let timed: Vec<(f32, f32)> = peers.all()
+        .filter_map(|p| p.quality.rtt_ms.map(|rtt| (rtt, p.quality.jitter_ms)))
+        .collect();
+    let timed_count = timed.len().max(1) as f32;
+    (
+        timed.iter().map(|(rtt, _)| rtt).sum::<f32>() / timed_count,
+        timed.iter().map(|(_, jitter)| jitter).sum::<f32>() / timed_count,
+        peers.all().map(|p| p.quality.loss).sum::<f32>() / count,
+        if peers.count() == 0 { 0.0 } else { peers.all().map(|p| p.quality.etx).sum::<f32>() / count },
+    )
+}
+
+/// Sleep until a deferred relay is due (forever if none is pending).
+async fn sleep_until_due(due: Option<std::time::Instant>) {
+    match due {
+        Some(due) => tokio::time::sleep_until(due.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+fn trace_event(outcome: TraceOutcome) -> NodeEvent {
+    let TraceOutcome { kind, target, reached, hops } = outcome;
+    match kind {
+        TraceKind::Ping => NodeEvent::PingResult { target, reached, hops },
+        TraceKind::Traceroute => NodeEvent::TraceResult { target, reached, hops },
+    }
+}
+
+/// Sequence number carried by route requests and replies for their sender.
+fn origin_seq(msg: &MeshMessage) -> Option<u32> {
+    match msg.msg_type {
+        MessageType::RouteRequest => RouteRequestPayload::from_message(msg).ok().map(|r| r.origin_seq),
+        MessageType::RouteReply => RouteReplyPayload::from_message(msg).ok().map(|r| r.target_seq),
+        _ => None,
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Subsystem tasks
+// ---------------------------------------------------------------------------
+
+/// Database writes and queries, done on a blocking thread beside the routing loop.
+enum StorageJob {
+    JoinGroup(String),
+    LeaveGroup(String),
+    LoadHistory { peer: Option<[u8; 32]>, group: Option<String> },
+    SetNickname { node_id: [u8; 32], nickname: String },
+    SetFavorite { node_id: [u8; 32], favorite: bool },
+    SetSafetyNumber { node_id: [u8; 32], safety: Option<String> },
+    SaveContact(Contact),
+    MarkDelivered(MessageId),
+    DeleteExpired,
+}
+
+/// Run the message database on its own thread. Without a database the jobs
+/// are simply dropped, as they were before it existed.
+fn spawn_storage(storage: Option<MeshStorage>, event_tx: mpsc::Sender<NodeEvent>) -> mpsc::UnboundedSender<StorageJob> {
+    let (job_tx, mut jobs) = mpsc::unbounded_channel();
+    tokio::task::spawn_blocking(move || {
+        while let Some(job) = jobs.blocking_recv() {
+            let Some(ref st) = storage else { continue };
+            match job {
+                StorageJob::JoinGroup(group) => { let _ = st.join_group(&group); }
+                StorageJob::LeaveGroup(group) => { let _ = st.leave_group(&group); }
+                StorageJob::LoadHistory { peer, group } => {
+                    let messages = if let Some(ref g) = group {
+                        st.get_group_history(g, 100).unwrap_or_default()
+                    } else if let Some(ref p) = peer {
+                        st.get_dm_history(p, 100).unwrap_or_default()
+                    } else {
+                        st.get_messages(100, None).unwrap_or_default()
+                    };
+                    let _ = event_tx.blocking_send(NodeEvent::HistoryLoaded { messages });
+                }
+                StorageJob::SetNickname { node_id, nickname } => { let _ = st.set_nickname(&node_id, &nickname); }
+                StorageJob::SetFavorite { node_id, favorite } => { let _ = st.set_favorite(&node_id, favorite); }
+                StorageJob::SetSafetyNumber { node_id, safety } => { let _ = st.set_safety_number(&node_id, safety.as_deref()); }
+                StorageJob::SaveContact(contact) => { let _ = st.save_contact(&contact); }
+                StorageJob::MarkDelivered(msg_id) => { let _ = st.mark_delivered(&msg_id); }
+                StorageJob::DeleteExpired => {
+                    let deleted = st.delete_expired().unwrap_or(0);
+                    if deleted > 0 {
+                        debug!("Cleaned up {} expired messages", deleted);
+                    }
+                }
+            }
+        }
+    });
+    job_tx
+}
+
+/// Store-and-forward work, done on a blocking thread beside the routing loop.
+enum CustodyJob {
+    Hold(MeshMessage),
+    /// A delivery receipt from `by`, which releases the message if it was its destination.
+    Release { msg_id: MessageId, by: [u8; 32] },
+    RecordReceipt(MessageId),
+    /// A peer connected: give back everything held for it.
+    TakeFor([u8; 32]),
+    /// These destinations have a route now: give back what is due for them.
+    HandOver(Vec<[u8; 32]>),
+    Expire,
+}
+
+/// Held messages the custody thread gives back for sending.
+struct Handover {
+    /// Straight onto this peer's link; None to send along the routing table.
+    peer: Option<[u8; 32]>,
+    msgs: Vec<MeshMessage>,
+}
+
+/// Run the DTN store on its own thread. `held` tracks how many messages it holds.
+fn spawn_custody(dtn: DtnStore, held: Arc<AtomicU32>) -> (mpsc::UnboundedSender<CustodyJob>, mpsc::Receiver<Handover>) {
+    let (job_tx, mut jobs) = mpsc::unbounded_channel();
+    let (handover_tx, handover_rx) = mpsc::channel(64);
+    tokio::task::spawn_blocking(move || {
+        while let Some(job) = jobs.blocking_recv() {
+            let handover = match job {
+                CustodyJob::Hold(msg) => {
+                    if dtn.hold(&msg).unwrap_or(false) {
+                        debug!("Holding {} for {}", hex::encode(&msg.msg_id[..4]),
+                            msg.destination.map(|d| hex::encode(&d[..4])).unwrap_or_default());
+                    }
+                    None
+                }
+                CustodyJob::Release { msg_id, by } => {
+                    if dtn.release(&msg_id, &by).unwrap_or(false) {
+                        debug!("Released held message {}", hex::encode(&msg_id[..4]));
+                    }
+                    None
+                }
+                CustodyJob::RecordReceipt(msg_id) => {
+                    let _ = dtn.record_receipt(&msg_id);
+                    None
+                }
+                CustodyJob::TakeFor(peer) => {
+                    let msgs = dtn.take_for(&peer).unwrap_or_default();
+                    if !msgs.is_empty() {
+                        info!("Handing {} held messages to {}", msgs.len(), hex::encode(&peer[..4]));
+                    }
+                    Some(Handover { peer: Some(peer), msgs })
+                }
+                CustodyJob::HandOver(reachable) => {
+                    let msgs = dtn.due_handovers(|dest| reachable.contains(dest)).unwrap_or_default();
+                    Some(Handover { peer: None, msgs })
+                }
+                CustodyJob::Expire => {
+                    let expired = dtn.expire().unwrap_or(0);
+                    if expired > 0 {
+                        debug!("Dropped {} held messages past their expiry", expired);
+                    }
+                    None
+                }
+            };
+            held.store(dtn.held_count().unwrap_or(0) as u32, Ordering::Relaxed);
+            if let Some(handover) = handover.filter(|h| !h.msgs.is_empty()) {
+                if handover_tx.blocking_send(handover).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+    (job_tx, handover_rx)
+}
+
+/// File transfers run on their own thread: offering a file hashes it, and
+/// receiving or serving one writes and reads chunks on disk. The routing
+/// loop passes in file commands and the file messages addressed to us;
+/// whatever the task sends goes back out through the routing loop.
+struct FileTask {
+    file_mgr: FileTransferManager,
+    policy: FileAcceptPolicy,
+    /// Own database connection, for the contact lookups the policy needs.
+    storage: Option<MeshStorage>,
+    our_node_id: [u8; 32],
+    /// Signs our offers, so receivers can trust the sender ID before auto-accepting.
+    signer: NodeIdentity,
+    outbound: mpsc::Sender<MeshMessage>,
+    event_tx: mpsc::Sender<NodeEvent>,
+}
+
+impl FileTask {
+    async fn run(
+        mut self,
+        mut commands: mpsc::UnboundedReceiver<NodeCommand>,
+        mut messages: mpsc::Receiver<(MeshMessage, String)>,
+    ) {
+        let mut file_timer = tokio::time::interval(FILE_RETRANSMIT_CHECK_INTERVAL);
+        loop {
+            tokio::select! {
+                cmd = commands.recv() => match cmd {
+                    Some(cmd) => self.on_command(cmd).await,
+                    None => break,
+                },
+                msg = messages.recv() => match msg {
+                    Some((msg, sender_name)) => self.on_message(msg, sender_name).await,
+                    None => break,
+                },
+                _ = file_timer.tick() => self.on_timer().await,
+            }
+        }
+        debug!("File transfer task ended");
+    }
+
+    /// Send a message through the routing loop. (Borrows only the channel:
+    /// the database connection isn't `Sync`, so `&self` can't cross an await.)
+    fn send(&self, msg: MeshMessage) -> impl std::future::Future<Output = ()> + '_ {
+        let outbound = &self.outbound;
+        async move {
+            let _ = outbound.send(msg).await;
+        }
+    }
+
+    /// Sign a message as ourselves and send it.
+    fn send_signed(&self, mut msg: MeshMessage) -> impl std::future::Future<Output = ()> + '_ {
+        msg.sign(&self.signer);
+        self.send(msg)
+    }
+
+    async fn on_command(&mut self, cmd: NodeCommand) {
+        let our_node_id = self.our_node_id;
+        match cmd {
+            NodeCommand::SendFile { dest, file_path } => {
+                match self.file_mgr.prepare_send(dest, std::path::Path::new(&file_path)) {
+                    Ok(metadata) => {
+                        self.send_signed(MeshMessage::file_offer(our_node_id, dest, &metadata)).await;
+                        info!("File offer sent: {} ({} bytes, {} chunks)",
+                            metadata.filename, metadata.size_bytes, metadata.chunk_count);
+                    }
+                    Err(e) => {
+                        warn!("Failed to prepare file: {}", e);
+                    }
+                }
+            }
+            NodeCommand::SendFileToGroup { group_name, file_path } => {
+                let audience = FileAudience::Group(group_name.clone());
+                match self.file_mgr.prepare_send_to(audience, std::path::Path::new(&file_path)) {
+                    Ok(offer) => {
+                        info!("File offer sent to group {}: {} ({} bytes)",
+                            group_name, offer.filename, offer.size_bytes);
+                        let payload = FileAnnouncePayload { offer, group: Some(group_name) };
+                        self.send_signed(MeshMessage::file_announce(our_node_id, &payload)).await;
+                    }
+                    Err(e) => {
+                        warn!("Failed to prepare file: {}", e);
+                    }
+                }
+            }
+            NodeCommand::SendFileBroadcast { file_path } => {
+                match self.file_mgr.prepare_send_to(FileAudience::Everyone, std::path::Path::new(&file_path)) {
+                    Ok(offer) => {
+                        info!("File offer sent to mesh: {} ({} bytes)", offer.filename, offer.size_bytes);
+                        let payload = FileAnnouncePayload { offer, group: None };
+                        self.send_signed(MeshMessage::file_announce(our_node_id, &payload)).await;
+                    }
+                    Err(e) => {
+                        warn!("Failed to prepare file: {}", e);
+                    }
+                }
+            }
+            NodeCommand::SendBundle { dest, paths } => {
+                let paths: Vec<std::path::PathBuf> = paths.iter().map(std::path::PathBuf::from).collect();
+                match self.file_mgr.prepare_bundle_to(FileAudience::Peer(dest), &paths) {
+                    Ok(metadata) => {
+                        self.send_signed(MeshMessage::file_offer(our_node_id, dest, &metadata)).await;
+                        info!("Bundle offer sent: {} ({} files, {} bytes)",
+                            metadata.filename, metadata.bundle.len(), metadata.size_bytes);
+                    }
+                    Err(e) => {
+                        warn!("Failed to prepare bundle: {}", e);
+                    }
+                }
+            }
+            NodeCommand::AcceptFileEntries { file_id, entries } => {
+                if let Some(sender_id) = self.file_mgr.accept_incoming_entries(&file_id, &entries) {
+                    self.send(MeshMessage::file_accept_entries(our_node_id, sender_id, file_id, entries)).await;
+                    info!("Accepted part of bundle {:?}", hex::encode(file_id));
+                }
+            }
+            NodeCommand::AcceptFile { file_id } => {
+                if let Some(sender_id) = self.file_mgr.accept_incoming(&file_id) {
+                    self.send(MeshMessage::file_accept(our_node_id, sender_id, file_id)).await;
+                    info!("Accepted file transfer {:?}", hex::encode(file_id));
+                }
+            }
+            NodeCommand::DeclineFile { file_id } => {
+                if let Some(sender_id) = self.file_mgr.decline_incoming(&file_id) {
+                    self.send(MeshMessage::file_decline(our_node_id, sender_id, file_id)).await;
+                    info!("Declined file transfer {:?}", hex::encode(file_id));
+                }
+            }
+            NodeCommand::SetFilePolicy { policy } => {
+                info!("File auto-accept policy updated");
+                self.policy = policy;
+            }
+            NodeCommand::FetchFile { content_hash } if self.file_mgr.want_file(content_hash) => {
+                self.send(MeshMessage::swarm_query(our_node_id, content_hash)).await;
+                info!("Looking for file {} in the swarm", hex::encode(content_hash));
+            }
+            NodeCommand::CancelFile { file_id } => {
+                if let Some(audience) = self.file_mgr.cancel_transfer(&file_id) {
+                    self.send(MeshMessage::file_cancel(our_node_id, audience.destination(), file_id, "Cancelled by peer")).await;
+                    info!("Cancelled file transfer {:?}", hex::encode(file_id));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    async fn on_message(&mut self, msg: MeshMessage, sender_name: String) {
+        let our_node_id = self.our_node_id;
+        match msg.msg_type {
+            MessageType::FileOffer => {
+                if let Ok(offer) = FileOfferPayload::from_message(&msg) {
+                    self.handle_offer(msg.sender_id, sender_name, offer, None, msg.verify_signature()).await;
+                }
+            }
+            MessageType::FileAnnounce => {
+                if let Ok(announce) = FileAnnouncePayload::from_message(&msg) {
+                    let audience = announce.group.map(FileAudience::Group).unwrap_or(FileAudience::Everyone);
+                    self.handle_offer(msg.sender_id, sender_name, announce.offer, Some(audience), msg.verify_signature()).await;
+                }
+            }
+            MessageType::FileAccept => {
+                if let Ok(accept) = FileAcceptPayload::from_message(&msg) {
+                    if self.file_mgr.mark_accepted_entries(&accept.file_id, msg.sender_id, &accept.entries) {
+                        // Open the send window; further chunks go out as acks arrive
+                        self.send_window(&accept.file_id).await;
+                    }
+                }
+            }
+            MessageType::FileChunkAck => {
+                if let Ok(ack) = bincode::deserialize::<FileChunkAckPayload>(&msg.payload) {
+                    if let Some(progress) = self.file_mgr.ack_chunk(&ack.file_id, &msg.sender_id, ack.sequence) {
+                        let _ = self.event_tx.send(NodeEvent::FileProgress {
+                            file_id: ack.file_id,
+                            pct: progress.pct,
+                            bytes_per_sec: progress.bytes_per_sec,
+                            eta_secs: progress.eta_secs,
+                        }).await;
+                        let direct = self.file_mgr.outgoing_audience(&ack.file_id)
+                            .map(|a| a.destination().is_some())
+                            .unwrap_or(false);
+                        if self.file_mgr.is_outgoing_acked(&ack.file_id) {
+                            // Group/mesh-wide offers stay open for late acceptors
+                            if direct {
+                                self.file_mgr.remove_outgoing(&ack.file_id);
+                            }
+                            info!("File transfer complete (sender side)");
+                        } else {
+                            self.send_window(&ack.file_id).await;
+                        }
+                    }
+                }
+            }
+            MessageType::FileDecline => {
+                if let Ok(decline) = bincode::deserialize::<FileControlPayload>(&msg.payload) {
+                    if self.file_mgr.handle_remote_decline(&decline.file_id, &msg.sender_id) {
+                        info!("File offer {:?} declined", hex::encode(decline.file_id));
+                        let _ = self.event_tx.send(NodeEvent::FileDeclined {
+                            file_id: decline.file_id,
+                            by_peer: msg.sender_id,
+                        }).await;
+                    }
+                }
+            }
+            MessageType::FileCancel => {
+                if let Ok(cancel) = bincode::deserialize::<FileControlPayload>(&msg.payload) {
+                    if self.file_mgr.handle_remote_cancel(&cancel.file_id, &msg.sender_id) {
+                        info!("File transfer {:?} cancelled: {}", hex::encode(cancel.file_id), cancel.reason);
+                        let _ = self.event_tx.send(NodeEvent::FileCancelled {
+                            file_id: cancel.file_id,
+                            by_peer: msg.sender_id,
+                            reason: cancel.reason,
+                        }).await;
+                    }
+                }
+            }
+            MessageType::FileChunk => {
+                if let Ok(chunk) = bincode::deserialize::<FileChunkPayload>(&msg.payload) {
+                    if let Some(pct) = self.file_mgr.receive_chunk(&chunk.file_id, chunk.sequence, chunk.data) {
+                        self.send(MeshMessage::file_chunk_ack(our_node_id, msg.sender_id, chunk.file_id, chunk.sequence)).await;
+                        self.report_incoming_chunk(&chunk.file_id, pct, Some(msg.sender_id)).await;
+                    }
+                }
+            }
+            MessageType::SwarmQuery => {
+                if let Ok(query) = bincode::deserialize::<SwarmQueryPayload>(&msg.payload) {
+                    if let Some(offer) = self.file_mgr.shared_offer(&query.content_hash, &msg.sender_id) {
+                        self.send(MeshMessage::swarm_have(our_node_id, msg.sender_id, &offer)).await;
+                    }
+                }
+            }
+            MessageType::SwarmHave => {
+                if let Ok(have) = SwarmHavePayload::from_message(&msg) {
+                    let content_hash = have.offer.sha256_hash;
+                    if let Some(file_id) = self.file_mgr.handle_swarm_have(msg.sender_id, have.offer) {
+                        if let Some(offer) = self.file_mgr.get_incoming_metadata(&file_id) {
+                            info!("Fetching {} from the swarm", offer.filename);
+                            let _ = self.event_tx.send(NodeEvent::FileFetchStarted {
+                                file_id,
+                                content_hash,
+                                filename: offer.filename.clone(),
+                                size: offer.size_bytes,
+                            }).await;
+                        }
+                    }
+                    self.send_swarm_requests().await;
+                }
+            }
+            MessageType::ChunkRequest => {
+                if let Ok(req) = bincode::deserialize::<ChunkRequestPayload>(&msg.payload) {
+                    for seq in req.sequences.into_iter().take(MAX_CHUNKS_PER_REQUEST) {
+                        if let Some(data) = self.file_mgr.read_shared_chunk(&req.content_hash, seq, &msg.sender_id) {
+                            let payload = ChunkDataPayload { content_hash: req.content_hash, sequence: seq, data };
+                            self.send(MeshMessage::chunk_data(our_node_id, msg.sender_id, &payload)).await;
+                        }
+                    }
+                }
+            }
+            MessageType::ChunkData => {
+                if let Ok(chunk) = bincode::deserialize::<ChunkDataPayload>(&msg.payload) {
+                    if let Some((file_id, pct)) = self.file_mgr.receive_swarm_chunk(&chunk.content_hash, chunk.sequence, chunk.data) {
+                        self.report_incoming_chunk(&file_id, pct, None).await;
+                        self.send_swarm_requests().await;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Resend chunks whose acks timed out, and keep swarm downloads asking.
+    async fn on_timer(&mut self) {
+        for expired in self.file_mgr.expire_stale(OFFER_TIMEOUT, STALL_TIMEOUT) {
+            warn!("File transfer {:?} failed: {}", hex::encode(expired.file_id), expired.reason);
+            self.send(MeshMessage::file_cancel(self.our_node_id, expired.audience.destination(), expired.file_id, &expired.reason)).await;
+            let _ = self.event_tx.send(NodeEvent::FileFailed {
+                file_id: expired.file_id,
+                reason: expired.reason,
+            }).await;
+        }
+        for file_id in self.file_mgr.active_outgoing() {
+            self.send_window(&file_id).await;
+        }
+        for content_hash in self.file_mgr.swarm_queries_due() {
+            self.send(MeshMessage::swarm_query(self.our_node_id, content_hash)).await;
+        }
+        self.send_swarm_requests().await;
+    }
+
+    /// Send as many chunks of an outgoing transfer as its window allows.
+    async fn send_window(&mut self, file_id: &[u8; 16]) {
+        let dest = match self.file_mgr.outgoing_audience(file_id) {
+            Some(audience) => audience.destination(),
+            None => return,
+        };
+        while let Some(chunk_payload) = self.file_mgr.next_chunk(file_id) {
+            self.send(MeshMessage::file_chunk(self.our_node_id, dest, &chunk_payload)).await;
+        }
+    }
+
+    /// Request missing chunks of swarm downloads from the holders we know about.
+    async fn send_swarm_requests(&mut self) {
+        for (holder, content_hash, sequences) in self.file_mgr.swarm_requests() {
+            self.send(MeshMessage::chunk_request(self.our_node_id, holder, content_hash, sequences)).await;
+        }
+    }
+
+    /// Report progress for a received chunk and, once every chunk is in, save the file.
+    /// On failure the sender (if any) is told with a FileCancel.
+    async fn report_incoming_chunk(&mut self, file_id: &[u8; 16], pct: u8, sender_id: Option<[u8; 32]>) {
+        let progress = self.file_mgr.incoming_progress(file_id);
+        let _ = self.event_tx.send(NodeEvent::FileProgress {
+            file_id: *file_id,
+            pct,
+            bytes_per_sec: progress.map(|p| p.bytes_per_sec).unwrap_or(0),
+            eta_secs: progress.and_then(|p| p.eta_secs),
+        }).await;
+
+        if !self.file_mgr.is_incoming_complete(file_id) {
+            return;
+        }
+        match self.file_mgr.finalize_incoming(file_id) {
+            Ok(path) => {
+                let _ = self.event_tx.send(NodeEvent::FileComplete {
+                    file_id: *file_id,
+                    path: path.to_string_lossy().to_string(),
+                }).await;
+                info!("File received: {}", path.display());
+            }
+            Err(e) => {
+                warn!("File finalization failed: {}", e);
+                if let Some(dest) = sender_id {
+                    self.send(MeshMessage::file_cancel(self.our_node_id, Some(dest), *file_id, &e)).await;
+                }
+                let _ = self.event_tx.send(NodeEvent::FileFailed {
+                    file_id: *file_id,
+                    reason: e,
+                }).await;
+            }
+        }
+    }
+
+    /// Look up whether a sender is a favorite or verified contact, for the file policy.
+    fn sender_trust(&self, sender_id: [u8; 32], name: String) -> SenderTrust {
+        let contact = self.storage.as_ref().and_then(|st| st.get_contact(&sender_id).ok().flatten());
+        let safety = NodeIdentity::safety_number(&self.our_node_id, &sender_id);
+        SenderTrust {
+            name,
+            favorite: contact.as_ref().is_some_and(|c| c.is_favorite),
+            verified: contact.as_ref().is_some_and(|c| c.safety_number.as_deref() == Some(safety.as_str())),
+        }
+    }
+
+    /// Register an incoming file offer and apply the auto-accept policy: accept or
+    /// decline it on the user's behalf, or raise FileOffered so they can decide.
+    /// `share_with` is the audience of a group or mesh-wide offer, which we
+    /// serve the finished file to as well. Favorites and verified contacts are
+    /// only trusted when the offer's signature proves it came from them.
+    async fn handle_offer(&mut self, sender_id: [u8; 32], sender_name: String, offer: FileOfferPayload, share_with: Option<FileAudience>, signed: bool) {
+        let trust = if signed {
+            self.sender_trust(sender_id, sender_name.clone())
+        } else {
+            SenderTrust { name: sender_name.clone(), ..Default::default() }
+        };
+        let file_id = offer.file_id;
+        if !self.file_mgr.register_incoming_shared(offer, sender_id, share_with) {
+            warn!("Ignoring file offer from {} whose sizes don't add up", sender_name);
+            return;
+        }
+        let Some(offer) = self.file_mgr.get_incoming_metadata(&file_id).cloned() else {
+            return;
+        };
+        let our_node_id = self.our_node_id;
+        match self.policy.evaluate(&offer, &trust, free_disk_space(self.file_mgr.incoming_dir())) {
+            PolicyDecision::Accept(reason) => {
+                if self.file_mgr.accept_incoming(&file_id).is_some() {
+                    info!("Auto-accepted {}: {}", offer.filename, reason);
+                    self.send(MeshMessage::file_accept(our_node_id, sender_id, file_id)).await;
+                    let _ = self.event_tx.send(NodeEvent::FileAutoAccepted {
+                        sender_id,
+                        sender_name,
+                        file_id,
+                        filename: offer.filename,
+                        size: offer.size_bytes,
+                        reason,
+                    }).await;
+                }
+            }
+            PolicyDecision::Reject(reason) => {
+                if self.file_mgr.decline_incoming(&file_id).is_some() {
+                    info!("Auto-rejected {}: {}", offer.filename, reason);
+                    self.send(MeshMessage::file_decline(our_node_id, sender_id, file_id)).await;
+                    let _ = self.event_tx.send(NodeEvent::FileAutoRejected {
+                        sender_id,
+                        sender_name,
+                        file_id,
+                        filename: offer.filename,
+                        reason,
+                    }).await;
+                }
+            }
+            PolicyDecision::Ask if !offer.bundle.is_empty() => {
+                let _ = self.event_tx.send(NodeEvent::BundleOffered {
+                    sender_id,
+                    sender_name,
+                    file_id,
+                    name: offer.filename,
+                    size: offer.size_bytes,
+                    entries: offer.bundle,
+                }).await;
+            }
+            PolicyDecision::Ask => {
+                let _ = self.event_tx.send(NodeEvent::FileOffered {
+                    sender_id,
+                    sender_name,
+                    file_id,
+                    filename: offer.filename,
+                    size: offer.size_bytes,
+                    preview: offer.preview,
+                }).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::LoopbackTransport;
+
+    /// Start a node on `transport` that keeps its files under `root/name`.
+    async fn loopback_node(root: &std::path::Path, name: &str, transport: LoopbackTransport) -> (NodeIdentity, NodeHandle, mpsc::Receiver<NodeEvent>) {
+        let dir = root.join(name);
+        std::fs::create_dir_all(&dir).unwrap();
+        start_mesh_node(NodeConfig {
+            display_name: name.into(),
+            key_path: dir.join("mesh_identity.key"),
+            data_dir: Some(dir),
+            transports: vec![Arc::new(transport)],
+            ..Default::default()
+        }).await.unwrap()
+    }
+
+    async fn next_event(events: &mut mpsc::Receiver<NodeEvent>, want: impl Fn(&NodeEvent) -> bool) -> NodeEvent {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let event = events.recv().await.expect("node stopped");
+                if want(&event) {
+                    return event;
+                }
+            }
+        }).await.expect("timed out waiting for node event")
+    }
+
+    #[tokio::test]
+    async fn test_nodes_talk_over_loopback_transport() {
+        let root = std::env::temp_dir().join(format!("mesh_test_node_{}", rand::random::<u32>()));
+        let (end_a, end_b) = LoopbackTransport::pair();
+        let (alice, alice_handle, mut alice_events) = loopback_node(&root, "alice", end_a).await;
+        let (bob, bob_handle, mut bob_events) = loopback_node(&root, "bob", end_b).await;
+
+        // Neither side dialed; the key exchange on the link pairs them up
+        next_event(&mut alice_events, |e| matches!(e, NodeEvent::PeerConnected { node_id, .. } if *node_id == bob.node_id)).await;
+        next_event(&mut bob_events, |e| matches!(e, NodeEvent::PeerConnected { node_id, .. } if *node_id == alice.node_id)).await;
+
+        alice_handle.send_direct(bob.node_id, "over the loop").await.unwrap();
+        let got = next_event(&mut bob_events, |e| matches!(e, NodeEvent::MessageReceived { .. })).await;
+        let NodeEvent::MessageReceived { sender_id, content, .. } = got else { unreachable!() };
+        assert_eq!(sender_id, alice.node_id);
+        assert_eq!(content, "over the loop");
+
+        alice_handle.shutdown().await.unwrap();
+        bob_handle.shutdown().await.unwrap();
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[tokio::test]
+    async fn test_datagram_offer_only_over_the_neighbors_link() {
+        use crate::transport::{read_message, write_message};
+        use tokio::net::{TcpStream, UdpSocket};
+
+        let dir = std::env::temp_dir().join(format!("mesh_test_node_offer_{}", rand::random::<u32>()));
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let (node, handle, mut events) = start_mesh_node(NodeConfig {
+            key_path: dir.join("mesh_identity.key"),
+            data_dir: Some(dir.clone()),
+            transports: vec![Arc::new(TcpTransport::new(port))],
+            ..Default::default()
+        }).await.unwrap();
+
+        let neighbor = [7u8; 32];
+        let kx = || KeyExchangePayload { x25519_public: [1u8; 32] }.to_message(neighbor, [0u8; 32]);
+        let offer = |udp: &UdpSocket| DatagramOfferPayload { port: udp.local_addr().unwrap().port(), token: [5u8; 8] }
+            .to_message(neighbor, node.node_id);
+        let probed = |udp: UdpSocket| async move {
+            let mut buf = [0u8; 2048];
+            tokio::time::timeout(Duration::from_secs(1), udp.recv_from(&mut buf)).await.is_ok()
+        };
+
+        let mut link = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        write_message(&mut link, &kx()).await.unwrap();
+        next_event(&mut events, |e| matches!(e, NodeEvent::PeerConnected { node_id, .. } if *node_id == neighbor)).await;
+        while read_message(&mut link).await.unwrap().unwrap().msg_type != MessageType::KeyExchange {}
+
+        // A second link claiming the same neighbor can't point its audio elsewhere
+        let hijack = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut impostor = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        write_message(&mut impostor, &kx()).await.unwrap();
+        write_message(&mut impostor, &offer(&hijack)).await.unwrap();
+        assert!(!probed(hijack).await);
+
+        let real = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        write_message(&mut link, &offer(&real)).await.unwrap();
+        assert!(probed(real).await);
+
+        handle.shutdown().await.unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_transport_identity_must_be_ours() {
+        let dir = std::env::temp_dir().join(format!("mesh_test_node_identity_{}", rand::random::<u32>()));
+        let someone_else = NodeIdentity::generate("someone else".into());
+        let quic = crate::quic::QuicTransport::new(&someone_else, 0).unwrap();
+        let started = start_mesh_node(NodeConfig {
+            key_path: dir.join("mesh_identity.key"),
+            data_dir: Some(dir.clone()),
+            transports: vec![Arc::new(quic)],
+            ..Default::default()
+        }).await;
+        assert!(started.is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_auto_accept_needs_a_signed_offer() {
+        let dir = std::env::temp_dir().join(format!("mesh_test_offer_trust_{}", rand::random::<u32>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let me = NodeIdentity::generate("me".into());
+        let friend = NodeIdentity::generate("friend".into());
+        let storage = MeshStorage::open(&dir).unwrap();
+        storage.save_contact(&Contact {
+            node_id: friend.node_id,
+            display_name: "friend".into(),
+            nickname: None,
+            bio: String::new(),
+            first_seen: 0,
+            last_seen: 0,
+            is_favorite: false,
+            safety_number: None,
+        }).unwrap();
+        storage.set_favorite(&friend.node_id, true).unwrap();
+
+        let (outbound, _outbound_rx) = mpsc::channel(16);
+        let (event_tx, mut events) = mpsc::channel(16);
+        let mut files = FileTask {
+            file_mgr: FileTransferManager::new(dir.join("received")),
+            policy: FileAcceptPolicy { accept_favorites: true, ..Default::default() },
+            storage: Some(storage),
+            our_node_id: me.node_id,
+            signer: me.clone(),
+            outbound,
+            event_tx,
+        };
+        let offer = |id: u8| FileOfferPayload {
+            file_id: [id; 16],
+            filename: "notes.txt".into(),
+            size_bytes: 10,
+            chunk_count: 1,
+            sha256_hash: [0u8; 32],
+            chunk_hashes: vec![[0u8; 32]],
+            preview: None,
+            bundle: Vec::new(),
+        };
+
+        // Anyone can put a favorite's ID on an unsigned offer; the user decides
+        let forged = MeshMessage::file_offer(friend.node_id, me.node_id, &offer(1));
+        files.on_message(forged, "friend".into()).await;
+        assert!(matches!(events.recv().await, Some(NodeEvent::FileOffered { file_id, .. }) if file_id == [1; 16]));
+
+        let mut signed = MeshMessage::file_offer(friend.node_id, me.node_id, &offer(2));
+        signed.sign(&friend);
+        files.on_message(signed, "friend".into()).await;
+        assert!(matches!(events.recv().await, Some(NodeEvent::FileAutoAccepted { file_id, .. }) if file_id == [2; 16]));
+
+        drop(files);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
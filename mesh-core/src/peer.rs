@@ -1,33 +1,123 @@
 use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::time::Instant;
-use tokio::sync::mpsc;
+use std::time::{Duration, Instant};
 
 use crate::crypto::SessionKeys;
-use crate::message::MeshMessage;
+use crate::datagram::DatagramPath;
+use crate::message::{MeshMessage, PingPayload};
+use crate::queue::{LinkSender, TrySendError};
+use crate::transport::{LinkAddr, CONGESTION_TIMEOUT, MAX_FRAME};
+
+/// Traffic counters for the link to a directly connected peer.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LinkStats {
+    /// Routed messages that arrived over this link.
+    pub received: u64,
+    /// Of those, how many we had already seen via another path.
+    pub duplicates: u64,
+    /// Messages we relayed onto this link on behalf of others.
+    pub forwarded: u64,
+    /// Messages the link's queue dropped because their traffic class was full.
+    pub dropped: u64,
+}
+
+/// Weight of the latest heartbeat in the smoothed loss ratio.
+const LOSS_ALPHA: f32 = 0.2;
+/// RTT and jitter smoothing gains (RFC 6298).
+const RTT_ALPHA: f32 = 0.125;
+const JITTER_BETA: f32 = 0.25;
+/// ETX of a link that loses nearly everything; keeps costs finite.
+pub const MAX_ETX: f32 = 10.0;
+
+/// Measured quality of the link to a directly connected peer, from
+/// timestamped heartbeat pings and the pongs that answer them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinkQuality {
+    /// Smoothed round-trip time; None until a timed pong has come back.
+    pub rtt_ms: Option<f32>,
+    /// Smoothed deviation of the round-trip time.
+    pub jitter_ms: f32,
+    /// Smoothed share of pings that went unanswered, 0.0 to 1.0.
+    pub loss: f32,
+    /// Expected transmissions per successful delivery (1.0 = lossless).
+    /// Pings measure both directions at once, so this is 1 / (1 - loss).
+    pub etx: f32,
+}
+
+impl Default for LinkQuality {
+    fn default() -> Self {
+        Self { rtt_ms: None, jitter_ms: 0.0, loss: 0.0, etx: 1.0 }
+    }
+}
+
+impl LinkQuality {
+    /// Link quality on a 0 (unusable) to 255 (perfect) scale, as advertised in link state.
+    pub fn score(&self) -> u8 {
+        (255.0 / self.etx.max(1.0)).round() as u8
+    }
+
+    fn ping_lost(&mut self) {
+        self.loss = self.loss * (1.0 - LOSS_ALPHA) + LOSS_ALPHA;
+        self.update_etx();
+    }
+
+    fn pong_received(&mut self, rtt_ms: Option<f32>) {
+        self.loss *= 1.0 - LOSS_ALPHA;
+        self.update_etx();
+        if let Some(sample) = rtt_ms {
+            match self.rtt_ms {
+                None => {
+                    self.rtt_ms = Some(sample);
+                    self.jitter_ms = sample / 2.0;
+                }
+                Some(srtt) => {
+                    self.jitter_ms = (1.0 - JITTER_BETA) * self.jitter_ms + JITTER_BETA * (srtt - sample).abs();
+                    self.rtt_ms = Some((1.0 - RTT_ALPHA) * srtt + RTT_ALPHA * sample);
+                }
+            }
+        }
+    }
+
+    fn update_etx(&mut self) {
+        self.etx = (1.0 / (1.0 - self.loss).max(f32::EPSILON)).min(MAX_ETX);
+    }
+}
 
 /// State of a connected peer.
 #[derive(Debug)]
 pub struct PeerState {
     pub node_id: [u8; 32],
     pub display_name: String,
-    pub addr: SocketAddr,
+    pub addr: LinkAddr,
     pub last_seen: Instant,
     pub session_keys: Option<SessionKeys>,
-    /// Channel to send messages to this peer's TCP write task.
-    pub sender: mpsc::Sender<MeshMessage>,
+    /// Priority queue feeding this peer's link write task.
+    pub sender: LinkSender,
+    /// Token that datagrams from this peer start with.
+    pub datagram_token: [u8; 8],
+    /// UDP path for loss-tolerant traffic, once the peer has offered one.
+    pub datagram: Option<DatagramPath>,
+    /// How long the link may stay congested before the peer is cut off,
+    /// as its transport allows; None to never cut it off for that.
+    pub congestion_timeout: Option<Duration>,
+    /// Largest encoded message the link's transport carries.
+    pub mtu: usize,
     // Profile fields
     pub bio: String,
     pub capabilities: Vec<String>,
     pub is_gateway: bool,
+    pub link: LinkStats,
+    pub quality: LinkQuality,
+    ping_seq: u32,
+    /// Sequence number of the heartbeat ping still waiting for its pong.
+    awaiting_pong: Option<u32>,
 }
 
 impl PeerState {
     pub fn new(
         node_id: [u8; 32],
         display_name: String,
-        addr: SocketAddr,
-        sender: mpsc::Sender<MeshMessage>,
+        addr: LinkAddr,
+        sender: LinkSender,
     ) -> Self {
         Self {
             node_id,
@@ -36,10 +126,61 @@ impl PeerState {
             last_seen: Instant::now(),
             session_keys: None,
             sender,
+            datagram_token: rand::random(),
+            datagram: None,
+            congestion_timeout: Some(CONGESTION_TIMEOUT),
+            mtu: MAX_FRAME,
             bio: String::new(),
             capabilities: Vec::new(),
             is_gateway: false,
+            link: LinkStats::default(),
+            quality: LinkQuality::default(),
+            ping_seq: 0,
+            awaiting_pong: None,
+        }
+    }
+
+    /// Queue a message for this peer. Loss-tolerant traffic goes over the
+    /// UDP path while it is up, and over the link otherwise. A message the
+    /// link can't carry is refused here rather than dropped by its writer.
+    pub fn try_send(&self, msg: MeshMessage) -> Result<(), TrySendError> {
+        if msg.is_loss_tolerant() {
+            if let (Some(path), Some(keys)) = (&self.datagram, &self.session_keys) {
+                if path.is_up(Instant::now()) && path.send_message(keys, &msg) {
+                    return Ok(());
+                }
+            }
+        }
+        if bincode::serialized_size(&msg).map_or(true, |len| len > self.mtu as u64) {
+            return Err(TrySendError::TooLarge);
         }
+        self.sender.try_send(msg)
+    }
+
+    /// Stamp the next heartbeat ping. If the previous one was never
+    /// answered, it counts as lost.
+    pub fn next_ping(&mut self) -> PingPayload {
+        if self.awaiting_pong.is_some() {
+            self.quality.ping_lost();
+        }
+        self.ping_seq = self.ping_seq.wrapping_add(1);
+        self.awaiting_pong = Some(self.ping_seq);
+        PingPayload { seq: self.ping_seq, sent_us: chrono::Utc::now().timestamp_micros() }
+    }
+
+    /// A pong came back. `echo` is the ping it answers (None from older
+    /// nodes, which answer with an empty pong that can't be timed).
+    pub fn pong_received(&mut self, echo: Option<PingPayload>) {
+        match (self.awaiting_pong, echo) {
+            (Some(seq), Some(ping)) if ping.seq == seq => {
+                let rtt_us = chrono::Utc::now().timestamp_micros() - ping.sent_us;
+                self.quality.pong_received(Some(rtt_us.max(0) as f32 / 1000.0));
+            }
+            (Some(_), None) => self.quality.pong_received(None),
+            // Answers an older ping that was already counted as lost
+            _ => return,
+        }
+        self.awaiting_pong = None;
     }
 
     pub fn touch(&mut self) {
@@ -60,6 +201,12 @@ pub struct PeerManager {
     peers: HashMap<[u8; 32], PeerState>,
 }
 
+impl Default for PeerManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PeerManager {
     pub fn new() -> Self {
         Self {
@@ -113,11 +260,23 @@ impl PeerManager {
         stale
     }
 
-    /// Get senders for all peers (for broadcasting).
-    pub fn broadcast_senders(&self) -> Vec<([u8; 32], mpsc::Sender<MeshMessage>)> {
-        self.peers.iter()
-            .map(|(id, p)| (*id, p.sender.clone()))
-            .collect()
+    /// Remove peers whose link has stayed congested for its timeout, and
+    /// close those links (a peer that stopped reading can block its writer).
+    pub fn prune_congested(&mut self) -> Vec<[u8; 32]> {
+        let congested: Vec<[u8; 32]> = self.peers.iter()
+            .filter(|(_, p)| {
+                let congested_for = p.sender.congested_for();
+                p.congestion_timeout.zip(congested_for).is_some_and(|(timeout, d)| d >= timeout)
+            })
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in &congested {
+            if let Some(peer) = self.remove(id) {
+                peer.sender.close();
+            }
+        }
+        congested
     }
 
     /// Get a list of all peer IDs.
@@ -125,3 +284,124 @@ impl PeerManager {
         self.peers.keys().copied().collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::link_queue;
+
+    fn peer() -> PeerState {
+        let (tx, _rx) = link_queue();
+        PeerState::new([2u8; 32], "Peer".into(), LinkAddr::Ip("127.0.0.1:7332".parse().unwrap()), tx)
+    }
+
+    #[test]
+    fn test_link_quality_from_heartbeats() {
+        let mut p = peer();
+        assert_eq!(p.quality.etx, 1.0);
+        assert_eq!(p.quality.score(), 255);
+
+        // Answered pings time the round trip
+        let ping = p.next_ping();
+        p.pong_received(Some(PingPayload { sent_us: ping.sent_us - 40_000, ..ping }));
+        let rtt = p.quality.rtt_ms.unwrap();
+        assert!((40.0..100.0).contains(&rtt), "rtt {}", rtt);
+        assert_eq!(p.quality.loss, 0.0);
+
+        // Every second ping lost: loss settles near half, ETX near two
+        for _ in 0..40 {
+            p.next_ping();
+            let ping = p.next_ping();
+            p.pong_received(Some(ping));
+        }
+        assert!((0.4..0.6).contains(&p.quality.loss), "loss {}", p.quality.loss);
+        assert!((1.6..2.4).contains(&p.quality.etx), "etx {}", p.quality.etx);
+        assert!(p.quality.jitter_ms > 0.0);
+
+        // A late pong for a ping already counted as lost changes nothing
+        let stale = p.next_ping();
+        p.next_ping();
+        let before = p.quality;
+        p.pong_received(Some(stale));
+        assert_eq!(p.quality, before);
+
+        // Untimed pongs from older nodes still count as answered
+        p.pong_received(None);
+        assert!(p.quality.loss < before.loss);
+        assert_eq!(p.quality.rtt_ms, before.rtt_ms);
+    }
+
+    #[test]
+    fn test_congested_peers_are_cut_off() {
+        let (link, _writer) = link_queue();
+        let mut peers = PeerManager::new();
+        peers.add(PeerState::new([2u8; 32], "Peer".into(), LinkAddr::Ip("127.0.0.1:7332".parse().unwrap()), link.clone()));
+        let chunk = || crate::message::MeshMessage::new(crate::message::MessageType::FileChunk, [1u8; 32], 5, None, vec![]);
+        while link.try_send(chunk()).is_ok() {}
+        assert!(peers.prune_congested().is_empty());
+
+        // A bearer that never gives up on a backlog keeps the peer
+        peers.get_mut(&[2u8; 32]).unwrap().congestion_timeout = None;
+        assert!(peers.prune_congested().is_empty());
+
+        peers.get_mut(&[2u8; 32]).unwrap().congestion_timeout = Some(Duration::ZERO);
+        assert_eq!(peers.prune_congested(), vec![[2u8; 32]]);
+        assert_eq!(peers.count(), 0);
+        assert_eq!(link.try_send(chunk()), Err(crate::queue::TrySendError::Closed));
+    }
+
+    #[tokio::test]
+    async fn test_voice_takes_udp_path_only_while_it_is_up() {
+        use crate::crypto::{generate_x25519_keypair, SessionKeys};
+        use crate::datagram::{Datagram, DatagramSocket};
+        use crate::message::{MessageType, VoiceStreamPayload};
+
+        let (_shutdown_tx, shutdown) = tokio::sync::watch::channel(false);
+        let (ours, _) = DatagramSocket::bind(0, shutdown.clone()).await.unwrap();
+        let (theirs, mut their_rx) = DatagramSocket::bind(0, shutdown).await.unwrap();
+        let (secret, public) = generate_x25519_keypair();
+        let keys = SessionKeys::from_exchange(&secret, &public.to_bytes());
+
+        let (link, mut writer) = link_queue();
+        let mut p = PeerState::new([2u8; 32], "Peer".into(), LinkAddr::Ip("127.0.0.1:7332".parse().unwrap()), link);
+        p.session_keys = Some(keys.clone());
+        let remote: std::net::SocketAddr = ([127, 0, 0, 1], theirs.port()).into();
+        p.datagram = Some(DatagramPath::new(ours, remote, [5u8; 8]));
+        let frame = VoiceStreamPayload { stream_id: [1u8; 16], sequence: 0, audio_frame: vec![0u8; 320] };
+        let voice = || MeshMessage::voice_stream([1u8; 32], [2u8; 32], &frame);
+
+        // Unconfirmed (UDP blocked, or not probed yet): the link carries it
+        p.try_send(voice()).unwrap();
+        assert_eq!(writer.recv().await.unwrap().msg_type, MessageType::VoiceStream);
+
+        let now = Instant::now();
+        p.datagram.as_mut().unwrap().receive(&keys, remote, 1, Datagram::Probe { heard: true }, now);
+        p.try_send(voice()).unwrap();
+        let (_, data) = tokio::time::timeout(std::time::Duration::from_secs(2), their_rx.recv()).await.unwrap().unwrap();
+        assert!(matches!(crate::datagram::open(&keys, &data).unwrap().1, Datagram::Message(m) if m.msg_type == MessageType::VoiceStream));
+
+        // Everything else stays on the link
+        p.try_send(MeshMessage::text_to([1u8; 32], [2u8; 32], "hi")).unwrap();
+        assert_eq!(writer.recv().await.unwrap().msg_type, MessageType::Text);
+    }
+
+    #[test]
+    fn test_messages_larger_than_the_link_are_refused() {
+        let (link, _writer) = link_queue();
+        let mut p = PeerState::new([2u8; 32], "Peer".into(), LinkAddr::Ip("127.0.0.1:7332".parse().unwrap()), link);
+        p.mtu = 200;
+        let sized = |len| MeshMessage::text_to([1u8; 32], [2u8; 32], &"x".repeat(len));
+        assert_eq!(p.try_send(sized(1000)), Err(TrySendError::TooLarge));
+        assert!(p.try_send(sized(10)).is_ok());
+    }
+
+    #[test]
+    fn test_dead_link_etx_is_capped() {
+        let mut p = peer();
+        for _ in 0..100 {
+            p.next_ping();
+        }
+        assert_eq!(p.quality.etx, MAX_ETX);
+        assert!(p.quality.score() <= 26);
+    }
+}
@@ -0,0 +1,419 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+use crate::message::{MeshMessage, Priority};
+
+/// What a full traffic class does with one more message.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overflow {
+    /// `send` waits for room and `try_send` is refused; nothing queued is lost.
+    Wait,
+    /// The oldest queued message makes room; a late one is worth less than a fresh one.
+    DropOldest,
+}
+
+/// Depth and overflow behaviour of one traffic class on a peer link.
+#[derive(Debug, Clone, Copy)]
+pub struct ClassLimit {
+    pub capacity: usize,
+    pub overflow: Overflow,
+}
+
+impl ClassLimit {
+    pub const fn of(priority: Priority) -> Self {
+        match priority {
+            Priority::Emergency => Self { capacity: 32, overflow: Overflow::Wait },
+            // A backlog of stale pings and route requests helps nobody
+            Priority::Control => Self { capacity: 64, overflow: Overflow::DropOldest },
+            Priority::Interactive => Self { capacity: 64, overflow: Overflow::Wait },
+            // 16 frames is 320 ms of audio; anything older is better skipped
+            Priority::Voice => Self { capacity: 16, overflow: Overflow::DropOldest },
+            // Chunks are up to 64 KB; file transfers retransmit and pace themselves on acks
+            Priority::Bulk => Self { capacity: 16, overflow: Overflow::Wait },
+        }
+    }
+}
+
+/// The link's writer has gone away (the connection closed).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinkClosed;
+
+impl std::fmt::Display for LinkClosed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("peer link closed")
+    }
+}
+
+impl std::error::Error for LinkClosed {}
+
+/// Why [`LinkSender::try_send`] didn't queue a message.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrySendError {
+    /// The message's class is full and keeps what it has.
+    Full,
+    /// The link's writer has gone away.
+    Closed,
+    /// The message is larger than the link's transport can carry.
+    TooLarge,
+}
+
+impl std::fmt::Display for TrySendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Full => f.write_str("peer link queue full"),
+            Self::Closed => f.write_str("peer link closed"),
+            Self::TooLarge => f.write_str("message too large for the peer link"),
+        }
+    }
+}
+
+impl std::error::Error for TrySendError {}
+
+struct State {
+    classes: [VecDeque<MeshMessage>; 5],
+    dropped: u64,
+    /// Since when some [`Overflow::Wait`] class has been at capacity without
+    /// the writer catching up. Classes that drop their oldest never count.
+    congested_since: Option<Instant>,
+    closed: bool,
+}
+
+impl State {
+    fn any_waiting_class_full(&self) -> bool {
+        Priority::ALL.iter().any(|p| {
+            let limit = ClassLimit::of(*p);
+            limit.overflow == Overflow::Wait && self.classes[*p as usize].len() >= limit.capacity
+        })
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Wakes the writer when something is queued or the last sender goes.
+    readable: Notify,
+    /// Wakes senders waiting for room.
+    writable: Notify,
+    /// Wakes the writer out of a blocked write when the node closes the link.
+    closing: Notify,
+    senders: AtomicUsize,
+}
+
+/// Create the outgoing queue of one peer link: a sender for the node and a
+/// receiver for the link's write task. The receiver always hands out the
+/// oldest message of the most urgent non-empty class.
+pub fn link_queue() -> (LinkSender, LinkReceiver) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            classes: Default::default(),
+            dropped: 0,
+            congested_since: None,
+            closed: false,
+        }),
+        readable: Notify::new(),
+        writable: Notify::new(),
+        closing: Notify::new(),
+        senders: AtomicUsize::new(1),
+    });
+    (LinkSender { shared: shared.clone() }, LinkReceiver { shared })
+}
+
+/// Queues messages for a peer link, by priority class.
+pub struct LinkSender {
+    shared: Arc<Shared>,
+}
+
+impl LinkSender {
+    /// Queue a message in its class. Waits for room if the class is full and
+    /// its policy is [`Overflow::Wait`]; otherwise drops the class's oldest message.
+    pub async fn send(&self, msg: MeshMessage) -> Result<(), LinkClosed> {
+        let mut msg = Some(msg);
+        loop {
+            let room = self.shared.writable.notified();
+            tokio::pin!(room);
+            room.as_mut().enable();
+            match self.push(&mut msg, false) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Full) => room.await,
+                Err(_) => return Err(LinkClosed),
+            }
+        }
+    }
+
+    /// Queue a message without waiting. A full [`Overflow::Wait`] class
+    /// refuses it (and counts it as dropped); a full [`Overflow::DropOldest`]
+    /// class makes room as usual.
+    pub fn try_send(&self, msg: MeshMessage) -> Result<(), TrySendError> {
+        self.push(&mut Some(msg), true)
+    }
+
+    /// Queue `msg`, taking it out of the option only if it was queued.
+    fn push(&self, msg: &mut Option<MeshMessage>, count_refusal: bool) -> Result<(), TrySendError> {
+        let Some(priority) = msg.as_ref().map(MeshMessage::priority) else {
+            return Ok(());
+        };
+        let limit = ClassLimit::of(priority);
+        let priority = priority as usize;
+        let mut state = self.shared.state.lock().expect("link queue poisoned");
+        if state.closed {
+            return Err(TrySendError::Closed);
+        }
+        if state.classes[priority].len() >= limit.capacity {
+            if limit.overflow == Overflow::Wait {
+                state.congested_since.get_or_insert_with(Instant::now);
+                if count_refusal {
+                    state.dropped += 1;
+                }
+                return Err(TrySendError::Full);
+            }
+            state.classes[priority].pop_front();
+            state.dropped += 1;
+        }
+        state.classes[priority].extend(msg.take());
+        drop(state);
+        self.shared.readable.notify_one();
+        Ok(())
+    }
+
+    /// Messages dropped from full classes so far.
+    pub fn dropped(&self) -> u64 {
+        self.shared.state.lock().map(|s| s.dropped).unwrap_or(0)
+    }
+
+    /// How long the link has had a refusing class at capacity without the
+    /// writer catching up; None while it keeps pace.
+    pub fn congested_for(&self) -> Option<Duration> {
+        self.shared.state.lock().ok()?.congested_since.map(|t| t.elapsed())
+    }
+
+    /// Shut the link down from the node's side: queued messages are
+    /// discarded, further sends fail, and the writer stops, even mid-write.
+    pub fn close(&self) {
+        if let Ok(mut state) = self.shared.state.lock() {
+            state.closed = true;
+            state.classes.iter_mut().for_each(VecDeque::clear);
+        }
+        self.shared.readable.notify_one();
+        self.shared.writable.notify_waiters();
+        self.shared.closing.notify_waiters();
+    }
+
+    /// Messages waiting to be written, per class (most urgent first).
+    pub fn queued(&self) -> [usize; 5] {
+        let state = self.shared.state.lock().expect("link queue poisoned");
+        Priority::ALL.map(|p| state.classes[p as usize].len())
+    }
+}
+
+impl Clone for LinkSender {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        Self { shared: self.shared.clone() }
+    }
+}
+
+impl Drop for LinkSender {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.readable.notify_one();
+        }
+    }
+}
+
+impl std::fmt::Debug for LinkSender {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LinkSender").field("queued", &self.queued()).finish()
+    }
+}
+
+/// The write task's end of a peer link queue.
+pub struct LinkReceiver {
+    shared: Arc<Shared>,
+}
+
+impl LinkReceiver {
+    /// Next message to write, most urgent class first. None once the link
+    /// was closed, or every sender is gone and the queue has drained.
+    pub async fn recv(&mut self) -> Option<MeshMessage> {
+        self.recv_from(|_| true).await
+    }
+
+    /// Like [`recv`](Self::recv), but only from the classes `ready` accepts;
+    /// the others stay queued. For bearers that write each class separately.
+    pub async fn recv_from(&mut self, ready: impl Fn(Priority) -> bool) -> Option<MeshMessage> {
+        loop {
+            let queued = self.shared.readable.notified();
+            tokio::pin!(queued);
+            queued.as_mut().enable();
+            {
+                let mut state = self.shared.state.lock().expect("link queue poisoned");
+                if state.closed {
+                    return None;
+                }
+                let next = Priority::ALL.iter().zip(state.classes.iter_mut())
+                    .filter(|(p, _)| ready(**p))
+                    .find_map(|(_, q)| q.pop_front());
+                if let Some(msg) = next {
+                    if !state.any_waiting_class_full() {
+                        state.congested_since = None;
+                    }
+                    drop(state);
+                    self.shared.writable.notify_waiters();
+                    return Some(msg);
+                }
+                if self.shared.senders.load(Ordering::Acquire) == 0 {
+                    return None;
+                }
+            }
+            queued.await;
+        }
+    }
+
+    /// Resolves once the node closes the link with [`LinkSender::close`].
+    pub async fn closed(&self) {
+        loop {
+            let closing = self.shared.closing.notified();
+            tokio::pin!(closing);
+            closing.as_mut().enable();
+            if self.shared.state.lock().map(|s| s.closed).unwrap_or(true) {
+                return;
+            }
+            closing.await;
+        }
+    }
+}
+
+impl Drop for LinkReceiver {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.shared.state.lock() {
+            state.closed = true;
+            state.classes.iter_mut().for_each(VecDeque::clear);
+        }
+        self.shared.writable.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::MessageType;
+    use std::time::Duration;
+
+    fn msg(msg_type: MessageType, tag: u8) -> MeshMessage {
+        MeshMessage::new(msg_type, [1u8; 32], 1, None, vec![tag])
+    }
+
+    #[tokio::test]
+    async fn test_urgent_classes_jump_the_queue() {
+        let (tx, mut rx) = link_queue();
+        for i in 0..10 {
+            tx.send(msg(MessageType::FileChunk, i)).await.unwrap();
+        }
+        tx.send(msg(MessageType::VoiceStream, 0)).await.unwrap();
+        tx.send(msg(MessageType::Text, 0)).await.unwrap();
+        tx.send(msg(MessageType::Pong, 0)).await.unwrap();
+        tx.send(msg(MessageType::SOS, 0)).await.unwrap();
+        assert_eq!(tx.queued(), [1, 1, 1, 1, 10]);
+
+        let mut order = Vec::new();
+        for _ in 0..5 {
+            order.push(rx.recv().await.unwrap().msg_type);
+        }
+        assert_eq!(order, vec![
+            MessageType::SOS, MessageType::Pong, MessageType::Text, MessageType::VoiceStream, MessageType::FileChunk,
+        ]);
+        // Within a class, first in first out
+        assert_eq!(rx.recv().await.unwrap().payload, vec![1]);
+    }
+
+    #[tokio::test]
+    async fn test_overflow_policies() {
+        let (tx, mut rx) = link_queue();
+
+        // Voice drops its oldest frames instead of growing
+        let voice = ClassLimit::of(Priority::Voice).capacity;
+        for i in 0..voice as u8 + 4 {
+            tx.send(msg(MessageType::VoiceStream, i)).await.unwrap();
+        }
+        assert_eq!(tx.dropped(), 4);
+        assert_eq!(rx.recv().await.unwrap().payload, vec![4]);
+
+        // Bulk makes the sender wait until the writer catches up
+        let bulk = ClassLimit::of(Priority::Bulk).capacity;
+        for i in 0..bulk as u8 {
+            tx.send(msg(MessageType::FileChunk, i)).await.unwrap();
+        }
+        let blocked = tx.clone();
+        let waiting = tokio::spawn(async move { blocked.send(msg(MessageType::FileChunk, 99)).await });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiting.is_finished());
+        while rx.recv().await.unwrap().msg_type != MessageType::FileChunk {}
+        assert!(tokio::time::timeout(Duration::from_secs(1), waiting).await.unwrap().unwrap().is_ok());
+        assert_eq!(tx.dropped(), 4);
+
+        // A closed link fails the send, and a drained queue with no senders ends
+        drop(rx);
+        assert_eq!(tx.send(msg(MessageType::Text, 0)).await, Err(LinkClosed));
+        let (tx, mut rx) = link_queue();
+        tx.send(msg(MessageType::Text, 0)).await.unwrap();
+        drop(tx);
+        assert!(rx.recv().await.is_some());
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_try_send_never_waits() {
+        let (tx, mut rx) = link_queue();
+        let bulk = ClassLimit::of(Priority::Bulk).capacity;
+        for i in 0..bulk as u8 {
+            assert_eq!(tx.try_send(msg(MessageType::FileChunk, i)), Ok(()));
+        }
+        assert_eq!(tx.congested_for(), None);
+
+        // A full waiting class refuses, and the link counts as congested until the writer catches up
+        assert_eq!(tx.try_send(msg(MessageType::FileChunk, 99)), Err(TrySendError::Full));
+        assert_eq!(tx.dropped(), 1);
+        assert!(tx.congested_for().is_some());
+        // Other classes still get through
+        assert_eq!(tx.try_send(msg(MessageType::SOS, 0)), Ok(()));
+        rx.recv().await.unwrap();
+        assert!(tx.congested_for().is_some());
+        rx.recv().await.unwrap();
+        assert_eq!(tx.congested_for(), None);
+
+        // Full voice and control classes shed their oldest, which is not congestion
+        for i in 0..=ClassLimit::of(Priority::Voice).capacity as u8 {
+            assert_eq!(tx.try_send(msg(MessageType::VoiceStream, i)), Ok(()));
+        }
+        assert_eq!(tx.congested_for(), None);
+
+        // Closing from the sender side stops the writer and refuses everything
+        let writer = tokio::spawn(async move {
+            rx.closed().await;
+            rx.recv().await
+        });
+        tx.close();
+        assert!(tokio::time::timeout(Duration::from_secs(1), writer).await.unwrap().unwrap().is_none());
+        assert_eq!(tx.try_send(msg(MessageType::SOS, 0)), Err(TrySendError::Closed));
+    }
+
+    #[tokio::test]
+    async fn test_recv_from_leaves_busy_classes_queued() {
+        let (tx, mut rx) = link_queue();
+        tx.send(msg(MessageType::FileChunk, 0)).await.unwrap();
+        tx.send(msg(MessageType::Text, 0)).await.unwrap();
+        let not_bulk = |p| p != Priority::Bulk;
+        assert_eq!(rx.recv_from(not_bulk).await.unwrap().msg_type, MessageType::Text);
+        assert_eq!(tx.queued(), [0, 0, 0, 0, 1]);
+
+        // Waits for a class it accepts rather than taking the chunk
+        let waiting = tokio::spawn(async move { (rx.recv_from(not_bulk).await, rx) });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        tx.send(msg(MessageType::SOS, 0)).await.unwrap();
+        let (got, mut rx) = tokio::time::timeout(Duration::from_secs(1), waiting).await.unwrap().unwrap();
+        assert_eq!(got.unwrap().msg_type, MessageType::SOS);
+        assert_eq!(rx.recv().await.unwrap().msg_type, MessageType::FileChunk);
+    }
+}
@@ -0,0 +1,548 @@
+//! QUIC transport: one connection per neighbor, with a stream per traffic
+//! class and live call audio in unreliable datagrams, so a stalled file
+//! transfer never holds up an SOS.
+//!
+//! Each node presents a self-signed certificate for its Ed25519 identity key
+//! and both ends check the other's. A dialed neighbor must be the node that
+//! discovery announced, and the accepting side knows who dialed before the
+//! first message arrives.
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use quinn::{Connection, Endpoint, RecvStream, SendStream};
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{CryptoProvider, WebPkiSupportedAlgorithms};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::{CertificateError, DigitallySignedStruct, DistinguishedName, SignatureScheme};
+use tokio::sync::{mpsc, watch, Notify};
+use tokio::task::JoinSet;
+use tracing::{info, debug, warn};
+use anyhow::{Result, Context};
+
+use crate::identity::NodeIdentity;
+use crate::message::{MeshMessage, MessageType, Priority};
+use crate::queue::{link_queue, LinkReceiver, LinkSender};
+use crate::transport::{self, BoxFuture, IncomingMessage, InboundConnection, LinkAddr, Reliability, Transport, MAX_FRAME};
+
+const ALPN: &[u8] = b"mesh/1";
+/// Name dialers put in the handshake; neighbors are checked by key, not name.
+const SERVER_NAME: &str = "mesh";
+const KEEP_ALIVE: Duration = Duration::from_secs(10);
+/// PKCS#8 v1 wrapping of a raw Ed25519 secret key (RFC 8410), up to the key.
+const ED25519_PKCS8_PREFIX: [u8; 16] = [
+    0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20,
+];
+/// SubjectPublicKeyInfo of an Ed25519 key, up to the key.
+const ED25519_SPKI_PREFIX: [u8; 12] = [0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00];
+
+/// QUIC over UDP, as an alternative to [`TcpTransport`](crate::TcpTransport).
+pub struct QuicTransport {
+    listen_port: u16,
+    node_id: [u8; 32],
+    cert: CertificateDer<'static>,
+    key: PrivatePkcs8KeyDer<'static>,
+    /// Bound by `listen`; dials go out from the same socket.
+    endpoint: Mutex<Option<Endpoint>>,
+}
+
+impl QuicTransport {
+    /// `identity` must be the node's own, the one at `NodeConfig::key_path`;
+    /// `start_mesh_node` refuses to start with any other.
+    pub fn new(identity: &NodeIdentity, listen_port: u16) -> Result<Self> {
+        let mut pkcs8 = ED25519_PKCS8_PREFIX.to_vec();
+        pkcs8.extend_from_slice(&identity.signing_key().to_bytes());
+        let key = PrivatePkcs8KeyDer::from(pkcs8);
+        let key_pair = rcgen::KeyPair::from_pkcs8_der_and_sign_algo(&key, &rcgen::PKCS_ED25519)?;
+        let cert = rcgen::CertificateParams::new(vec![SERVER_NAME.to_string()])?.self_signed(&key_pair)?;
+        Ok(Self { listen_port, node_id: identity.node_id, cert: cert.der().clone(), key, endpoint: Mutex::new(None) })
+    }
+
+    /// Address the transport is bound to, once listening.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.endpoint.lock().unwrap().as_ref()?.local_addr().ok()
+    }
+
+    fn server_config(&self) -> Result<quinn::ServerConfig> {
+        let mut tls = rustls::ServerConfig::builder_with_provider(provider())
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_client_cert_verifier(Arc::new(IdentityVerifier::new(None)))
+            .with_single_cert(vec![self.cert.clone()], PrivateKeyDer::Pkcs8(self.key.clone_key()))?;
+        tls.alpn_protocols = vec![ALPN.to_vec()];
+        let mut config = quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls)?));
+        config.transport_config(transport_config());
+        Ok(config)
+    }
+
+    fn client_config(&self, peer: [u8; 32]) -> Result<quinn::ClientConfig> {
+        let mut tls = rustls::ClientConfig::builder_with_provider(provider())
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(IdentityVerifier::new(Some(peer))))
+            .with_client_auth_cert(vec![self.cert.clone()], PrivateKeyDer::Pkcs8(self.key.clone_key()))?;
+        tls.alpn_protocols = vec![ALPN.to_vec()];
+        let mut config = quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls)?));
+        config.transport_config(transport_config());
+        Ok(config)
+    }
+}
+
+impl Transport for QuicTransport {
+    fn name(&self) -> &'static str {
+        "quic"
+    }
+
+    fn mtu(&self) -> usize {
+        MAX_FRAME
+    }
+
+    fn identity(&self) -> Option<[u8; 32]> {
+        Some(self.node_id)
+    }
+
+    /// Each class arrives in order on its own stream, but classes overtake
+    /// each other, and call audio that doesn't make it is not resent.
+    fn reliability(&self) -> Reliability {
+        Reliability { reliable: true, ordered: false }
+    }
+
+    fn discovery_port(&self) -> Option<u16> {
+        Some(self.listen_port)
+    }
+
+    fn listen(
+        &self,
+        incoming_tx: mpsc::Sender<IncomingMessage>,
+        inbound_conn_tx: mpsc::Sender<InboundConnection>,
+        shutdown: watch::Receiver<bool>,
+    ) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            let bind_addr = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), self.listen_port);
+            let endpoint = Endpoint::server(self.server_config()?, bind_addr)
+                .context(format!("Failed to bind QUIC on UDP port {}", self.listen_port))?;
+            info!("QUIC transport listening on {}", endpoint.local_addr()?);
+            *self.endpoint.lock().unwrap() = Some(endpoint.clone());
+
+            let mut shutdown_rx = shutdown;
+            tokio::spawn(async move {
+                loop {
+                    tokio::select! {
+                        Some(incoming) = endpoint.accept() => {
+                            tokio::spawn(accept_link(incoming, incoming_tx.clone(), inbound_conn_tx.clone()));
+                        }
+                        _ = shutdown_rx.changed() => {
+                            info!("QUIC listener shutting down");
+                            endpoint.close(0u32.into(), b"shutdown");
+                            break;
+                        }
+                    }
+                }
+            });
+            Ok(())
+        })
+    }
+
+    fn can_dial(&self, addr: &LinkAddr) -> bool {
+        matches!(addr, LinkAddr::Ip(_))
+    }
+
+    fn dial(
+        &self,
+        addr: LinkAddr,
+        peer: [u8; 32],
+        incoming_tx: mpsc::Sender<IncomingMessage>,
+    ) -> BoxFuture<'_, Result<LinkSender>> {
+        Box::pin(async move {
+            let LinkAddr::Ip(addr) = addr else {
+                anyhow::bail!("QUIC cannot dial {}", addr);
+            };
+            let endpoint = self.endpoint.lock().unwrap().clone().context("QUIC transport is not listening")?;
+            let connection = endpoint.connect_with(self.client_config(peer)?, addr, SERVER_NAME)?.await?;
+            debug!("Connected to peer at {} over QUIC", addr);
+            let (write_tx, write_rx) = link_queue();
+            tokio::spawn(run_link(connection, addr.into(), peer, incoming_tx, write_rx));
+            Ok(write_tx)
+        })
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn transport_config() -> Arc<quinn::TransportConfig> {
+    let mut config = quinn::TransportConfig::default();
+    config.keep_alive_interval(Some(KEEP_ALIVE));
+    Arc::new(config)
+}
+
+/// Finish the handshake of a neighbor that dialed us and run its link.
+async fn accept_link(
+    incoming: quinn::Incoming,
+    incoming_tx: mpsc::Sender<IncomingMessage>,
+    inbound_conn_tx: mpsc::Sender<InboundConnection>,
+) {
+    let connection = match incoming.await {
+        Ok(connection) => connection,
+        Err(e) => {
+            debug!("QUIC handshake failed: {}", e);
+            return;
+        }
+    };
+    // The verifier has already refused anything without a usable identity
+    let Some(peer) = peer_identity(&connection) else { return };
+    let addr = LinkAddr::Ip(connection.remote_address());
+    debug!("Incoming QUIC connection from {} ({})", addr, hex::encode(&peer[..4]));
+
+    let (write_tx, write_rx) = link_queue();
+    let _ = inbound_conn_tx.send(InboundConnection {
+        addr: addr.clone(),
+        transport: "quic",
+        sender: write_tx,
+        dialed: true,
+        peer: Arc::new(peer.into()),
+    }).await;
+    run_link(connection, addr, peer, incoming_tx, write_rx).await;
+}
+
+fn peer_identity(connection: &Connection) -> Option<[u8; 32]> {
+    let certs = connection.peer_identity()?.downcast::<Vec<CertificateDer<'static>>>().ok()?;
+    certified_identity(certs.first()?).ok()
+}
+
+/// Carry a link over an established connection until either side ends it.
+async fn run_link(
+    connection: Connection,
+    addr: LinkAddr,
+    peer: [u8; 32],
+    incoming_tx: mpsc::Sender<IncomingMessage>,
+    write_rx: LinkReceiver,
+) {
+    let mut read_task = tokio::spawn(read_link(connection.clone(), addr.clone(), peer, incoming_tx));
+    let mut write_task = tokio::spawn(write_link(connection.clone(), addr.clone(), write_rx));
+    tokio::select! {
+        _ = &mut read_task => write_task.abort(),
+        _ = &mut write_task => read_task.abort(),
+    }
+    connection.close(0u32.into(), b"");
+    debug!("Peer connection {} closed", addr);
+}
+
+/// Pass everything the neighbor sends, on any stream or as a datagram, to the node.
+async fn read_link(connection: Connection, addr: LinkAddr, peer: [u8; 32], incoming_tx: mpsc::Sender<IncomingMessage>) {
+    // Dropped with this task when the link ends, which stops the stream readers
+    let mut streams = JoinSet::new();
+    loop {
+        tokio::select! {
+            stream = connection.accept_uni() => match stream {
+                Ok(stream) => {
+                    streams.spawn(read_stream(stream, addr.clone(), peer, incoming_tx.clone()));
+                }
+                Err(e) => {
+                    debug!("Peer {} connection ended: {}", addr, e);
+                    break;
+                }
+            },
+            datagram = connection.read_datagram() => match datagram {
+                Ok(data) => match MeshMessage::from_bytes(&data) {
+                    Ok(msg) => deliver(msg, &addr, peer, &incoming_tx).await,
+                    Err(e) => warn!("Peer {} invalid datagram: {}", addr, e),
+                },
+                Err(e) => {
+                    debug!("Peer {} connection ended: {}", addr, e);
+                    break;
+                }
+            },
+        }
+    }
+}
+
+async fn read_stream(mut stream: RecvStream, addr: LinkAddr, peer: [u8; 32], incoming_tx: mpsc::Sender<IncomingMessage>) {
+    loop {
+        match transport::read_frame(&mut stream, MAX_FRAME).await {
+            Ok(Some(buf)) => match MeshMessage::from_bytes(&buf) {
+                Ok(msg) => deliver(msg, &addr, peer, &incoming_tx).await,
+                Err(e) => warn!("Peer {} invalid message: {}", addr, e),
+            },
+            Ok(None) => break,
+            Err(e) => {
+                warn!("Peer {} read error: {}", addr, e);
+                break;
+            }
+        }
+    }
+}
+
+async fn deliver(msg: MeshMessage, addr: &LinkAddr, peer: [u8; 32], incoming_tx: &mpsc::Sender<IncomingMessage>) {
+    // Key exchange travels one hop, so here it can only be the certified neighbor's
+    if msg.msg_type == MessageType::KeyExchange && msg.sender_id != peer {
+        warn!("Peer {} sent a key exchange for another identity", addr);
+        return;
+    }
+    let _ = incoming_tx.send(IncomingMessage { msg, from_addr: addr.clone(), from_peer: Some(peer) }).await;
+}
+
+/// Write each traffic class on a stream of its own, and call audio as
+/// datagrams. A class whose stream stalls stops being taken off the queue;
+/// the other classes keep flowing.
+async fn write_link(connection: Connection, addr: LinkAddr, mut write_rx: LinkReceiver) {
+    let taken = Arc::new(Notify::new());
+    let mut writers = JoinSet::new();
+    let lanes: Vec<mpsc::Sender<MeshMessage>> = Priority::ALL
+        .iter()
+        .map(|&class| {
+            let (tx, rx) = mpsc::channel(1);
+            writers.spawn(write_stream(connection.clone(), class, rx, taken.clone()));
+            tx
+        })
+        .collect();
+
+    loop {
+        let freed = taken.notified();
+        tokio::pin!(freed);
+        freed.as_mut().enable();
+        let ready = |class: Priority| lanes[class as usize].capacity() > 0;
+        let msg = tokio::select! {
+            msg = write_rx.recv_from(ready) => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+            _ = freed => continue,
+            result = writers.join_next() => {
+                if let Some(Ok(Err(e))) = result {
+                    warn!("Peer {} write error: {}", addr, e);
+                }
+                break;
+            }
+        };
+        let class = msg.priority();
+        if class == Priority::Voice && send_datagram(&connection, &msg) {
+            continue;
+        }
+        if lanes[class as usize].try_send(msg).is_err() {
+            break;
+        }
+    }
+    debug!("Write task for {} ended", addr);
+}
+
+/// Write one class's messages in order on a stream opened when the first arrives.
+async fn write_stream(
+    connection: Connection,
+    class: Priority,
+    mut rx: mpsc::Receiver<MeshMessage>,
+    taken: Arc<Notify>,
+) -> Result<()> {
+    let mut stream: Option<SendStream> = None;
+    while let Some(msg) = rx.recv().await {
+        taken.notify_one();
+        let stream = match &mut stream {
+            Some(stream) => stream,
+            None => {
+                let opened = connection.open_uni().await?;
+                // When packets must wait, the more urgent classes go out first
+                opened.set_priority(Priority::Bulk as i32 - class as i32)?;
+                stream.insert(opened)
+            }
+        };
+        transport::write_message(stream, &msg).await?;
+    }
+    Ok(())
+}
+
+/// Send call audio unreliably, if it fits in one datagram.
+fn send_datagram(connection: &Connection, msg: &MeshMessage) -> bool {
+    let data = msg.to_bytes();
+    connection.max_datagram_size().is_some_and(|max| data.len() <= max)
+        && connection.send_datagram(data.into()).is_ok()
+}
+
+/// The node ID a certificate speaks for: its Ed25519 subject key.
+fn certified_identity(cert: &CertificateDer<'_>) -> Result<[u8; 32], rustls::Error> {
+    let bad_encoding = || rustls::Error::InvalidCertificate(CertificateError::BadEncoding);
+    let cert = webpki::EndEntityCert::try_from(cert).map_err(|_| bad_encoding())?;
+    let spki = cert.subject_public_key_info();
+    spki.as_ref()
+        .strip_prefix(&ED25519_SPKI_PREFIX[..])
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(bad_encoding)
+}
+
+/// Accepts the certificates `QuicTransport` issues. There is no CA: the
+/// certificate's Ed25519 key is the node ID, and the handshake must be
+/// signed with it.
+#[derive(Debug)]
+struct IdentityVerifier {
+    /// The node a dial was meant for; any node may dial us.
+    expected: Option<[u8; 32]>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl IdentityVerifier {
+    fn new(expected: Option<[u8; 32]>) -> Self {
+        Self { expected, algorithms: provider().signature_verification_algorithms }
+    }
+
+    fn check(&self, cert: &CertificateDer<'_>) -> Result<(), rustls::Error> {
+        let identity = certified_identity(cert)?;
+        if self.expected.is_some_and(|expected| expected != identity) {
+            return Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure));
+        }
+        Ok(())
+    }
+}
+
+impl ServerCertVerifier for IdentityVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.check(end_entity).map(|_| ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        vec![SignatureScheme::ED25519]
+    }
+}
+
+impl ClientCertVerifier for IdentityVerifier {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        self.check(end_entity).map(|_| ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        vec![SignatureScheme::ED25519]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{SOSPayload, VoiceStreamPayload};
+
+    async fn listening(identity: &NodeIdentity) -> (QuicTransport, mpsc::Receiver<IncomingMessage>, mpsc::Receiver<InboundConnection>, watch::Sender<bool>) {
+        let transport = QuicTransport::new(identity, 0).unwrap();
+        let (incoming_tx, incoming_rx) = mpsc::channel(16);
+        let (conn_tx, conn_rx) = mpsc::channel(1);
+        let (shutdown_tx, shutdown) = watch::channel(false);
+        transport.listen(incoming_tx, conn_tx, shutdown).await.unwrap();
+        (transport, incoming_rx, conn_rx, shutdown_tx)
+    }
+
+    fn loopback(transport: &QuicTransport) -> LinkAddr {
+        LinkAddr::Ip(([127, 0, 0, 1], transport.local_addr().unwrap().port()).into())
+    }
+
+    async fn recv(rx: &mut mpsc::Receiver<IncomingMessage>) -> IncomingMessage {
+        tokio::time::timeout(Duration::from_secs(5), rx.recv()).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_link_carries_every_class_and_knows_its_peer() {
+        let alice = NodeIdentity::generate("alice".into());
+        let bob = NodeIdentity::generate("bob".into());
+        let (a, _a_in, _a_conns, _a_shutdown) = listening(&alice).await;
+        let (b, mut b_in, mut b_conns, _b_shutdown) = listening(&bob).await;
+
+        let (from_b_tx, mut from_b) = mpsc::channel(16);
+        let to_b = a.dial(loopback(&b), bob.node_id, from_b_tx).await.unwrap();
+        let link = tokio::time::timeout(Duration::from_secs(5), b_conns.recv()).await.unwrap().unwrap();
+        assert_eq!(link.transport, "quic");
+
+        let sos = SOSPayload { text: "help".into(), location: None };
+        let frame = VoiceStreamPayload { stream_id: [1u8; 16], sequence: 0, audio_frame: vec![0u8; 640] };
+        to_b.send(MeshMessage::text_to(alice.node_id, bob.node_id, "hi")).await.unwrap();
+        to_b.send(MeshMessage::sos(alice.node_id, &sos)).await.unwrap();
+        to_b.send(MeshMessage::voice_stream(alice.node_id, bob.node_id, &frame)).await.unwrap();
+
+        // The accepting side knows who dialed from the certificate, before any key exchange
+        let mut got = Vec::new();
+        for _ in 0..3 {
+            let incoming = recv(&mut b_in).await;
+            assert_eq!(incoming.from_peer, Some(alice.node_id));
+            got.push(incoming.msg.msg_type);
+        }
+        got.sort_by_key(|t| *t as u8);
+        assert_eq!(got, vec![MessageType::Text, MessageType::SOS, MessageType::VoiceStream]);
+
+        link.sender.send(MeshMessage::text_to(bob.node_id, alice.node_id, "hello")).await.unwrap();
+        let reply = recv(&mut from_b).await;
+        assert_eq!((reply.msg.payload.as_slice(), reply.from_peer), (&b"hello"[..], Some(bob.node_id)));
+    }
+
+    #[tokio::test]
+    async fn test_dial_refuses_a_different_identity() {
+        let alice = NodeIdentity::generate("alice".into());
+        let bob = NodeIdentity::generate("bob".into());
+        let mallory = NodeIdentity::generate("mallory".into());
+        let (a, _a_in, _a_conns, _a_shutdown) = listening(&alice).await;
+        let (m, _m_in, _m_conns, _m_shutdown) = listening(&mallory).await;
+
+        // Discovery said bob is at this address, but the node there can't prove it
+        let (tx, _) = mpsc::channel(1);
+        assert!(a.dial(loopback(&m), bob.node_id, tx).await.is_err());
+
+        let (tx, _) = mpsc::channel(1);
+        assert!(a.dial(loopback(&m), mallory.node_id, tx).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_certificate_carries_node_id() {
+        let identity = NodeIdentity::generate("alice".into());
+        let transport = QuicTransport::new(&identity, 0).unwrap();
+        assert_eq!(certified_identity(&transport.cert).unwrap(), identity.node_id);
+        assert_eq!(transport.identity(), Some(identity.node_id));
+        assert!(certified_identity(&CertificateDer::from(vec![0u8; 16])).is_err());
+    }
+}
@@ -1,10 +1,52 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::time::{Duration, Instant};
 
-use crate::message::{MeshMessage, MessageId, MessageType};
+use crate::message::{MeshMessage, MessageId, MessageType, RouteRequestPayload};
 
 const SEEN_EXPIRY: Duration = Duration::from_secs(300); // 5 minutes
 const MAX_SEEN_CACHE: usize = 10_000;
+/// The seen cache is split into this many generations of SEEN_EXPIRY / N each.
+const SEEN_BUCKETS: usize = 5;
+/// Broadcasts that have travelled fewer hops than this are always relayed
+/// under gossip, so a message can't die out right next to its source.
+const GOSSIP_SURE_HOPS: u8 = 1;
+
+/// How new broadcasts are relayed to neighbors. Directed messages follow
+/// routes and SOS is always flooded in full, whatever the strategy.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum FloodStrategy {
+    /// Relay every new broadcast to every neighbor.
+    #[default]
+    Full,
+    /// Relay each new broadcast with this probability (0.0 - 1.0).
+    Gossip { probability: f32 },
+    /// Wait a random delay up to `max_delay`, then relay only if fewer than
+    /// `threshold` copies were heard in the meantime.
+    Counter { threshold: u8, max_delay: Duration },
+    /// Wait a random delay up to `max_delay`, then relay only if some neighbor
+    /// is not already covered by the nodes we heard the broadcast from.
+    NeighborCoverage { max_delay: Duration },
+}
+
+/// What to do with a new broadcast after processing it.
+#[derive(Debug, PartialEq)]
+pub enum RelayPlan {
+    /// Forward it right away.
+    Now,
+    /// Held until [`Router::due_relays`] decides.
+    Deferred,
+    /// Not relayed by this node.
+    Suppressed,
+}
+
+/// A broadcast waiting out its jitter before a suppression decision.
+struct PendingRelay {
+    msg: MeshMessage,
+    due: Instant,
+    copies: u8,
+    /// Neighbors we heard a copy from (they already have it).
+    heard_from: HashSet<[u8; 32]>,
+}
 
 /// Mesh network statistics.
 #[derive(Debug, Clone, Default)]
@@ -15,6 +57,8 @@ pub struct MeshStats {
     pub unique_nodes_seen: u32,
     pub total_hops_observed: u64,
     pub hop_count_samples: u64,
+    /// Broadcasts the flood strategy chose not to relay.
+    pub broadcasts_suppressed: u64,
 }
 
 impl MeshStats {
@@ -27,15 +71,78 @@ impl MeshStats {
     }
 }
 
+/// Message IDs seen recently, kept in rotating time buckets.
+///
+/// Inserts go into the newest bucket and lookups check every bucket, both
+/// O(1). Expiry drops the oldest bucket whole instead of scanning entries.
+/// When the newest bucket fills up before its time is over, it rotates early:
+/// a flood of unique IDs shortens how long we remember things, but never
+/// makes us turn traffic away.
+struct SeenCache {
+    buckets: VecDeque<HashSet<MessageId>>,
+    newest_started: Instant,
+    span: Duration,
+    bucket_capacity: usize,
+}
+
+impl SeenCache {
+    fn new(expiry: Duration, capacity: usize) -> Self {
+        Self {
+            buckets: std::iter::repeat_with(HashSet::new).take(SEEN_BUCKETS).collect(),
+            newest_started: Instant::now(),
+            span: expiry / SEEN_BUCKETS as u32,
+            bucket_capacity: capacity.div_ceil(SEEN_BUCKETS),
+        }
+    }
+
+    fn contains(&self, id: &MessageId) -> bool {
+        self.buckets.iter().any(|b| b.contains(id))
+    }
+
+    fn insert(&mut self, id: MessageId) {
+        self.expire();
+        if self.buckets[0].len() >= self.bucket_capacity {
+            self.rotate();
+        }
+        self.buckets[0].insert(id);
+    }
+
+    /// Drop buckets that have aged out, one per elapsed span.
+    fn expire(&mut self) {
+        let elapsed = self.newest_started.elapsed();
+        if elapsed < self.span {
+            return;
+        }
+        let spans = (elapsed.as_millis() / self.span.as_millis().max(1)) as usize;
+        for _ in 0..spans.min(SEEN_BUCKETS) {
+            self.rotate();
+        }
+    }
+
+    fn rotate(&mut self) {
+        let mut oldest = self.buckets.pop_back().unwrap_or_default();
+        oldest.clear();
+        self.buckets.push_front(oldest);
+        self.newest_started = Instant::now();
+    }
+
+    fn len(&self) -> usize {
+        self.buckets.iter().map(HashSet::len).sum()
+    }
+}
+
 /// Flooding router with TTL-based hop limiting and message deduplication.
 pub struct Router {
     /// Cache of already-seen message IDs to prevent forwarding loops.
-    seen: HashSet<MessageId>,
-    seen_times: Vec<(MessageId, Instant)>,
+    seen: SeenCache,
     our_node_id: [u8; 32],
     /// Track all unique node IDs ever seen (including via relay).
     pub all_nodes_seen: HashSet<[u8; 32]>,
     pub stats: MeshStats,
+    flood: FloodStrategy,
+    pending_relays: HashMap<MessageId, PendingRelay>,
+    /// Each neighbor's own neighbors, from its signed link state.
+    neighbor_sets: HashMap<[u8; 32], HashSet<[u8; 32]>>,
 }
 
 impl Router {
@@ -43,12 +150,104 @@ impl Router {
         let mut all_nodes_seen = HashSet::new();
         all_nodes_seen.insert(our_node_id);
         Self {
-            seen: HashSet::new(),
-            seen_times: Vec::new(),
+            seen: SeenCache::new(SEEN_EXPIRY, MAX_SEEN_CACHE),
             our_node_id,
             all_nodes_seen,
             stats: MeshStats::default(),
+            flood: FloodStrategy::default(),
+            pending_relays: HashMap::new(),
+            neighbor_sets: HashMap::new(),
+        }
+    }
+
+    pub fn set_flood_strategy(&mut self, strategy: FloodStrategy) {
+        self.flood = strategy;
+    }
+
+    /// Record the signed neighbor list a directly connected peer advertised.
+    pub fn set_neighbor_set(&mut self, peer: [u8; 32], neighbors: HashSet<[u8; 32]>) {
+        self.neighbor_sets.insert(peer, neighbors);
+    }
+
+    pub fn forget_neighbor(&mut self, peer: &[u8; 32]) {
+        self.neighbor_sets.remove(peer);
+    }
+
+    /// Decide how a new message that should be forwarded gets relayed.
+    /// `upstream` is the neighbor it arrived from.
+    pub fn plan_relay(&mut self, msg: &MeshMessage, upstream: Option<[u8; 32]>) -> RelayPlan {
+        if msg.destination.is_some() || msg.msg_type == MessageType::SOS {
+            return RelayPlan::Now;
+        }
+        let max_delay = match self.flood {
+            FloodStrategy::Full => return RelayPlan::Now,
+            FloodStrategy::Gossip { probability } => {
+                if msg.hop_count < GOSSIP_SURE_HOPS || rand::random::<f32>() < probability {
+                    return RelayPlan::Now;
+                }
+                self.stats.broadcasts_suppressed += 1;
+                return RelayPlan::Suppressed;
+            }
+            FloodStrategy::Counter { max_delay, .. } => max_delay,
+            FloodStrategy::NeighborCoverage { max_delay } => max_delay,
+        };
+        let jitter = max_delay.mul_f64(rand::random::<f64>());
+        self.pending_relays.insert(msg.msg_id, PendingRelay {
+            msg: msg.clone(),
+            due: Instant::now() + jitter,
+            copies: 1,
+            heard_from: upstream.into_iter().collect(),
+        });
+        RelayPlan::Deferred
+    }
+
+    /// Note a duplicate copy of a message, in case its relay is still pending.
+    pub fn heard_again(&mut self, msg_id: &MessageId, from: Option<[u8; 32]>) {
+        if let Some(pending) = self.pending_relays.get_mut(msg_id) {
+            pending.copies = pending.copies.saturating_add(1);
+            pending.heard_from.extend(from);
+        }
+    }
+
+    /// When the next deferred relay is due, if any.
+    pub fn next_relay_due(&self) -> Option<Instant> {
+        self.pending_relays.values().map(|p| p.due).min()
+    }
+
+    /// Settle deferred relays whose delay is over. Returns each message to
+    /// forward with the neighbors to leave out, given our current `neighbors`.
+    pub fn due_relays(&mut self, neighbors: &[[u8; 32]]) -> Vec<(MeshMessage, Vec<[u8; 32]>)> {
+        let now = Instant::now();
+        let due: Vec<MessageId> = self.pending_relays.iter()
+            .filter(|(_, p)| p.due <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        let mut relays = Vec::new();
+        for id in due {
+            let Some(pending) = self.pending_relays.remove(&id) else { continue };
+            let mut exclude: HashSet<[u8; 32]> = pending.heard_from.clone();
+            exclude.insert(pending.msg.sender_id);
+            let relay = match self.flood {
+                FloodStrategy::Counter { threshold, .. } => pending.copies < threshold,
+                FloodStrategy::NeighborCoverage { .. } => {
+                    // Whoever sent us a copy has also reached its own neighbors
+                    for sender in &pending.heard_from {
+                        if let Some(covered) = self.neighbor_sets.get(sender) {
+                            exclude.extend(covered.iter().copied());
+                        }
+                    }
+                    neighbors.iter().any(|n| !exclude.contains(n))
+                }
+                // Strategy changed while this was pending
+                FloodStrategy::Full | FloodStrategy::Gossip { .. } => true,
+            };
+            let forwarded = if relay { self.prepare_forward(&pending.msg) } else { None };
+            match forwarded {
+                Some(forwarded) => relays.push((forwarded, exclude.into_iter().collect())),
+                None => self.stats.broadcasts_suppressed += 1,
+            }
         }
+        relays
     }
 
     /// Check if we should process/forward this message.
@@ -59,29 +258,18 @@ impl Router {
             return false;
         }
 
-        // SOS messages get priority - process even if dedup cache is full
-        let is_sos = msg.msg_type == MessageType::SOS;
-
         // Check TTL
         if msg.ttl == 0 {
             return false;
         }
 
-        // Check dedup (SOS bypasses when cache is full)
+        // Check dedup
         if self.seen.contains(&msg.msg_id) {
             return false;
         }
 
-        // If cache is full and not SOS, reject
-        if !is_sos && self.seen.len() >= MAX_SEEN_CACHE {
-            self.cleanup();
-            if self.seen.len() >= MAX_SEEN_CACHE {
-                return false;
-            }
-        }
-
-        // Mark as seen
-        self.mark_seen(msg.msg_id);
+        // Mark as seen (a full cache forgets its oldest IDs rather than refusing new ones)
+        self.seen.insert(msg.msg_id);
 
         // Track this sender as a unique node
         self.all_nodes_seen.insert(msg.sender_id);
@@ -98,36 +286,23 @@ impl Router {
         true
     }
 
-    /// Record hop information from a message's TTL.
+    /// Record hop information from the message header.
     fn record_hops(&mut self, msg: &MeshMessage) {
-        // Estimate original TTL based on message type
-        let original_ttl: u8 = match msg.msg_type {
-            MessageType::Text => 10,
-            MessageType::PublicBroadcast => 50,
-            MessageType::SOS => 255,
-            MessageType::ProfileUpdate => 3,
-            MessageType::Voice => 10,
-            MessageType::VoiceStream | MessageType::CallStart | MessageType::CallEnd => 2,
-            MessageType::FileOffer | MessageType::FileChunk | MessageType::FileAccept => 10,
-            MessageType::ReadReceipt | MessageType::GroupMessage | MessageType::Disappearing => 10,
-            MessageType::TypingStart | MessageType::TypingStop => 1,
-            MessageType::CheckIn | MessageType::Triage | MessageType::ResourceReq => 50,
-            MessageType::GroupJoin | MessageType::GroupLeave => 10,
-            _ => return, // Don't track hops for Discovery/Ping/Pong
+        let Some(hops) = msg.hops() else {
+            return;
         };
-
-        let hops = original_ttl.saturating_sub(msg.ttl) as u64;
         if hops > 0 {
-            self.stats.total_hops_observed += hops;
+            self.stats.total_hops_observed += hops as u64;
             self.stats.hop_count_samples += 1;
         }
     }
 
-    /// Prepare a message for forwarding: decrement TTL, check if still valid.
+    /// Prepare a message for forwarding: decrement TTL, count the hop, check if still valid.
     /// Returns a cloned message with decremented TTL, or None if TTL expired.
     pub fn prepare_forward(&mut self, msg: &MeshMessage) -> Option<MeshMessage> {
         let mut forwarded = msg.clone();
         if forwarded.decrement_ttl() {
+            forwarded.hop_count = forwarded.hop_count.saturating_add(1);
             self.stats.messages_relayed += 1;
             Some(forwarded)
         } else {
@@ -151,27 +326,9 @@ impl Router {
         }
     }
 
-    fn mark_seen(&mut self, msg_id: MessageId) {
-        self.seen.insert(msg_id);
-        self.seen_times.push((msg_id, Instant::now()));
-
-        // Periodic cleanup
-        if self.seen_times.len() > MAX_SEEN_CACHE {
-            self.cleanup();
-        }
-    }
-
-    /// Remove expired entries from the seen cache.
+    /// Drop expired entries from the seen cache.
     pub fn cleanup(&mut self) {
-        let now = Instant::now();
-        self.seen_times.retain(|(id, time)| {
-            if now.duration_since(*time) > SEEN_EXPIRY {
-                self.seen.remove(id);
-                false
-            } else {
-                true
-            }
-        });
+        self.seen.expire();
     }
 
     pub fn seen_count(&self) -> usize {
@@ -184,37 +341,223 @@ impl Router {
 // ---------------------------------------------------------------------------
 
 const ROUTE_EXPIRY: Duration = Duration::from_secs(120);
+/// How long to wait for a route reply before retrying with a wider ring.
+const RREQ_TIMEOUT: Duration = Duration::from_secs(2);
+/// TTL of each successive route request: a nearby ring first, then the mesh.
+const RREQ_TTLS: [u8; 3] = [3, 6, 10];
+/// Messages held per destination while its route is being discovered.
+const MAX_AWAITING_ROUTE: usize = 64;
+/// A relayed path must beat a direct link by this much ETX before traffic
+/// for a neighbor detours through it, so routes don't flap on noise.
+const DETOUR_MARGIN: f32 = 0.5;
 
 struct RouteEntry {
     next_hop: [u8; 32],
     hop_count: u8,
+    /// Destination sequence number this route was learned with (0 = unknown).
+    seq: u32,
     last_updated: Instant,
 }
 
-/// Routing table: tracks best next-hop for each known destination.
+/// A route discovery in progress and the messages waiting on it.
+struct Discovery {
+    attempt: usize,
+    sent_at: Instant,
+    queued: Vec<MeshMessage>,
+}
+
+/// Outcome of handing a directed message to [`RoutingTable::await_route`].
+pub enum RouteWait {
+    /// Parked behind a discovery that is already running.
+    Queued,
+    /// Parked; flood this route request to start the discovery.
+    Request(MeshMessage),
+    /// Too many messages are already waiting; send this one without a route.
+    Full,
+}
+
+/// Progress reported by [`RoutingTable::poll_discoveries`].
+pub enum DiscoveryStep {
+    /// No reply yet; flood this wider route request.
+    Retry(MeshMessage),
+    /// Every attempt went unanswered. The parked messages are handed back.
+    Failed {
+        target: [u8; 32],
+        queued: Vec<MeshMessage>,
+    },
+}
+
+/// Routing table: tracks best next-hop for each known destination, and
+/// on-demand (AODV-style) discoveries for destinations with no route.
 pub struct RoutingTable {
     routes: HashMap<[u8; 32], RouteEntry>,
+    /// Measured ETX of each neighbor link (unknown links count as perfect).
+    link_etx: HashMap<[u8; 32], f32>,
+    discoveries: HashMap<[u8; 32], Discovery>,
+    /// Our own sequence number, bumped for every request and reply we originate.
+    own_seq: u32,
+}
+
+impl Default for RoutingTable {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl RoutingTable {
     pub fn new() -> Self {
-        Self { routes: HashMap::new() }
+        Self {
+            routes: HashMap::new(),
+            link_etx: HashMap::new(),
+            discoveries: HashMap::new(),
+            own_seq: 0,
+        }
+    }
+
+    /// Record the measured ETX of the link to a neighbor.
+    pub fn set_link_etx(&mut self, neighbor: [u8; 32], etx: f32) {
+        self.link_etx.insert(neighbor, etx);
+    }
+
+    /// Expected transmissions to reach a destination `hop_count` hops away
+    /// through neighbor `via`: the first link as measured, every further
+    /// hop (which we can't see) as a perfect one.
+    pub fn route_cost(&self, via: &[u8; 32], hop_count: u8) -> f32 {
+        let first = self.link_etx.get(via).copied().unwrap_or(1.0);
+        first + hop_count.saturating_sub(1) as f32
     }
 
     /// Update routing table with information from a received message.
     /// The sender is `via` (direct peer), the message originated from `origin` with `hop_count` hops.
     pub fn update_route(&mut self, origin: [u8; 32], via: [u8; 32], hop_count: u8) {
-        let entry = self.routes.entry(origin).or_insert(RouteEntry {
+        let cost = self.route_cost(&via, hop_count);
+        let current = self.routes.get(&origin).map(|e| (self.route_cost(&e.next_hop, e.hop_count), e.last_updated));
+        // Update if cheaper path, same cost refreshed, or the old route expired
+        let better = match current {
+            Some((old_cost, updated)) => cost <= old_cost || updated.elapsed() > ROUTE_EXPIRY,
+            None => true,
+        };
+        if better {
+            let entry = self.routes.entry(origin).or_insert(RouteEntry {
+                next_hop: via,
+                hop_count,
+                seq: 0,
+                last_updated: Instant::now(),
+            });
+            entry.next_hop = via;
+            entry.hop_count = hop_count;
+            entry.last_updated = Instant::now();
+        }
+    }
+
+    /// Update a route from a request or reply that carries the origin's
+    /// sequence number. A newer sequence number always wins, even over a
+    /// cheaper path; an older one is stale and ignored.
+    pub fn update_route_seq(&mut self, origin: [u8; 32], via: [u8; 32], hop_count: u8, seq: u32) {
+        if let Some(entry) = self.routes.get(&origin) {
+            let live = entry.last_updated.elapsed() < ROUTE_EXPIRY;
+            let costlier = self.route_cost(&via, hop_count) > self.route_cost(&entry.next_hop, entry.hop_count);
+            if live && (seq < entry.seq || (seq == entry.seq && costlier)) {
+                return;
+            }
+        }
+        self.routes.insert(origin, RouteEntry {
             next_hop: via,
             hop_count,
+            seq,
             last_updated: Instant::now(),
         });
-        // Update if shorter path or same path refreshed
-        if hop_count <= entry.hop_count || entry.last_updated.elapsed() > ROUTE_EXPIRY {
-            entry.next_hop = via;
-            entry.hop_count = hop_count;
-            entry.last_updated = Instant::now();
+    }
+
+    /// Hop count of the current route to a destination.
+    pub fn hops_to(&self, dest: &[u8; 32]) -> Option<u8> {
+        self.routes.get(dest)
+            .filter(|e| e.last_updated.elapsed() < ROUTE_EXPIRY)
+            .map(|e| e.hop_count)
+    }
+
+    /// Bump and return our own sequence number.
+    pub fn next_seq(&mut self) -> u32 {
+        self.own_seq = self.own_seq.wrapping_add(1).max(1);
+        self.own_seq
+    }
+
+    /// Sequence number to answer a route request with: never older than what
+    /// the requester already knows about us.
+    pub fn reply_seq(&mut self, requested: u32) -> u32 {
+        self.own_seq = self.own_seq.max(requested);
+        self.next_seq()
+    }
+
+    /// Start discovering a route to `target` unless one is already underway.
+    /// Returns the route request to flood.
+    pub fn discover(&mut self, origin: [u8; 32], target: [u8; 32]) -> Option<MeshMessage> {
+        if self.discoveries.contains_key(&target) {
+            return None;
         }
+        self.discoveries.insert(target, Discovery {
+            attempt: 0,
+            sent_at: Instant::now(),
+            queued: Vec::new(),
+        });
+        Some(self.route_request(origin, target, RREQ_TTLS[0]))
+    }
+
+    /// Park a directed message we originated until its destination has a route.
+    pub fn await_route(&mut self, msg: &MeshMessage) -> RouteWait {
+        let Some(target) = msg.destination else {
+            return RouteWait::Full;
+        };
+        let request = self.discover(msg.sender_id, target);
+        let discovery = self.discoveries.get_mut(&target).expect("discovery just ensured");
+        if discovery.queued.len() >= MAX_AWAITING_ROUTE {
+            return RouteWait::Full;
+        }
+        discovery.queued.push(msg.clone());
+        match request {
+            Some(rreq) => RouteWait::Request(rreq),
+            None => RouteWait::Queued,
+        }
+    }
+
+    /// Whether a route request for `target` is still waiting on a reply.
+    pub fn is_discovering(&self, target: &[u8; 32]) -> bool {
+        self.discoveries.contains_key(target)
+    }
+
+    /// A route reply for `target` arrived: end its discovery and return the
+    /// messages that were waiting for it. None if we weren't looking.
+    pub fn route_found(&mut self, target: &[u8; 32]) -> Option<Vec<MeshMessage>> {
+        self.discoveries.remove(target).map(|d| d.queued)
+    }
+
+    /// Retry or give up on discoveries whose reply is overdue.
+    pub fn poll_discoveries(&mut self, origin: [u8; 32]) -> Vec<DiscoveryStep> {
+        let overdue: Vec<[u8; 32]> = self.discoveries.iter()
+            .filter(|(_, d)| d.sent_at.elapsed() >= RREQ_TIMEOUT)
+            .map(|(target, _)| *target)
+            .collect();
+        let mut steps = Vec::new();
+        for target in overdue {
+            let attempt = self.discoveries[&target].attempt + 1;
+            if attempt < RREQ_TTLS.len() {
+                let rreq = self.route_request(origin, target, RREQ_TTLS[attempt]);
+                if let Some(d) = self.discoveries.get_mut(&target) {
+                    d.attempt = attempt;
+                    d.sent_at = Instant::now();
+                }
+                steps.push(DiscoveryStep::Retry(rreq));
+            } else if let Some(d) = self.discoveries.remove(&target) {
+                steps.push(DiscoveryStep::Failed { target, queued: d.queued });
+            }
+        }
+        steps
+    }
+
+    fn route_request(&mut self, origin: [u8; 32], target: [u8; 32], ttl: u8) -> MeshMessage {
+        let target_seq = self.routes.get(&target).map(|e| e.seq).unwrap_or(0);
+        let rreq = RouteRequestPayload { target, origin_seq: self.next_seq(), target_seq };
+        rreq.to_message(origin, ttl)
     }
 
     /// Look up the best next hop for a destination.
@@ -228,6 +571,38 @@ impl RoutingTable {
         })
     }
 
+    /// Pick the neighbor a directed message for `dest` should go to: the
+    /// destination itself when it is a neighbor (unless its link is so lossy
+    /// that a learned relayed path is clearly cheaper), else the learned next
+    /// hop if that link is still up. None means no route is known and the
+    /// caller floods.
+    pub fn next_hop(&self, dest: &[u8; 32], is_neighbor: impl Fn(&[u8; 32]) -> bool) -> Option<[u8; 32]> {
+        let learned = self.routes.get(dest)
+            .filter(|e| e.last_updated.elapsed() < ROUTE_EXPIRY && is_neighbor(&e.next_hop));
+        if is_neighbor(dest) {
+            let direct = self.route_cost(dest, 1);
+            let detour = learned
+                .filter(|e| e.next_hop != *dest)
+                .filter(|e| self.route_cost(&e.next_hop, e.hop_count) + DETOUR_MARGIN < direct);
+            return Some(detour.map(|e| e.next_hop).unwrap_or(*dest));
+        }
+        learned.map(|e| e.next_hop)
+    }
+
+    /// Destinations with an unexpired route.
+    pub fn destinations(&self) -> Vec<[u8; 32]> {
+        self.routes.iter()
+            .filter(|(_, e)| e.last_updated.elapsed() < ROUTE_EXPIRY)
+            .map(|(dest, _)| *dest)
+            .collect()
+    }
+
+    /// Forget every route through a neighbor whose link went down.
+    pub fn remove_via(&mut self, next_hop: &[u8; 32]) {
+        self.link_etx.remove(next_hop);
+        self.routes.retain(|_, e| e.next_hop != *next_hop);
+    }
+
     /// Remove expired routes.
     pub fn cleanup(&mut self) {
         self.routes.retain(|_, e| e.last_updated.elapsed() < ROUTE_EXPIRY);
@@ -346,6 +721,200 @@ mod tests {
         assert_eq!(router.stats.messages_received, 2); // Both are broadcasts
     }
 
+    #[test]
+    fn test_routing_table_next_hop() {
+        let (a, b, c, far) = ([2u8; 32], [3u8; 32], [4u8; 32], [9u8; 32]);
+        let neighbors = [a, b];
+        let is_neighbor = |id: &[u8; 32]| neighbors.contains(id);
+
+        let mut table = RoutingTable::new();
+        assert_eq!(table.next_hop(&far, is_neighbor), None);
+        assert_eq!(table.next_hop(&a, is_neighbor), Some(a));
+
+        // Longer path first, then a shorter one replaces it
+        table.update_route(far, a, 4);
+        assert_eq!(table.next_hop(&far, is_neighbor), Some(a));
+        table.update_route(far, b, 2);
+        table.update_route(far, a, 3);
+        assert_eq!(table.next_hop(&far, is_neighbor), Some(b));
+
+        // A next hop that is no longer connected is not used
+        table.update_route([8u8; 32], c, 1);
+        assert_eq!(table.next_hop(&[8u8; 32], is_neighbor), None);
+
+        table.remove_via(&b);
+        assert_eq!(table.next_hop(&far, is_neighbor), None);
+    }
+
+    #[test]
+    fn test_routing_prefers_good_links() {
+        let (good, lossy, far) = ([2u8; 32], [3u8; 32], [9u8; 32]);
+        let neighbors = [good, lossy];
+        let is_neighbor = |id: &[u8; 32]| neighbors.contains(id);
+        let mut table = RoutingTable::new();
+        table.set_link_etx(good, 1.1);
+        table.set_link_etx(lossy, 4.0);
+
+        // Three clean hops beat two over a link that loses most frames
+        table.update_route(far, good, 3);
+        table.update_route(far, lossy, 2);
+        assert_eq!(table.next_hop(&far, is_neighbor), Some(good));
+        assert!(table.route_cost(&good, 3) < table.route_cost(&lossy, 2));
+
+        // Even a direct neighbor is reached through the clean link when it's much cheaper...
+        table.update_route(lossy, good, 2);
+        assert_eq!(table.next_hop(&lossy, is_neighbor), Some(good));
+        // ...but not for a marginal gain
+        table.set_link_etx(lossy, 2.3);
+        assert_eq!(table.next_hop(&lossy, is_neighbor), Some(lossy));
+    }
+
+    #[test]
+    fn test_route_sequence_numbers() {
+        let (a, b, far) = ([2u8; 32], [3u8; 32], [9u8; 32]);
+        let is_neighbor = |id: &[u8; 32]| *id == a || *id == b;
+        let mut table = RoutingTable::new();
+
+        table.update_route_seq(far, a, 2, 5);
+        // A shorter path with an older sequence number is stale
+        table.update_route_seq(far, b, 1, 4);
+        assert_eq!(table.next_hop(&far, is_neighbor), Some(a));
+        // A fresher one wins even if it is longer
+        table.update_route_seq(far, b, 3, 6);
+        assert_eq!(table.next_hop(&far, is_neighbor), Some(b));
+        assert_eq!(table.hops_to(&far), Some(3));
+
+        // Replies never go backwards from what the requester knows
+        assert_eq!(table.reply_seq(40), 41);
+        assert_eq!(table.next_seq(), 42);
+    }
+
+    #[test]
+    fn test_route_discovery_queue() {
+        let (us, far) = ([1u8; 32], [9u8; 32]);
+        let mut table = RoutingTable::new();
+        let dm = |text: &str| MeshMessage::new(MessageType::Text, us, 10, Some(far), text.as_bytes().to_vec());
+
+        let rreq = match table.await_route(&dm("one")) {
+            RouteWait::Request(rreq) => rreq,
+            _ => panic!("first message should start a discovery"),
+        };
+        assert_eq!(rreq.msg_type, MessageType::RouteRequest);
+        assert_eq!(rreq.destination, None);
+        assert_eq!(RouteRequestPayload::from_message(&rreq).unwrap().target, far);
+        assert!(matches!(table.await_route(&dm("two")), RouteWait::Queued));
+        assert!(table.discover(us, far).is_none());
+        assert!(table.poll_discoveries(us).is_empty());
+
+        let queued = table.route_found(&far).unwrap();
+        assert_eq!(queued.len(), 2);
+        assert_eq!(queued[1].payload, b"two");
+        assert!(!table.is_discovering(&far));
+        assert!(table.route_found(&far).is_none());
+    }
+
+    #[test]
+    fn test_hop_count_on_forward() {
+        let mut router = Router::new([1u8; 32]);
+        // Sent with a non-default TTL: hops come from the header, not a per-type guess
+        let msg = make_msg([2u8; 32], 4);
+        let once = router.prepare_forward(&msg).unwrap();
+        let twice = router.prepare_forward(&once).unwrap();
+        assert_eq!((twice.ttl, twice.initial_ttl, twice.hop_count), (2, 4, 2));
+
+        assert!(router.should_process(&twice));
+        assert_eq!(router.stats.total_hops_observed, 2);
+        assert_eq!(router.stats.hop_count_samples, 1);
+    }
+
+    #[test]
+    fn test_gossip_flooding() {
+        let mut router = Router::new([1u8; 32]);
+        router.set_flood_strategy(FloodStrategy::Gossip { probability: 0.0 });
+
+        let mut relayed = make_msg([2u8; 32], 5);
+        relayed.hop_count = 2;
+        assert_eq!(router.plan_relay(&relayed, Some([2u8; 32])), RelayPlan::Suppressed);
+        // Straight from the source, and SOS, always go out
+        assert_eq!(router.plan_relay(&make_msg([2u8; 32], 5), Some([2u8; 32])), RelayPlan::Now);
+        let mut sos = MeshMessage::new(MessageType::SOS, [2u8; 32], 255, None, b"help".to_vec());
+        sos.hop_count = 2;
+        assert_eq!(router.plan_relay(&sos, Some([2u8; 32])), RelayPlan::Now);
+        assert_eq!(router.stats.broadcasts_suppressed, 1);
+    }
+
+    #[test]
+    fn test_counter_flooding() {
+        let (a, b, c) = ([2u8; 32], [3u8; 32], [4u8; 32]);
+        let mut router = Router::new([1u8; 32]);
+        router.set_flood_strategy(FloodStrategy::Counter { threshold: 3, max_delay: Duration::ZERO });
+
+        let quiet = make_msg([9u8; 32], 5);
+        let busy = make_msg([8u8; 32], 5);
+        assert_eq!(router.plan_relay(&quiet, Some(a)), RelayPlan::Deferred);
+        assert_eq!(router.plan_relay(&busy, Some(a)), RelayPlan::Deferred);
+        router.heard_again(&quiet.msg_id, Some(b));
+        router.heard_again(&busy.msg_id, Some(b));
+        router.heard_again(&busy.msg_id, Some(c));
+        assert!(router.next_relay_due().is_some());
+
+        let relays = router.due_relays(&[a, b, c]);
+        assert_eq!(relays.len(), 1);
+        let (forwarded, exclude) = &relays[0];
+        assert_eq!(forwarded.msg_id, quiet.msg_id);
+        assert_eq!(forwarded.ttl, 4);
+        assert!(exclude.contains(&a) && exclude.contains(&b) && !exclude.contains(&c));
+        assert_eq!(router.stats.broadcasts_suppressed, 1);
+        assert!(router.next_relay_due().is_none());
+    }
+
+    #[test]
+    fn test_neighbor_coverage_flooding() {
+        let (a, b, c) = ([2u8; 32], [3u8; 32], [4u8; 32]);
+        let mut router = Router::new([1u8; 32]);
+        router.set_flood_strategy(FloodStrategy::NeighborCoverage { max_delay: Duration::ZERO });
+        router.set_neighbor_set(a, [b].into_iter().collect());
+
+        // Everyone we reach already heard it from `a`
+        let covered = make_msg([9u8; 32], 5);
+        router.plan_relay(&covered, Some(a));
+        assert!(router.due_relays(&[a, b]).is_empty());
+
+        // `c` is out of `a`'s reach, so we relay, but only towards it
+        let partial = make_msg([9u8; 32], 5);
+        router.plan_relay(&partial, Some(a));
+        let relays = router.due_relays(&[a, b, c]);
+        assert_eq!(relays.len(), 1);
+        assert!(!relays[0].1.contains(&c));
+    }
+
+    #[test]
+    fn test_seen_cache_under_flood() {
+        let mut router = Router::new([1u8; 32]);
+        let recent = make_msg([2u8; 32], 5);
+
+        // Far more unique spam than the cache holds
+        for _ in 0..(MAX_SEEN_CACHE * 2) {
+            assert!(router.should_process(&make_msg([3u8; 32], 5)));
+        }
+        assert!(router.seen_count() <= MAX_SEEN_CACHE);
+
+        // Legitimate traffic still gets through, and is still deduplicated
+        assert!(router.should_process(&recent));
+        assert!(!router.should_process(&recent));
+    }
+
+    #[test]
+    fn test_seen_cache_expiry() {
+        let mut cache = SeenCache::new(Duration::from_millis(50), 100);
+        cache.insert([7u8; 32]);
+        assert!(cache.contains(&[7u8; 32]));
+        std::thread::sleep(Duration::from_millis(60));
+        cache.expire();
+        assert!(!cache.contains(&[7u8; 32]));
+        assert_eq!(cache.len(), 0);
+    }
+
     #[test]
     fn test_sos_priority() {
         let our_id = [1u8; 32];
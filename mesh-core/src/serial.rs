@@ -0,0 +1,597 @@
+//! Serial-line transport for packet radios (LoRa modems, VHF TNCs).
+//!
+//! Messages are split into fragments that fit the radio's frame size, and
+//! each fragment goes out as one KISS (or bare SLIP) frame. The link is
+//! treated as point-to-point: one radio on each end, as in a bridge between
+//! two sites.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, watch};
+use tokio_serial::SerialPortBuilderExt;
+use tracing::{info, debug, warn};
+use anyhow::{Context, Result};
+
+use crate::message::MeshMessage;
+use crate::transport::{
+    accept_framed_link, BoxFuture, InboundConnection, IncomingMessage, LinkAddr, LinkRead, LinkWrite,
+    Reliability, Transport,
+};
+
+const FEND: u8 = 0xC0;
+const FESC: u8 = 0xDB;
+const TFEND: u8 = 0xDC;
+const TFESC: u8 = 0xDD;
+/// KISS command byte for a data frame on TNC port 0.
+const KISS_DATA: u8 = 0x00;
+
+/// Fragment header: flags, message sequence, fragment index, fragment count.
+const FRAGMENT_HEADER: usize = 4;
+const FLAG_COMPACT: u8 = 0x01;
+const MAX_FRAGMENTS: usize = u8::MAX as usize;
+/// Give up on a message whose fragments stop arriving.
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_PARTIAL_MESSAGES: usize = 16;
+/// A duty-cycle limited radio may save up the airtime it earns over this long.
+const DUTY_CYCLE_BURST: Duration = Duration::from_secs(60);
+const REOPEN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How frames are delimited on the serial line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// KISS, as spoken by TNCs and most LoRa modems: SLIP plus a command byte.
+    Kiss,
+    /// Plain SLIP (RFC 1055).
+    Slip,
+}
+
+/// Settings for a serial-attached radio.
+#[derive(Debug, Clone)]
+pub struct SerialConfig {
+    /// Device path, e.g. `/dev/ttyUSB0` or `COM3`.
+    pub path: String,
+    pub baud: u32,
+    pub framing: Framing,
+    /// Largest frame the radio sends in one go, fragment header included.
+    pub frame_mtu: usize,
+    /// Send messages with the compact header.
+    pub compact: bool,
+    /// On-air bit rate, to work out airtime; `None` uses the serial baud rate.
+    pub air_bitrate: Option<u32>,
+    /// Share of time the radio may transmit, e.g. 0.01 for the 1% limit on
+    /// EU 868 MHz. 1.0 means no limit.
+    pub duty_cycle: f32,
+}
+
+impl Default for SerialConfig {
+    fn default() -> Self {
+        Self {
+            path: String::new(),
+            baud: 9600,
+            framing: Framing::Kiss,
+            frame_mtu: 255,
+            compact: true,
+            air_bitrate: None,
+            duty_cycle: 1.0,
+        }
+    }
+}
+
+/// Mesh traffic over a serial-attached radio.
+pub struct SerialTransport {
+    config: SerialConfig,
+}
+
+impl SerialTransport {
+    pub fn new(config: SerialConfig) -> Self {
+        Self { config }
+    }
+}
+
+fn open_port(config: &SerialConfig) -> Result<tokio_serial::SerialStream> {
+    tokio_serial::new(&config.path, config.baud)
+        .open_native_async()
+        .with_context(|| format!("Failed to open serial port {}", config.path))
+}
+
+impl Transport for SerialTransport {
+    fn name(&self) -> &'static str {
+        "serial"
+    }
+
+    fn mtu(&self) -> usize {
+        // Zero for a frame too small to carry any data; `listen` refuses those
+        self.config.frame_mtu.saturating_sub(FRAGMENT_HEADER) * MAX_FRAGMENTS
+    }
+
+    fn reliability(&self) -> Reliability {
+        Reliability { reliable: false, ordered: true }
+    }
+
+    /// At radio speeds a full class can take minutes to drain; a neighbor
+    /// that is really gone still times out by going quiet.
+    fn congestion_timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Opens the port and runs the link on it, reopening it if the radio
+    /// goes away.
+    fn listen(
+        &self,
+        incoming_tx: mpsc::Sender<IncomingMessage>,
+        inbound_conn_tx: mpsc::Sender<InboundConnection>,
+        shutdown: watch::Receiver<bool>,
+    ) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            if self.config.frame_mtu <= FRAGMENT_HEADER {
+                anyhow::bail!("Serial frame MTU {} is too small", self.config.frame_mtu);
+            }
+            let mut port = Some(open_port(&self.config)?);
+            info!("Serial transport on {} at {} baud", self.config.path, self.config.baud);
+
+            let config = self.config.clone();
+            let mut shutdown_rx = shutdown;
+            tokio::spawn(async move {
+                loop {
+                    let stream = match port.take() {
+                        Some(stream) => Some(stream),
+                        None => match open_port(&config) {
+                            Ok(stream) => Some(stream),
+                            Err(e) => {
+                                debug!("{:#}", e);
+                                None
+                            }
+                        },
+                    };
+                    if let Some(stream) = stream {
+                        let (reader, writer) = serial_link(&config, stream);
+                        let addr = LinkAddr::Named(config.path.clone());
+                        tokio::select! {
+                            _ = accept_framed_link(reader, writer, addr, "serial", false, incoming_tx.clone(), inbound_conn_tx.clone()) => {
+                                warn!("Serial link on {} ended", config.path);
+                            }
+                            _ = shutdown_rx.changed() => break,
+                        }
+                    }
+                    tokio::select! {
+                        _ = tokio::time::sleep(REOPEN_INTERVAL) => {}
+                        _ = shutdown_rx.changed() => break,
+                    }
+                }
+                info!("Serial transport on {} shutting down", config.path);
+            });
+            Ok(())
+        })
+    }
+}
+
+fn serial_link<S>(config: &SerialConfig, stream: S) -> (impl LinkRead, impl LinkWrite)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (read_half, write_half) = tokio::io::split(stream);
+    let reader = SerialRead {
+        inner: read_half,
+        path: config.path.clone(),
+        buf: Vec::new(),
+        pos: 0,
+        deframer: Deframer::new(config.framing, config.frame_mtu),
+        reassembler: Reassembler::default(),
+    };
+    let writer = SerialWrite {
+        inner: write_half,
+        config: config.clone(),
+        seq: 0,
+        duty: DutyCycle::new(config.duty_cycle),
+        bitrate: config.air_bitrate.unwrap_or(config.baud).max(1),
+    };
+    (reader, writer)
+}
+
+struct SerialRead<R> {
+    inner: R,
+    path: String,
+    /// Bytes read from the port but not yet deframed.
+    buf: Vec<u8>,
+    pos: usize,
+    deframer: Deframer,
+    reassembler: Reassembler,
+}
+
+impl<R: AsyncRead + Unpin + Send + 'static> LinkRead for SerialRead<R> {
+    fn read(&mut self) -> BoxFuture<'_, Result<Option<MeshMessage>>> {
+        Box::pin(async move {
+            loop {
+                if self.pos == self.buf.len() {
+                    self.buf.resize(1024, 0);
+                    let n = self.inner.read(&mut self.buf).await?;
+                    if n == 0 {
+                        return Ok(None);
+                    }
+                    self.buf.truncate(n);
+                    self.pos = 0;
+                }
+                while self.pos < self.buf.len() {
+                    let byte = self.buf[self.pos];
+                    self.pos += 1;
+                    let Some(frame) = self.deframer.push(byte) else { continue };
+                    let Some((compact, data)) = self.reassembler.push(&frame, Instant::now()) else { continue };
+                    let decoded = if compact {
+                        MeshMessage::from_compact_bytes(&data)
+                    } else {
+                        MeshMessage::from_bytes(&data)
+                    };
+                    match decoded {
+                        Ok(msg) => return Ok(Some(msg)),
+                        Err(e) => warn!("Serial {} invalid message: {}", self.path, e),
+                    }
+                }
+            }
+        })
+    }
+}
+
+struct SerialWrite<W> {
+    inner: W,
+    config: SerialConfig,
+    seq: u8,
+    duty: DutyCycle,
+    bitrate: u32,
+}
+
+impl<W: AsyncWrite + Unpin + Send + 'static> LinkWrite for SerialWrite<W> {
+    fn write<'a>(&'a mut self, msg: &'a MeshMessage) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let data = if self.config.compact { msg.to_compact_bytes() } else { msg.to_bytes() };
+            self.seq = self.seq.wrapping_add(1);
+            let Some(fragments) = fragment(&data, self.config.compact, self.seq, self.config.frame_mtu) else {
+                warn!("Serial {}: {} byte {:?} message is too large for the link, dropped",
+                    self.config.path, data.len(), msg.msg_type);
+                return Ok(());
+            };
+            for piece in fragments {
+                let frame = encode_frame(self.config.framing, &piece);
+                let airtime = Duration::from_secs_f64(frame.len() as f64 * 8.0 / self.bitrate as f64);
+                let wait = self.duty.wait_for(airtime, Instant::now());
+                if !wait.is_zero() {
+                    debug!("Serial {}: waiting {:?} for duty cycle", self.config.path, wait);
+                    tokio::time::sleep(wait).await;
+                }
+                self.inner.write_all(&frame).await?;
+            }
+            self.inner.flush().await?;
+            Ok(())
+        })
+    }
+}
+
+/// Wrap one frame for the line, escaping the delimiter bytes.
+fn encode_frame(framing: Framing, data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + 8);
+    out.push(FEND);
+    if framing == Framing::Kiss {
+        out.push(KISS_DATA);
+    }
+    for &b in data {
+        match b {
+            FEND => out.extend_from_slice(&[FESC, TFEND]),
+            FESC => out.extend_from_slice(&[FESC, TFESC]),
+            _ => out.push(b),
+        }
+    }
+    out.push(FEND);
+    out
+}
+
+/// Splits the byte stream from the port back into frames.
+struct Deframer {
+    framing: Framing,
+    max_len: usize,
+    frame: Vec<u8>,
+    escaped: bool,
+    /// Set when a frame overran `max_len`; it is discarded at the next FEND.
+    overrun: bool,
+}
+
+impl Deframer {
+    fn new(framing: Framing, frame_mtu: usize) -> Self {
+        Self { framing, max_len: frame_mtu + 1, frame: Vec::new(), escaped: false, overrun: false }
+    }
+
+    fn push(&mut self, byte: u8) -> Option<Vec<u8>> {
+        if byte == FEND {
+            let frame = std::mem::take(&mut self.frame);
+            let overrun = std::mem::replace(&mut self.overrun, false);
+            self.escaped = false;
+            if overrun || frame.is_empty() {
+                return None;
+            }
+            return match self.framing {
+                Framing::Slip => Some(frame),
+                // Only data frames; the TNC may also report on other commands
+                Framing::Kiss if frame[0] & 0x0F == KISS_DATA => Some(frame[1..].to_vec()),
+                Framing::Kiss => None,
+            };
+        }
+        let byte = if self.escaped {
+            self.escaped = false;
+            match byte {
+                TFEND => FEND,
+                TFESC => FESC,
+                other => other,
+            }
+        } else if byte == FESC {
+            self.escaped = true;
+            return None;
+        } else {
+            byte
+        };
+        if self.frame.len() < self.max_len {
+            self.frame.push(byte);
+        } else {
+            self.overrun = true;
+        }
+        None
+    }
+}
+
+/// Cut an encoded message into frames of at most `frame_mtu` bytes.
+/// `None` if it would take more fragments than the header can number, or
+/// a frame has no room for data after the header.
+fn fragment(data: &[u8], compact: bool, seq: u8, frame_mtu: usize) -> Option<Vec<Vec<u8>>> {
+    let piece_len = frame_mtu.checked_sub(FRAGMENT_HEADER).filter(|&n| n > 0)?;
+    let count = data.len().div_ceil(piece_len);
+    if count > MAX_FRAGMENTS {
+        return None;
+    }
+    let flags = if compact { FLAG_COMPACT } else { 0 };
+    Some(data.chunks(piece_len).enumerate().map(|(i, piece)| {
+        let mut frame = Vec::with_capacity(FRAGMENT_HEADER + piece.len());
+        frame.extend_from_slice(&[flags, seq, i as u8, count as u8]);
+        frame.extend_from_slice(piece);
+        frame
+    }).collect())
+}
+
+struct Partial {
+    compact: bool,
+    pieces: Vec<Option<Vec<u8>>>,
+    started: Instant,
+}
+
+/// Collects fragments until a message is whole.
+#[derive(Default)]
+struct Reassembler {
+    partial: HashMap<u8, Partial>,
+}
+
+impl Reassembler {
+    /// Add a fragment; returns the message (and whether it uses the compact
+    /// header) once its last missing fragment arrives.
+    fn push(&mut self, frame: &[u8], now: Instant) -> Option<(bool, Vec<u8>)> {
+        self.partial.retain(|_, p| now.duration_since(p.started) < REASSEMBLY_TIMEOUT);
+        let [flags, seq, index, count] = *frame.first_chunk::<FRAGMENT_HEADER>()?;
+        let (index, count) = (index as usize, count as usize);
+        if count == 0 || index >= count {
+            return None;
+        }
+        let compact = flags & FLAG_COMPACT != 0;
+        let piece = frame[FRAGMENT_HEADER..].to_vec();
+        if count == 1 {
+            return Some((compact, piece));
+        }
+
+        // A different shape under the same sequence number is a new message
+        if self.partial.get(&seq).is_some_and(|p| p.pieces.len() != count || p.compact != compact) {
+            self.partial.remove(&seq);
+        }
+        if !self.partial.contains_key(&seq) && self.partial.len() >= MAX_PARTIAL_MESSAGES {
+            let oldest = self.partial.iter().min_by_key(|(_, p)| p.started).map(|(s, _)| *s);
+            if let Some(oldest) = oldest {
+                self.partial.remove(&oldest);
+            }
+        }
+        let partial = self.partial.entry(seq).or_insert_with(|| Partial {
+            compact,
+            pieces: vec![None; count],
+            started: now,
+        });
+        partial.pieces[index] = Some(piece);
+        if partial.pieces.iter().any(Option::is_none) {
+            return None;
+        }
+        let done = self.partial.remove(&seq)?;
+        Some((done.compact, done.pieces.into_iter().flatten().flatten().collect()))
+    }
+}
+
+/// Keeps transmissions within a duty cycle. Airtime is earned at `ratio`
+/// seconds per second, banked up to a minute's worth, and spent per frame.
+struct DutyCycle {
+    ratio: f32,
+    budget: Duration,
+    max_budget: Duration,
+    last: Instant,
+}
+
+impl DutyCycle {
+    fn new(ratio: f32) -> Self {
+        let ratio = ratio.clamp(0.0001, 1.0);
+        let max_budget = DUTY_CYCLE_BURST.mul_f32(ratio);
+        Self { ratio, budget: max_budget, max_budget, last: Instant::now() }
+    }
+
+    /// How long to wait before sending a frame that takes `airtime` on air.
+    fn wait_for(&mut self, airtime: Duration, now: Instant) -> Duration {
+        if self.ratio >= 1.0 {
+            return Duration::ZERO;
+        }
+        let earned = now.saturating_duration_since(self.last).mul_f32(self.ratio);
+        self.budget = (self.budget + earned).min(self.max_budget);
+        self.last = self.last.max(now);
+        if self.budget >= airtime {
+            self.budget -= airtime;
+            return Duration::ZERO;
+        }
+        let wait = (airtime - self.budget).div_f32(self.ratio);
+        self.budget = Duration::ZERO;
+        self.last = now + wait;
+        wait
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::MessageType;
+
+    fn deframe(framing: Framing, bytes: &[u8]) -> Vec<Vec<u8>> {
+        let mut deframer = Deframer::new(framing, 255);
+        bytes.iter().filter_map(|&b| deframer.push(b)).collect()
+    }
+
+    #[test]
+    fn test_kiss_and_slip_escaping() {
+        let data = [0x01, FEND, 0x02, FESC, FESC, TFEND, FEND];
+        for framing in [Framing::Kiss, Framing::Slip] {
+            let frame = encode_frame(framing, &data);
+            assert_eq!(frame.iter().filter(|&&b| b == FEND).count(), 2);
+            // Line noise and empty frames between frames are ignored
+            let mut line = vec![FEND, FEND];
+            line.extend_from_slice(&frame);
+            line.extend_from_slice(&frame);
+            assert_eq!(deframe(framing, &line), vec![data.to_vec(), data.to_vec()]);
+        }
+
+        // KISS frames for other TNC commands (here: TXDELAY) are not data
+        assert!(deframe(Framing::Kiss, &[FEND, 0x01, 50, FEND]).is_empty());
+        // A frame longer than the MTU is dropped, and the next one still comes through
+        let mut line = vec![FEND, KISS_DATA];
+        line.extend(std::iter::repeat_n(7u8, 400));
+        line.extend_from_slice(&encode_frame(Framing::Kiss, b"ok"));
+        assert_eq!(deframe(Framing::Kiss, &line), vec![b"ok".to_vec()]);
+    }
+
+    #[test]
+    fn test_fragmentation_and_reassembly() {
+        let data: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        let frames = fragment(&data, true, 9, 100).unwrap();
+        assert_eq!(frames.len(), 11);
+        assert!(frames.iter().all(|f| f.len() <= 100));
+
+        // Fragments of two messages interleaved, one of them out of order
+        let other = fragment(b"short one", false, 10, 100).unwrap();
+        let now = Instant::now();
+        let mut r = Reassembler::default();
+        for f in frames.iter().rev().skip(1) {
+            assert!(r.push(f, now).is_none());
+        }
+        assert_eq!(r.push(&other[0], now), Some((false, b"short one".to_vec())));
+        assert_eq!(r.push(&frames[10], now), Some((true, data.clone())));
+
+        // A message missing a fragment is abandoned after a while
+        for f in &frames[1..] {
+            r.push(f, now);
+        }
+        assert!(r.push(&frames[0], now + REASSEMBLY_TIMEOUT).is_none());
+        assert_eq!(r.partial[&9].pieces.iter().flatten().count(), 1);
+
+        assert!(fragment(&vec![0u8; 96 * 256], true, 0, 100).is_none());
+        // Frames with no room past the header carry nothing
+        assert!(fragment(b"data", true, 0, FRAGMENT_HEADER).is_none());
+        assert!(fragment(b"data", true, 0, 2).is_none());
+        let tiny = SerialTransport::new(SerialConfig { frame_mtu: 2, ..Default::default() });
+        assert_eq!(tiny.mtu(), 0);
+    }
+
+    #[test]
+    fn test_duty_cycle_limits_airtime() {
+        let start = Instant::now();
+        let mut duty = DutyCycle::new(0.01);
+        duty.last = start;
+        // A minute at 1% banks 600 ms of airtime
+        assert_eq!(duty.wait_for(Duration::from_millis(500), start), Duration::ZERO);
+        // The next 200 ms frame is 100 ms short, which takes 10 s to earn
+        let wait = duty.wait_for(Duration::from_millis(200), start);
+        assert!((wait.as_secs_f32() - 10.0).abs() < 0.01, "{:?}", wait);
+        // Right after that wait the budget is empty again
+        let wait = duty.wait_for(Duration::from_millis(100), start + wait);
+        assert!((wait.as_secs_f32() - 10.0).abs() < 0.01, "{:?}", wait);
+
+        let mut unlimited = DutyCycle::new(1.0);
+        assert_eq!(unlimited.wait_for(Duration::from_secs(5), start), Duration::ZERO);
+    }
+
+    /// A raw pseudo-terminal pair; the slave side stands in for a radio's serial
+    /// port. The returned slave handle keeps the pair alive until the test ends.
+    #[cfg(target_os = "linux")]
+    fn pty() -> (std::fs::File, std::fs::File, String) {
+        use std::os::fd::FromRawFd;
+        let (mut master, mut slave) = (0, 0);
+        let rc = unsafe {
+            let mut raw: libc::termios = std::mem::zeroed();
+            libc::cfmakeraw(&mut raw);
+            libc::openpty(&mut master, &mut slave, std::ptr::null_mut(), &raw, std::ptr::null())
+        };
+        assert_eq!(rc, 0, "openpty failed");
+        let path = std::fs::read_link(format!("/proc/self/fd/{}", slave)).unwrap();
+        let (master, slave) = unsafe { (std::fs::File::from_raw_fd(master), std::fs::File::from_raw_fd(slave)) };
+        (master, slave, path.to_string_lossy().into_owned())
+    }
+
+    /// Two radios in range of each other: bytes from one pty come out of the other.
+    #[cfg(target_os = "linux")]
+    fn bridge(a: std::fs::File, b: std::fs::File) {
+        use std::io::{Read, Write};
+        for (mut from, mut to) in [(a.try_clone().unwrap(), b.try_clone().unwrap()), (b, a)] {
+            std::thread::spawn(move || {
+                let mut buf = [0u8; 256];
+                while let Ok(n) = from.read(&mut buf) {
+                    if n == 0 || to.write_all(&buf[..n]).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_serial_link_over_pty() {
+        let (master_a, _slave_a, path_a) = pty();
+        let (master_b, _slave_b, path_b) = pty();
+        bridge(master_a, master_b);
+
+        let (_shutdown_tx, shutdown) = watch::channel(false);
+        let mut ends = Vec::new();
+        for (path, compact) in [(path_a, true), (path_b, false)] {
+            let transport = SerialTransport::new(SerialConfig {
+                path,
+                baud: 115_200,
+                frame_mtu: 64,
+                compact,
+                ..Default::default()
+            });
+            let (in_tx, in_rx) = mpsc::channel(8);
+            let (conn_tx, mut conn_rx) = mpsc::channel(1);
+            transport.listen(in_tx, conn_tx, shutdown.clone()).await.unwrap();
+            let conn = conn_rx.recv().await.unwrap();
+            assert_eq!(conn.transport, "serial");
+            ends.push((conn, in_rx));
+        }
+
+        // Big enough to need many fragments; each side decodes the other's header mode
+        let text = "over the air ".repeat(40);
+        let msg = MeshMessage::text([1u8; 32], &text);
+        ends[0].0.sender.send(msg.clone()).await.unwrap();
+        let got = tokio::time::timeout(Duration::from_secs(5), ends[1].1.recv()).await.unwrap().unwrap();
+        assert_eq!((got.msg.msg_type, got.msg.msg_id), (MessageType::Text, msg.msg_id));
+        assert_eq!(got.msg.payload, text.as_bytes());
+
+        let reply = MeshMessage::text([2u8; 32], "heard you");
+        ends[1].0.sender.send(reply).await.unwrap();
+        let got = tokio::time::timeout(Duration::from_secs(5), ends[0].1.recv()).await.unwrap().unwrap();
+        assert_eq!(got.msg.payload, b"heard you");
+    }
+}
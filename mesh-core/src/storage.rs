@@ -267,6 +267,23 @@ impl MeshStorage {
         Ok(())
     }
 
+    pub fn set_favorite(&self, node_id: &[u8; 32], favorite: bool) -> Result<()> {
+        self.db.execute(
+            "UPDATE contacts SET is_favorite = ?1 WHERE node_id = ?2",
+            params![favorite as i32, node_id.as_slice()],
+        )?;
+        Ok(())
+    }
+
+    /// Record the safety number the user verified with this contact (None to unverify).
+    pub fn set_safety_number(&self, node_id: &[u8; 32], safety_number: Option<&str>) -> Result<()> {
+        self.db.execute(
+            "UPDATE contacts SET safety_number = ?1 WHERE node_id = ?2",
+            params![safety_number, node_id.as_slice()],
+        )?;
+        Ok(())
+    }
+
     pub fn get_contact(&self, node_id: &[u8; 32]) -> Result<Option<Contact>> {
         let mut stmt = self.db.prepare(
             "SELECT node_id, display_name, nickname, bio, first_seen, last_seen, is_favorite, safety_number FROM contacts WHERE node_id = ?1"
@@ -379,6 +396,12 @@ mod tests {
         let c = storage.get_contact(&[3u8; 32]).unwrap().unwrap();
         assert_eq!(c.nickname.as_deref(), Some("Bobby"));
         assert_eq!(c.effective_name(), "Bobby");
+
+        storage.set_favorite(&[3u8; 32], true).unwrap();
+        storage.set_safety_number(&[3u8; 32], Some("12345")).unwrap();
+        let c = storage.get_contact(&[3u8; 32]).unwrap().unwrap();
+        assert!(c.is_favorite);
+        assert_eq!(c.safety_number.as_deref(), Some("12345"));
     }
 
     #[test]
@@ -0,0 +1,279 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::time::{Duration, Instant};
+
+use crate::message::{LinkInfo, LinkStatePayload};
+
+/// How often each node floods its signed neighbor list.
+pub const LINK_STATE_INTERVAL: Duration = Duration::from_secs(30);
+/// Drop a node's neighbor list if it hasn't been refreshed in this long
+/// (the node left, or the mesh split).
+const LINK_STATE_EXPIRY: Duration = Duration::from_secs(120);
+
+/// Quality as reported by the lower and the higher node ID of a link.
+type EdgeReports = (Option<u8>, Option<u8>);
+
+struct Advert {
+    display_name: String,
+    issued_ms: i64,
+    links: Vec<LinkInfo>,
+    received: Instant,
+}
+
+/// Neighbor lists gossiped by other nodes, kept until they go stale.
+#[derive(Default)]
+pub struct TopologyMap {
+    adverts: HashMap<[u8; 32], Advert>,
+}
+
+impl TopologyMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a node's (already verified) neighbor list. Returns false if
+    /// we hold a newer one, e.g. when an old copy arrives by a slow path.
+    pub fn update(&mut self, origin: [u8; 32], ls: LinkStatePayload) -> bool {
+        if self.adverts.get(&origin).is_some_and(|a| a.issued_ms >= ls.issued_ms) {
+            return false;
+        }
+        self.adverts.insert(origin, Advert {
+            display_name: ls.display_name,
+            issued_ms: ls.issued_ms,
+            links: ls.links,
+            received: Instant::now(),
+        });
+        true
+    }
+
+    /// Drop neighbor lists nobody has refreshed. Returns how many went.
+    pub fn expire(&mut self) -> usize {
+        let before = self.adverts.len();
+        self.adverts.retain(|_, a| a.received.elapsed() < LINK_STATE_EXPIRY);
+        before - self.adverts.len()
+    }
+
+    /// Number of nodes whose neighbor list we currently hold.
+    pub fn reporting(&self) -> usize {
+        self.adverts.len()
+    }
+
+    /// The whole-mesh graph: everything gossiped, plus our own live links.
+    pub fn graph(&self, us: [u8; 32], our_name: &str, our_links: &[LinkInfo]) -> TopologyGraph {
+        let mut nodes: BTreeMap<[u8; 32], TopologyNode> = BTreeMap::new();
+        let mut edges: BTreeMap<([u8; 32], [u8; 32]), EdgeReports> = BTreeMap::new();
+
+        let ours = std::iter::once((us, our_name, our_links));
+        let theirs = self.adverts.iter()
+            .filter(|(id, _)| **id != us)
+            .map(|(id, a)| (*id, a.display_name.as_str(), a.links.as_slice()));
+        for (origin, name, links) in ours.chain(theirs) {
+            nodes.insert(origin, TopologyNode {
+                node_id: origin,
+                display_name: name.to_string(),
+                is_self: origin == us,
+                reported: true,
+            });
+            for link in links.iter().filter(|l| l.node_id != origin) {
+                let (key, from_low) = if origin < link.node_id {
+                    ((origin, link.node_id), true)
+                } else {
+                    ((link.node_id, origin), false)
+                };
+                let entry = edges.entry(key).or_default();
+                if from_low { entry.0 = Some(link.quality) } else { entry.1 = Some(link.quality) }
+            }
+        }
+
+        // Neighbors that never sent a list of their own (older nodes, or lists still in flight)
+        for (a, b) in edges.keys() {
+            for id in [a, b] {
+                nodes.entry(*id).or_insert_with(|| TopologyNode {
+                    node_id: *id,
+                    display_name: String::new(),
+                    is_self: false,
+                    reported: false,
+                });
+            }
+        }
+
+        let links = edges.into_iter().map(|((a, b), (qa, qb))| TopologyLink {
+            a,
+            b,
+            // The weaker direction is what traffic both ways has to live with
+            quality: qa.into_iter().chain(qb).min().unwrap_or(0),
+            both_ends: qa.is_some() && qb.is_some(),
+        }).collect();
+
+        let mut nodes: Vec<TopologyNode> = nodes.into_values().collect();
+        nodes.sort_by_key(|n| !n.is_self);
+        TopologyGraph { nodes, links }
+    }
+}
+
+/// A node on the mesh map.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopologyNode {
+    pub node_id: [u8; 32],
+    /// Empty if the node never advertised itself.
+    pub display_name: String,
+    pub is_self: bool,
+    /// The node sent its own neighbor list; otherwise we only know it from others' lists.
+    pub reported: bool,
+}
+
+impl TopologyNode {
+    /// Display name, or the short node ID if it has none.
+    pub fn label(&self) -> String {
+        if self.display_name.is_empty() {
+            hex::encode(&self.node_id[..4])
+        } else {
+            self.display_name.clone()
+        }
+    }
+}
+
+/// A link between two nodes. Each link appears once, whichever end reported it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopologyLink {
+    pub a: [u8; 32],
+    pub b: [u8; 32],
+    /// 0 (unusable) to 255 (perfect); the lower of the two ends' figures.
+    pub quality: u8,
+    /// Both ends list each other (one-sided links are often just coming up or going down).
+    pub both_ends: bool,
+}
+
+impl TopologyLink {
+    pub fn quality_pct(&self) -> u32 {
+        self.quality as u32 * 100 / 255
+    }
+}
+
+/// Snapshot of the whole mesh as gossiped neighbor lists describe it.
+#[derive(Debug, Clone, Default)]
+pub struct TopologyGraph {
+    /// This node first.
+    pub nodes: Vec<TopologyNode>,
+    pub links: Vec<TopologyLink>,
+}
+
+impl TopologyGraph {
+    /// `{"nodes":[{"node_id","name","is_self","reported"}],"links":[{"a","b","quality","both_ends"}]}`
+    /// with quality in percent.
+    pub fn to_json(&self) -> String {
+        let nodes: Vec<String> = self.nodes.iter().map(|n| format!(
+            r#"{{"node_id":"{}","name":"{}","is_self":{},"reported":{}}}"#,
+            hex::encode(n.node_id), json_escape(&n.display_name), n.is_self, n.reported,
+        )).collect();
+        let links: Vec<String> = self.links.iter().map(|l| format!(
+            r#"{{"a":"{}","b":"{}","quality":{},"both_ends":{}}}"#,
+            hex::encode(l.a), hex::encode(l.b), l.quality_pct(), l.both_ends,
+        )).collect();
+        format!(r#"{{"nodes":[{}],"links":[{}]}}"#, nodes.join(","), links.join(","))
+    }
+
+    /// Graphviz DOT. Render with e.g. `neato -Tpng mesh.dot -o mesh.png`.
+    /// Line width follows link quality; one-sided links and nodes only known
+    /// from others' lists are dashed, and this node is drawn bold.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("graph mesh {\n  node [shape=box, style=rounded];\n");
+        for n in &self.nodes {
+            let style = match (n.is_self, n.reported) {
+                (true, _) => "\"rounded,bold\"",
+                (false, true) => "rounded",
+                (false, false) => "\"rounded,dashed\"",
+            };
+            let _ = writeln!(
+                dot, "  \"{}\" [label=\"{}\\n{}\", style={}];",
+                hex::encode(n.node_id), dot_escape(&n.label()), hex::encode(&n.node_id[..4]), style,
+            );
+        }
+        for l in &self.links {
+            let _ = writeln!(
+                dot, "  \"{}\" -- \"{}\" [label=\"{}%\", penwidth={:.1}{}];",
+                hex::encode(l.a), hex::encode(l.b), l.quality_pct(),
+                1.0 + 3.0 * l.quality as f32 / 255.0,
+                if l.both_ends { "" } else { ", style=dashed" },
+            );
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => { let _ = write!(out, "\\u{:04x}", c as u32); }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn advert(issued_ms: i64, name: &str, links: &[([u8; 32], u8)]) -> LinkStatePayload {
+        LinkStatePayload {
+            issued_ms,
+            display_name: name.into(),
+            links: links.iter().map(|(node_id, quality)| LinkInfo { node_id: *node_id, quality: *quality }).collect(),
+        }
+    }
+
+    #[test]
+    fn test_graph_merges_both_ends() {
+        let (us, a, b, c) = ([1u8; 32], [2u8; 32], [3u8; 32], [4u8; 32]);
+        let mut map = TopologyMap::new();
+        assert!(map.update(a, advert(10, "A", &[(us, 200), (b, 100)])));
+        assert!(map.update(b, advert(10, "B", &[(a, 150), (c, 255)])));
+        // An older list arriving late doesn't replace a newer one
+        assert!(!map.update(a, advert(5, "A", &[])));
+
+        let graph = map.graph(us, "Us", &[LinkInfo { node_id: a, quality: 255 }]);
+        assert!(graph.nodes[0].is_self);
+        assert_eq!(graph.nodes.len(), 4);
+        // C only appears in B's list
+        let node_c = graph.nodes.iter().find(|n| n.node_id == c).unwrap();
+        assert!(!node_c.reported);
+        assert_eq!(node_c.label(), hex::encode(&c[..4]));
+
+        assert_eq!(graph.links.len(), 3);
+        let us_a = graph.links.iter().find(|l| l.a == us && l.b == a).unwrap();
+        assert!(us_a.both_ends);
+        assert_eq!(us_a.quality, 200);
+        let a_b = graph.links.iter().find(|l| l.a == a && l.b == b).unwrap();
+        assert_eq!(a_b.quality, 100);
+        let b_c = graph.links.iter().find(|l| l.a == b && l.b == c).unwrap();
+        assert!(!b_c.both_ends);
+
+        let json = graph.to_json();
+        assert!(json.starts_with(r#"{"nodes":[{"node_id":"0101"#));
+        assert!(json.contains(r#""quality":39,"both_ends":true"#));
+        let dot = graph.to_dot();
+        assert!(dot.starts_with("graph mesh {"));
+        assert_eq!(dot.matches(" -- ").count(), 3);
+        assert!(dot.contains("style=dashed"));
+    }
+
+    #[test]
+    fn test_escaping() {
+        let mut map = TopologyMap::new();
+        map.update([2u8; 32], advert(1, "Tent \"B\"\n", &[([1u8; 32], 255)]));
+        let graph = map.graph([1u8; 32], "Us", &[]);
+        assert!(graph.to_json().contains(r#""name":"Tent \"B\"\u000a""#));
+        assert!(graph.to_dot().contains(r#"label="Tent \"B\" \n"#));
+        assert_eq!(map.expire(), 0);
+        assert_eq!(map.reporting(), 1);
+    }
+}
@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::message::{MeshMessage, TracePayload, TraceReplyPayload, TraceStamp};
+
+/// Longest path a traceroute probes.
+pub const MAX_TRACE_HOPS: u8 = 10;
+/// Give up on probes that haven't answered by now (covers route discovery).
+const TRACE_TIMEOUT: Duration = Duration::from_secs(8);
+
+/// Which diagnostic a result belongs to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceKind {
+    /// One probe with a full TTL; the reply carries the path it took.
+    Ping,
+    /// One probe per distance, so every hop reports back on its own.
+    Traceroute,
+}
+
+/// One hop of a ping or traceroute result.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceHop {
+    /// None when nothing answered at this distance: the path breaks here.
+    pub node_id: Option<[u8; 32]>,
+    /// Round trip from us to this hop, measured on our clock.
+    pub rtt_ms: Option<u32>,
+    /// The hop's own clock when the probe passed (Unix millis, 0 if unknown).
+    pub at_ms: i64,
+}
+
+/// A finished ping or traceroute.
+#[derive(Debug, Clone)]
+pub struct TraceOutcome {
+    pub kind: TraceKind,
+    pub target: [u8; 32],
+    pub reached: bool,
+    pub hops: Vec<TraceHop>,
+}
+
+struct PendingTrace {
+    kind: TraceKind,
+    target: [u8; 32],
+    started: Instant,
+    probes: u8,
+    /// Replies by probe TTL, with their round trip.
+    replies: HashMap<u8, (TraceReplyPayload, u32)>,
+}
+
+/// Tracks the pings and traceroutes we started until they finish.
+#[derive(Default)]
+pub struct TraceTracker {
+    traces: HashMap<u64, PendingTrace>,
+    /// Probe ID -> (trace it belongs to, TTL it was sent with).
+    probes: HashMap<u64, (u64, u8)>,
+}
+
+/// Stamp for a node handling a probe right now.
+pub fn stamp(node_id: [u8; 32]) -> TraceStamp {
+    TraceStamp { node_id, at_ms: chrono::Utc::now().timestamp_millis() }
+}
+
+impl TraceTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start a ping or traceroute and return the probes to send.
+    pub fn start(&mut self, kind: TraceKind, origin: [u8; 32], target: [u8; 32]) -> Vec<MeshMessage> {
+        let ttls: Vec<u8> = match kind {
+            TraceKind::Ping => vec![MAX_TRACE_HOPS],
+            TraceKind::Traceroute => (1..=MAX_TRACE_HOPS).collect(),
+        };
+        let trace_id = rand::random::<u64>();
+        let mut msgs = Vec::new();
+        for &ttl in &ttls {
+            let probe_id = rand::random::<u64>();
+            self.probes.insert(probe_id, (trace_id, ttl));
+            let probe = TracePayload { probe_id, path: Vec::new() };
+            msgs.push(probe.to_message(origin, target, ttl));
+        }
+        self.traces.insert(trace_id, PendingTrace {
+            kind,
+            target,
+            started: Instant::now(),
+            probes: ttls.len() as u8,
+            replies: HashMap::new(),
+        });
+        msgs
+    }
+
+    /// Record a reply. Returns the outcome if this completed its trace.
+    pub fn on_reply(&mut self, reply: TraceReplyPayload) -> Option<TraceOutcome> {
+        let (trace_id, ttl) = self.probes.get(&reply.probe_id).copied()?;
+        let trace = self.traces.get_mut(&trace_id)?;
+        let rtt = trace.started.elapsed().as_millis().min(u32::MAX as u128) as u32;
+        // When probes were flooded, the first answer at each distance wins
+        trace.replies.entry(ttl).or_insert((reply, rtt));
+
+        let done = match trace.kind {
+            TraceKind::Ping => true,
+            TraceKind::Traceroute => {
+                let reached_at = trace.replies.iter().filter(|(_, (r, _))| r.reached).map(|(t, _)| *t).min();
+                reached_at.is_some_and(|r| (1..r).all(|t| trace.replies.contains_key(&t)))
+                    || trace.replies.len() == trace.probes as usize
+            }
+        };
+        done.then(|| self.finish(trace_id))
+    }
+
+    /// Finish traces whose time is up, reporting whatever came back.
+    pub fn expire(&mut self) -> Vec<TraceOutcome> {
+        let overdue: Vec<u64> = self.traces.iter()
+            .filter(|(_, t)| t.started.elapsed() >= TRACE_TIMEOUT)
+            .map(|(id, _)| *id)
+            .collect();
+        overdue.into_iter().map(|id| self.finish(id)).collect()
+    }
+
+    fn finish(&mut self, trace_id: u64) -> TraceOutcome {
+        self.probes.retain(|_, (t, _)| *t != trace_id);
+        let trace = self.traces.remove(&trace_id).expect("finishing a known trace");
+        let reached = trace.replies.values().any(|(r, _)| r.reached);
+        let hops = match trace.kind {
+            TraceKind::Ping => trace.replies.into_values().next().map(|(reply, rtt)| {
+                let last = reply.path.len().saturating_sub(1);
+                reply.path.iter().enumerate().map(|(i, s)| TraceHop {
+                    node_id: Some(s.node_id),
+                    rtt_ms: (i == last).then_some(rtt),
+                    at_ms: s.at_ms,
+                }).collect()
+            }).unwrap_or_default(),
+            TraceKind::Traceroute => {
+                // Up to the destination, or one past the last hop that answered
+                let limit = trace.replies.iter().filter(|(_, (r, _))| r.reached).map(|(t, _)| *t).min()
+                    .or_else(|| trace.replies.keys().max().map(|t| (t + 1).min(trace.probes)))
+                    .unwrap_or(1);
+                (1..=limit).map(|ttl| match trace.replies.get(&ttl) {
+                    Some((reply, rtt)) => TraceHop {
+                        node_id: reply.path.last().map(|s| s.node_id),
+                        rtt_ms: Some(*rtt),
+                        at_ms: reply.path.last().map(|s| s.at_ms).unwrap_or(0),
+                    },
+                    None => TraceHop { node_id: None, rtt_ms: None, at_ms: 0 },
+                }).collect()
+            }
+        };
+        TraceOutcome { kind: trace.kind, target: trace.target, reached, hops }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply_to(probe: &MeshMessage, path: &[[u8; 32]], reached: bool) -> TraceReplyPayload {
+        TraceReplyPayload {
+            probe_id: TracePayload::from_message(probe).unwrap().probe_id,
+            path: path.iter().map(|id| stamp(*id)).collect(),
+            reached,
+        }
+    }
+
+    #[test]
+    fn test_traceroute_finds_break() {
+        let (us, a, b, target) = ([1u8; 32], [2u8; 32], [3u8; 32], [9u8; 32]);
+        let mut tracker = TraceTracker::new();
+        let probes = tracker.start(TraceKind::Traceroute, us, target);
+        assert_eq!(probes.len(), MAX_TRACE_HOPS as usize);
+        assert_eq!(probes[0].ttl, 1);
+
+        // Hops 1 and 2 answer, nothing beyond
+        assert!(tracker.on_reply(reply_to(&probes[1], &[a, b], false)).is_none());
+        assert!(tracker.on_reply(reply_to(&probes[0], &[a], false)).is_none());
+        assert!(tracker.expire().is_empty());
+
+        let outcome = tracker.finish(*tracker.traces.keys().next().unwrap());
+        assert!(!outcome.reached);
+        let ids: Vec<_> = outcome.hops.iter().map(|h| h.node_id).collect();
+        assert_eq!(ids, vec![Some(a), Some(b), None]);
+        assert!(tracker.probes.is_empty());
+    }
+
+    #[test]
+    fn test_traceroute_and_ping_reach_target() {
+        let (us, a, target) = ([1u8; 32], [2u8; 32], [9u8; 32]);
+        let mut tracker = TraceTracker::new();
+        let probes = tracker.start(TraceKind::Traceroute, us, target);
+        assert!(tracker.on_reply(reply_to(&probes[1], &[a, target], true)).is_none());
+        let outcome = tracker.on_reply(reply_to(&probes[0], &[a], false)).unwrap();
+        assert!(outcome.reached);
+        assert_eq!(outcome.hops.len(), 2);
+        assert_eq!(outcome.hops[1].node_id, Some(target));
+        // Late replies for a finished trace are ignored
+        assert!(tracker.on_reply(reply_to(&probes[2], &[a, target], true)).is_none());
+
+        let ping = tracker.start(TraceKind::Ping, us, target);
+        assert_eq!(ping.len(), 1);
+        let outcome = tracker.on_reply(reply_to(&ping[0], &[a, target], true)).unwrap();
+        assert_eq!(outcome.kind, TraceKind::Ping);
+        assert_eq!(outcome.hops.len(), 2);
+        assert!(outcome.hops[0].rtt_ms.is_none() && outcome.hops[1].rtt_ms.is_some());
+    }
+}
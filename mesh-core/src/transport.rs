@@ -1,17 +1,120 @@
+use std::fmt;
+use std::future::Future;
 use std::net::{SocketAddr, Ipv4Addr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::mpsc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::sync::{mpsc, watch};
 use tracing::{info, debug, warn};
 use anyhow::{Result, Context};
 
 use crate::message::MeshMessage;
+use crate::queue::{link_queue, LinkReceiver, LinkSender};
 
 const TCP_PORT: u16 = 7332;
+/// Largest frame accepted on stream links.
+pub const MAX_FRAME: usize = 1_000_000;
+/// Default for [`Transport::congestion_timeout`].
+pub const CONGESTION_TIMEOUT: Duration = Duration::from_secs(20);
 
-/// Read a length-prefixed message from a TCP stream.
-/// Format: [4-byte big-endian length][message bytes]
-pub async fn read_message(stream: &mut TcpStream) -> Result<Option<MeshMessage>> {
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Where a link goes, in the terms of the transport that carries it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum LinkAddr {
+    Ip(SocketAddr),
+    /// Bearers without IP addresses: a serial device, a loopback end, a BLE handle.
+    Named(String),
+}
+
+impl fmt::Display for LinkAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkAddr::Ip(addr) => addr.fmt(f),
+            LinkAddr::Named(name) => f.write_str(name),
+        }
+    }
+}
+
+impl From<SocketAddr> for LinkAddr {
+    fn from(addr: SocketAddr) -> Self {
+        LinkAddr::Ip(addr)
+    }
+}
+
+/// What a bearer promises about delivery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reliability {
+    /// Frames are retransmitted until they arrive or the link fails.
+    pub reliable: bool,
+    /// Frames arrive in the order they were sent.
+    pub ordered: bool,
+}
+
+/// A bearer the node can carry mesh traffic over.
+///
+/// A transport accepts links in `listen` and announces each one as an
+/// [`InboundConnection`]; the neighbor's identity is learned from its key
+/// exchange, unless the bearer already authenticated it. Transports that can
+/// reach an address the node learned from discovery also implement `dial`.
+pub trait Transport: Send + Sync {
+    /// Short name for logs and the peer list, e.g. "tcp".
+    fn name(&self) -> &'static str;
+
+    /// Largest encoded message a single frame may carry. The node refuses
+    /// larger ones before they reach the link's queue.
+    fn mtu(&self) -> usize;
+
+    fn reliability(&self) -> Reliability;
+
+    /// How long one of a link's traffic classes may stay full before the
+    /// node gives up on the neighbor and disconnects it. None for bearers
+    /// so slow that a long backlog is normal.
+    fn congestion_timeout(&self) -> Option<Duration> {
+        Some(CONGESTION_TIMEOUT)
+    }
+
+    /// Node the transport proves it is to neighbors, for bearers that
+    /// authenticate one themselves (QUIC's certificate). It has to be the
+    /// node's own identity.
+    fn identity(&self) -> Option<[u8; 32]> {
+        None
+    }
+
+    /// Port that LAN discovery should advertise for this transport, if any.
+    fn discovery_port(&self) -> Option<u16> {
+        None
+    }
+
+    /// Start accepting links until `shutdown` fires.
+    fn listen(
+        &self,
+        incoming_tx: mpsc::Sender<IncomingMessage>,
+        inbound_conn_tx: mpsc::Sender<InboundConnection>,
+        shutdown: watch::Receiver<bool>,
+    ) -> BoxFuture<'_, Result<()>>;
+
+    fn can_dial(&self, _addr: &LinkAddr) -> bool {
+        false
+    }
+
+    /// Open a link to a known neighbor.
+    fn dial(
+        &self,
+        addr: LinkAddr,
+        _peer: [u8; 32],
+        _incoming_tx: mpsc::Sender<IncomingMessage>,
+    ) -> BoxFuture<'_, Result<LinkSender>> {
+        let name = self.name();
+        Box::pin(async move { anyhow::bail!("{} transport cannot dial {}", name, addr) })
+    }
+}
+
+/// Read one length-prefixed frame, refusing anything over `max_len`.
+/// Returns `None` at a clean end of stream.
+pub(crate) async fn read_frame<R: AsyncRead + Unpin>(stream: &mut R, max_len: usize) -> Result<Option<Vec<u8>>> {
     let mut len_buf = [0u8; 4];
     match stream.read_exact(&mut len_buf).await {
         Ok(_) => {}
@@ -20,39 +123,63 @@ pub async fn read_message(stream: &mut TcpStream) -> Result<Option<MeshMessage>>
     }
 
     let len = u32::from_be_bytes(len_buf) as usize;
-    if len > 1_000_000 {
+    if len > max_len {
         anyhow::bail!("Message too large: {} bytes", len);
     }
 
     let mut msg_buf = vec![0u8; len];
     stream.read_exact(&mut msg_buf).await?;
+    Ok(Some(msg_buf))
+}
 
-    let msg = MeshMessage::from_bytes(&msg_buf)?;
-    Ok(Some(msg))
+/// Read a length-prefixed message from a stream.
+/// Format: [4-byte big-endian length][message bytes]
+pub async fn read_message<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Option<MeshMessage>> {
+    match read_frame(stream, MAX_FRAME).await? {
+        Some(buf) => Ok(Some(MeshMessage::from_bytes(&buf)?)),
+        None => Ok(None),
+    }
 }
 
-/// Write a length-prefixed message to a TCP stream.
-pub async fn write_message(stream: &mut TcpStream, msg: &MeshMessage) -> Result<()> {
+/// Write a length-prefixed message to a stream.
+pub async fn write_message<W: AsyncWrite + Unpin>(stream: &mut W, msg: &MeshMessage) -> Result<()> {
     let frame = msg.to_frame();
     stream.write_all(&frame).await?;
     stream.flush().await?;
     Ok(())
 }
 
-/// An incoming message received from a TCP peer.
+/// An incoming message received from a neighbor.
 #[derive(Debug)]
 pub struct IncomingMessage {
     pub msg: MeshMessage,
-    pub from_addr: SocketAddr,
+    pub from_addr: LinkAddr,
+    /// Identity of the neighbor on the other end of the link this arrived on.
+    /// `None` until an inbound connection has completed its key exchange.
+    pub from_peer: Option<[u8; 32]>,
 }
 
-/// Notification when a new inbound TCP connection is established.
+/// Notification when a transport accepts a new link.
 /// The node orchestrator uses this to register the peer and send messages back.
 pub struct InboundConnection {
-    pub addr: SocketAddr,
-    pub sender: mpsc::Sender<MeshMessage>,
+    pub addr: LinkAddr,
+    pub transport: &'static str,
+    pub sender: LinkSender,
+    /// The neighbor opened this link, so it starts the key exchange as any
+    /// dialer does. On links nobody dialed (serial, loopback) we introduce
+    /// ourselves instead.
+    pub dialed: bool,
+    /// Neighbor the link belongs to. Already set where the bearer itself
+    /// authenticated it, e.g. from QUIC's certificate; otherwise the node
+    /// sets it once it accepts the link's key exchange.
+    pub peer: LinkPeer,
 }
 
+/// The neighbor a link belongs to, once that is settled: when we dial it,
+/// when the bearer authenticates it, or when the node accepts the link's key
+/// exchange. Until then nothing read from the link is attributed to anyone.
+pub type LinkPeer = Arc<OnceLock<[u8; 32]>>;
+
 /// TCP transport listener + connection manager.
 pub struct TcpTransport {
     listen_port: u16,
@@ -72,7 +199,7 @@ impl TcpTransport {
         &self,
         incoming_tx: mpsc::Sender<IncomingMessage>,
         inbound_conn_tx: mpsc::Sender<InboundConnection>,
-        shutdown: tokio::sync::watch::Receiver<bool>,
+        shutdown: watch::Receiver<bool>,
     ) -> Result<()> {
         let addr = format!("0.0.0.0:{}", self.listen_port);
         // Use SO_REUSEADDR so we can restart quickly without TIME_WAIT issues
@@ -100,7 +227,7 @@ impl TcpTransport {
                                 debug!("Incoming TCP connection from {}", addr);
                                 let tx = incoming_tx.clone();
                                 let conn_tx = inbound_conn_tx.clone();
-                                tokio::spawn(handle_incoming_connection(stream, addr, tx, conn_tx));
+                                tokio::spawn(accept_link(stream, addr.into(), "tcp", MAX_FRAME, true, tx, conn_tx));
                             }
                             Err(e) => {
                                 warn!("TCP accept error: {}", e);
@@ -122,103 +249,336 @@ impl TcpTransport {
     /// Returns a sender to write messages to the peer.
     pub async fn connect_to_peer(
         addr: SocketAddr,
+        node_id: [u8; 32],
         incoming_tx: mpsc::Sender<IncomingMessage>,
-    ) -> Result<(mpsc::Sender<MeshMessage>, tokio::task::JoinHandle<()>)> {
+    ) -> Result<(LinkSender, tokio::task::JoinHandle<()>)> {
         let stream = TcpStream::connect(addr).await?;
         debug!("Connected to peer at {}", addr);
+        Ok(open_link(stream, addr.into(), node_id, MAX_FRAME, incoming_tx))
+    }
+}
+
+impl Transport for TcpTransport {
+    fn name(&self) -> &'static str {
+        "tcp"
+    }
+
+    fn mtu(&self) -> usize {
+        MAX_FRAME
+    }
+
+    fn reliability(&self) -> Reliability {
+        Reliability { reliable: true, ordered: true }
+    }
+
+    fn discovery_port(&self) -> Option<u16> {
+        Some(self.listen_port)
+    }
 
-        let (write_tx, write_rx) = mpsc::channel::<MeshMessage>(64);
-        let handle = tokio::spawn(handle_peer_connection(stream, addr, incoming_tx, write_rx));
+    fn listen(
+        &self,
+        incoming_tx: mpsc::Sender<IncomingMessage>,
+        inbound_conn_tx: mpsc::Sender<InboundConnection>,
+        shutdown: watch::Receiver<bool>,
+    ) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.start_listener(incoming_tx, inbound_conn_tx, shutdown))
+    }
 
-        Ok((write_tx, handle))
+    fn can_dial(&self, addr: &LinkAddr) -> bool {
+        matches!(addr, LinkAddr::Ip(_))
+    }
+
+    fn dial(
+        &self,
+        addr: LinkAddr,
+        peer: [u8; 32],
+        incoming_tx: mpsc::Sender<IncomingMessage>,
+    ) -> BoxFuture<'_, Result<LinkSender>> {
+        Box::pin(async move {
+            let LinkAddr::Ip(addr) = addr else {
+                anyhow::bail!("TCP cannot dial {}", addr);
+            };
+            let (sender, _task) = Self::connect_to_peer(addr, peer, incoming_tx).await?;
+            Ok(sender)
+        })
     }
 }
 
-/// Handle a bidirectional peer connection (used for both incoming and outgoing).
-async fn handle_peer_connection(
-    stream: TcpStream,
-    addr: SocketAddr,
+/// Two transports joined back to back in memory, one link between them.
+/// Lets tests run whole nodes against each other without sockets.
+pub struct LoopbackTransport {
+    name: String,
+    stream: Mutex<Option<DuplexStream>>,
+}
+
+impl LoopbackTransport {
+    pub fn pair() -> (Self, Self) {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let end = |name: &str, stream| Self { name: name.into(), stream: Mutex::new(Some(stream)) };
+        (end("loopback:a", a), end("loopback:b", b))
+    }
+}
+
+impl Transport for LoopbackTransport {
+    fn name(&self) -> &'static str {
+        "loopback"
+    }
+
+    fn mtu(&self) -> usize {
+        MAX_FRAME
+    }
+
+    fn reliability(&self) -> Reliability {
+        Reliability { reliable: true, ordered: true }
+    }
+
+    /// Hands the node its single link straight away.
+    fn listen(
+        &self,
+        incoming_tx: mpsc::Sender<IncomingMessage>,
+        inbound_conn_tx: mpsc::Sender<InboundConnection>,
+        _shutdown: watch::Receiver<bool>,
+    ) -> BoxFuture<'_, Result<()>> {
+        let stream = self.stream.lock().unwrap().take();
+        let addr = LinkAddr::Named(self.name.clone());
+        Box::pin(async move {
+            let stream = stream.context("Loopback transport is already in use")?;
+            tokio::spawn(accept_link(stream, addr, "loopback", MAX_FRAME, false, incoming_tx, inbound_conn_tx));
+            Ok(())
+        })
+    }
+}
+
+/// Receiving half of a link, in whatever framing its bearer uses.
+pub trait LinkRead: Send + 'static {
+    /// The next message, or `None` once the link has ended. Frames that
+    /// arrive but don't decode are skipped.
+    fn read(&mut self) -> BoxFuture<'_, Result<Option<MeshMessage>>>;
+}
+
+/// Sending half of a link.
+pub trait LinkWrite: Send + 'static {
+    fn write<'a>(&'a mut self, msg: &'a MeshMessage) -> BoxFuture<'a, Result<()>>;
+}
+
+/// Length-prefixed messages over a byte stream.
+struct StreamRead<R> {
+    inner: R,
+    addr: LinkAddr,
+    max_frame: usize,
+}
+
+impl<R: AsyncRead + Unpin + Send + 'static> LinkRead for StreamRead<R> {
+    fn read(&mut self) -> BoxFuture<'_, Result<Option<MeshMessage>>> {
+        Box::pin(async move {
+            loop {
+                let Some(buf) = read_frame(&mut self.inner, self.max_frame).await? else {
+                    return Ok(None);
+                };
+                match MeshMessage::from_bytes(&buf) {
+                    Ok(msg) => return Ok(Some(msg)),
+                    Err(e) => warn!("Peer {} invalid message: {}", self.addr, e),
+                }
+            }
+        })
+    }
+}
+
+struct StreamWrite<W>(W);
+
+impl<W: AsyncWrite + Unpin + Send + 'static> LinkWrite for StreamWrite<W> {
+    fn write<'a>(&'a mut self, msg: &'a MeshMessage) -> BoxFuture<'a, Result<()>> {
+        Box::pin(write_message(&mut self.0, msg))
+    }
+}
+
+fn split_stream<S>(stream: S, addr: &LinkAddr, max_frame: usize) -> (impl LinkRead, impl LinkWrite)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (read_half, write_half) = tokio::io::split(stream);
+    let reader = StreamRead { inner: read_half, addr: addr.clone(), max_frame };
+    (reader, StreamWrite(write_half))
+}
+
+/// Start the read/write tasks for a link we opened to a known neighbor.
+pub fn open_link<S>(
+    stream: S,
+    addr: LinkAddr,
+    peer: [u8; 32],
+    max_frame: usize,
+    incoming_tx: mpsc::Sender<IncomingMessage>,
+) -> (LinkSender, tokio::task::JoinHandle<()>)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, writer) = split_stream(stream, &addr, max_frame);
+    let (write_tx, write_rx) = link_queue();
+    let handle = tokio::spawn(handle_peer_connection(reader, writer, addr, Arc::new(peer.into()), incoming_tx, write_rx));
+    (write_tx, handle)
+}
+
+/// Run a stream link a transport accepted. `dialed` is true if the neighbor
+/// opened it (see [`InboundConnection::dialed`]).
+pub async fn accept_link<S>(
+    stream: S,
+    addr: LinkAddr,
+    transport: &'static str,
+    max_frame: usize,
+    dialed: bool,
+    incoming_tx: mpsc::Sender<IncomingMessage>,
+    inbound_conn_tx: mpsc::Sender<InboundConnection>,
+) where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, writer) = split_stream(stream, &addr, max_frame);
+    accept_framed_link(reader, writer, addr, transport, dialed, incoming_tx, inbound_conn_tx).await;
+}
+
+/// Run a link a transport accepted: create its write queue and notify the
+/// orchestrator so it can register the peer and send messages back.
+pub async fn accept_framed_link(
+    reader: impl LinkRead,
+    writer: impl LinkWrite,
+    addr: LinkAddr,
+    transport: &'static str,
+    dialed: bool,
     incoming_tx: mpsc::Sender<IncomingMessage>,
-    mut write_rx: mpsc::Receiver<MeshMessage>,
+    inbound_conn_tx: mpsc::Sender<InboundConnection>,
 ) {
-    let (mut read_half, mut write_half) = stream.into_split();
+    let (write_tx, write_rx) = link_queue();
+    let peer = LinkPeer::default();
+    let _ = inbound_conn_tx.send(InboundConnection {
+        addr: addr.clone(),
+        transport,
+        sender: write_tx,
+        dialed,
+        peer: peer.clone(),
+    }).await;
+    handle_peer_connection(reader, writer, addr, peer, incoming_tx, write_rx).await;
+}
 
+/// Handle a bidirectional peer connection (used for both incoming and outgoing).
+///
+/// Messages are attributed to `link_peer` once it is set.
+pub(crate) async fn handle_peer_connection(
+    mut reader: impl LinkRead,
+    mut writer: impl LinkWrite,
+    addr: LinkAddr,
+    link_peer: LinkPeer,
+    incoming_tx: mpsc::Sender<IncomingMessage>,
+    mut write_rx: LinkReceiver,
+) {
     // Read task
     let tx = incoming_tx.clone();
-    let read_task = tokio::spawn(async move {
+    let from_addr = addr.clone();
+    let mut read_task = tokio::spawn(async move {
+        let addr = from_addr;
         loop {
-            let mut len_buf = [0u8; 4];
-            match read_half.read_exact(&mut len_buf).await {
-                Ok(_) => {}
+            let msg = match reader.read().await {
+                Ok(Some(msg)) => msg,
+                Ok(None) => break,
                 Err(e) => {
-                    if e.kind() != std::io::ErrorKind::UnexpectedEof {
-                        warn!("Peer {} read error: {}", addr, e);
-                    }
+                    warn!("Peer {} read error: {}", addr, e);
                     break;
                 }
-            }
-
-            let len = u32::from_be_bytes(len_buf) as usize;
-            if len > 1_000_000 {
-                warn!("Peer {} sent oversized message: {} bytes", addr, len);
-                break;
-            }
-
-            let mut msg_buf = vec![0u8; len];
-            if let Err(e) = read_half.read_exact(&mut msg_buf).await {
-                warn!("Peer {} read payload error: {}", addr, e);
-                break;
-            }
-
-            match MeshMessage::from_bytes(&msg_buf) {
-                Ok(msg) => {
-                    let _ = tx.send(IncomingMessage { msg, from_addr: addr }).await;
-                }
-                Err(e) => {
-                    warn!("Peer {} invalid message: {}", addr, e);
-                }
-            }
+            };
+            let from_peer = link_peer.get().copied();
+            let _ = tx.send(IncomingMessage { msg, from_addr: addr.clone(), from_peer }).await;
         }
         debug!("Read task for {} ended", addr);
     });
 
-    // Write task
-    let write_task = tokio::spawn(async move {
+    // Write task: most urgent traffic class first. A peer that stops reading
+    // blocks the write; the node then closes the link, which ends it here.
+    let write_addr = addr.clone();
+    let mut write_task = tokio::spawn(async move {
+        let addr = write_addr;
         while let Some(msg) = write_rx.recv().await {
-            let frame = msg.to_frame();
-            if let Err(e) = write_half.write_all(&frame).await {
+            let written = tokio::select! {
+                r = writer.write(&msg) => r,
+                _ = write_rx.closed() => break,
+            };
+            if let Err(e) = written {
                 warn!("Peer {} write error: {}", addr, e);
                 break;
             }
-            if let Err(e) = write_half.flush().await {
-                warn!("Peer {} flush error: {}", addr, e);
-                break;
-            }
         }
         debug!("Write task for {} ended", addr);
     });
 
-    // Wait for either task to finish
+    // Wait for either task to finish, then stop the other
     tokio::select! {
-        _ = read_task => {}
-        _ = write_task => {}
+        _ = &mut read_task => write_task.abort(),
+        _ = &mut write_task => read_task.abort(),
     }
     debug!("Peer connection {} closed", addr);
 }
 
-/// Handle an incoming TCP connection: create a write channel and notify the
-/// orchestrator so it can register the peer and send messages back.
-async fn handle_incoming_connection(
-    stream: TcpStream,
-    addr: SocketAddr,
-    incoming_tx: mpsc::Sender<IncomingMessage>,
-    inbound_conn_tx: mpsc::Sender<InboundConnection>,
-) {
-    let (write_tx, write_rx) = mpsc::channel::<MeshMessage>(64);
-    // Notify the orchestrator about this new inbound connection
-    let _ = inbound_conn_tx.send(InboundConnection {
-        addr,
-        sender: write_tx,
-    }).await;
-    handle_peer_connection(stream, addr, incoming_tx, write_rx).await;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{KeyExchangePayload, MessageType};
+
+    #[tokio::test]
+    async fn test_inbound_link_is_attributed_once_accepted() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (incoming_tx, mut incoming_rx) = mpsc::channel(8);
+        let (conn_tx, mut conn_rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            let (stream, from) = listener.accept().await.unwrap();
+            accept_link(stream, from.into(), "tcp", MAX_FRAME, true, incoming_tx, conn_tx).await;
+        });
+
+        let neighbor = [7u8; 32];
+        let origin = [9u8; 32];
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let conn = conn_rx.recv().await.unwrap();
+
+        let relayed = MeshMessage::new(MessageType::Text, origin, 5, None, b"early".to_vec());
+        write_message(&mut client, &relayed).await.unwrap();
+        let kx = KeyExchangePayload { x25519_public: [1u8; 32] }.to_message(neighbor, [0u8; 32]);
+        write_message(&mut client, &kx).await.unwrap();
+
+        // Claiming an identity isn't enough; nothing is attributed to the
+        // link until the node accepts it
+        assert_eq!(incoming_rx.recv().await.unwrap().from_peer, None);
+        assert_eq!(incoming_rx.recv().await.unwrap().from_peer, None);
+
+        conn.peer.set(neighbor).unwrap();
+        let relayed = MeshMessage::new(MessageType::Text, origin, 5, None, b"late".to_vec());
+        write_message(&mut client, &relayed).await.unwrap();
+        let late = incoming_rx.recv().await.unwrap();
+        assert_eq!(late.msg.sender_id, origin);
+        assert_eq!(late.from_peer, Some(neighbor));
+    }
+
+    #[tokio::test]
+    async fn test_loopback_pair_carries_messages_both_ways() {
+        let (a, b) = LoopbackTransport::pair();
+        let (_shutdown_tx, shutdown) = watch::channel(false);
+        let (a_in_tx, mut a_in) = mpsc::channel(8);
+        let (a_conn_tx, mut a_conn) = mpsc::channel(1);
+        let (b_in_tx, mut b_in) = mpsc::channel(8);
+        let (b_conn_tx, mut b_conn) = mpsc::channel(1);
+        a.listen(a_in_tx.clone(), a_conn_tx.clone(), shutdown.clone()).await.unwrap();
+        b.listen(b_in_tx, b_conn_tx, shutdown.clone()).await.unwrap();
+        assert!(a.listen(a_in_tx, a_conn_tx, shutdown).await.is_err());
+        assert!(!a.can_dial(&LinkAddr::Named("loopback:b".into())));
+
+        let a_link = a_conn.recv().await.unwrap();
+        let b_link = b_conn.recv().await.unwrap();
+        assert_eq!(a_link.transport, "loopback");
+
+        let hello = MeshMessage::new(MessageType::Text, [1u8; 32], 5, None, b"hello".to_vec());
+        a_link.sender.send(hello).await.unwrap();
+        let got = b_in.recv().await.unwrap();
+        assert_eq!(got.msg.payload, b"hello");
+        assert_eq!(got.from_addr, LinkAddr::Named("loopback:b".into()));
+
+        let reply = MeshMessage::new(MessageType::Text, [2u8; 32], 5, None, b"hi".to_vec());
+        b_link.sender.send(reply).await.unwrap();
+        assert_eq!(a_in.recv().await.unwrap().msg.payload, b"hi");
+    }
 }
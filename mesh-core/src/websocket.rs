@@ -0,0 +1,508 @@
+//! WebSocket transport, so people without the app can join from a browser.
+//!
+//! One port serves both the web client page (`GET /`) and the links it opens
+//! (`/mesh`). Each WebSocket binary message is one bincode `MeshMessage`,
+//! the same encoding TCP frames carry. The page keeps its own Ed25519
+//! identity in the browser and opens the link by signing a challenge with
+//! it. From then on the link only carries messages that identity signed,
+//! and the node treats each tab like any other neighbor.
+
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::{Role, WebSocketConfig};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use tracing::{info, debug, warn};
+use anyhow::{Result, Context};
+
+use crate::message::{MeshMessage, MessageType};
+use crate::queue::link_queue;
+use crate::transport::{
+    handle_peer_connection, BoxFuture, IncomingMessage, InboundConnection, LinkAddr, LinkPeer, LinkRead, LinkWrite,
+    Reliability, Transport, MAX_FRAME,
+};
+
+const WS_PORT: u16 = 7380;
+const LINK_PATH: &str = "/mesh";
+const WEB_CLIENT: &str = include_str!("../web/client.html");
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+/// Time a client gets to send its request before the connection is dropped.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+type WsSink = SplitSink<WebSocketStream<TcpStream>, Message>;
+type WsSource = SplitStream<WebSocketStream<TcpStream>>;
+
+/// Serves the web client and accepts the WebSocket links it opens.
+pub struct WebSocketTransport {
+    listen_port: u16,
+    local_addr: Mutex<Option<SocketAddr>>,
+}
+
+impl WebSocketTransport {
+    pub fn new(listen_port: u16) -> Self {
+        Self { listen_port, local_addr: Mutex::new(None) }
+    }
+
+    pub fn default_port() -> u16 {
+        WS_PORT
+    }
+
+    /// Address the transport is bound to, once listening.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        *self.local_addr.lock().unwrap()
+    }
+}
+
+impl Transport for WebSocketTransport {
+    fn name(&self) -> &'static str {
+        "websocket"
+    }
+
+    fn mtu(&self) -> usize {
+        MAX_FRAME
+    }
+
+    fn reliability(&self) -> Reliability {
+        Reliability { reliable: true, ordered: true }
+    }
+
+    /// Browsers only ever connect in, so there is nothing to dial.
+    fn listen(
+        &self,
+        incoming_tx: mpsc::Sender<IncomingMessage>,
+        inbound_conn_tx: mpsc::Sender<InboundConnection>,
+        shutdown: watch::Receiver<bool>,
+    ) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            let listener = TcpListener::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), self.listen_port))
+                .await
+                .context(format!("Failed to bind web client on port {}", self.listen_port))?;
+            let local_addr = listener.local_addr()?;
+            *self.local_addr.lock().unwrap() = Some(local_addr);
+            info!("Web client served on http://{}/", local_addr);
+
+            let mut shutdown_rx = shutdown;
+            tokio::spawn(async move {
+                loop {
+                    tokio::select! {
+                        result = listener.accept() => {
+                            match result {
+                                Ok((stream, addr)) => {
+                                    let tx = incoming_tx.clone();
+                                    let conn_tx = inbound_conn_tx.clone();
+                                    tokio::spawn(async move {
+                                        if let Err(e) = serve(stream, addr, tx, conn_tx).await {
+                                            debug!("Web client connection from {} failed: {}", addr, e);
+                                        }
+                                    });
+                                }
+                                Err(e) => warn!("Web client accept error: {}", e),
+                            }
+                        }
+                        _ = shutdown_rx.changed() => {
+                            info!("WebSocket listener shutting down");
+                            break;
+                        }
+                    }
+                }
+            });
+            Ok(())
+        })
+    }
+}
+
+/// Answer one HTTP request: the page, or the upgrade to a mesh link.
+async fn serve(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    incoming_tx: mpsc::Sender<IncomingMessage>,
+    inbound_conn_tx: mpsc::Sender<InboundConnection>,
+) -> Result<()> {
+    let (head, rest) = tokio::time::timeout(REQUEST_TIMEOUT, read_request_head(&mut stream))
+        .await
+        .context("Timed out waiting for the request")??;
+    let mut lines = head.lines();
+    let request_line: Vec<&str> = lines.next().unwrap_or_default().split_whitespace().collect();
+    let [method, target, ..] = request_line[..] else {
+        anyhow::bail!("Malformed request line");
+    };
+    let path = target.split('?').next().unwrap_or(target);
+    let headers: HashMap<String, &str> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim()))
+        .collect();
+    let upgrade = headers.get("upgrade").is_some_and(|v| v.eq_ignore_ascii_case("websocket"));
+
+    match (method, path, headers.get("sec-websocket-key")) {
+        ("GET", LINK_PATH, Some(_)) if upgrade && !same_origin(&headers) => {
+            respond(&mut stream, "403 Forbidden", "text/plain", "Links from other sites are refused").await?
+        }
+        ("GET", LINK_PATH, Some(key)) if upgrade => {
+            let response = format!(
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                derive_accept_key(key.as_bytes()),
+            );
+            stream.write_all(response.as_bytes()).await?;
+            let config = WebSocketConfig::default().max_message_size(Some(MAX_FRAME)).max_frame_size(Some(MAX_FRAME));
+            let socket = WebSocketStream::from_partially_read(stream, rest, Role::Server, Some(config)).await;
+            let (mut sink, mut source) = socket.split();
+            let intro = tokio::time::timeout(REQUEST_TIMEOUT, introduction(&mut sink, &mut source))
+                .await
+                .context("Timed out waiting for the introduction")??;
+            let peer = intro.sender_id;
+            debug!("Web client link from {} ({})", addr, hex::encode(&peer[..4]));
+            let addr = LinkAddr::Ip(addr);
+            let reader = WsRead { source, addr: addr.clone(), peer, intro: Some(intro) };
+            let (write_tx, write_rx) = link_queue();
+            let link_peer: LinkPeer = Arc::new(peer.into());
+            let _ = inbound_conn_tx.send(InboundConnection {
+                addr: addr.clone(),
+                transport: "websocket",
+                sender: write_tx,
+                dialed: true,
+                peer: link_peer.clone(),
+            }).await;
+            handle_peer_connection(reader, WsWrite(sink), addr, link_peer, incoming_tx, write_rx).await;
+        }
+        ("GET", "/", _) => respond(&mut stream, "200 OK", "text/html; charset=utf-8", &web_client()).await?,
+        _ => respond(&mut stream, "404 Not Found", "text/plain", "Not found").await?,
+    }
+    Ok(())
+}
+
+/// Browsers send `Origin` on every WebSocket request, so a page from another
+/// site can't open links from its visitors' browsers. Other clients leave it out.
+fn same_origin(headers: &HashMap<String, &str>) -> bool {
+    let Some(origin) = headers.get("origin") else { return true };
+    let host = origin.strip_prefix("http://").or_else(|| origin.strip_prefix("https://"));
+    host.is_some() && host == headers.get("host").copied()
+}
+
+/// Send a fresh challenge and wait for the page's key exchange, which must
+/// use the challenge as its message id and be signed by the sender. That
+/// proves the page holds the key it claims, and that the introduction
+/// isn't replayed from another link.
+async fn introduction(sink: &mut WsSink, source: &mut WsSource) -> Result<MeshMessage> {
+    let challenge: [u8; 32] = rand::random();
+    sink.send(Message::Text(hex::encode(challenge).into())).await?;
+    while let Some(frame) = source.next().await {
+        if let Message::Binary(data) = frame? {
+            let msg = MeshMessage::from_bytes(&data)?;
+            anyhow::ensure!(
+                msg.msg_type == MessageType::KeyExchange && msg.msg_id == challenge && msg.verify_signature(),
+                "Introduction is not a key exchange signed over the challenge"
+            );
+            return Ok(msg);
+        }
+    }
+    anyhow::bail!("Closed before introducing itself")
+}
+
+/// Read up to the end of the request head. Returns the head and whatever
+/// arrived after it.
+async fn read_request_head(stream: &mut TcpStream) -> Result<(String, Vec<u8>)> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let rest = buf.split_off(end + 4);
+            return Ok((String::from_utf8_lossy(&buf).into_owned(), rest));
+        }
+        if buf.len() > MAX_REQUEST_HEAD {
+            anyhow::bail!("Request head too large");
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            anyhow::bail!("Connection closed mid-request");
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &str) -> Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        status, content_type, body.len(),
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// The page, with the message type table it encodes with filled in.
+fn web_client() -> String {
+    WEB_CLIENT.replace("/*MESSAGE_TYPES*/{}", &message_type_table())
+}
+
+/// `{"Ping":[1,2],...}`: each type's bincode variant index and wire code.
+/// Bincode writes the index, not the code, so the page can't hardcode either
+/// without drifting from this build.
+fn message_type_table() -> String {
+    let entries: Vec<String> = (0u32..)
+        .map_while(|index| bincode::deserialize::<MessageType>(&index.to_le_bytes()).ok().map(|t| (index, t)))
+        .map(|(index, t)| format!("\"{:?}\":[{},{}]", t, index, t as u8))
+        .collect();
+    format!("{{{}}}", entries.join(","))
+}
+
+struct WsRead {
+    source: WsSource,
+    addr: LinkAddr,
+    /// The identity the page proved in its introduction.
+    peer: [u8; 32],
+    /// The introduction itself, passed on as the link's first message.
+    intro: Option<MeshMessage>,
+}
+
+impl LinkRead for WsRead {
+    fn read(&mut self) -> BoxFuture<'_, Result<Option<MeshMessage>>> {
+        Box::pin(async move {
+            if let Some(intro) = self.intro.take() {
+                return Ok(Some(intro));
+            }
+            while let Some(frame) = self.source.next().await {
+                match frame? {
+                    // A tab only speaks for itself, so anything else is forged
+                    Message::Binary(data) => match MeshMessage::from_bytes(&data) {
+                        Ok(msg) if msg.sender_id == self.peer && msg.verify_signature() => return Ok(Some(msg)),
+                        Ok(msg) => warn!("Web client {} sent a {:?} not signed by its own key", self.addr, msg.msg_type),
+                        Err(e) => warn!("Peer {} invalid message: {}", self.addr, e),
+                    },
+                    Message::Close(_) => break,
+                    // Pings are answered by tungstenite; text isn't part of the protocol
+                    _ => {}
+                }
+            }
+            Ok(None)
+        })
+    }
+}
+
+struct WsWrite(WsSink);
+
+impl LinkWrite for WsWrite {
+    fn write<'a>(&'a mut self, msg: &'a MeshMessage) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            self.0.send(Message::Binary(msg.to_bytes().into())).await?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::NodeIdentity;
+    use crate::message::KeyExchangePayload;
+    use crate::node::{start_mesh_node, NodeConfig, NodeEvent, NodeHandle};
+
+    type Client = WebSocketStream<TcpStream>;
+
+    async fn get(addr: SocketAddr, path: &str) -> String {
+        request(addr, &format!("GET {} HTTP/1.1\r\nHost: mesh\r\n\r\n", path)).await
+    }
+
+    async fn request(addr: SocketAddr, head: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(head.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    async fn connect(addr: SocketAddr) -> Client {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let url = format!("ws://{}{}", addr, LINK_PATH);
+        tokio_tungstenite::client_async(url, stream).await.unwrap().0
+    }
+
+    /// Answer the node's challenge the way the page does.
+    async fn introduce(client: &mut Client, identity: &NodeIdentity) {
+        let challenge = match client.next().await.unwrap().unwrap() {
+            Message::Text(text) => hex::decode(text.as_str()).unwrap(),
+            other => panic!("expected a challenge, got {:?}", other),
+        };
+        let mut kx = KeyExchangePayload { x25519_public: [3u8; 32] }.to_message(identity.node_id, [0u8; 32]);
+        kx.msg_id = challenge.try_into().unwrap();
+        kx.sign(identity);
+        send(client, &kx).await;
+    }
+
+    async fn web_node(dir: &std::path::Path) -> (NodeIdentity, NodeHandle, mpsc::Receiver<NodeEvent>, SocketAddr) {
+        let transport = Arc::new(WebSocketTransport::new(0));
+        let (node, handle, events) = start_mesh_node(NodeConfig {
+            display_name: "laptop".into(),
+            key_path: dir.join("mesh_identity.key"),
+            data_dir: Some(dir.to_path_buf()),
+            transports: vec![transport.clone()],
+            ..Default::default()
+        }).await.unwrap();
+        let addr = ([127, 0, 0, 1], transport.local_addr().unwrap().port()).into();
+        (node, handle, events, addr)
+    }
+
+    async fn next_message(client: &mut Client, want: MessageType) -> MeshMessage {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Message::Binary(data) = client.next().await.unwrap().unwrap() {
+                    let msg = MeshMessage::from_bytes(&data).unwrap();
+                    if msg.msg_type == want {
+                        return msg;
+                    }
+                }
+            }
+        }).await.expect("timed out waiting for a message")
+    }
+
+    async fn next_event(events: &mut mpsc::Receiver<NodeEvent>, want: impl Fn(&NodeEvent) -> bool) -> NodeEvent {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let event = events.recv().await.expect("node stopped");
+                if want(&event) {
+                    return event;
+                }
+            }
+        }).await.expect("timed out waiting for node event")
+    }
+
+    async fn send(client: &mut Client, msg: &MeshMessage) {
+        client.send(Message::Binary(msg.to_bytes().into())).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_serves_page_with_current_message_types() {
+        let transport = WebSocketTransport::new(0);
+        let (incoming_tx, _incoming) = mpsc::channel(1);
+        let (conn_tx, _conns) = mpsc::channel(1);
+        let (_shutdown_tx, shutdown) = watch::channel(false);
+        transport.listen(incoming_tx, conn_tx, shutdown).await.unwrap();
+        let addr: SocketAddr = ([127, 0, 0, 1], transport.local_addr().unwrap().port()).into();
+
+        let page = get(addr, "/").await;
+        assert!(page.starts_with("HTTP/1.1 200 OK"));
+        assert!(!page.contains("/*MESSAGE_TYPES*/"));
+        let text_index = bincode::serialize(&MessageType::Text).unwrap()[0];
+        assert!(page.contains(&format!("\"Text\":[{},16]", text_index)));
+        assert!(page.contains("\"DeliveryReceipt\":["));
+        assert!(get(addr, "/missing").await.starts_with("HTTP/1.1 404"));
+    }
+
+    #[test]
+    fn test_page_signatures_verify() {
+        // Made by the page's own sha2/Ed25519 code, run under node: seed 1..=32
+        // signs a Text with every id byte 7
+        let seed: [u8; 32] = std::array::from_fn(|i| i as u8 + 1);
+        let public = hex::decode("79b5562e8fe654f94078b112e8a98ba7901f853ae695bed7e0e3910bad049664").unwrap();
+        let signature = hex::decode(
+            "ca60450033843ab686aaccb373d8d2583c311c987043ecd8d54afbef8669a286\
+             4daa7cf39fd032c03755a4371ad7ba66d76cced641564a4408b5fe7baa6ef806",
+        ).unwrap();
+        assert_eq!(ed25519_dalek::SigningKey::from_bytes(&seed).verifying_key().to_bytes()[..], public[..]);
+
+        let mut msg = MeshMessage::text(public.try_into().unwrap(), "hello from the browser");
+        msg.msg_id = [7u8; 32];
+        msg.signature = Some(signature);
+        assert!(msg.verify_signature());
+        msg.payload = b"hello from someone else".to_vec();
+        assert!(!msg.verify_signature());
+    }
+
+    #[tokio::test]
+    async fn test_browser_joins_node_like_any_neighbor() {
+        let dir = std::env::temp_dir().join(format!("mesh_test_ws_{}", rand::random::<u32>()));
+        let (node, handle, mut events, addr) = web_node(&dir).await;
+
+        // The page introduces itself with a key exchange, then talks
+        let browser = NodeIdentity::generate("web".into());
+        let mut client = connect(addr).await;
+        introduce(&mut client, &browser).await;
+        next_event(&mut events, |e| matches!(e, NodeEvent::PeerConnected { node_id, .. } if *node_id == browser.node_id)).await;
+
+        let mut hello = MeshMessage::text(browser.node_id, "hello from the browser");
+        hello.sign(&browser);
+        send(&mut client, &hello).await;
+        let got = next_event(&mut events, |e| matches!(e, NodeEvent::MessageReceived { .. })).await;
+        let NodeEvent::MessageReceived { sender_id, content, .. } = got else { unreachable!() };
+        assert_eq!((sender_id, content.as_str()), (browser.node_id, "hello from the browser"));
+
+        handle.send_broadcast("hello from the laptop").await.unwrap();
+        let reply = next_message(&mut client, MessageType::Text).await;
+        assert_eq!((reply.sender_id, reply.payload.as_slice()), (node.node_id, &b"hello from the laptop"[..]));
+
+        handle.shutdown().await.unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_unsigned_and_forged_frames_are_dropped() {
+        let dir = std::env::temp_dir().join(format!("mesh_test_ws_{}", rand::random::<u32>()));
+        let (_node, handle, mut events, addr) = web_node(&dir).await;
+        let browser = NodeIdentity::generate("web".into());
+        let mut client = connect(addr).await;
+        introduce(&mut client, &browser).await;
+
+        send(&mut client, &MeshMessage::text(browser.node_id, "unsigned")).await;
+        let mut forged = MeshMessage::text(browser.node_id, "forged");
+        forged.sign(&NodeIdentity::generate("mallory".into()));
+        send(&mut client, &forged).await;
+        let someone_else = NodeIdentity::generate("carol".into());
+        let mut relayed = MeshMessage::text(someone_else.node_id, "in someone else's name");
+        relayed.sign(&someone_else);
+        send(&mut client, &relayed).await;
+        let mut genuine = MeshMessage::text(browser.node_id, "genuine");
+        genuine.sign(&browser);
+        send(&mut client, &genuine).await;
+
+        let got = next_event(&mut events, |e| matches!(e, NodeEvent::MessageReceived { .. })).await;
+        let NodeEvent::MessageReceived { content, .. } = got else { unreachable!() };
+        assert_eq!(content, "genuine");
+
+        handle.shutdown().await.unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_link_needs_a_signed_introduction() {
+        let dir = std::env::temp_dir().join(format!("mesh_test_ws_{}", rand::random::<u32>()));
+        let (_node, handle, mut events, addr) = web_node(&dir).await;
+
+        // A key exchange signed over anything but this link's challenge is refused
+        let browser = NodeIdentity::generate("web".into());
+        let mut client = connect(addr).await;
+        assert!(matches!(client.next().await, Some(Ok(Message::Text(_)))));
+        let mut kx = KeyExchangePayload { x25519_public: [3u8; 32] }.to_message(browser.node_id, [0u8; 32]);
+        kx.sign(&browser);
+        send(&mut client, &kx).await;
+        let closed = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(Ok(frame)) = client.next().await {
+                if frame.is_close() {
+                    break;
+                }
+            }
+        }).await;
+        assert!(closed.is_ok(), "link stayed open");
+        while let Ok(event) = events.try_recv() {
+            assert!(!matches!(event, NodeEvent::PeerConnected { .. }));
+        }
+
+        // Other sites' pages can't open links at all
+        let cross_site = request(addr, &format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nOrigin: http://elsewhere.example\r\nUpgrade: websocket\r\n\
+             Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            LINK_PATH, addr,
+        )).await;
+        assert!(cross_site.starts_with("HTTP/1.1 403"));
+
+        handle.shutdown().await.unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -6,7 +6,7 @@ use once_cell::sync::OnceCell;
 use tokio::runtime::Runtime;
 use tokio::sync::mpsc;
 
-use mesh_core::{NodeConfig, NodeEvent, NodeHandle, NodeIdentity, start_mesh_node};
+use mesh_core::{BundleEntry, FileAcceptPolicy, FloodStrategy, TraceHop, NodeConfig, NodeEvent, NodeHandle, NodeIdentity, start_mesh_node};
 use mesh_core::{TriagePayload, TriageLevel, ResourceRequestPayload, CheckInPayload};
 
 /// Global state for the FFI layer.
@@ -29,6 +29,7 @@ fn init_node(name: String, listen_port: u16, data_dir: String) -> Result<(), ()>
         listen_port,
         key_path: PathBuf::from(&data_dir).join("mesh_identity.key"),
         data_dir: Some(PathBuf::from(&data_dir)),
+        ..Default::default()
     };
 
     let runtime = Runtime::new().map_err(|_| ())?;
@@ -82,6 +83,78 @@ fn to_c_string(s: &str) -> *mut c_char {
     CString::new(s).unwrap_or_default().into_raw()
 }
 
+/// Split a comma-separated list, trimming entries and dropping empty ones.
+fn parse_csv(list: &str) -> Vec<String> {
+    list.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect()
+}
+
+/// Build a flood strategy from flat FFI arguments.
+/// `mode`: 0 = full, 1 = gossip, 2 = counter, 3 = neighbor coverage.
+fn build_flood_strategy(mode: i32, probability: f32, threshold: i32, max_delay_ms: i32) -> Option<FloodStrategy> {
+    let max_delay = std::time::Duration::from_millis(max_delay_ms.max(0) as u64);
+    match mode {
+        0 => Some(FloodStrategy::Full),
+        1 => Some(FloodStrategy::Gossip { probability: probability.clamp(0.0, 1.0) }),
+        2 => Some(FloodStrategy::Counter { threshold: threshold.clamp(1, 255) as u8, max_delay }),
+        3 => Some(FloodStrategy::NeighborCoverage { max_delay }),
+        _ => None,
+    }
+}
+
+/// Build a file auto-accept policy from flat FFI arguments (sizes <= 0 mean "no limit").
+fn build_file_policy(
+    accept_favorites: bool,
+    accept_verified: bool,
+    max_auto_size: i64,
+    allowed_types: &str,
+    blocked_extensions: &str,
+    min_free_disk: i64,
+) -> FileAcceptPolicy {
+    // Entries containing a '/' are MIME types, the rest are extensions
+    let (mime, ext): (Vec<String>, Vec<String>) = parse_csv(allowed_types).into_iter().partition(|t| t.contains('/'));
+    FileAcceptPolicy {
+        accept_favorites,
+        accept_verified,
+        max_auto_size: (max_auto_size > 0).then_some(max_auto_size as u64),
+        allowed_extensions: ext.into_iter().map(|e| e.trim_start_matches('.').to_string()).collect(),
+        allowed_mime_types: mime,
+        blocked_extensions: parse_csv(blocked_extensions).into_iter().map(|e| e.trim_start_matches('.').to_string()).collect(),
+        min_free_disk: (min_free_disk > 0).then_some(min_free_disk as u64),
+    }
+}
+
+/// Parse comma-separated bundle entry indices ("0,2,5").
+fn parse_entry_list(list: &str) -> Option<Vec<u32>> {
+    parse_csv(list).iter().map(|s| s.parse().ok()).collect()
+}
+
+/// Bundle manifest as JSON: {"name":..,"entries":[{"path":..,"size":..},..]}.
+fn bundle_json(name: &str, entries: &[BundleEntry]) -> String {
+    let escape = |s: &str| s.replace('\\', "\\\\").replace('"', "\\\"");
+    let items: Vec<String> = entries.iter()
+        .map(|e| format!(r#"{{"path":"{}","size":{}}}"#, escape(&e.path), e.size))
+        .collect();
+    format!(r#"{{"name":"{}","entries":[{}]}}"#, escape(name), items.join(","))
+}
+
+/// JSON for a ping/traceroute result: hops in order, `node_id` null where nothing answered.
+fn trace_json(reached: bool, hops: &[TraceHop]) -> String {
+    let items: Vec<String> = hops.iter().map(|h| {
+        format!(
+            r#"{{"node_id":{},"rtt_ms":{},"at_ms":{}}}"#,
+            h.node_id.map(|id| format!("\"{}\"", hex::encode(id))).unwrap_or_else(|| "null".into()),
+            h.rtt_ms.map(|r| r.to_string()).unwrap_or_else(|| "null".into()),
+            h.at_ms,
+        )
+    }).collect();
+    format!(r#"{{"reached":{},"hops":[{}]}}"#, reached, items.join(","))
+}
+
+/// Round trip to the last hop that answered, or -1.
+fn trace_rtt(hops: &[TraceHop]) -> i64 {
+    hops.iter().rev().find_map(|h| h.rtt_ms).map(|r| r as i64).unwrap_or(-1)
+}
+
 fn parse_hex_node_id(hex_str: &str) -> Option<[u8; 32]> {
     let bytes = hex::decode(hex_str).ok()?;
     if bytes.len() != 32 { return None; }
@@ -130,6 +203,9 @@ pub unsafe extern "C" fn mesh_init(
 }
 
 /// Send a broadcast text message.
+///
+/// # Safety
+/// `text` must be a valid NUL-terminated C string.
 #[no_mangle]
 pub unsafe extern "C" fn mesh_send_broadcast(text: *const c_char) -> i32 {
     let text = match CStr::from_ptr(text).to_str() {
@@ -143,6 +219,9 @@ pub unsafe extern "C" fn mesh_send_broadcast(text: *const c_char) -> i32 {
 }
 
 /// Send a direct text message to a specific node.
+///
+/// # Safety
+/// `dest_hex` and `text` must be valid NUL-terminated C strings.
 #[no_mangle]
 pub unsafe extern "C" fn mesh_send_direct(
     dest_hex: *const c_char,
@@ -167,6 +246,9 @@ pub unsafe extern "C" fn mesh_send_direct(
 }
 
 /// Send a file to a specific node.
+///
+/// # Safety
+/// `dest_hex` and `file_path` must be valid NUL-terminated C strings.
 #[no_mangle]
 pub unsafe extern "C" fn mesh_send_file(dest_hex: *const c_char, file_path: *const c_char) -> i32 {
     let state = match STATE.get() { Some(s) => s, None => return -1 };
@@ -177,7 +259,36 @@ pub unsafe extern "C" fn mesh_send_file(dest_hex: *const c_char, file_path: *con
     state.runtime.block_on(h.send_file(dest, path)).map(|_| 0i32).unwrap_or(-1)
 }
 
+/// Offer a file to every member of a group; chunks are flooded once for all of them.
+///
+/// # Safety
+/// `group_name` and `file_path` must be valid NUL-terminated C strings.
+#[no_mangle]
+pub unsafe extern "C" fn mesh_send_file_to_group(group_name: *const c_char, file_path: *const c_char) -> i32 {
+    let state = match STATE.get() { Some(s) => s, None => return -1 };
+    let group = match CStr::from_ptr(group_name).to_str() { Ok(s) => s, Err(_) => return -1 };
+    let path = match CStr::from_ptr(file_path).to_str() { Ok(s) => s, Err(_) => return -1 };
+    let h = state.handle.clone();
+    state.runtime.block_on(h.send_file_to_group(group, path)).map(|_| 0i32).unwrap_or(-1)
+}
+
+/// Offer a file to the whole mesh; chunks are flooded once for all receivers.
+///
+/// # Safety
+/// `file_path` must be a valid NUL-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn mesh_send_file_broadcast(file_path: *const c_char) -> i32 {
+    let state = match STATE.get() { Some(s) => s, None => return -1 };
+    let path = match CStr::from_ptr(file_path).to_str() { Ok(s) => s, Err(_) => return -1 };
+    let h = state.handle.clone();
+    state.runtime.block_on(h.send_file_broadcast(path)).map(|_| 0i32).unwrap_or(-1)
+}
+
 /// Send a voice note.
+///
+/// # Safety
+/// `dest_hex` may be null; otherwise it must be a valid C string.
+/// `audio_data` must point to at least `audio_len` readable bytes.
 #[no_mangle]
 pub unsafe extern "C" fn mesh_send_voice(
     dest_hex: *const c_char,
@@ -199,6 +310,9 @@ pub unsafe extern "C" fn mesh_send_voice(
 }
 
 /// Send a public broadcast message.
+///
+/// # Safety
+/// `text` must be a valid NUL-terminated C string.
 #[no_mangle]
 pub unsafe extern "C" fn mesh_send_public_broadcast(text: *const c_char) -> i32 {
     let state = match STATE.get() { Some(s) => s, None => return -1 };
@@ -208,6 +322,9 @@ pub unsafe extern "C" fn mesh_send_public_broadcast(text: *const c_char) -> i32
 }
 
 /// Send an SOS emergency broadcast.
+///
+/// # Safety
+/// `text` must be a valid NUL-terminated C string.
 #[no_mangle]
 pub unsafe extern "C" fn mesh_send_sos(text: *const c_char, lat: f64, lon: f64) -> i32 {
     let state = match STATE.get() { Some(s) => s, None => return -1 };
@@ -218,6 +335,9 @@ pub unsafe extern "C" fn mesh_send_sos(text: *const c_char, lat: f64, lon: f64)
 }
 
 /// Update node profile.
+///
+/// # Safety
+/// `name` and `bio` must be valid NUL-terminated C strings.
 #[no_mangle]
 pub unsafe extern "C" fn mesh_update_profile(name: *const c_char, bio: *const c_char) -> i32 {
     let state = match STATE.get() { Some(s) => s, None => return -1 };
@@ -228,6 +348,9 @@ pub unsafe extern "C" fn mesh_update_profile(name: *const c_char, bio: *const c_
 }
 
 /// Accept a file transfer by file_id (16 bytes hex = 32 chars).
+///
+/// # Safety
+/// `file_id_hex` must be a valid NUL-terminated C string.
 #[no_mangle]
 pub unsafe extern "C" fn mesh_accept_file(file_id_hex: *const c_char) -> i32 {
     let state = match STATE.get() { Some(s) => s, None => return -1 };
@@ -239,6 +362,188 @@ pub unsafe extern "C" fn mesh_accept_file(file_id_hex: *const c_char) -> i32 {
     state.runtime.block_on(h.accept_file(file_id)).map(|_| 0i32).unwrap_or(-1)
 }
 
+/// Accept only some entries of a bundle offer. `entries` is a comma-separated
+/// list of manifest indices ("0,2,5").
+///
+/// # Safety
+/// `file_id_hex` and `entries` must be valid NUL-terminated C strings.
+#[no_mangle]
+pub unsafe extern "C" fn mesh_accept_file_entries(file_id_hex: *const c_char, entries: *const c_char) -> i32 {
+    let state = match STATE.get() { Some(s) => s, None => return -1 };
+    let hex_str = match CStr::from_ptr(file_id_hex).to_str() { Ok(s) => s, Err(_) => return -1 };
+    let list = match CStr::from_ptr(entries).to_str() { Ok(s) => s, Err(_) => return -1 };
+    let bytes = match hex::decode(hex_str) { Ok(b) if b.len() == 16 => b, _ => return -1 };
+    let entries = match parse_entry_list(list) { Some(e) if !e.is_empty() => e, _ => return -1 };
+    let mut file_id = [0u8; 16];
+    file_id.copy_from_slice(&bytes);
+    let h = state.handle.clone();
+    state.runtime.block_on(h.accept_file_entries(file_id, entries)).map(|_| 0i32).unwrap_or(-1)
+}
+
+/// Send several files and/or directories to a peer as one bundle.
+/// `paths` is newline-separated.
+///
+/// # Safety
+/// `dest_hex` and `paths` must be valid NUL-terminated C strings.
+#[no_mangle]
+pub unsafe extern "C" fn mesh_send_bundle(dest_hex: *const c_char, paths: *const c_char) -> i32 {
+    let state = match STATE.get() { Some(s) => s, None => return -1 };
+    let dest_str = match CStr::from_ptr(dest_hex).to_str() { Ok(s) => s, Err(_) => return -1 };
+    let paths = match CStr::from_ptr(paths).to_str() { Ok(s) => s, Err(_) => return -1 };
+    let dest = match parse_hex_node_id(dest_str) { Some(b) => b, None => return -1 };
+    let paths: Vec<String> = paths.lines().filter(|l| !l.is_empty()).map(String::from).collect();
+    let h = state.handle.clone();
+    state.runtime.block_on(h.send_bundle(dest, paths)).map(|_| 0i32).unwrap_or(-1)
+}
+
+/// Decline an incoming file offer by file_id (hex).
+///
+/// # Safety
+/// `file_id_hex` must be a valid NUL-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn mesh_decline_file(file_id_hex: *const c_char) -> i32 {
+    let state = match STATE.get() { Some(s) => s, None => return -1 };
+    let hex_str = match CStr::from_ptr(file_id_hex).to_str() { Ok(s) => s, Err(_) => return -1 };
+    let bytes = match hex::decode(hex_str) { Ok(b) if b.len() == 16 => b, _ => return -1 };
+    let mut file_id = [0u8; 16];
+    file_id.copy_from_slice(&bytes);
+    let h = state.handle.clone();
+    state.runtime.block_on(h.decline_file(file_id)).map(|_| 0i32).unwrap_or(-1)
+}
+
+/// Cancel an in-progress file transfer (either direction) by file_id (hex).
+///
+/// # Safety
+/// `file_id_hex` must be a valid NUL-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn mesh_cancel_file(file_id_hex: *const c_char) -> i32 {
+    let state = match STATE.get() { Some(s) => s, None => return -1 };
+    let hex_str = match CStr::from_ptr(file_id_hex).to_str() { Ok(s) => s, Err(_) => return -1 };
+    let bytes = match hex::decode(hex_str) { Ok(b) if b.len() == 16 => b, _ => return -1 };
+    let mut file_id = [0u8; 16];
+    file_id.copy_from_slice(&bytes);
+    let h = state.handle.clone();
+    state.runtime.block_on(h.cancel_file(file_id)).map(|_| 0i32).unwrap_or(-1)
+}
+
+/// Mark a contact as a favorite (non-zero) or not (zero).
+///
+/// # Safety
+/// `node_id_hex` must be a valid NUL-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn mesh_set_favorite(node_id_hex: *const c_char, favorite: i32) -> i32 {
+    let state = match STATE.get() { Some(s) => s, None => return -1 };
+    let hex_str = match CStr::from_ptr(node_id_hex).to_str() { Ok(s) => s, Err(_) => return -1 };
+    let node_id = match parse_hex_node_id(hex_str) { Some(b) => b, None => return -1 };
+    let h = state.handle.clone();
+    state.runtime.block_on(h.set_favorite(node_id, favorite != 0)).map(|_| 0i32).unwrap_or(-1)
+}
+
+/// Mark a contact's safety number as verified (non-zero) or clear it (zero).
+///
+/// # Safety
+/// `node_id_hex` must be a valid NUL-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn mesh_set_verified(node_id_hex: *const c_char, verified: i32) -> i32 {
+    let state = match STATE.get() { Some(s) => s, None => return -1 };
+    let hex_str = match CStr::from_ptr(node_id_hex).to_str() { Ok(s) => s, Err(_) => return -1 };
+    let node_id = match parse_hex_node_id(hex_str) { Some(b) => b, None => return -1 };
+    let h = state.handle.clone();
+    state.runtime.block_on(h.set_verified(node_id, verified != 0)).map(|_| 0i32).unwrap_or(-1)
+}
+
+/// Look for a route to a node. The answer arrives as a route_found or no_route event.
+///
+/// # Safety
+/// `node_id_hex` must be a valid NUL-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn mesh_discover_route(node_id_hex: *const c_char) -> i32 {
+    let state = match STATE.get() { Some(s) => s, None => return -1 };
+    let hex_str = match CStr::from_ptr(node_id_hex).to_str() { Ok(s) => s, Err(_) => return -1 };
+    let node_id = match parse_hex_node_id(hex_str) { Some(b) => b, None => return -1 };
+    let h = state.handle.clone();
+    state.runtime.block_on(h.discover_route(node_id)).map(|_| 0i32).unwrap_or(-1)
+}
+
+/// Choose how broadcasts are relayed: `mode` 0 = full flood, 1 = gossip with
+/// `probability`, 2 = counter-based (skip after `threshold` copies heard within
+/// a random delay up to `max_delay_ms`), 3 = neighbor coverage (same delay).
+/// SOS is always fully flooded.
+#[no_mangle]
+pub extern "C" fn mesh_set_flood_strategy(mode: i32, probability: f32, threshold: i32, max_delay_ms: i32) -> i32 {
+    let state = match STATE.get() { Some(s) => s, None => return -1 };
+    let strategy = match build_flood_strategy(mode, probability, threshold, max_delay_ms) { Some(s) => s, None => return -1 };
+    let h = state.handle.clone();
+    state.runtime.block_on(h.set_flood_strategy(strategy)).map(|_| 0i32).unwrap_or(-1)
+}
+
+/// Ping a node through the mesh. The answer arrives as a ping_result event.
+///
+/// # Safety
+/// `node_id_hex` must be a valid NUL-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn mesh_ping_node(node_id_hex: *const c_char) -> i32 {
+    let state = match STATE.get() { Some(s) => s, None => return -1 };
+    let hex_str = match CStr::from_ptr(node_id_hex).to_str() { Ok(s) => s, Err(_) => return -1 };
+    let node_id = match parse_hex_node_id(hex_str) { Some(b) => b, None => return -1 };
+    let h = state.handle.clone();
+    state.runtime.block_on(h.mesh_ping(node_id)).map(|_| 0i32).unwrap_or(-1)
+}
+
+/// Trace the path to a node. The answer arrives as a trace_result event.
+///
+/// # Safety
+/// `node_id_hex` must be a valid NUL-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn mesh_traceroute(node_id_hex: *const c_char) -> i32 {
+    let state = match STATE.get() { Some(s) => s, None => return -1 };
+    let hex_str = match CStr::from_ptr(node_id_hex).to_str() { Ok(s) => s, Err(_) => return -1 };
+    let node_id = match parse_hex_node_id(hex_str) { Some(b) => b, None => return -1 };
+    let h = state.handle.clone();
+    state.runtime.block_on(h.traceroute(node_id)).map(|_| 0i32).unwrap_or(-1)
+}
+
+/// Set the file auto-accept policy.
+/// `allowed_types` is a comma-separated list of extensions and/or MIME types
+/// ("pdf,jpg,image/*"); `blocked_extensions` likewise ("exe,bat"). Either may be
+/// null for none. Sizes <= 0 mean no limit.
+///
+/// # Safety
+/// `allowed_types` and `blocked_extensions` may be null; otherwise they must be valid C strings.
+#[no_mangle]
+pub unsafe extern "C" fn mesh_set_file_policy(
+    accept_favorites: i32,
+    accept_verified: i32,
+    max_auto_size: i64,
+    allowed_types: *const c_char,
+    blocked_extensions: *const c_char,
+    min_free_disk: i64,
+) -> i32 {
+    let state = match STATE.get() { Some(s) => s, None => return -1 };
+    let allowed = if allowed_types.is_null() { "" } else {
+        match CStr::from_ptr(allowed_types).to_str() { Ok(s) => s, Err(_) => return -1 }
+    };
+    let blocked = if blocked_extensions.is_null() { "" } else {
+        match CStr::from_ptr(blocked_extensions).to_str() { Ok(s) => s, Err(_) => return -1 }
+    };
+    let policy = build_file_policy(accept_favorites != 0, accept_verified != 0, max_auto_size, allowed, blocked, min_free_disk);
+    let h = state.handle.clone();
+    state.runtime.block_on(h.set_file_policy(policy)).map(|_| 0i32).unwrap_or(-1)
+}
+
+/// Download a file by its SHA-256 (64 hex chars) from any nodes holding it.
+///
+/// # Safety
+/// `content_hash_hex` must be a valid NUL-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn mesh_fetch_file(content_hash_hex: *const c_char) -> i32 {
+    let state = match STATE.get() { Some(s) => s, None => return -1 };
+    let hex_str = match CStr::from_ptr(content_hash_hex).to_str() { Ok(s) => s, Err(_) => return -1 };
+    let content_hash = match parse_hex_node_id(hex_str) { Some(h) => h, None => return -1 };
+    let h = state.handle.clone();
+    state.runtime.block_on(h.fetch_file(content_hash)).map(|_| 0i32).unwrap_or(-1)
+}
+
 /// Nuke: destroy identity and stop node.
 #[no_mangle]
 pub extern "C" fn mesh_nuke() -> i32 {
@@ -271,7 +576,18 @@ pub extern "C" fn mesh_get_peers_list() -> i32 {
     state.runtime.block_on(h.get_peers()).map(|_| 0i32).unwrap_or(-1)
 }
 
+/// Request the mesh map (will be returned via mesh_poll_event as event_type 43).
+#[no_mangle]
+pub extern "C" fn mesh_get_topology() -> i32 {
+    let state = match STATE.get() { Some(s) => s, None => return -1 };
+    let h = state.handle.clone();
+    state.runtime.block_on(h.get_topology()).map(|_| 0i32).unwrap_or(-1)
+}
+
 /// Start a voice call with a peer.
+///
+/// # Safety
+/// `peer_hex` must be a valid NUL-terminated C string.
 #[no_mangle]
 pub unsafe extern "C" fn mesh_start_call(peer_hex: *const c_char) -> i32 {
     let state = match STATE.get() { Some(s) => s, None => return -1 };
@@ -290,6 +606,10 @@ pub extern "C" fn mesh_end_call() -> i32 {
 }
 
 /// Send an audio frame during a call.
+///
+/// # Safety
+/// `peer_hex` must be a valid NUL-terminated C string.
+/// `data` must point to at least `data_len` readable bytes.
 #[no_mangle]
 pub unsafe extern "C" fn mesh_send_audio_frame(
     peer_hex: *const c_char,
@@ -309,6 +629,8 @@ pub unsafe extern "C" fn mesh_send_audio_frame(
 // New C FFI functions for extended features
 // ---------------------------------------------------------------------------
 
+/// # Safety
+/// `dest_hex` and `msg_id_hex` must be valid NUL-terminated C strings.
 #[no_mangle]
 pub unsafe extern "C" fn mesh_send_read_receipt(dest_hex: *const c_char, msg_id_hex: *const c_char) -> i32 {
     let state = match STATE.get() { Some(s) => s, None => return -1 };
@@ -320,6 +642,8 @@ pub unsafe extern "C" fn mesh_send_read_receipt(dest_hex: *const c_char, msg_id_
     state.runtime.block_on(h.send_read_receipt(dest, mid)).map(|_| 0i32).unwrap_or(-1)
 }
 
+/// # Safety
+/// `dest_hex` may be null; otherwise it must be a valid C string.
 #[no_mangle]
 pub unsafe extern "C" fn mesh_send_typing_start(dest_hex: *const c_char) -> i32 {
     let state = match STATE.get() { Some(s) => s, None => return -1 };
@@ -331,6 +655,8 @@ pub unsafe extern "C" fn mesh_send_typing_start(dest_hex: *const c_char) -> i32
     state.runtime.block_on(h.send_typing_start(dest)).map(|_| 0i32).unwrap_or(-1)
 }
 
+/// # Safety
+/// `dest_hex` may be null; otherwise it must be a valid C string.
 #[no_mangle]
 pub unsafe extern "C" fn mesh_send_typing_stop(dest_hex: *const c_char) -> i32 {
     let state = match STATE.get() { Some(s) => s, None => return -1 };
@@ -342,6 +668,8 @@ pub unsafe extern "C" fn mesh_send_typing_stop(dest_hex: *const c_char) -> i32 {
     state.runtime.block_on(h.send_typing_stop(dest)).map(|_| 0i32).unwrap_or(-1)
 }
 
+/// # Safety
+/// `group_name` must be a valid NUL-terminated C string.
 #[no_mangle]
 pub unsafe extern "C" fn mesh_join_group(group_name: *const c_char) -> i32 {
     let state = match STATE.get() { Some(s) => s, None => return -1 };
@@ -350,6 +678,8 @@ pub unsafe extern "C" fn mesh_join_group(group_name: *const c_char) -> i32 {
     state.runtime.block_on(h.join_group(name)).map(|_| 0i32).unwrap_or(-1)
 }
 
+/// # Safety
+/// `group_name` must be a valid NUL-terminated C string.
 #[no_mangle]
 pub unsafe extern "C" fn mesh_leave_group(group_name: *const c_char) -> i32 {
     let state = match STATE.get() { Some(s) => s, None => return -1 };
@@ -358,6 +688,8 @@ pub unsafe extern "C" fn mesh_leave_group(group_name: *const c_char) -> i32 {
     state.runtime.block_on(h.leave_group(name)).map(|_| 0i32).unwrap_or(-1)
 }
 
+/// # Safety
+/// `group_name` and `text` must be valid NUL-terminated C strings.
 #[no_mangle]
 pub unsafe extern "C" fn mesh_send_group_message(group_name: *const c_char, text: *const c_char) -> i32 {
     let state = match STATE.get() { Some(s) => s, None => return -1 };
@@ -367,6 +699,8 @@ pub unsafe extern "C" fn mesh_send_group_message(group_name: *const c_char, text
     state.runtime.block_on(h.send_group_message(name, text)).map(|_| 0i32).unwrap_or(-1)
 }
 
+/// # Safety
+/// `victim_id` and `notes` must be valid NUL-terminated C strings.
 #[no_mangle]
 pub unsafe extern "C" fn mesh_send_triage(level: i32, victim_id: *const c_char, notes: *const c_char, lat: f64, lon: f64) -> i32 {
     let state = match STATE.get() { Some(s) => s, None => return -1 };
@@ -381,6 +715,8 @@ pub unsafe extern "C" fn mesh_send_triage(level: i32, victim_id: *const c_char,
     state.runtime.block_on(h.send_triage(payload)).map(|_| 0i32).unwrap_or(-1)
 }
 
+/// # Safety
+/// `category` and `description` must be valid NUL-terminated C strings.
 #[no_mangle]
 pub unsafe extern "C" fn mesh_send_resource_request(
     category: *const c_char, description: *const c_char, urgency: i32, lat: f64, lon: f64, quantity: i32,
@@ -396,6 +732,8 @@ pub unsafe extern "C" fn mesh_send_resource_request(
     state.runtime.block_on(h.send_resource_request(payload)).map(|_| 0i32).unwrap_or(-1)
 }
 
+/// # Safety
+/// `status` and `message` must be valid NUL-terminated C strings.
 #[no_mangle]
 pub unsafe extern "C" fn mesh_send_check_in(status: *const c_char, lat: f64, lon: f64, message: *const c_char) -> i32 {
     let state = match STATE.get() { Some(s) => s, None => return -1 };
@@ -407,6 +745,9 @@ pub unsafe extern "C" fn mesh_send_check_in(status: *const c_char, lat: f64, lon
     state.runtime.block_on(h.send_check_in(payload)).map(|_| 0i32).unwrap_or(-1)
 }
 
+/// # Safety
+/// `text` must be a valid NUL-terminated C string.
+/// `dest_hex` may be null; otherwise it must be a valid C string.
 #[no_mangle]
 pub unsafe extern "C" fn mesh_send_disappearing(dest_hex: *const c_char, text: *const c_char, ttl_seconds: i32) -> i32 {
     let state = match STATE.get() { Some(s) => s, None => return -1 };
@@ -419,6 +760,8 @@ pub unsafe extern "C" fn mesh_send_disappearing(dest_hex: *const c_char, text: *
     state.runtime.block_on(h.send_disappearing(dest, text, ttl_seconds as u32)).map(|_| 0i32).unwrap_or(-1)
 }
 
+/// # Safety
+/// `peer_hex` and `group_name` may be null; otherwise each must be a valid C string.
 #[no_mangle]
 pub unsafe extern "C" fn mesh_load_history(peer_hex: *const c_char, group_name: *const c_char) -> i32 {
     let state = match STATE.get() { Some(s) => s, None => return -1 };
@@ -443,11 +786,19 @@ pub unsafe extern "C" fn mesh_load_history(peer_hex: *const c_char, group_name:
 ///   5=file_offered, 6=file_progress, 7=file_complete, 8=voice_received,
 ///   9=profile_updated, 10=gateway_found, 11=stats, 12=sos_received,
 ///   13=call_incoming, 14=audio_frame, 15=call_ended, 16=peer_list,
-///   17=public_broadcast, 18=gateway_lost, 19=nuked, 20=stopped
+///   17=public_broadcast, 18=gateway_lost, 19=nuked, 20=stopped,
+///   21=message_delivered, 22=typing_started, 23=typing_stopped, 24=group_message,
+///   25=group_joined, 26=group_left, 27=triage, 28=resource_request, 29=check_in,
+///   30=disappearing, 31=history_loaded, 32=file_declined, 33=file_cancelled,
+///   34=file_failed, 35=file_fetch_started, 36=file_auto_accepted, 37=file_auto_rejected,
+///   38=bundle_offered (data = manifest JSON), 39=route_found (value = hops),
+///   40=no_route, 41=ping_result / 42=trace_result (data = hops JSON,
+///   value = round trip to the last hop that answered in ms, or -1),
+///   43=topology (data = mesh map JSON, extra = Graphviz DOT, value = node count)
 #[repr(C)]
 pub struct MeshEvent {
     pub event_type: i32,
-    /// Node ID as hex string (for peer events and messages)
+    /// Node ID as hex string (for peer events and messages; content hash for file_fetch_started)
     pub node_id: *mut c_char,
     /// Display name or message content
     pub data: *mut c_char,
@@ -457,12 +808,14 @@ pub struct MeshEvent {
     pub extra: *mut c_char,
     /// Numeric value (progress %, duration, etc.)
     pub value: i64,
-    /// Float values (lat, lon for SOS)
+    /// Float values (lat, lon for SOS; bytes/sec and ETA seconds for file progress)
     pub float1: f64,
     pub float2: f64,
-    /// Binary data pointer and length (for audio)
+    /// Binary data pointer and length (audio for voice notes, thumbnail for file offers)
     pub binary_data: *mut u8,
     pub binary_len: u32,
+    /// MIME type of `binary_data` when it is an image preview, otherwise null
+    pub binary_mime: *mut c_char,
 }
 
 impl MeshEvent {
@@ -478,10 +831,24 @@ impl MeshEvent {
             float2: 0.0,
             binary_data: std::ptr::null_mut(),
             binary_len: 0,
+            binary_mime: std::ptr::null_mut(),
         }
     }
 }
 
+/// Hand a byte buffer to the caller as a raw pointer and length (null for empty).
+/// Caller frees it with mesh_free_binary.
+fn into_raw_binary(data: Vec<u8>) -> (*mut u8, u32) {
+    if data.is_empty() {
+        return (std::ptr::null_mut(), 0);
+    }
+    let len = data.len() as u32;
+    let mut boxed = data.into_boxed_slice();
+    let ptr = boxed.as_mut_ptr();
+    std::mem::forget(boxed);
+    (ptr, len)
+}
+
 /// Get the node ID as a hex string. Caller must free with mesh_free_string.
 #[no_mangle]
 pub extern "C" fn mesh_get_node_id() -> *mut c_char {
@@ -535,19 +902,39 @@ fn convert_event(event: NodeEvent) -> MeshEvent {
             sender_name: to_c_string(&sender_name),
             ..MeshEvent::empty()
         },
-        NodeEvent::FileOffered { sender_id, sender_name, file_id, filename, size } => MeshEvent {
-            event_type: 5,
+        NodeEvent::FileOffered { sender_id, sender_name, file_id, filename, size, preview } => {
+            let (binary_mime, (binary_data, binary_len)) = match preview {
+                Some(p) => (to_c_string(&p.mime_type), into_raw_binary(p.data)),
+                None => (std::ptr::null_mut(), (std::ptr::null_mut(), 0)),
+            };
+            MeshEvent {
+                event_type: 5,
+                node_id: to_c_string(&hex::encode(sender_id)),
+                data: to_c_string(&filename),
+                sender_name: to_c_string(&sender_name),
+                extra: to_c_string(&hex::encode(file_id)),
+                value: size as i64,
+                binary_data,
+                binary_len,
+                binary_mime,
+                ..MeshEvent::empty()
+            }
+        },
+        NodeEvent::BundleOffered { sender_id, sender_name, file_id, name, size, entries } => MeshEvent {
+            event_type: 38,
             node_id: to_c_string(&hex::encode(sender_id)),
-            data: to_c_string(&filename),
+            data: to_c_string(&bundle_json(&name, &entries)),
             sender_name: to_c_string(&sender_name),
             extra: to_c_string(&hex::encode(file_id)),
             value: size as i64,
             ..MeshEvent::empty()
         },
-        NodeEvent::FileProgress { file_id, pct } => MeshEvent {
+        NodeEvent::FileProgress { file_id, pct, bytes_per_sec, eta_secs } => MeshEvent {
             event_type: 6,
             extra: to_c_string(&hex::encode(file_id)),
             value: pct as i64,
+            float1: bytes_per_sec as f64,
+            float2: eta_secs.map(|e| e as f64).unwrap_or(-1.0),
             ..MeshEvent::empty()
         },
         NodeEvent::FileComplete { file_id, path } => MeshEvent {
@@ -556,6 +943,50 @@ fn convert_event(event: NodeEvent) -> MeshEvent {
             extra: to_c_string(&hex::encode(file_id)),
             ..MeshEvent::empty()
         },
+        NodeEvent::FileDeclined { file_id, by_peer } => MeshEvent {
+            event_type: 32,
+            node_id: to_c_string(&hex::encode(by_peer)),
+            extra: to_c_string(&hex::encode(file_id)),
+            ..MeshEvent::empty()
+        },
+        NodeEvent::FileCancelled { file_id, by_peer, reason } => MeshEvent {
+            event_type: 33,
+            node_id: to_c_string(&hex::encode(by_peer)),
+            data: to_c_string(&reason),
+            extra: to_c_string(&hex::encode(file_id)),
+            ..MeshEvent::empty()
+        },
+        NodeEvent::FileFailed { file_id, reason } => MeshEvent {
+            event_type: 34,
+            data: to_c_string(&reason),
+            extra: to_c_string(&hex::encode(file_id)),
+            ..MeshEvent::empty()
+        },
+        NodeEvent::FileAutoAccepted { sender_id, sender_name, file_id, filename, size, reason } => MeshEvent {
+            event_type: 36,
+            node_id: to_c_string(&hex::encode(sender_id)),
+            data: to_c_string(&format!("{}: {}", filename, reason)),
+            sender_name: to_c_string(&sender_name),
+            extra: to_c_string(&hex::encode(file_id)),
+            value: size as i64,
+            ..MeshEvent::empty()
+        },
+        NodeEvent::FileAutoRejected { sender_id, sender_name, file_id, filename, reason } => MeshEvent {
+            event_type: 37,
+            node_id: to_c_string(&hex::encode(sender_id)),
+            data: to_c_string(&format!("{}: {}", filename, reason)),
+            sender_name: to_c_string(&sender_name),
+            extra: to_c_string(&hex::encode(file_id)),
+            ..MeshEvent::empty()
+        },
+        NodeEvent::FileFetchStarted { file_id, content_hash, filename, size } => MeshEvent {
+            event_type: 35,
+            node_id: to_c_string(&hex::encode(content_hash)),
+            data: to_c_string(&filename),
+            extra: to_c_string(&hex::encode(file_id)),
+            value: size as i64,
+            ..MeshEvent::empty()
+        },
         NodeEvent::VoiceReceived { sender_id, sender_name, audio_data, duration_ms } => {
             let len = audio_data.len() as u32;
             let ptr = if audio_data.is_empty() {
@@ -600,9 +1031,10 @@ fn convert_event(event: NodeEvent) -> MeshEvent {
                 )
             }).collect();
             let json = format!(
-                r#"{{"total_peers":{},"messages_relayed":{},"messages_received":{},"unique_nodes_seen":{},"avg_hops":{:.2},"interfaces":[{}],"active_interface":"{}"}}"#,
+                r#"{{"total_peers":{},"messages_relayed":{},"messages_received":{},"unique_nodes_seen":{},"avg_hops":{:.2},"broadcasts_suppressed":{},"messages_held":{},"avg_rtt_ms":{:.1},"avg_jitter_ms":{:.1},"avg_loss":{:.3},"avg_etx":{:.2},"interfaces":[{}],"active_interface":"{}"}}"#,
                 stats.total_peers, stats.messages_relayed, stats.messages_received,
-                stats.unique_nodes_seen, stats.avg_hops,
+                stats.unique_nodes_seen, stats.avg_hops, stats.broadcasts_suppressed, stats.messages_held,
+                stats.avg_rtt_ms, stats.avg_jitter_ms, stats.avg_loss, stats.avg_etx,
                 iface_entries.join(","),
                 stats.active_interface.replace('"', r#"\""#),
             );
@@ -657,12 +1089,21 @@ fn convert_event(event: NodeEvent) -> MeshEvent {
             // Serialize peer list as JSON
             let entries: Vec<String> = peers.iter().map(|p| {
                 format!(
-                    r#"{{"node_id":"{}","name":"{}","addr":"{}","is_gateway":{},"bio":"{}"}}"#,
+                    r#"{{"node_id":"{}","name":"{}","addr":"{}","is_gateway":{},"bio":"{}","rx":{},"dup":{},"fwd":{},"drop":{},"rtt_ms":{},"jitter_ms":{:.1},"loss":{:.3},"etx":{:.2},"udp":{}}}"#,
                     hex::encode(p.node_id),
                     p.display_name.replace('"', r#"\""#),
                     p.addr.replace('"', r#"\""#),
                     p.is_gateway,
                     p.bio.replace('"', r#"\""#),
+                    p.link.received,
+                    p.link.duplicates,
+                    p.link.forwarded,
+                    p.link.dropped,
+                    p.quality.rtt_ms.map(|r| format!("{:.1}", r)).unwrap_or_else(|| "null".into()),
+                    p.quality.jitter_ms,
+                    p.quality.loss,
+                    p.quality.etx,
+                    p.udp,
                 )
             }).collect();
             let json = format!("[{}]", entries.join(","));
@@ -685,6 +1126,38 @@ fn convert_event(event: NodeEvent) -> MeshEvent {
             node_id: to_c_string(&hex::encode(node_id)),
             ..MeshEvent::empty()
         },
+        NodeEvent::RouteFound { node_id, hops } => MeshEvent {
+            event_type: 39,
+            node_id: to_c_string(&hex::encode(node_id)),
+            value: hops as i64,
+            ..MeshEvent::empty()
+        },
+        NodeEvent::NoRoute { node_id } => MeshEvent {
+            event_type: 40,
+            node_id: to_c_string(&hex::encode(node_id)),
+            ..MeshEvent::empty()
+        },
+        NodeEvent::PingResult { target, reached, hops } => MeshEvent {
+            event_type: 41,
+            node_id: to_c_string(&hex::encode(target)),
+            data: to_c_string(&trace_json(reached, &hops)),
+            value: trace_rtt(&hops),
+            ..MeshEvent::empty()
+        },
+        NodeEvent::TraceResult { target, reached, hops } => MeshEvent {
+            event_type: 42,
+            node_id: to_c_string(&hex::encode(target)),
+            data: to_c_string(&trace_json(reached, &hops)),
+            value: trace_rtt(&hops),
+            ..MeshEvent::empty()
+        },
+        NodeEvent::Topology { graph } => MeshEvent {
+            event_type: 43,
+            data: to_c_string(&graph.to_json()),
+            extra: to_c_string(&graph.to_dot()),
+            value: graph.nodes.len() as i64,
+            ..MeshEvent::empty()
+        },
         NodeEvent::MessageDelivered { msg_id, by_peer } => MeshEvent {
             event_type: 21,
             node_id: to_c_string(&hex::encode(by_peer)),
@@ -829,7 +1302,7 @@ pub unsafe extern "C" fn mesh_free_binary(ptr: *mut u8, len: u32) {
 mod jni_bindings {
     use jni::JNIEnv;
     use jni::objects::{JClass, JObject, JString, JValue, JByteArray};
-    use jni::sys::{jint, jstring, jobject, jdouble};
+    use jni::sys::{jint, jlong, jstring, jobject, jdouble, jfloat};
 
     use super::*;
 
@@ -896,6 +1369,27 @@ mod jni_bindings {
         state.runtime.block_on(h.send_file(dest, &path)).map(|_| 0i32).unwrap_or(-1)
     }
 
+    #[no_mangle]
+    pub extern "system" fn Java_com_mesh_app_MeshBridge_meshSendFileToGroup(
+        mut env: JNIEnv, _class: JClass, group_name: JString, file_path: JString,
+    ) -> jint {
+        let state = match STATE.get() { Some(s) => s, None => return -1 };
+        let group: String = match env.get_string(&group_name) { Ok(s) => s.into(), Err(_) => return -1 };
+        let path: String = match env.get_string(&file_path) { Ok(s) => s.into(), Err(_) => return -1 };
+        let h = state.handle.clone();
+        state.runtime.block_on(h.send_file_to_group(&group, &path)).map(|_| 0i32).unwrap_or(-1)
+    }
+
+    #[no_mangle]
+    pub extern "system" fn Java_com_mesh_app_MeshBridge_meshSendFileBroadcast(
+        mut env: JNIEnv, _class: JClass, file_path: JString,
+    ) -> jint {
+        let state = match STATE.get() { Some(s) => s, None => return -1 };
+        let path: String = match env.get_string(&file_path) { Ok(s) => s.into(), Err(_) => return -1 };
+        let h = state.handle.clone();
+        state.runtime.block_on(h.send_file_broadcast(&path)).map(|_| 0i32).unwrap_or(-1)
+    }
+
     #[no_mangle]
     pub extern "system" fn Java_com_mesh_app_MeshBridge_meshAcceptFile(
         mut env: JNIEnv, _class: JClass, file_id_hex: JString,
@@ -909,6 +1403,158 @@ mod jni_bindings {
         state.runtime.block_on(h.accept_file(file_id)).map(|_| 0i32).unwrap_or(-1)
     }
 
+    #[no_mangle]
+    pub extern "system" fn Java_com_mesh_app_MeshBridge_meshAcceptFileEntries(
+        mut env: JNIEnv, _class: JClass, file_id_hex: JString, entries: JString,
+    ) -> jint {
+        let state = match STATE.get() { Some(s) => s, None => return -1 };
+        let hex_str: String = match env.get_string(&file_id_hex) { Ok(s) => s.into(), Err(_) => return -1 };
+        let list: String = match env.get_string(&entries) { Ok(s) => s.into(), Err(_) => return -1 };
+        let bytes = match hex::decode(&hex_str) { Ok(b) if b.len() == 16 => b, _ => return -1 };
+        let entries = match parse_entry_list(&list) { Some(e) if !e.is_empty() => e, _ => return -1 };
+        let mut file_id = [0u8; 16];
+        file_id.copy_from_slice(&bytes);
+        let h = state.handle.clone();
+        state.runtime.block_on(h.accept_file_entries(file_id, entries)).map(|_| 0i32).unwrap_or(-1)
+    }
+
+    #[no_mangle]
+    pub extern "system" fn Java_com_mesh_app_MeshBridge_meshSendBundle(
+        mut env: JNIEnv, _class: JClass, dest_hex: JString, paths: JString,
+    ) -> jint {
+        let state = match STATE.get() { Some(s) => s, None => return -1 };
+        let dest_str: String = match env.get_string(&dest_hex) { Ok(s) => s.into(), Err(_) => return -1 };
+        let paths: String = match env.get_string(&paths) { Ok(s) => s.into(), Err(_) => return -1 };
+        let dest = match parse_hex_node_id(&dest_str) { Some(b) => b, None => return -1 };
+        let paths: Vec<String> = paths.lines().filter(|l| !l.is_empty()).map(String::from).collect();
+        let h = state.handle.clone();
+        state.runtime.block_on(h.send_bundle(dest, paths)).map(|_| 0i32).unwrap_or(-1)
+    }
+
+    #[no_mangle]
+    pub extern "system" fn Java_com_mesh_app_MeshBridge_meshDeclineFile(
+        mut env: JNIEnv, _class: JClass, file_id_hex: JString,
+    ) -> jint {
+        let state = match STATE.get() { Some(s) => s, None => return -1 };
+        let hex_str: String = match env.get_string(&file_id_hex) { Ok(s) => s.into(), Err(_) => return -1 };
+        let bytes = match hex::decode(&hex_str) { Ok(b) if b.len() == 16 => b, _ => return -1 };
+        let mut file_id = [0u8; 16];
+        file_id.copy_from_slice(&bytes);
+        let h = state.handle.clone();
+        state.runtime.block_on(h.decline_file(file_id)).map(|_| 0i32).unwrap_or(-1)
+    }
+
+    #[no_mangle]
+    pub extern "system" fn Java_com_mesh_app_MeshBridge_meshCancelFile(
+        mut env: JNIEnv, _class: JClass, file_id_hex: JString,
+    ) -> jint {
+        let state = match STATE.get() { Some(s) => s, None => return -1 };
+        let hex_str: String = match env.get_string(&file_id_hex) { Ok(s) => s.into(), Err(_) => return -1 };
+        let bytes = match hex::decode(&hex_str) { Ok(b) if b.len() == 16 => b, _ => return -1 };
+        let mut file_id = [0u8; 16];
+        file_id.copy_from_slice(&bytes);
+        let h = state.handle.clone();
+        state.runtime.block_on(h.cancel_file(file_id)).map(|_| 0i32).unwrap_or(-1)
+    }
+
+    #[no_mangle]
+    pub extern "system" fn Java_com_mesh_app_MeshBridge_meshSetFavorite(
+        mut env: JNIEnv, _class: JClass, node_id_hex: JString, favorite: jint,
+    ) -> jint {
+        let state = match STATE.get() { Some(s) => s, None => return -1 };
+        let hex_str: String = match env.get_string(&node_id_hex) { Ok(s) => s.into(), Err(_) => return -1 };
+        let node_id = match parse_hex_node_id(&hex_str) { Some(b) => b, None => return -1 };
+        let h = state.handle.clone();
+        state.runtime.block_on(h.set_favorite(node_id, favorite != 0)).map(|_| 0i32).unwrap_or(-1)
+    }
+
+    #[no_mangle]
+    pub extern "system" fn Java_com_mesh_app_MeshBridge_meshSetVerified(
+        mut env: JNIEnv, _class: JClass, node_id_hex: JString, verified: jint,
+    ) -> jint {
+        let state = match STATE.get() { Some(s) => s, None => return -1 };
+        let hex_str: String = match env.get_string(&node_id_hex) { Ok(s) => s.into(), Err(_) => return -1 };
+        let node_id = match parse_hex_node_id(&hex_str) { Some(b) => b, None => return -1 };
+        let h = state.handle.clone();
+        state.runtime.block_on(h.set_verified(node_id, verified != 0)).map(|_| 0i32).unwrap_or(-1)
+    }
+
+    #[no_mangle]
+    pub extern "system" fn Java_com_mesh_app_MeshBridge_meshDiscoverRoute(
+        mut env: JNIEnv, _class: JClass, node_id_hex: JString,
+    ) -> jint {
+        let state = match STATE.get() { Some(s) => s, None => return -1 };
+        let hex_str: String = match env.get_string(&node_id_hex) { Ok(s) => s.into(), Err(_) => return -1 };
+        let node_id = match parse_hex_node_id(&hex_str) { Some(b) => b, None => return -1 };
+        let h = state.handle.clone();
+        state.runtime.block_on(h.discover_route(node_id)).map(|_| 0i32).unwrap_or(-1)
+    }
+
+    #[no_mangle]
+    pub extern "system" fn Java_com_mesh_app_MeshBridge_meshPingNode(
+        mut env: JNIEnv, _class: JClass, node_id_hex: JString,
+    ) -> jint {
+        let state = match STATE.get() { Some(s) => s, None => return -1 };
+        let hex_str: String = match env.get_string(&node_id_hex) { Ok(s) => s.into(), Err(_) => return -1 };
+        let node_id = match parse_hex_node_id(&hex_str) { Some(b) => b, None => return -1 };
+        let h = state.handle.clone();
+        state.runtime.block_on(h.mesh_ping(node_id)).map(|_| 0i32).unwrap_or(-1)
+    }
+
+    #[no_mangle]
+    pub extern "system" fn Java_com_mesh_app_MeshBridge_meshTraceroute(
+        mut env: JNIEnv, _class: JClass, node_id_hex: JString,
+    ) -> jint {
+        let state = match STATE.get() { Some(s) => s, None => return -1 };
+        let hex_str: String = match env.get_string(&node_id_hex) { Ok(s) => s.into(), Err(_) => return -1 };
+        let node_id = match parse_hex_node_id(&hex_str) { Some(b) => b, None => return -1 };
+        let h = state.handle.clone();
+        state.runtime.block_on(h.traceroute(node_id)).map(|_| 0i32).unwrap_or(-1)
+    }
+
+    #[no_mangle]
+    pub extern "system" fn Java_com_mesh_app_MeshBridge_meshSetFloodStrategy(
+        _env: JNIEnv, _class: JClass, mode: jint, probability: jfloat, threshold: jint, max_delay_ms: jint,
+    ) -> jint {
+        let state = match STATE.get() { Some(s) => s, None => return -1 };
+        let strategy = match build_flood_strategy(mode, probability, threshold, max_delay_ms) { Some(s) => s, None => return -1 };
+        let h = state.handle.clone();
+        state.runtime.block_on(h.set_flood_strategy(strategy)).map(|_| 0i32).unwrap_or(-1)
+    }
+
+    #[no_mangle]
+    pub extern "system" fn Java_com_mesh_app_MeshBridge_meshSetFilePolicy(
+        mut env: JNIEnv, _class: JClass,
+        accept_favorites: jint, accept_verified: jint, max_auto_size: jlong,
+        allowed_types: JString, blocked_extensions: JString, min_free_disk: jlong,
+    ) -> jint {
+        let state = match STATE.get() { Some(s) => s, None => return -1 };
+        let allowed: String = if env.is_same_object(&allowed_types, JObject::null()).unwrap_or(true) {
+            String::new()
+        } else {
+            match env.get_string(&allowed_types) { Ok(s) => s.into(), Err(_) => return -1 }
+        };
+        let blocked: String = if env.is_same_object(&blocked_extensions, JObject::null()).unwrap_or(true) {
+            String::new()
+        } else {
+            match env.get_string(&blocked_extensions) { Ok(s) => s.into(), Err(_) => return -1 }
+        };
+        let policy = build_file_policy(accept_favorites != 0, accept_verified != 0, max_auto_size, &allowed, &blocked, min_free_disk);
+        let h = state.handle.clone();
+        state.runtime.block_on(h.set_file_policy(policy)).map(|_| 0i32).unwrap_or(-1)
+    }
+
+    #[no_mangle]
+    pub extern "system" fn Java_com_mesh_app_MeshBridge_meshFetchFile(
+        mut env: JNIEnv, _class: JClass, content_hash_hex: JString,
+    ) -> jint {
+        let state = match STATE.get() { Some(s) => s, None => return -1 };
+        let hex_str: String = match env.get_string(&content_hash_hex) { Ok(s) => s.into(), Err(_) => return -1 };
+        let content_hash = match parse_hex_node_id(&hex_str) { Some(h) => h, None => return -1 };
+        let h = state.handle.clone();
+        state.runtime.block_on(h.fetch_file(content_hash)).map(|_| 0i32).unwrap_or(-1)
+    }
+
     #[no_mangle]
     pub extern "system" fn Java_com_mesh_app_MeshBridge_meshSendVoice(
         mut env: JNIEnv, _class: JClass,
@@ -994,6 +1640,15 @@ mod jni_bindings {
         state.runtime.block_on(h.get_peers()).map(|_| 0i32).unwrap_or(-1)
     }
 
+    #[no_mangle]
+    pub extern "system" fn Java_com_mesh_app_MeshBridge_meshGetTopology(
+        _env: JNIEnv, _class: JClass,
+    ) -> jint {
+        let state = match STATE.get() { Some(s) => s, None => return -1 };
+        let h = state.handle.clone();
+        state.runtime.block_on(h.get_topology()).map(|_| 0i32).unwrap_or(-1)
+    }
+
     #[no_mangle]
     pub extern "system" fn Java_com_mesh_app_MeshBridge_meshStartCall(
         mut env: JNIEnv, _class: JClass, peer_hex: JString,
@@ -1187,6 +1842,11 @@ mod jni_bindings {
             None => return std::ptr::null_mut(),
         };
 
+        let binary_mime = match &event {
+            NodeEvent::FileOffered { preview: Some(p), .. } => Some(p.mime_type.clone()),
+            _ => None,
+        };
+
         let (event_type, node_id, data, sender_name, extra, value, float1, float2, binary) = match event {
             NodeEvent::Started { node_id } =>
                 (4i32, Some(node_id), None, None, None, 0i64, 0.0, 0.0, None),
@@ -1196,12 +1856,31 @@ mod jni_bindings {
                 (2, Some(hex::encode(node_id)), None, None, None, 0, 0.0, 0.0, None),
             NodeEvent::MessageReceived { sender_id, sender_name, content } =>
                 (3, Some(hex::encode(sender_id)), Some(content), Some(sender_name), None, 0, 0.0, 0.0, None),
-            NodeEvent::FileOffered { sender_id, sender_name, file_id, filename, size } =>
-                (5, Some(hex::encode(sender_id)), Some(filename), Some(sender_name), Some(hex::encode(file_id)), size as i64, 0.0, 0.0, None),
-            NodeEvent::FileProgress { file_id, pct } =>
-                (6, None, None, None, Some(hex::encode(file_id)), pct as i64, 0.0, 0.0, None),
+            NodeEvent::FileOffered { sender_id, sender_name, file_id, filename, size, preview } =>
+                (5, Some(hex::encode(sender_id)), Some(filename), Some(sender_name), Some(hex::encode(file_id)), size as i64, 0.0, 0.0,
+                 preview.map(|p| p.data)),
+            NodeEvent::BundleOffered { sender_id, sender_name, file_id, name, size, entries } =>
+                (38, Some(hex::encode(sender_id)), Some(bundle_json(&name, &entries)), Some(sender_name),
+                 Some(hex::encode(file_id)), size as i64, 0.0, 0.0, None),
+            NodeEvent::FileProgress { file_id, pct, bytes_per_sec, eta_secs } =>
+                (6, None, None, None, Some(hex::encode(file_id)), pct as i64,
+                 bytes_per_sec as f64, eta_secs.map(|e| e as f64).unwrap_or(-1.0), None),
             NodeEvent::FileComplete { file_id, path } =>
                 (7, None, Some(path), None, Some(hex::encode(file_id)), 0, 0.0, 0.0, None),
+            NodeEvent::FileDeclined { file_id, by_peer } =>
+                (32, Some(hex::encode(by_peer)), None, None, Some(hex::encode(file_id)), 0, 0.0, 0.0, None),
+            NodeEvent::FileCancelled { file_id, by_peer, reason } =>
+                (33, Some(hex::encode(by_peer)), Some(reason), None, Some(hex::encode(file_id)), 0, 0.0, 0.0, None),
+            NodeEvent::FileFailed { file_id, reason } =>
+                (34, None, Some(reason), None, Some(hex::encode(file_id)), 0, 0.0, 0.0, None),
+            NodeEvent::FileAutoAccepted { sender_id, sender_name, file_id, filename, size, reason } =>
+                (36, Some(hex::encode(sender_id)), Some(format!("{}: {}", filename, reason)), Some(sender_name),
+                 Some(hex::encode(file_id)), size as i64, 0.0, 0.0, None),
+            NodeEvent::FileAutoRejected { sender_id, sender_name, file_id, filename, reason } =>
+                (37, Some(hex::encode(sender_id)), Some(format!("{}: {}", filename, reason)), Some(sender_name),
+                 Some(hex::encode(file_id)), 0, 0.0, 0.0, None),
+            NodeEvent::FileFetchStarted { file_id, content_hash, filename, size } =>
+                (35, Some(hex::encode(content_hash)), Some(filename), None, Some(hex::encode(file_id)), size as i64, 0.0, 0.0, None),
             NodeEvent::VoiceReceived { sender_id, sender_name, audio_data, duration_ms } =>
                 (8, Some(hex::encode(sender_id)), None, Some(sender_name), None, duration_ms as i64, 0.0, 0.0, Some(audio_data)),
             NodeEvent::ProfileUpdated { node_id, name, bio } =>
@@ -1219,9 +1898,10 @@ mod jni_bindings {
                     )
                 }).collect();
                 let json = format!(
-                    r#"{{"total_peers":{},"messages_relayed":{},"messages_received":{},"unique_nodes_seen":{},"avg_hops":{:.2},"interfaces":[{}],"active_interface":"{}"}}"#,
+                    r#"{{"total_peers":{},"messages_relayed":{},"messages_received":{},"unique_nodes_seen":{},"avg_hops":{:.2},"broadcasts_suppressed":{},"messages_held":{},"avg_rtt_ms":{:.1},"avg_jitter_ms":{:.1},"avg_loss":{:.3},"avg_etx":{:.2},"interfaces":[{}],"active_interface":"{}"}}"#,
                     stats.total_peers, stats.messages_relayed, stats.messages_received,
-                    stats.unique_nodes_seen, stats.avg_hops,
+                    stats.unique_nodes_seen, stats.avg_hops, stats.broadcasts_suppressed, stats.messages_held,
+                    stats.avg_rtt_ms, stats.avg_jitter_ms, stats.avg_loss, stats.avg_etx,
                     iface_entries.join(","),
                     stats.active_interface.replace('"', r#"\""#),
                 );
@@ -1240,8 +1920,11 @@ mod jni_bindings {
             NodeEvent::PeerList { peers } => {
                 let entries: Vec<String> = peers.iter().map(|p| {
                     format!(
-                        r#"{{"node_id":"{}","name":"{}","addr":"{}","is_gateway":{},"bio":"{}"}}"#,
+                        r#"{{"node_id":"{}","name":"{}","addr":"{}","is_gateway":{},"bio":"{}","rx":{},"dup":{},"fwd":{},"drop":{},"rtt_ms":{},"jitter_ms":{:.1},"loss":{:.3},"etx":{:.2},"udp":{}}}"#,
                         hex::encode(p.node_id), p.display_name, p.addr, p.is_gateway, p.bio,
+                        p.link.received, p.link.duplicates, p.link.forwarded, p.link.dropped,
+                        p.quality.rtt_ms.map(|r| format!("{:.1}", r)).unwrap_or_else(|| "null".into()),
+                        p.quality.jitter_ms, p.quality.loss, p.quality.etx, p.udp,
                     )
                 }).collect();
                 let json = format!("[{}]", entries.join(","));
@@ -1251,6 +1934,16 @@ mod jni_bindings {
                 (17, Some(hex::encode(sender_id)), Some(text), Some(sender_name), None, 0, 0.0, 0.0, None),
             NodeEvent::GatewayLost { node_id } =>
                 (18, Some(hex::encode(node_id)), None, None, None, 0, 0.0, 0.0, None),
+            NodeEvent::RouteFound { node_id, hops } =>
+                (39, Some(hex::encode(node_id)), None, None, None, hops as i64, 0.0, 0.0, None),
+            NodeEvent::NoRoute { node_id } =>
+                (40, Some(hex::encode(node_id)), None, None, None, 0, 0.0, 0.0, None),
+            NodeEvent::PingResult { target, reached, hops } =>
+                (41, Some(hex::encode(target)), Some(trace_json(reached, &hops)), None, None, trace_rtt(&hops), 0.0, 0.0, None),
+            NodeEvent::TraceResult { target, reached, hops } =>
+                (42, Some(hex::encode(target)), Some(trace_json(reached, &hops)), None, None, trace_rtt(&hops), 0.0, 0.0, None),
+            NodeEvent::Topology { graph } =>
+                (43, None, Some(graph.to_json()), None, Some(graph.to_dot()), graph.nodes.len() as i64, 0.0, 0.0, None),
             NodeEvent::MessageDelivered { msg_id, by_peer } =>
                 (21, Some(hex::encode(by_peer)), None, None, Some(hex::encode(msg_id)), 0, 0.0, 0.0, None),
             NodeEvent::TypingStarted { peer, peer_name } =>
@@ -1301,6 +1994,7 @@ mod jni_bindings {
         let j_data = make_jstring(&mut env, &data);
         let j_sender_name = make_jstring(&mut env, &sender_name);
         let j_extra = make_jstring(&mut env, &extra);
+        let j_binary_mime = make_jstring(&mut env, &binary_mime);
 
         // Create byte array for binary data
         let j_binary = match binary {
@@ -1313,10 +2007,10 @@ mod jni_bindings {
             _ => JObject::null(),
         };
 
-        // Construct MeshBridge$MeshEvent(int, String?, String?, String?, String?, long, double, double, byte[]?)
+        // Construct MeshBridge$MeshEvent(int, String?, String?, String?, String?, long, double, double, byte[]?, String?)
         let result = env.new_object(
             "com/mesh/app/MeshBridge$MeshEvent",
-            "(ILjava/lang/String;Ljava/lang/String;Ljava/lang/String;Ljava/lang/String;JDD[B)V",
+            "(ILjava/lang/String;Ljava/lang/String;Ljava/lang/String;Ljava/lang/String;JDD[BLjava/lang/String;)V",
             &[
                 JValue::Int(event_type),
                 JValue::Object(&j_node_id),
@@ -1327,6 +2021,7 @@ mod jni_bindings {
                 JValue::Double(float1),
                 JValue::Double(float2),
                 JValue::Object(&j_binary),
+                JValue::Object(&j_binary_mime),
             ],
         );
 
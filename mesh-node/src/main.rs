@@ -1,6 +1,6 @@
 #![windows_subsystem = "windows"]
 
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use std::sync::{Arc, Mutex as StdMutex, mpsc as std_mpsc};
 use std::time::Instant;
 
@@ -9,8 +9,9 @@ use eframe::egui;
 use egui::{Color32, CornerRadius, FontId, RichText, Stroke, StrokeKind, Vec2};
 use egui_extras::{TableBuilder, Column};
 
-use mesh_core::{NodeConfig, NodeEvent, NodeHandle, MeshStats, start_mesh_node, NodeIdentity};
+use mesh_core::{NodeConfig, NodeEvent, NodeHandle, MeshStats, start_mesh_node, NodeIdentity, BundleEntry, FloodStrategy, TopologyGraph, LinkQuality};
 use mesh_core::{TriagePayload, TriageLevel, ResourceRequestPayload, CheckInPayload};
+use mesh_core::{SerialConfig, SerialTransport, TcpTransport, Transport, WebSocketTransport};
 
 // ---------------------------------------------------------------------------
 // Colours (Discord-inspired dark theme)
@@ -50,6 +51,10 @@ struct PeerEntry {
     display_name: String,
     is_gateway: bool,
     bio: String,
+    /// From the last peer list refresh
+    quality: Option<LinkQuality>,
+    /// Call audio currently goes over UDP
+    udp: bool,
 }
 
 struct FileEntry {
@@ -57,9 +62,12 @@ struct FileEntry {
     filename: String,
     size: u64,
     progress: u8,
+    bytes_per_sec: u64,
+    eta_secs: Option<u32>,
     done: bool,
     incoming: bool,
     path: Option<String>,
+    failed: Option<String>,
 }
 
 #[derive(PartialEq, Clone, Copy)]
@@ -87,6 +95,9 @@ struct MeshApp {
     peers: Vec<PeerEntry>,
     files: Vec<FileEntry>,
     stats: MeshStats,
+    topology: TopologyGraph,
+    /// Last time link quality was requested for the Peers tab or call banner
+    last_link_refresh: Instant,
     gateway_name: Option<String>,
 
     // UI state
@@ -95,6 +106,11 @@ struct MeshApp {
     dm_target: Option<([u8; 32], String)>,
     show_nuke_confirm: bool,
     pending_file_offer: Option<([u8; 16], String, String, u64)>,
+    /// Thumbnail bytes for the pending offer, turned into a texture on first draw
+    offer_preview: Option<Vec<u8>>,
+    offer_preview_tex: Option<egui::TextureHandle>,
+    /// Manifest of the pending offer when it is a bundle, with the user's selection
+    offer_entries: Vec<(BundleEntry, bool)>,
     should_quit: bool,
 
     // Settings tab state
@@ -195,6 +211,8 @@ impl MeshApp {
                     display_name,
                     is_gateway: false,
                     bio: String::new(),
+                    quality: None,
+                    udp: false,
                 });
             }
             NodeEvent::PeerDisconnected { node_id } => {
@@ -228,15 +246,18 @@ impl MeshApp {
                 }
                 self.push_system(format!("* {} updated their profile", name));
             }
-            NodeEvent::FileOffered { sender_name, file_id, filename, size, .. } => {
+            NodeEvent::FileOffered { sender_name, file_id, filename, size, preview, .. } => {
                 self.files.push(FileEntry {
                     file_id,
                     filename: filename.clone(),
                     size,
                     progress: 0,
+                    bytes_per_sec: 0,
+                    eta_secs: None,
                     done: false,
                     incoming: true,
                     path: None,
+                    failed: None,
                 });
                 let size_str = format_size(size);
                 self.push_system(format!(
@@ -244,10 +265,40 @@ impl MeshApp {
                     sender_name, filename, size_str
                 ));
                 self.pending_file_offer = Some((file_id, sender_name, filename, size));
+                self.offer_preview = preview.map(|p| p.data);
+                self.offer_preview_tex = None;
+                self.offer_entries.clear();
             }
-            NodeEvent::FileProgress { file_id, pct } => {
+            NodeEvent::BundleOffered { sender_name, file_id, name, size, entries, .. } => {
+                self.files.push(FileEntry {
+                    file_id,
+                    filename: name.clone(),
+                    size,
+                    progress: 0,
+                    bytes_per_sec: 0,
+                    eta_secs: None,
+                    done: false,
+                    incoming: true,
+                    path: None,
+                    failed: None,
+                });
+                self.push_system(format!(
+                    "{} offers {} ({} files, {}):",
+                    sender_name, name, entries.len(), format_size(size)
+                ));
+                for (i, entry) in entries.iter().enumerate() {
+                    self.push_system(format!("  [{}] {} ({})", i, entry.path, format_size(entry.size)));
+                }
+                self.pending_file_offer = Some((file_id, sender_name, name, size));
+                self.offer_preview = None;
+                self.offer_preview_tex = None;
+                self.offer_entries = entries.into_iter().map(|e| (e, true)).collect();
+            }
+            NodeEvent::FileProgress { file_id, pct, bytes_per_sec, eta_secs } => {
                 if let Some(f) = self.files.iter_mut().find(|f| f.file_id == file_id) {
                     f.progress = pct;
+                    f.bytes_per_sec = bytes_per_sec;
+                    f.eta_secs = eta_secs;
                 }
             }
             NodeEvent::FileComplete { file_id, path } => {
@@ -258,6 +309,66 @@ impl MeshApp {
                 }
                 self.push_system(format!("File received: {}", path));
             }
+            NodeEvent::FileFetchStarted { file_id, filename, size, .. } => {
+                self.files.push(FileEntry {
+                    file_id,
+                    filename: filename.clone(),
+                    size,
+                    progress: 0,
+                    bytes_per_sec: 0,
+                    eta_secs: None,
+                    done: false,
+                    incoming: true,
+                    path: None,
+                    failed: None,
+                });
+                self.push_system(format!("Fetching from the mesh: {} ({})", filename, format_size(size)));
+            }
+            NodeEvent::FileAutoAccepted { sender_name, file_id, filename, size, reason, .. } => {
+                self.files.push(FileEntry {
+                    file_id,
+                    filename: filename.clone(),
+                    size,
+                    progress: 0,
+                    bytes_per_sec: 0,
+                    eta_secs: None,
+                    done: false,
+                    incoming: true,
+                    path: None,
+                    failed: None,
+                });
+                self.push_system(format!(
+                    "Auto-accepted {} ({}) from {}: {}",
+                    filename, format_size(size), sender_name, reason
+                ));
+            }
+            NodeEvent::FileAutoRejected { sender_name, filename, reason, .. } => {
+                self.push_system(format!("Auto-rejected {} from {}: {}", filename, sender_name, reason));
+            }
+            NodeEvent::FileDeclined { file_id, .. } => {
+                if let Some(f) = self.files.iter_mut().find(|f| f.file_id == file_id) {
+                    f.failed = Some("Declined".into());
+                }
+                self.push_system("File offer was declined".into());
+            }
+            NodeEvent::FileCancelled { file_id, reason, .. } => {
+                if let Some(f) = self.files.iter_mut().find(|f| f.file_id == file_id) {
+                    f.failed = Some(format!("Cancelled: {}", reason));
+                }
+                if self.pending_file_offer.as_ref().map(|o| o.0) == Some(file_id) {
+                    self.pending_file_offer = None;
+                }
+                self.push_system(format!("File transfer cancelled: {}", reason));
+            }
+            NodeEvent::FileFailed { file_id, reason } => {
+                if let Some(f) = self.files.iter_mut().find(|f| f.file_id == file_id) {
+                    f.failed = Some(reason.clone());
+                }
+                if self.pending_file_offer.as_ref().map(|o| o.0) == Some(file_id) {
+                    self.pending_file_offer = None;
+                }
+                self.push_system(format!("File transfer failed: {}", reason));
+            }
             NodeEvent::VoiceReceived { sender_name, audio_data, duration_ms, .. } => {
                 self.push_voice_note(sender_name, audio_data, duration_ms);
             }
@@ -294,10 +405,47 @@ impl MeshApp {
                 self.gateway_name = None;
                 self.push_system("Gateway lost".into());
             }
+            NodeEvent::RouteFound { node_id, hops } => {
+                self.push_system(format!("Route to {}: {} hop(s)", hex::encode(&node_id[..4]), hops));
+            }
+            NodeEvent::NoRoute { node_id } => {
+                self.push_system(format!("No route to {}", hex::encode(&node_id[..4])));
+            }
+            NodeEvent::PingResult { target, reached, hops } => {
+                let rtt = hops.iter().rev().find_map(|h| h.rtt_ms);
+                match (reached, rtt) {
+                    (true, Some(ms)) => self.push_system(format!(
+                        "Ping {}: {} ms over {} hop(s)", hex::encode(&target[..4]), ms, hops.len().saturating_sub(1),
+                    )),
+                    _ => self.push_system(format!("Ping {}: no answer", hex::encode(&target[..4]))),
+                }
+            }
+            NodeEvent::TraceResult { target, reached, hops } => {
+                let status = if reached { "" } else { " (not reached)" };
+                self.push_system(format!("Trace to {}{}:", hex::encode(&target[..4]), status));
+                for (i, hop) in hops.iter().enumerate() {
+                    let line = match (hop.node_id, hop.rtt_ms) {
+                        (Some(id), Some(ms)) => format!("  {}. {}  {} ms", i + 1, self.node_label(&id), ms),
+                        (Some(id), None) => format!("  {}. {}", i + 1, self.node_label(&id)),
+                        (None, _) => format!("  {}. *", i + 1),
+                    };
+                    self.push_system(line);
+                }
+            }
             NodeEvent::Stats { stats } => {
                 self.stats = stats;
             }
-            NodeEvent::PeerList { .. } => {}
+            NodeEvent::PeerList { peers } => {
+                for entry in peers {
+                    if let Some(p) = self.peers.iter_mut().find(|p| p.node_id == entry.node_id) {
+                        p.quality = Some(entry.quality);
+                        p.udp = entry.udp;
+                    }
+                }
+            }
+            NodeEvent::Topology { graph } => {
+                self.topology = graph;
+            }
             NodeEvent::MessageDelivered { .. } => {
                 // Read receipt received - could update UI message status
             }
@@ -357,6 +505,26 @@ impl MeshApp {
         }
     }
 
+    /// A connected peer's display name, or any full node ID in hex.
+    fn resolve_node(&self, name: &str) -> Option<[u8; 32]> {
+        self.peers.iter().find(|p| p.display_name.to_lowercase() == name.to_lowercase())
+            .map(|p| p.node_id)
+            .or_else(|| {
+                hex::decode(name).ok().filter(|b| b.len() == 32).map(|b| {
+                    let mut id = [0u8; 32];
+                    id.copy_from_slice(&b);
+                    id
+                })
+            })
+    }
+
+    /// Peer name if we know it, otherwise a short node ID.
+    fn node_label(&self, node_id: &[u8; 32]) -> String {
+        self.peers.iter().find(|p| &p.node_id == node_id)
+            .map(|p| p.display_name.clone())
+            .unwrap_or_else(|| hex::encode(&node_id[..4]))
+    }
+
     fn spawn_cmd<F>(&self, f: F)
     where
         F: std::future::Future<Output = Result<()>> + Send + 'static,
@@ -439,12 +607,136 @@ impl MeshApp {
                     self.push_system("Usage: /send <peer> <filepath>".into());
                 }
             }
+            "/sendgroup" => {
+                if parts.len() >= 3 {
+                    let group_name = parts[1].to_string();
+                    let path = parts[2].to_string();
+                    self.push_system(format!("Offering file to group {}: {}", group_name, path));
+                    let h = self.handle.clone();
+                    self.spawn_cmd(async move { h.send_file_to_group(&group_name, &path).await });
+                } else {
+                    self.push_system("Usage: /sendgroup <group> <filepath>".into());
+                }
+            }
+            "/sendmany" => {
+                if parts.len() >= 3 {
+                    let name = parts[1];
+                    let paths: Vec<String> = parts[2].split(';')
+                        .map(|p| p.trim().to_string())
+                        .filter(|p| !p.is_empty())
+                        .collect();
+                    if let Some(peer) = self.peers.iter().find(|p| {
+                        p.display_name.to_lowercase() == name.to_lowercase()
+                            || hex::encode(&p.node_id[..4]) == name
+                    }) {
+                        let dest = peer.node_id;
+                        self.push_system(format!("Sending {} paths as one bundle", paths.len()));
+                        let h = self.handle.clone();
+                        self.spawn_cmd(async move { h.send_bundle(dest, paths).await });
+                    } else {
+                        self.push_system(format!("Unknown peer: {}", name));
+                    }
+                } else {
+                    self.push_system("Usage: /sendmany <peer> <path1;path2;...>".into());
+                }
+            }
+            "/sendall" => {
+                if parts.len() >= 2 {
+                    let path = parts[1].to_string();
+                    self.push_system(format!("Offering file to the whole mesh: {}", path));
+                    let h = self.handle.clone();
+                    self.spawn_cmd(async move { h.send_file_broadcast(&path).await });
+                } else {
+                    self.push_system("Usage: /sendall <filepath>".into());
+                }
+            }
+            "/fetch" => {
+                let hash = parts.get(1).and_then(|h| hex::decode(h).ok()).filter(|b| b.len() == 32);
+                if let Some(bytes) = hash {
+                    let mut content_hash = [0u8; 32];
+                    content_hash.copy_from_slice(&bytes);
+                    self.push_system("Looking for file in the mesh...".into());
+                    let h = self.handle.clone();
+                    self.spawn_cmd(async move { h.fetch_file(content_hash).await });
+                } else {
+                    self.push_system("Usage: /fetch <sha256 hex>".into());
+                }
+            }
+            "/route" => {
+                if let Some(node_id) = parts.get(1).and_then(|name| self.resolve_node(name)) {
+                    self.push_system(format!("Looking for a route to {}...", hex::encode(&node_id[..4])));
+                    let h = self.handle.clone();
+                    self.spawn_cmd(async move { h.discover_route(node_id).await });
+                } else {
+                    self.push_system("Usage: /route <peer name | node id hex>".into());
+                }
+            }
+            "/ping" | "/trace" => {
+                let ping = parts[0] == "/ping";
+                if let Some(node_id) = parts.get(1).and_then(|name| self.resolve_node(name)) {
+                    let what = if ping { "Pinging" } else { "Tracing route to" };
+                    self.push_system(format!("{} {}...", what, hex::encode(&node_id[..4])));
+                    let h = self.handle.clone();
+                    if ping {
+                        self.spawn_cmd(async move { h.mesh_ping(node_id).await });
+                    } else {
+                        self.spawn_cmd(async move { h.traceroute(node_id).await });
+                    }
+                } else {
+                    self.push_system(format!("Usage: {} <peer name | node id hex>", parts[0]));
+                }
+            }
+            "/flood" => {
+                // "/flood gossip 0.6", "/flood counter 3", "/flood coverage", "/flood full"
+                let max_delay = std::time::Duration::from_millis(200);
+                let arg = parts.get(2).copied();
+                let strategy = match parts.get(1).map(|m| m.to_lowercase()).as_deref() {
+                    Some("full") => Some(FloodStrategy::Full),
+                    Some("gossip") => arg.and_then(|p| p.parse::<f32>().ok())
+                        .filter(|p| (0.0..=1.0).contains(p))
+                        .map(|probability| FloodStrategy::Gossip { probability }),
+                    Some("counter") => Some(FloodStrategy::Counter {
+                        threshold: arg.and_then(|k| k.parse().ok()).unwrap_or(3),
+                        max_delay,
+                    }),
+                    Some("coverage") => Some(FloodStrategy::NeighborCoverage { max_delay }),
+                    _ => None,
+                };
+                if let Some(strategy) = strategy {
+                    self.push_system(format!("Flood strategy: {:?}", strategy));
+                    let h = self.handle.clone();
+                    self.spawn_cmd(async move { h.set_flood_strategy(strategy).await });
+                } else {
+                    self.push_system("Usage: /flood full | gossip <0.0-1.0> | counter [k] | coverage".into());
+                }
+            }
             "/accept" => {
-                if let Some(f) = self.files.iter().rev().find(|f| f.incoming && !f.done && f.progress == 0) {
+                // "/accept 0,2" picks entries of a bundle offer
+                let entries: Option<Vec<u32>> = parts.get(1)
+                    .map(|list| list.split(',').filter_map(|i| i.trim().parse().ok()).collect());
+                if let Some(f) = self.files.iter().rev().find(|f| f.incoming && !f.done && f.failed.is_none() && f.progress == 0) {
                     let file_id = f.file_id;
                     let h = self.handle.clone();
                     self.push_system("File transfer accepted".into());
-                    self.spawn_cmd(async move { h.accept_file(file_id).await });
+                    self.pending_file_offer = None;
+                    match entries {
+                        Some(entries) if !entries.is_empty() => {
+                            self.spawn_cmd(async move { h.accept_file_entries(file_id, entries).await });
+                        }
+                        _ => self.spawn_cmd(async move { h.accept_file(file_id).await }),
+                    }
+                } else {
+                    self.push_system("No pending file offers".into());
+                }
+            }
+            "/decline" => {
+                if let Some(f) = self.files.iter_mut().rev().find(|f| f.incoming && !f.done && f.failed.is_none() && f.progress == 0) {
+                    let file_id = f.file_id;
+                    f.failed = Some("Declined".into());
+                    let h = self.handle.clone();
+                    self.pending_file_offer = None;
+                    self.push_system("File offer declined".into());
+                    self.spawn_cmd(async move { h.decline_file(file_id).await });
                 } else {
                     self.push_system("No pending file offers".into());
                 }
@@ -487,6 +779,11 @@ impl MeshApp {
                 self.spawn_cmd(async move { h.get_stats().await });
                 self.active_tab = Tab::Settings;
             }
+            "/map" => {
+                let h = self.handle.clone();
+                self.spawn_cmd(async move { h.get_topology().await });
+                self.active_tab = Tab::Settings;
+            }
             "/peers" => {
                 self.active_tab = Tab::Peers;
             }
@@ -639,7 +936,12 @@ impl MeshApp {
                 self.push_system("Commands:".into());
                 self.push_system("  /dm <name> <msg>       - Direct message".into());
                 self.push_system("  /send <peer> <path>    - Send file".into());
-                self.push_system("  /accept                - Accept file offer".into());
+                self.push_system("  /sendgroup <g> <path>  - Send file to a group".into());
+                self.push_system("  /sendmany <peer> <p1;p2> - Send files/folders as one bundle".into());
+                self.push_system("  /sendall <path>        - Send file to everyone".into());
+                self.push_system("  /fetch <sha256>        - Download a file by hash".into());
+                self.push_system("  /accept [0,2,..]       - Accept file offer (or some bundle entries)".into());
+                self.push_system("  /decline               - Decline file offer".into());
                 self.push_system("  /voice <peer> <path>   - Send voice file".into());
                 self.push_system("  /call <peer>           - Start voice call".into());
                 self.push_system("  /endcall               - End voice call".into());
@@ -651,7 +953,12 @@ impl MeshApp {
                 self.push_system("  /resource <cat> <u> <d>- Resource request".into());
                 self.push_system("  /checkin [status] [msg]- Safety check-in".into());
                 self.push_system("  /disappear <s> <msg>   - Disappearing message".into());
+                self.push_system("  /route <peer|id>       - Find a route to a node".into());
+                self.push_system("  /ping <peer|id>        - Ping a node through the mesh".into());
+                self.push_system("  /trace <peer|id>       - Show each hop to a node".into());
+                self.push_system("  /flood <mode> [arg]    - Broadcast relay strategy".into());
                 self.push_system("  /stats                 - Show mesh stats".into());
+                self.push_system("  /map                   - Show the mesh map".into());
                 self.push_system("  /peers                 - Show peer list".into());
                 self.push_system("  /nuke                  - Destroy identity & exit".into());
             }
@@ -1052,6 +1359,13 @@ impl eframe::App for MeshApp {
         // Check file picker results
         self.check_file_pick_result();
 
+        // Keep link quality fresh while someone is looking at it
+        if (self.active_tab == Tab::Peers || self.in_call.is_some()) && self.last_link_refresh.elapsed().as_secs() >= 5 {
+            self.last_link_refresh = Instant::now();
+            let h = self.handle.clone();
+            self.spawn_cmd(async move { h.get_peers().await });
+        }
+
         // Expire typing indicators (5 second timeout)
         self.typing_peers.retain(|(_, _, t)| t.elapsed().as_secs() < 5);
 
@@ -1225,9 +1539,19 @@ impl MeshApp {
             ui.add_space(4.0);
 
             // Call banner in chat
-            if let Some((_, name)) = self.in_call.clone() {
+            if let Some((peer_id, name)) = self.in_call.clone() {
+                let peer = self.peers.iter().find(|p| p.node_id == peer_id);
+                let quality = peer.and_then(|p| p.quality);
+                let udp = peer.is_some_and(|p| p.udp);
                 ui.horizontal(|ui| {
                     ui.label(RichText::new(format!("In call with {}", name)).color(ACCENT_MAGENTA).strong());
+                    // Tells the user whether choppy audio is the link's fault
+                    if let Some(q) = quality {
+                        ui.label(RichText::new(link_summary(&q)).color(link_color(&q)).font(FontId::proportional(11.0)));
+                    }
+                    if udp {
+                        ui.label(RichText::new("UDP").color(TEXT_MUTED).font(FontId::proportional(11.0)));
+                    }
                     if ui.button(RichText::new("End Call").color(ACCENT_RED).strong()).clicked() {
                         self.end_call();
                     }
@@ -1481,10 +1805,10 @@ impl MeshApp {
                 let text_height = 20.0;
 
                 // Collect peer data before building the table
-                let peer_rows: Vec<(usize, String, String, bool, String)> = self.peers.iter().enumerate()
+                let peer_rows: Vec<(usize, String, String, bool, String, Option<LinkQuality>)> = self.peers.iter().enumerate()
                     .map(|(i, p)| {
                         let short = hex::encode(&p.node_id[..4]);
-                        (i, p.display_name.clone(), short, p.is_gateway, p.bio.clone())
+                        (i, p.display_name.clone(), short, p.is_gateway, p.bio.clone(), p.quality)
                     })
                     .collect();
 
@@ -1500,16 +1824,18 @@ impl MeshApp {
                     .column(Column::initial(80.0).at_least(60.0))
                     .column(Column::initial(40.0).at_least(30.0))
                     .column(Column::initial(60.0).at_least(40.0))
+                    .column(Column::initial(170.0).at_least(100.0))
                     .column(Column::remainder().at_least(80.0))
                     .header(text_height, |mut header| {
                         header.col(|ui| { ui.label(RichText::new("Name").color(ACCENT_YELLOW).strong()); });
                         header.col(|ui| { ui.label(RichText::new("ID").color(ACCENT_YELLOW).strong()); });
                         header.col(|ui| { ui.label(RichText::new("GW").color(ACCENT_YELLOW).strong()); });
                         header.col(|ui| { ui.label(RichText::new("Call").color(ACCENT_YELLOW).strong()); });
+                        header.col(|ui| { ui.label(RichText::new("Link").color(ACCENT_YELLOW).strong()); });
                         header.col(|ui| { ui.label(RichText::new("Bio").color(ACCENT_YELLOW).strong()); });
                     })
                     .body(|mut body| {
-                        for (i, name, short, is_gw, bio) in &peer_rows {
+                        for (i, name, short, is_gw, bio, quality) in &peer_rows {
                             body.row(text_height, |mut row| {
                                 row.col(|ui| {
                                     let color = if *is_gw { ACCENT_YELLOW } else { ACCENT_GREEN };
@@ -1530,6 +1856,12 @@ impl MeshApp {
                                         call_peer = Some((self.peers[*i].node_id, name.clone()));
                                     }
                                 });
+                                row.col(|ui| {
+                                    match quality {
+                                        Some(q) => { ui.label(RichText::new(link_summary(q)).color(link_color(q))); }
+                                        None => { ui.label(RichText::new("-").color(TEXT_MUTED)); }
+                                    }
+                                });
                                 row.col(|ui| { ui.label(RichText::new(bio).color(TEXT_MUTED)); });
                             });
                         }
@@ -1556,10 +1888,17 @@ impl MeshApp {
                 let available_height = ui.available_height();
                 let text_height = 22.0;
 
-                let file_rows: Vec<(String, String, String, u8, bool)> = self.files.iter()
+                let file_rows: Vec<(String, String, String, u8, String, bool, Option<String>)> = self.files.iter()
                     .map(|f| {
                         let dir = if f.incoming { "IN" } else { "OUT" };
-                        (dir.to_string(), f.filename.clone(), format_size(f.size), f.progress, f.done)
+                        let mut rate = String::new();
+                        if f.bytes_per_sec > 0 {
+                            rate = format!(" - {}/s", format_size(f.bytes_per_sec));
+                            if let Some(eta) = f.eta_secs {
+                                rate.push_str(&format!(", {}s left", eta));
+                            }
+                        }
+                        (dir.to_string(), f.filename.clone(), format_size(f.size), f.progress, rate, f.done, f.failed.clone())
                     })
                     .collect();
 
@@ -1579,7 +1918,7 @@ impl MeshApp {
                         header.col(|ui| { ui.label(RichText::new("Status").color(ACCENT_YELLOW).strong()); });
                     })
                     .body(|mut body| {
-                        for (dir, filename, size, progress, done) in &file_rows {
+                        for (dir, filename, size, progress, rate, done, failed) in &file_rows {
                             body.row(text_height, |mut row| {
                                 row.col(|ui| {
                                     let color = if dir == "IN" { ACCENT_GREEN } else { ACCENT_BLUE };
@@ -1590,10 +1929,12 @@ impl MeshApp {
                                 row.col(|ui| {
                                     if *done {
                                         ui.label(RichText::new("Complete").color(ACCENT_GREEN));
+                                    } else if let Some(reason) = failed {
+                                        ui.label(RichText::new(reason).color(ACCENT_RED));
                                     } else {
                                         ui.add(
                                             egui::ProgressBar::new(*progress as f32 / 100.0)
-                                                .text(format!("{}%", progress))
+                                                .text(format!("{}%{}", progress, rate))
                                                 .fill(ACCENT_CYAN),
                                         );
                                     }
@@ -1881,13 +2222,17 @@ impl MeshApp {
                             stat(ui, "Messages Received:", format!("{}", self.stats.messages_received));
                             stat(ui, "Unique Nodes Seen:", format!("{}", self.stats.unique_nodes_seen));
                             stat(ui, "Average Hops:", format!("{:.1}", self.stats.avg_hops));
+                            stat(ui, "Broadcasts Suppressed:", format!("{}", self.stats.broadcasts_suppressed));
+                            stat(ui, "Held for Offline Nodes:", format!("{}", self.stats.messages_held));
+                            stat(ui, "Avg Link RTT:", format!("{:.0} ms (±{:.0} ms)", self.stats.avg_rtt_ms, self.stats.avg_jitter_ms));
+                            stat(ui, "Avg Link Loss:", format!("{:.0}% (ETX {:.2})", self.stats.avg_loss * 100.0, self.stats.avg_etx));
 
                             ui.label(RichText::new("").color(TEXT_MUTED));
                             ui.label(RichText::new("").color(TEXT_MUTED));
                             ui.end_row();
 
                             stat(ui, "Gateway:", self.gateway_name.as_deref().unwrap_or("None").to_string());
-                            stat(ui, "Active Files:", format!("{}", self.files.iter().filter(|f| !f.done).count()));
+                            stat(ui, "Active Files:", format!("{}", self.files.iter().filter(|f| !f.done && f.failed.is_none()).count()));
                         });
 
                     ui.add_space(8.0);
@@ -1899,6 +2244,58 @@ impl MeshApp {
                     ui.add_space(16.0);
                     ui.separator();
 
+                    // --- Mesh Map Section ---
+                    ui.add_space(8.0);
+                    ui.label(RichText::new("Mesh Map").color(ACCENT_CYAN).strong().font(FontId::proportional(16.0)));
+                    ui.add_space(8.0);
+
+                    let names: HashMap<[u8; 32], String> = self.topology.nodes.iter()
+                        .map(|n| (n.node_id, n.label()))
+                        .collect();
+                    let reporting = self.topology.nodes.iter().filter(|n| n.reported).count();
+                    ui.label(RichText::new(format!(
+                        "{} nodes ({} reporting), {} links",
+                        self.topology.nodes.len(), reporting, self.topology.links.len(),
+                    )).color(TEXT_PRIMARY));
+
+                    egui::Grid::new("topology_grid")
+                        .num_columns(3)
+                        .spacing([20.0, 4.0])
+                        .striped(true)
+                        .show(ui, |ui| {
+                            for link in &self.topology.links {
+                                let pct = link.quality_pct();
+                                let color = match pct {
+                                    70.. => ACCENT_GREEN,
+                                    40..=69 => ACCENT_YELLOW,
+                                    _ => ACCENT_RED,
+                                };
+                                let name = |id: &[u8; 32]| names.get(id).cloned().unwrap_or_else(|| hex::encode(&id[..4]));
+                                ui.label(RichText::new(name(&link.a)).color(TEXT_PRIMARY));
+                                ui.label(RichText::new(name(&link.b)).color(TEXT_PRIMARY));
+                                let one_sided = if link.both_ends { "" } else { " (one side)" };
+                                ui.label(RichText::new(format!("{}%{}", pct, one_sided)).color(color));
+                                ui.end_row();
+                            }
+                        });
+
+                    ui.add_space(8.0);
+                    ui.horizontal(|ui| {
+                        if ui.button(RichText::new("Refresh Map").color(ACCENT_CYAN)).clicked() {
+                            let h = self.handle.clone();
+                            self.spawn_cmd(async move { h.get_topology().await });
+                        }
+                        if ui.button(RichText::new("Export JSON").color(ACCENT_BLUE)).clicked() {
+                            export_topology("mesh-map.json", self.topology.to_json());
+                        }
+                        if ui.button(RichText::new("Export DOT").color(ACCENT_BLUE)).clicked() {
+                            export_topology("mesh-map.dot", self.topology.to_dot());
+                        }
+                    });
+
+                    ui.add_space(16.0);
+                    ui.separator();
+
                     // --- Groups Section ---
                     ui.add_space(8.0);
                     ui.label(RichText::new("Groups").color(ACCENT_CYAN).strong().font(FontId::proportional(16.0)));
@@ -1951,9 +2348,34 @@ impl MeshApp {
                         .collect();
                     for (peer_id, peer_name) in &peer_data_for_verify {
                         let safety = NodeIdentity::safety_number(&self.our_node_id, peer_id);
+                        let mut verify: Option<bool> = None;
+                        let mut favorite: Option<bool> = None;
                         ui.collapsing(RichText::new(format!("Verify: {}", peer_name)).color(ACCENT_YELLOW), |ui| {
                             ui.label(RichText::new(&safety).color(TEXT_PRIMARY).font(FontId::monospace(12.0)));
+                            ui.horizontal(|ui| {
+                                if ui.button("Mark verified").clicked() {
+                                    verify = Some(true);
+                                }
+                                if ui.button("Unverify").clicked() {
+                                    verify = Some(false);
+                                }
+                                if ui.button("Favorite").clicked() {
+                                    favorite = Some(true);
+                                }
+                                if ui.button("Unfavorite").clicked() {
+                                    favorite = Some(false);
+                                }
+                            });
                         });
+                        let node_id = *peer_id;
+                        if let Some(verified) = verify {
+                            let h = self.handle.clone();
+                            self.spawn_cmd(async move { h.set_verified(node_id, verified).await });
+                        }
+                        if let Some(fav) = favorite {
+                            let h = self.handle.clone();
+                            self.spawn_cmd(async move { h.set_favorite(node_id, fav).await });
+                        }
                     }
 
                     if peer_data_for_verify.is_empty() {
@@ -2027,6 +2449,18 @@ impl MeshApp {
         let (file_id, sender, filename, size) = self.pending_file_offer.clone().unwrap();
         let size_str = format_size(size);
 
+        if self.offer_preview_tex.is_none() {
+            if let Some(bytes) = self.offer_preview.take() {
+                if let Ok(img) = image::load_from_memory(&bytes) {
+                    let rgba = img.to_rgba8();
+                    let size = [rgba.width() as usize, rgba.height() as usize];
+                    let color = egui::ColorImage::from_rgba_unmultiplied(size, rgba.as_raw());
+                    self.offer_preview_tex = Some(ctx.load_texture("file_offer_preview", color, Default::default()));
+                }
+            }
+        }
+        let preview_tex = self.offer_preview_tex.clone();
+
         egui::Window::new("Incoming File")
             .collapsible(false)
             .resizable(false)
@@ -2047,6 +2481,19 @@ impl MeshApp {
                     ui.label(RichText::new("Size:").color(ACCENT_YELLOW));
                     ui.label(RichText::new(&size_str).color(TEXT_PRIMARY));
                 });
+                if let Some(tex) = &preview_tex {
+                    ui.add_space(8.0);
+                    ui.image((tex.id(), tex.size_vec2()));
+                }
+                if !self.offer_entries.is_empty() {
+                    ui.add_space(8.0);
+                    egui::ScrollArea::vertical().max_height(200.0).show(ui, |ui| {
+                        for (entry, selected) in self.offer_entries.iter_mut() {
+                            let label = format!("{} ({})", entry.path, format_size(entry.size));
+                            ui.checkbox(selected, RichText::new(label).color(TEXT_PRIMARY));
+                        }
+                    });
+                }
                 ui.add_space(12.0);
                 ui.horizontal(|ui| {
                     let accept_btn = egui::Button::new(
@@ -2057,7 +2504,16 @@ impl MeshApp {
                         self.pending_file_offer = None;
                         self.push_system(format!("Accepting file: {}", filename));
                         let h = self.handle.clone();
-                        self.spawn_cmd(async move { h.accept_file(file_id).await });
+                        let selected: Vec<u32> = self.offer_entries.iter().enumerate()
+                            .filter(|(_, (_, on))| *on)
+                            .map(|(i, _)| i as u32)
+                            .collect();
+                        if selected.is_empty() || selected.len() == self.offer_entries.len() {
+                            self.spawn_cmd(async move { h.accept_file(file_id).await });
+                        } else {
+                            self.spawn_cmd(async move { h.accept_file_entries(file_id, selected).await });
+                        }
+                        self.offer_entries.clear();
                     }
                     ui.add_space(20.0);
                     let decline_btn = egui::Button::new(
@@ -2065,7 +2521,12 @@ impl MeshApp {
                     );
                     if ui.add(decline_btn).clicked() {
                         self.pending_file_offer = None;
+                        if let Some(f) = self.files.iter_mut().find(|f| f.file_id == file_id) {
+                            f.failed = Some("Declined".into());
+                        }
                         self.push_system(format!("Declined file: {}", filename));
+                        let h = self.handle.clone();
+                        self.spawn_cmd(async move { h.decline_file(file_id).await });
                     }
                 });
             });
@@ -2120,6 +2581,36 @@ fn format_size(bytes: u64) -> String {
     }
 }
 
+/// "45 ms ±6, 3% loss" (RTT appears once a timed pong has come back).
+fn link_summary(q: &LinkQuality) -> String {
+    let loss = format!("{:.0}% loss", q.loss * 100.0);
+    match q.rtt_ms {
+        Some(rtt) => format!("{:.0} ms ±{:.0}, {}", rtt, q.jitter_ms, loss),
+        None => loss,
+    }
+}
+
+fn link_color(q: &LinkQuality) -> Color32 {
+    if q.etx < 1.3 {
+        ACCENT_GREEN
+    } else if q.etx < 2.0 {
+        ACCENT_YELLOW
+    } else {
+        ACCENT_RED
+    }
+}
+
+/// Ask where to save a mesh map export and write it there (off the UI thread).
+fn export_topology(default_name: &'static str, contents: String) {
+    std::thread::spawn(move || {
+        if let Some(path) = rfd::FileDialog::new().set_file_name(default_name).save_file() {
+            if let Err(e) = std::fs::write(&path, contents) {
+                tracing::error!("Failed to export mesh map to {}: {}", path.display(), e);
+            }
+        }
+    });
+}
+
 fn whoami() -> String {
     std::env::var("USERNAME")
         .or_else(|_| std::env::var("USER"))
@@ -2158,8 +2649,10 @@ fn resample_audio(mono_16k: &[i16], src_rate: u32, dst_rate: u32, dst_channels:
 // ---------------------------------------------------------------------------
 
 fn main() -> Result<()> {
-    // Parse args
-    let args: Vec<String> = std::env::args().collect();
+    // Parse args. `--web` anywhere also serves the browser client, which
+    // anyone on the network can then join through
+    let web = std::env::args().any(|arg| arg == "--web");
+    let args: Vec<String> = std::env::args().filter(|arg| arg != "--web").collect();
     let name = args.get(1).cloned().unwrap_or_else(|| {
         let hostname = whoami();
         format!("mesh-{}", &hostname[..hostname.len().min(8)])
@@ -2168,6 +2661,23 @@ fn main() -> Result<()> {
         .get(2)
         .and_then(|s| s.parse().ok())
         .unwrap_or(7332);
+    // Optional serial-attached radio: device path, then baud rate
+    let serial = args.get(3).map(|path| SerialConfig {
+        path: path.clone(),
+        baud: args.get(4).and_then(|s| s.parse().ok()).unwrap_or(9600),
+        ..Default::default()
+    });
+
+    // The web client is served at the same offset from the node's port as
+    // the defaults, so several nodes on one machine don't collide
+    let web_offset = WebSocketTransport::default_port() - TcpTransport::default_port();
+    let web_port = if web {
+        Some(port.checked_add(web_offset).ok_or_else(|| {
+            anyhow::anyhow!("Port {} leaves no room for the web client {} above it", port, web_offset)
+        })?)
+    } else {
+        None
+    };
 
     // Build tokio runtime manually (eframe owns the main thread)
     let rt = Arc::new(tokio::runtime::Runtime::new()?);
@@ -2179,6 +2689,17 @@ fn main() -> Result<()> {
             listen_port: port,
             key_path: std::path::PathBuf::from(format!("mesh_identity_{}.key", port)),
             data_dir: None,
+            transports: {
+                let mut transports: Vec<Arc<dyn Transport>> = vec![Arc::new(TcpTransport::new(port))];
+                if let Some(web_port) = web_port {
+                    transports.push(Arc::new(WebSocketTransport::new(web_port)));
+                }
+                if let Some(serial) = serial {
+                    transports.push(Arc::new(SerialTransport::new(serial)));
+                }
+                transports
+            },
+            ..Default::default()
         };
         start_mesh_node(config).await
     })?;
@@ -2219,12 +2740,17 @@ fn main() -> Result<()> {
         peers: Vec::new(),
         files: Vec::new(),
         stats: MeshStats::default(),
+        topology: TopologyGraph::default(),
+        last_link_refresh: Instant::now(),
         gateway_name: None,
         input: String::new(),
         active_tab: Tab::Chat,
         dm_target: None,
         show_nuke_confirm: false,
         pending_file_offer: None,
+        offer_preview: None,
+        offer_preview_tex: None,
+        offer_entries: Vec::new(),
         should_quit: false,
         settings_name: name.clone(),
         bio: String::new(),